@@ -0,0 +1,80 @@
+#ifndef HS110_H
+#define HS110_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define HS110_OK 0
+
+#define HS110_ERR_NULL_POINTER -1
+
+#define HS110_ERR_COMMAND_FAILED -2
+
+#define HS110_ERR_NO_EMETER -3
+
+typedef struct Hs110Device Hs110Device;
+
+/**
+ * Meter reading normalized to V, A, W and kWh regardless of the hardware revision.
+ */
+typedef struct Hs110Realtime {
+  double voltage_v;
+  double current_a;
+  double power_w;
+  double total_kwh;
+} Hs110Realtime;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a device for `address`, a host with an optional ":port" (9999 if omitted). Returns NULL if `address` is NULL or not UTF-8.
+ * The returned handle must be released with `hs110_device_free`.
+ *
+ * # Safety
+ *
+ * `address` must be NULL or point to a NUL-terminated string.
+ */
+struct Hs110Device *hs110_device_new(const char *address);
+
+/**
+ * # Safety
+ *
+ * `device` must be NULL or a handle returned by `hs110_device_new`.
+ */
+int hs110_device_on(const struct Hs110Device *device);
+
+/**
+ * # Safety
+ *
+ * `device` must be NULL or a handle returned by `hs110_device_new`.
+ */
+int hs110_device_off(const struct Hs110Device *device);
+
+/**
+ * Reads the meter into `out`. `out` is left untouched unless `HS110_OK` is returned.
+ *
+ * # Safety
+ *
+ * `device` must be NULL or a handle returned by `hs110_device_new`, and `out` must be NULL or
+ * point to writable memory for one `Hs110Realtime`.
+ */
+int hs110_device_get_realtime(const struct Hs110Device *device, struct Hs110Realtime *out);
+
+/**
+ * # Safety
+ *
+ * `device` must be NULL or a handle returned by `hs110_device_new` that has not been freed yet.
+ */
+void hs110_device_free(struct Hs110Device *device);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* HS110_H */
@@ -0,0 +1,219 @@
+/*
+ * Flags consumption that is unusual for a device at that time of day, such as a
+ * freezer compressor that never cycles off. A baseline is learnt per device and
+ * hour of the day (local time) as the rolling median and median absolute
+ * deviation of the power samples seen in that hour:
+ *
+ *   let events = AnomalyDetector::new(4.0).attach(watcher.spawn().into());
+ *   engine.run(events);
+ *
+ * A sample is out of line when it is more than `factor` scaled MADs and
+ * `min_deviation_w` away from the median. An alert is raised once `sustain`
+ * samples in a row are, and not again until the device is back to normal.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use chrono::{DateTime, Local, Timelike};
+
+use crate::events::{Alert, Event};
+
+/// Makes the MAD comparable to a standard deviation for normally distributed samples.
+const MAD_SCALE: f64 = 1.4826;
+
+#[derive(Clone, Debug, Default)]
+struct Baseline {
+    hours: [VecDeque<f64>; 24],
+    outliers: usize,
+    alerted: bool,
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+pub struct AnomalyDetector {
+    factor: f64,
+    min_deviation_w: f64,
+    window: usize,
+    min_samples: usize,
+    sustain: usize,
+    baselines: HashMap<String, Baseline>,
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> AnomalyDetector {
+        AnomalyDetector::new(4.0)
+    }
+}
+
+impl AnomalyDetector {
+    pub fn new(factor: f64) -> AnomalyDetector {
+        AnomalyDetector {
+            factor,
+            min_deviation_w: 5.0,
+            window: 7 * 60,
+            min_samples: 30,
+            sustain: 3,
+            baselines: HashMap::new(),
+        }
+    }
+
+    /// Deviations smaller than this are never anomalous, however steady the baseline.
+    pub fn min_deviation(&mut self, watts: f64) -> &mut AnomalyDetector {
+        self.min_deviation_w = watts;
+        self
+    }
+
+    /// Number of samples kept per hour of the day.
+    pub fn window(&mut self, samples: usize) -> &mut AnomalyDetector {
+        self.window = samples.max(1);
+        self
+    }
+
+    /// Samples needed in an hour before it is judged at all.
+    pub fn min_samples(&mut self, samples: usize) -> &mut AnomalyDetector {
+        self.min_samples = samples.max(1);
+        self
+    }
+
+    /// Consecutive out of line samples needed to raise an alert.
+    pub fn sustain(&mut self, samples: usize) -> &mut AnomalyDetector {
+        self.sustain = samples.max(1);
+        self
+    }
+
+    /// Judges a sample against the baseline, then adds it to the baseline.
+    pub fn observe(&mut self, device: &str, at: DateTime<Local>, power_w: f64) -> Option<Alert> {
+        let baseline = self.baselines.entry(String::from(device)).or_default();
+        let samples = &mut baseline.hours[at.hour() as usize];
+
+        let mut alert = None;
+        if samples.len() >= self.min_samples {
+            let mut values: Vec<f64> = samples.iter().copied().collect();
+            let expected = median(&mut values);
+            for v in values.iter_mut() {
+                *v = (*v - expected).abs();
+            }
+            let spread = MAD_SCALE * median(&mut values);
+
+            let distance = (power_w - expected).abs();
+            if distance > self.min_deviation_w && distance > self.factor * spread {
+                baseline.outliers += 1;
+                if baseline.outliers >= self.sustain && !baseline.alerted {
+                    baseline.alerted = true;
+                    alert = Some(Alert::PowerAnomaly {
+                        power_w,
+                        baseline_w: expected,
+                        deviation: if spread > 0.0 { distance / spread } else { f64::INFINITY },
+                    });
+                }
+            } else {
+                baseline.outliers = 0;
+                baseline.alerted = false;
+            }
+        }
+
+        if samples.len() >= self.window {
+            samples.pop_front();
+        }
+        samples.push_back(power_w);
+        alert
+    }
+
+    /// Learns from power samples, returning an `AlertRaised` event when one is anomalous.
+    pub fn handle(&mut self, event: &Event) -> Option<Event> {
+        self.handle_at(event, Local::now())
+    }
+
+    pub fn handle_at(&mut self, event: &Event, now: DateTime<Local>) -> Option<Event> {
+        match event {
+            Event::PowerSample { device, reading } =>
+                self.observe(device, now, reading.power_w).map(|alert| Event::AlertRaised {
+                    device: device.clone(),
+                    alert,
+                }),
+            _ => None,
+        }
+    }
+
+    /// Passes `events` through, adding alerts right after the samples that raised them.
+    pub fn attach(mut self, events: Receiver<Event>) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for event in events {
+                let alert = self.handle(&event);
+                if tx.send(event).is_err() {
+                    return;
+                }
+                if let Some(alert) = alert {
+                    if tx.send(alert).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Local, TimeZone};
+    use crate::events::{Alert, Event};
+    use crate::reading::PowerReading;
+    use super::AnomalyDetector;
+
+    #[test]
+    fn test_compressor_stuck_on() {
+        let mut detector = AnomalyDetector::new(4.0);
+        detector.min_samples(20);
+        let start = Local.with_ymd_and_hms(2024, 6, 3, 14, 0, 0).unwrap();
+
+        // A freezer cycling between idle and the compressor running.
+        for m in 0..40u32 {
+            let power_w = if m.is_multiple_of(4) { 90.0 } else { 2.0 };
+            assert_eq!(detector.observe("freezer", start + Duration::minutes(m.into()), power_w), None);
+        }
+
+        // Running flat out at a time the baseline has never seen it.
+        let stuck = Local.with_ymd_and_hms(2024, 6, 4, 14, 0, 0).unwrap();
+        let alerts: Vec<_> = (0..6)
+            .filter_map(|m| detector.observe("freezer", stuck + Duration::minutes(m), 150.0))
+            .collect();
+        assert_eq!(alerts.len(), 1);
+        assert!(matches!(alerts[0], Alert::PowerAnomaly { power_w, baseline_w, .. }
+                         if power_w == 150.0 && baseline_w == 2.0));
+
+        // Other hours have no baseline yet.
+        let evening = Local.with_ymd_and_hms(2024, 6, 4, 20, 0, 0).unwrap();
+        assert_eq!(detector.observe("freezer", evening, 150.0), None);
+    }
+
+    #[test]
+    fn test_alerts_from_events() {
+        let mut detector = AnomalyDetector::new(3.0);
+        detector.min_samples(5).sustain(1);
+        let now = Local.with_ymd_and_hms(2024, 6, 3, 9, 0, 0).unwrap();
+        let sample = |power_w| Event::PowerSample {
+            device: String::from("tv"),
+            reading: PowerReading { power_w, ..PowerReading::default() },
+        };
+
+        for _ in 0..5 {
+            assert_eq!(detector.handle_at(&sample(0.5), now), None);
+        }
+        // Within the minimum deviation of a flat baseline.
+        assert_eq!(detector.handle_at(&sample(3.0), now), None);
+        assert!(matches!(detector.handle_at(&sample(80.0), now),
+                         Some(Event::AlertRaised { device, .. }) if device == "tv"));
+        assert_eq!(detector.handle_at(&Event::DeviceOnline { device: String::from("tv") }, now), None);
+    }
+}
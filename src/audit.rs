@@ -0,0 +1,243 @@
+/*
+ * Opt-in audit trail of the commands that change devices, for bridges shared by
+ * several people:
+ *
+ *   let log: SharedAuditSink = Arc::new(Mutex::new(JsonLines::new(File::create("audit.log")?)));
+ *   let rack = rack.audited(log.clone(), Some("alice"));
+ *
+ * Every mutating command (see `commands::is_read_only`) is recorded with when it
+ * was sent, by whom, to which device, and whether the device accepted it. Reads
+ * aren't recorded. Passwords, such as those for `netif.set_stainfo` and
+ * `cnCloud.bind`, are replaced with `REDACTED` before the command is recorded.
+ */
+
+use std::io::Write;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::TpLinkDevice;
+use crate::commands::is_read_only;
+use crate::protocol::{decrypt_payload, size_from_bytes};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub at: DateTime<Utc>,
+    pub actor: Option<String>,
+    /// The device's address.
+    pub device: String,
+    pub command: Value,
+    /// The transport error or the first non-zero `err_code`, `None` if the device accepted it.
+    pub error: Option<String>,
+}
+
+pub trait AuditSink: Send {
+    fn record(&mut self, record: &AuditRecord);
+}
+
+impl<F: FnMut(&AuditRecord) + Send> AuditSink for F {
+    fn record(&mut self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+impl AuditSink for Sender<AuditRecord> {
+    fn record(&mut self, record: &AuditRecord) {
+        let _ = self.send(record.clone());
+    }
+}
+
+/// Writes one JSON object per line, e.g. to an append-only file.
+pub struct JsonLines<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    pub fn new(out: W) -> JsonLines<W> {
+        JsonLines {
+            out,
+        }
+    }
+}
+
+impl<W: Write + Send> AuditSink for JsonLines<W> {
+    fn record(&mut self, record: &AuditRecord) {
+        if let Ok(line) = serde_json::to_string(record) {
+            let _ = writeln!(self.out, "{}", line);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+pub type SharedAuditSink = Arc<Mutex<dyn AuditSink>>;
+
+/// Keys whose values are never written to the audit trail.
+pub const SECRET_KEYS: &[&str] = &["password", "passwd", "pwd", "token"];
+
+pub const REDACTED: &str = "<redacted>";
+
+fn redact(command: &mut Value) {
+    if let Value::Object(fields) = command {
+        for (key, value) in fields.iter_mut() {
+            if SECRET_KEYS.contains(&key.as_str()) {
+                *value = Value::from(REDACTED);
+            } else {
+                redact(value);
+            }
+        }
+    }
+}
+
+fn first_error(response: &Value) -> Option<i64> {
+    match response {
+        Value::Object(fields) => fields.iter().find_map(|(key, value)| match value.as_i64() {
+            Some(code) if key == "err_code" && code != 0 => Some(code),
+            _ => first_error(value),
+        }),
+        _ => None,
+    }
+}
+
+pub(crate) fn check(response: &Result<Vec<u8>, PlugError>) -> Result<(), String> {
+    let frame = response.as_ref().map_err(|e| e.to_string())?;
+    if frame.len() < 4 || frame.len() < size_from_bytes(frame) + 4 {
+        return Err(String::from("Truncated response"));
+    }
+    let response: Value = serde_json::from_slice(&decrypt_payload(frame)).map_err(|e| e.to_string())?;
+    match first_error(&response) {
+        Some(code) => Err(format!("err_code {}", code)),
+        None => Ok(()),
+    }
+}
+
+pub struct Audited {
+    inner: Arc<dyn Transport>,
+    sink: SharedAuditSink,
+    actor: Option<String>,
+}
+
+impl Audited {
+    pub fn new(inner: Arc<dyn Transport>, sink: SharedAuditSink, actor: Option<&str>) -> Audited {
+        Audited {
+            inner,
+            sink,
+            actor: actor.map(String::from),
+        }
+    }
+}
+
+impl Transport for Audited {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let command = if frame.len() >= 4 && frame.len() >= size_from_bytes(frame) + 4 {
+            serde_json::from_slice::<Value>(&decrypt_payload(frame)).ok()
+        } else {
+            None
+        };
+        let response = self.inner.request(address, frame);
+
+        match command {
+            Some(mut command) if !is_read_only(&command) => {
+                redact(&mut command);
+                let record = AuditRecord {
+                    at: Utc::now(),
+                    actor: self.actor.clone(),
+                    device: String::from(address),
+                    command,
+                    error: check(&response).err(),
+                };
+                if let Ok(mut sink) = self.sink.lock() {
+                    sink.record(&record);
+                }
+            }
+            _ => {}
+        }
+        response
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose mutating commands are recorded in `sink`.
+    pub fn audited(&self, sink: SharedAuditSink, actor: Option<&str>) -> TpLinkDevice {
+        self.with_inner(Arc::new(Audited::new(self.transport.clone(), sink, actor)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use serde_json::Value;
+    use crate::TpLinkDevice;
+    use crate::commands;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::{AuditRecord, JsonLines, SharedAuditSink, REDACTED};
+
+    fn plug() -> TpLinkDevice {
+        let transport = |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let response = if request["system"].get("reboot").is_some() {
+                r#"{"system":{"reboot":{"err_code":-1,"err_msg":"busy"}}}"#
+            } else {
+                r#"{"system":{"set_relay_state":{"err_code":0}}}"#
+            };
+            Ok(encrypt_payload(response.as_bytes().to_vec()))
+        };
+        TpLinkDevice::with_transport("192.168.1.20:9999", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_records_writes_only() {
+        let (tx, rx) = mpsc::channel::<AuditRecord>();
+        let sink: SharedAuditSink = Arc::new(Mutex::new(tx));
+        let device = plug().audited(sink, Some("alice"));
+
+        device.off().unwrap();
+        let _ = device.get_meter_info();
+        let _ = device.reboot();
+
+        let records: Vec<_> = rx.try_iter().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].command, commands::set_relay_state(0));
+        assert_eq!((records[0].actor.as_deref(), records[0].device.as_str()),
+                   (Some("alice"), "192.168.1.20:9999"));
+        assert_eq!(records[0].error, None);
+        assert_eq!(records[1].error.as_deref(), Some("err_code -1"));
+    }
+
+    #[test]
+    fn test_redacts_secrets() {
+        let (tx, rx) = mpsc::channel::<AuditRecord>();
+        let device = plug().audited(Arc::new(Mutex::new(tx)), None);
+
+        device.connect_to_ap("home", "hunter2").unwrap();
+        device.connect_to_cloud("alice@example.com", "s3cret").unwrap();
+
+        let records: Vec<_> = rx.try_iter().collect();
+        let wifi = &records[0].command["netif"]["set_stainfo"];
+        assert_eq!((wifi["ssid"].as_str(), wifi["password"].as_str()), (Some("home"), Some(REDACTED)));
+        let cloud = &records[1].command["cnCloud"]["bind"];
+        assert_eq!((cloud["username"].as_str(), cloud["password"].as_str()),
+                   (Some("alice@example.com"), Some(REDACTED)));
+        assert!(!serde_json::to_string(&records).unwrap().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let out = Arc::new(Mutex::new(JsonLines::new(Vec::new())));
+        plug().audited(out.clone(), None).on().unwrap();
+
+        let text = String::from_utf8(out.lock().unwrap().out.clone()).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.contains(r#""error":null"#), "{}", text);
+    }
+}
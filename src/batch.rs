@@ -0,0 +1,576 @@
+/*
+ * Writes samples to a time-series database in batches from a background
+ * thread, so the poller never waits on the database:
+ *
+ *   let influx = InfluxHttp::new("http://influx:8086/api/v2/write?org=home&bucket=power").token(token);
+ *   scheduler.sink(BatchSink::new(influx).batch_size(500).flush_every(Duration::from_secs(10)));
+ *
+ * Samples are encoded as they arrive and queued. The writer sends a batch once
+ * `batch_size` records are waiting or the oldest has waited `flush_every`. If
+ * sending fails the batch goes back to the front of the queue and is tried
+ * again after a backoff that doubles up to `max_backoff`, so an outage loses
+ * nothing as long as the queue has room. When it doesn't, `Overflow` decides:
+ * drop the oldest records, or make the poller wait. Either way `stats` counts
+ * what was dropped and every failed attempt, with the last error. A batch the
+ * database refuses outright, such as malformed lines, is dropped rather than
+ * retried for ever. Dropping the sink sends what is left, once.
+ *
+ * `InfluxHttp` and `Pushgateway`, with the `push` feature, write to InfluxDB's
+ * HTTP API and to a Prometheus Pushgateway.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::metrics::label;
+use crate::scheduler::Sample;
+use crate::sink::Sink;
+use crate::types::PlugError;
+
+/// One sample, encoded for the destination.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub device: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// Worth trying again later, e.g. the database can't be reached.
+    Retry(PlugError),
+    /// The database won't take these records however often they are sent.
+    Reject(PlugError),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Retry(e) => write!(f, "{}", e),
+            SendError::Reject(e) => write!(f, "rejected: {}", e),
+        }
+    }
+}
+
+impl From<PlugError> for SendError {
+    fn from(e: PlugError) -> SendError {
+        SendError::Retry(e)
+    }
+}
+
+pub trait Destination: Send + Sync {
+    /// The sample as a record, or `None` to leave it out, e.g. a failed reading.
+    fn encode(&self, sample: &Sample) -> Option<Record>;
+
+    /// Stores the records, in order.
+    fn send(&self, records: &[Record]) -> Result<(), SendError>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Make room by dropping the oldest records.
+    #[default]
+    DropOldest,
+    /// Make the poller wait until there is room.
+    Block,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchStats {
+    /// Records waiting, including a batch being sent.
+    pub queued: usize,
+    pub sent: u64,
+    /// Records lost to a full queue or a rejected batch.
+    pub dropped: u64,
+    /// Failed attempts at sending a batch.
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    batch_size: usize,
+    flush_every: Duration,
+    capacity: usize,
+    overflow: Overflow,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+#[derive(Default)]
+struct Queue {
+    records: VecDeque<Record>,
+    /// Records taken by the writer and not yet sent.
+    sending: usize,
+    /// Someone is waiting in `flush`, so don't wait out `flush_every`.
+    flushing: bool,
+    closed: bool,
+    stats: BatchStats,
+}
+
+impl Queue {
+    /// Drops the oldest records beyond `capacity`.
+    fn trim(&mut self, capacity: usize) {
+        while self.records.len() > capacity {
+            self.records.pop_front();
+            self.stats.dropped += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub struct BatchSink {
+    destination: Arc<dyn Destination>,
+    config: Config,
+    shared: Arc<Shared>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl BatchSink {
+    pub fn new<D: Destination + 'static>(destination: D) -> BatchSink {
+        BatchSink {
+            destination: Arc::new(destination),
+            config: Config {
+                batch_size: 1000,
+                flush_every: Duration::from_secs(10),
+                capacity: 100_000,
+                overflow: Overflow::DropOldest,
+                backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(300),
+            },
+            shared: Arc::new(Shared::default()),
+            writer: None,
+        }
+    }
+
+    pub fn batch_size(mut self, records: usize) -> BatchSink {
+        self.config.batch_size = records.max(1);
+        self
+    }
+
+    pub fn flush_every(mut self, interval: Duration) -> BatchSink {
+        self.config.flush_every = interval;
+        self
+    }
+
+    /// Records kept waiting at most, not counting a batch being sent.
+    pub fn capacity(mut self, records: usize, overflow: Overflow) -> BatchSink {
+        self.config.capacity = records.max(1);
+        self.config.overflow = overflow;
+        self
+    }
+
+    /// Waits `backoff` after the first failure, doubling it after each further one up to `max_backoff`.
+    pub fn backoff(mut self, backoff: Duration, max_backoff: Duration) -> BatchSink {
+        self.config.backoff = backoff;
+        self.config.max_backoff = max_backoff.max(backoff);
+        self
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        let queue = self.shared.lock();
+        BatchStats {
+            queued: queue.records.len() + queue.sending,
+            ..queue.stats.clone()
+        }
+    }
+
+    /// Waits up to `timeout` for everything queued to be sent. Returns whether it was.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.lock();
+        queue.flushing = true;
+        self.shared.changed.notify_all();
+        while !queue.records.is_empty() || queue.sending > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || self.writer.is_none() {
+                queue.flushing = false;
+                return false;
+            }
+            queue = self.shared.changed.wait_timeout(queue, left)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+        }
+        queue.flushing = false;
+        true
+    }
+
+    fn push(&mut self, record: Record) {
+        if self.writer.is_none() {
+            let (destination, shared, config) = (self.destination.clone(), self.shared.clone(), self.config.clone());
+            self.writer = Some(thread::spawn(move || write_batches(destination.as_ref(), &shared, &config)));
+        }
+        let mut queue = self.shared.lock();
+        if self.config.overflow == Overflow::Block {
+            while queue.records.len() >= self.config.capacity && !queue.closed {
+                queue = self.shared.changed.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+        }
+        queue.records.push_back(record);
+        queue.trim(self.config.capacity);
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Sink for BatchSink {
+    fn write(&mut self, sample: &Sample) {
+        if let Some(record) = self.destination.encode(sample) {
+            self.push(record);
+        }
+    }
+}
+
+impl Drop for BatchSink {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The writer thread: sends batches until the sink is dropped.
+fn write_batches(destination: &dyn Destination, shared: &Shared, config: &Config) {
+    let mut backoff = config.backoff;
+    loop {
+        let batch: Vec<Record> = {
+            let mut queue = shared.lock();
+            let mut first_seen = Instant::now();
+            loop {
+                if queue.closed || queue.records.len() >= config.batch_size || queue.flushing {
+                    break;
+                }
+                if queue.records.is_empty() {
+                    queue = shared.changed.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+                    first_seen = Instant::now();
+                    continue;
+                }
+                let left = config.flush_every.saturating_sub(first_seen.elapsed());
+                if left.is_zero() {
+                    break;
+                }
+                queue = shared.changed.wait_timeout(queue, left).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+            }
+            if queue.records.is_empty() {
+                if queue.closed {
+                    return;
+                }
+                continue;
+            }
+            let n = queue.records.len().min(config.batch_size);
+            queue.sending = n;
+            let batch = queue.records.drain(..n).collect();
+            // Room for pollers waiting on a full queue.
+            shared.changed.notify_all();
+            batch
+        };
+
+        let result = destination.send(&batch);
+        let mut queue = shared.lock();
+        queue.sending = 0;
+        match result {
+            Ok(()) => {
+                queue.stats.sent += batch.len() as u64;
+                backoff = config.backoff;
+            }
+            Err(SendError::Reject(e)) => {
+                queue.stats.dropped += batch.len() as u64;
+                queue.stats.failures += 1;
+                queue.stats.last_error = Some(e.to_string());
+            }
+            Err(SendError::Retry(e)) => {
+                queue.stats.failures += 1;
+                queue.stats.last_error = Some(e.to_string());
+                if queue.closed {
+                    queue.stats.dropped += (batch.len() + queue.records.len()) as u64;
+                    queue.records.clear();
+                    shared.changed.notify_all();
+                    return;
+                }
+                for record in batch.into_iter().rev() {
+                    queue.records.push_front(record);
+                }
+                queue.trim(config.capacity);
+                shared.changed.notify_all();
+                let wait = backoff;
+                backoff = (backoff * 2).min(config.max_backoff);
+                let _ = shared.changed.wait_timeout_while(queue, wait, |queue| !queue.closed);
+                continue;
+            }
+        }
+        shared.changed.notify_all();
+    }
+}
+
+/// The sample in the Prometheus text exposition format, without timestamps.
+pub fn prometheus_text(sample: &Sample) -> Option<String> {
+    let r = sample.reading.as_ref().ok()?;
+    let device = label(&sample.device);
+    let mut text = String::new();
+    for (name, value) in [
+        ("hs1x0_voltage_volts", Some(r.voltage_v)),
+        ("hs1x0_current_amperes", Some(r.current_a)),
+        ("hs1x0_power_watts", Some(r.power_w)),
+        ("hs1x0_apparent_power_voltamperes", Some(r.apparent_power_va())),
+        ("hs1x0_power_factor", r.power_factor()),
+        ("hs1x0_energy_kilowatt_hours_total", Some(r.total_kwh)),
+    ] {
+        if let Some(value) = value {
+            let _ = writeln!(text, "{}{{device=\"{}\"}} {}", name, device, value);
+        }
+    }
+    Some(text)
+}
+
+#[cfg(feature = "push")]
+pub use http::{InfluxHttp, Pushgateway};
+
+#[cfg(feature = "push")]
+mod http {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use crate::scheduler::Sample;
+    use crate::sink::InfluxSink;
+    use crate::types::PlugError;
+    use super::{prometheus_text, Destination, Record, SendError};
+
+    fn agent() -> ureq::Agent {
+        ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(30)))
+            .build()
+            .into()
+    }
+
+    /// Client errors other than 429 won't go away by sending again.
+    fn failure(url: &str, error: ureq::Error) -> SendError {
+        let e = PlugError::new(format!("Writing to {} failed: {}", url, error).as_str());
+        match error {
+            ureq::Error::StatusCode(code) if (400..500).contains(&code) && code != 429 => SendError::Reject(e),
+            _ => SendError::Retry(e),
+        }
+    }
+
+    /// InfluxDB's write API, in line protocol with nanosecond timestamps.
+    pub struct InfluxHttp {
+        url: String,
+        token: Option<String>,
+        measurement: String,
+        agent: ureq::Agent,
+    }
+
+    impl InfluxHttp {
+        /// `url` is the whole write endpoint, e.g. `http://influx:8086/api/v2/write?org=home&bucket=power`
+        /// or `http://influx:8086/write?db=power` for InfluxDB 1.
+        pub fn new(url: &str) -> InfluxHttp {
+            InfluxHttp {
+                url: String::from(url),
+                token: None,
+                measurement: String::from("power"),
+                agent: agent(),
+            }
+        }
+
+        pub fn token(mut self, token: &str) -> InfluxHttp {
+            self.token = Some(String::from(token));
+            self
+        }
+
+        pub fn measurement(mut self, measurement: &str) -> InfluxHttp {
+            self.measurement = String::from(measurement);
+            self
+        }
+    }
+
+    impl Destination for InfluxHttp {
+        fn encode(&self, sample: &Sample) -> Option<Record> {
+            Some(Record {
+                device: sample.device.clone(),
+                body: InfluxSink::<Vec<u8>>::line(&self.measurement, sample)?,
+            })
+        }
+
+        fn send(&self, records: &[Record]) -> Result<(), SendError> {
+            let body: Vec<&str> = records.iter().map(|record| record.body.as_str()).collect();
+            let url = match self.url.contains("precision=") {
+                true => self.url.clone(),
+                false => format!("{}{}precision=ns", self.url, if self.url.contains('?') { '&' } else { '?' }),
+            };
+            let mut request = self.agent.post(url.as_str())
+                .header("Content-Type", "text/plain; charset=utf-8");
+            if let Some(token) = &self.token {
+                request = request.header("Authorization", format!("Token {}", token));
+            }
+            request.send(body.join("\n").as_str())
+                .map(|_| ())
+                .map_err(|e| failure(&self.url, e))
+        }
+    }
+
+    /// A Prometheus Pushgateway, one group per device under `job`. The gateway
+    /// keeps only the latest value, so of a batch only each device's last
+    /// sample is pushed.
+    pub struct Pushgateway {
+        url: String,
+        job: String,
+        agent: ureq::Agent,
+    }
+
+    /// A grouping key value as a path segment, base64 encoded if it isn't plain.
+    pub(super) fn segment(name: &str, value: &str) -> String {
+        if !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b)) {
+            return format!("{}/{}", name, value);
+        }
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut encoded = String::new();
+        for chunk in value.as_bytes().chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        // An empty value has to be sent as a single `=`.
+        format!("{}@base64/{}", name, if encoded.is_empty() { "=" } else { encoded.as_str() })
+    }
+
+    impl Pushgateway {
+        /// `url` is the gateway's base URL, e.g. `http://pushgateway:9091`.
+        pub fn new(url: &str, job: &str) -> Pushgateway {
+            Pushgateway {
+                url: String::from(url.trim_end_matches('/')),
+                job: String::from(job),
+                agent: agent(),
+            }
+        }
+    }
+
+    impl Destination for Pushgateway {
+        fn encode(&self, sample: &Sample) -> Option<Record> {
+            Some(Record {
+                device: sample.device.clone(),
+                body: prometheus_text(sample)?,
+            })
+        }
+
+        fn send(&self, records: &[Record]) -> Result<(), SendError> {
+            let latest: BTreeMap<&str, &str> = records.iter()
+                .map(|record| (record.device.as_str(), record.body.as_str()))
+                .collect();
+            for (device, body) in latest {
+                let url = format!("{}/metrics/{}/{}", self.url, segment("job", &self.job), segment("device", device));
+                self.agent.put(url.as_str())
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .send(body)
+                    .map_err(|e| failure(&url, e))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use chrono::{TimeZone, Utc};
+    use crate::reading::PowerReading;
+    use crate::scheduler::Sample;
+    use crate::sink::Sink;
+    use crate::types::PlugError;
+    use super::{prometheus_text, BatchSink, Destination, Overflow, Record, SendError};
+
+    /// Fails the first `failures` sends, then keeps what it is sent.
+    struct Flaky {
+        failures: AtomicUsize,
+        stored: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl Destination for Flaky {
+        fn encode(&self, sample: &Sample) -> Option<Record> {
+            let power = sample.reading.as_ref().ok()?.power_w;
+            Some(Record { device: sample.device.clone(), body: power.to_string() })
+        }
+
+        fn send(&self, records: &[Record]) -> Result<(), SendError> {
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(PlugError::new("Connection refused").into());
+            }
+            self.stored.lock().unwrap().push(records.iter().map(|r| r.body.clone()).collect());
+            Ok(())
+        }
+    }
+
+    fn sample(power_w: f64) -> Sample {
+        Sample {
+            device: String::from("heater"),
+            taken_at: Utc.timestamp_opt(1700000000, 0).unwrap(),
+            reading: Ok(PowerReading { voltage_v: 230.0, current_a: power_w / 230.0, power_w, ..PowerReading::default() }),
+        }
+    }
+
+    #[test]
+    fn test_retries_without_losing_samples() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = BatchSink::new(Flaky { failures: AtomicUsize::new(2), stored: stored.clone() })
+            .batch_size(2)
+            .flush_every(Duration::from_millis(10))
+            .backoff(Duration::from_millis(5), Duration::from_millis(20));
+        for power in [1.0, 2.0, 3.0] {
+            sink.write(&sample(power));
+        }
+        assert!(sink.flush(Duration::from_secs(5)));
+
+        assert_eq!(*stored.lock().unwrap(), [vec!["1", "2"], vec!["3"]]);
+        let stats = sink.stats();
+        assert_eq!((stats.sent, stats.dropped, stats.failures, stats.queued), (3, 0, 2, 0));
+        assert!(stats.last_error.unwrap().contains("Connection refused"));
+    }
+
+    #[test]
+    fn test_drops_oldest_when_full() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let mut sink = BatchSink::new(Flaky { failures: AtomicUsize::new(usize::MAX), stored })
+            .batch_size(10)
+            .flush_every(Duration::from_secs(60))
+            .capacity(3, Overflow::DropOldest);
+        for power in 0..5 {
+            sink.write(&sample(power as f64));
+        }
+        let stats = sink.stats();
+        assert_eq!((stats.queued, stats.dropped), (3, 2));
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let text = prometheus_text(&sample(46.0)).unwrap();
+        assert!(text.contains("hs1x0_power_watts{device=\"heater\"} 46\n"), "{}", text);
+        assert!(text.contains("hs1x0_power_factor{device=\"heater\"} 1\n"), "{}", text);
+        let failed = Sample { reading: Err(PlugError::new("timed out")), ..sample(0.0) };
+        assert_eq!(prometheus_text(&failed), None);
+    }
+
+    #[cfg(feature = "push")]
+    #[test]
+    fn test_grouping_key() {
+        use super::http::segment;
+        assert_eq!(segment("device", "heater-1"), "device/heater-1");
+        assert_eq!(segment("device", "living room"), "device@base64/bGl2aW5nIHJvb20");
+        assert_eq!(segment("device", ""), "device@base64/=");
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hs110::types::PlugError;
+
+/// `--name value` options and bare `--flag`s, plus positional arguments.
+#[derive(Debug, Default)]
+pub struct Args {
+    options: HashMap<String, Option<String>>,
+    pub positional: Vec<String>,
+}
+
+impl Args {
+    pub fn parse(argv: impl IntoIterator<Item = String>) -> Result<Args, PlugError> {
+        let mut args = Args::default();
+        let mut argv = argv.into_iter().peekable();
+        while let Some(arg) = argv.next() {
+            match arg.strip_prefix("--") {
+                Some("") => return Err(PlugError::new("Empty option name")),
+                Some(name) => {
+                    let value = argv.next_if(|next| !next.starts_with("--"));
+                    args.options.insert(String::from(name), value);
+                }
+                None => args.positional.push(arg),
+            }
+        }
+        Ok(args)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).and_then(|v| v.as_deref())
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str, PlugError> {
+        self.get(name).ok_or_else(|| PlugError::new(format!("Missing --{}", name).as_str()))
+    }
+
+    pub fn parsed<T: FromStr>(&self, name: &str, default: T) -> Result<T, PlugError> {
+        match self.get(name) {
+            Some(value) => value.parse()
+                .map_err(|_| PlugError::new(format!("Invalid value for --{}: {}", name, value).as_str())),
+            None => Ok(default),
+        }
+    }
+
+    /// Seconds, fractions allowed.
+    pub fn seconds(&self, name: &str, default: f64) -> Result<Duration, PlugError> {
+        let seconds: f64 = self.parsed(name, default)?;
+        Duration::try_from_secs_f64(seconds)
+            .map_err(|_| PlugError::new(format!("Invalid value for --{}: {}", name, seconds).as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+
+    #[test]
+    fn test_parse() {
+        let argv = ["lamp", "--timeout", "1.5", "--json", "--rounds", "3"].map(String::from);
+        let args = Args::parse(argv).unwrap();
+        assert_eq!(args.positional, ["lamp"]);
+        assert!(args.flag("json"));
+        assert_eq!(args.get("json"), None);
+        assert_eq!(args.parsed("rounds", 1).unwrap(), 3);
+        assert_eq!(args.seconds("timeout", 2.0).unwrap().as_millis(), 1500);
+        assert!(args.parsed::<u32>("timeout", 1).is_err());
+    }
+}
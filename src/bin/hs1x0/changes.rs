@@ -0,0 +1,71 @@
+/*
+ * What changed on a device since a snapshot saved earlier:
+ *
+ *   hs1x0 changes --host 192.168.1.20 --file heater.json [--keep]
+ *
+ * The first run only saves a snapshot. Later runs list the differences and
+ * replace it, unless `--keep` holds on to the old one, e.g. to keep comparing
+ * with yesterday's from a daily cron job.
+ */
+
+use chrono::{Duration, Utc};
+
+use hs110::TpLinkDevice;
+use hs110::snapshot::DeviceSnapshot;
+use hs110::types::PlugError;
+
+use crate::args::Args;
+
+/// A rough "3h" or "2d" for how long ago something was.
+pub fn ago(elapsed: Duration) -> String {
+    match elapsed.num_minutes() {
+        m if m < 1 => String::from("just now"),
+        m if m < 60 => format!("{}m ago", m),
+        m if m < 48 * 60 => format!("{}h ago", m / 60),
+        m => format!("{}d ago", m / (24 * 60)),
+    }
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let device = TpLinkDevice::new(args.require("host")?);
+    let file = args.require("file")?;
+    let now = device.snapshot()?;
+
+    let saved: Option<DeviceSnapshot> = match std::fs::read(file) {
+        Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    match &saved {
+        Some(before) => {
+            let changes = before.diff(&now);
+            println!("since {} ({}):", before.taken_at.to_rfc3339(), ago(Utc::now() - before.taken_at));
+            if changes.is_empty() {
+                println!("  no changes");
+            }
+            for change in changes {
+                println!("  {}", change);
+            }
+        }
+        None => println!("saved a first snapshot to {}", file),
+    }
+
+    if saved.is_none() || !args.flag("keep") {
+        std::fs::write(file, serde_json::to_string_pretty(&now)? + "\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use super::ago;
+
+    #[test]
+    fn test_ago() {
+        assert_eq!(ago(Duration::seconds(20)), "just now");
+        assert_eq!(ago(Duration::minutes(90)), "1h ago");
+        assert_eq!(ago(Duration::hours(23)), "23h ago");
+        assert_eq!(ago(Duration::days(3)), "3d ago");
+    }
+}
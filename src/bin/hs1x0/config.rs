@@ -0,0 +1,148 @@
+/*
+ * The daemon's configuration, a TOML file:
+ *
+ *   interval = 30
+ *   state_file = "/var/lib/hs1x0/state.json"
+ *
+ *   [tariff]
+ *   price_per_kwh = 0.32
+ *   currency = "EUR"
+ *
+ *   [alerts]
+ *   voltage = [207, 253]
+ *   anomaly_factor = 4.0
+ *
+ *   [limits]
+ *   max_frame_size = 65536   # bytes
+ *   read_deadline = 10       # seconds for a whole reply
+ *
+ *   [[device]]
+ *   name = "heater"
+ *   host = "192.168.1.20"
+ *
+ *   [[device]]
+ *   name = "Kettle"   # no host: found by discovery, by alias
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Deserialize;
+
+use hs110::tariff::Tariff;
+use hs110::transport::TcpTransport;
+use hs110::types::PlugError;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub host: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Alerts {
+    /// The allowed voltage band, low and high.
+    pub voltage: Option<(f64, f64)>,
+    /// How many scaled MADs from the usual power make an anomaly.
+    pub anomaly_factor: Option<f64>,
+}
+
+/// What a device may send back; unset ones keep `TcpTransport`'s defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub max_frame_size: Option<usize>,
+    pub read_deadline: Option<f64>,
+}
+
+impl Limits {
+    pub fn transport(&self) -> TcpTransport {
+        let mut transport = TcpTransport::default();
+        if let Some(bytes) = self.max_frame_size {
+            transport = transport.max_frame_size(bytes);
+        }
+        if let Some(deadline) = self.read_deadline.and_then(|s| Duration::try_from_secs_f64(s).ok()) {
+            transport = transport.deadline(deadline);
+        }
+        transport
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Seconds between polls.
+    pub interval: f64,
+    pub state_file: Option<PathBuf>,
+    pub tariff: Option<Tariff>,
+    pub alerts: Alerts,
+    pub limits: Limits,
+    #[serde(rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            interval: 30.0,
+            state_file: None,
+            tariff: None,
+            alerts: Alerts::default(),
+            limits: Limits::default(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, PlugError> {
+        toml::from_str(text).map_err(|e| PlugError::new(e.to_string().trim_end()))
+    }
+
+    pub fn load(path: &Path) -> Result<Config, PlugError> {
+        Config::parse(&fs::read_to_string(path)?)
+            .map_err(|e| PlugError::new(format!("{}: {}", path.display(), e).as_str()))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::try_from_secs_f64(self.interval).unwrap_or(Duration::from_secs(30)).max(Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            interval = 10
+
+            [tariff]
+            price_per_kwh = 0.32
+            currency = "EUR"
+
+            [alerts]
+            voltage = [207, 253]
+
+            [limits]
+            max_frame_size = 16384
+
+            [[device]]
+            name = "heater"
+            host = "192.168.1.20"
+
+            [[device]]
+            name = "Kettle"
+        "#).unwrap();
+        assert_eq!(config.interval().as_secs(), 10);
+        assert_eq!(config.devices[0].host.as_deref(), Some("192.168.1.20"));
+        assert_eq!(config.devices[1].host, None);
+        assert_eq!(config.tariff.unwrap().currency, "EUR");
+        assert_eq!(config.alerts.voltage, Some((207.0, 253.0)));
+        assert_eq!((config.limits.max_frame_size, config.limits.read_deadline), (Some(16384), None));
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("[[device]]\nname = 1").is_err());
+    }
+}
@@ -0,0 +1,36 @@
+/*
+ * Read-only protocol checks against one device, to paste into a support report:
+ *
+ *   hs1x0 conformance --host 192.168.1.20 [--json]
+ *
+ * Fails if any check failed, so it can gate a firmware upgrade in a script.
+ */
+
+use hs110::TpLinkDevice;
+use hs110::conformance::{self, Outcome};
+use hs110::types::PlugError;
+
+use crate::args::Args;
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let report = conformance::run(&TpLinkDevice::new(args.require("host")?));
+    if args.flag("json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        let or_unknown = |field: &Option<String>| field.clone().unwrap_or_else(|| String::from("?"));
+        println!("{} {} hw {} fw {}", report.address, or_unknown(&report.model), or_unknown(&report.hw_ver),
+                 or_unknown(&report.sw_ver));
+        for check in &report.checks {
+            let (mark, detail) = match &check.outcome {
+                Outcome::Passed(detail) => ("ok  ", detail),
+                Outcome::Failed(detail) => ("FAIL", detail),
+                Outcome::Skipped(detail) => ("skip", detail),
+            };
+            println!("  {} {:<15} {:>5} ms  {}", mark, check.name, check.elapsed_ms, detail);
+        }
+    }
+    match report.failures().count() {
+        0 => Ok(()),
+        n => Err(PlugError::new(format!("{} of {} checks failed", n, report.checks.len()).as_str())),
+    }
+}
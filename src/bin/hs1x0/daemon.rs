@@ -0,0 +1,251 @@
+/*
+ * Watches the devices in a config file and logs what happens to them, one
+ * line per event, until stopped:
+ *
+ *   hs1x0 daemon --config /etc/hs1x0.toml
+ *
+ * Meant to run as a service: see `lifecycle` for signals and readiness. The
+ * last-known state is saved after every poll and once more on the way out.
+ *
+ * The config file is checked for changes before every poll. Devices added to
+ * it are watched and removed ones dropped, tariff and alert changes apply from
+ * the next event on, and a file that fails to parse is reported and ignored.
+ * Entries without a host are looked up by alias with a discovery broadcast,
+ * again on every reload until found.
+ *
+ * With `--metrics 127.0.0.1:9100`, command latencies and errors of every
+ * device are served there for Prometheus to scrape. With the `otel` feature,
+ * `--otlp http://collector:4318` sends every command as a span, and the same
+ * metrics once a minute, to an OpenTelemetry collector.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "otel")]
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use hs110::TpLinkDevice;
+use hs110::anomaly::AnomalyDetector;
+use hs110::discovery;
+use hs110::events::Event;
+use hs110::metrics;
+#[cfg(feature = "otel")]
+use hs110::otel::Otlp;
+use hs110::types::PlugError;
+use hs110::voltage::VoltageMonitor;
+use hs110::watcher::Watcher;
+
+use crate::args::Args;
+use crate::config::{Alerts, Config, DeviceConfig, Limits};
+use crate::lifecycle;
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Set by `--otlp`, before any device is made.
+#[cfg(feature = "otel")]
+static OTLP: OnceLock<Arc<Otlp>> = OnceLock::new();
+
+fn instrumented(device: TpLinkDevice, limits: &Limits) -> TpLinkDevice {
+    let device = device.over_tcp(limits.transport()).metered(metrics::metrics());
+    #[cfg(feature = "otel")]
+    if let Some(otlp) = OTLP.get() {
+        return device.traced(otlp.clone());
+    }
+    device
+}
+
+/// Devices for `entries`, discovering those without a host. Ones not found are left out.
+fn resolve(entries: &[&DeviceConfig], limits: &Limits) -> Vec<(String, TpLinkDevice)> {
+    let device = |device: TpLinkDevice| instrumented(device, limits);
+    let mut found = Vec::new();
+    let mut aliases = Vec::new();
+    for entry in entries {
+        match &entry.host {
+            Some(host) => found.push((entry.name.clone(), device(TpLinkDevice::new(host.as_str())))),
+            None => aliases.push(entry.name.as_str()),
+        }
+    }
+    if aliases.is_empty() {
+        return found;
+    }
+    let discovered = match discovery::discover(DISCOVERY_TIMEOUT) {
+        Ok(discovered) => discovery::merge(discovered),
+        Err(e) => {
+            eprintln!("discovery: {}", e);
+            Vec::new()
+        }
+    };
+    for alias in aliases {
+        match discovered.iter().find(|d| d.sysinfo().is_some_and(|s| s.alias == alias)) {
+            Some(found_device) => found.push((String::from(alias), device(found_device.device()))),
+            None => eprintln!("{}: not found by discovery", alias),
+        }
+    }
+    found
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct Daemon {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    config: Config,
+    watcher: Watcher,
+    voltage: Option<VoltageMonitor>,
+    anomaly: Option<AnomalyDetector>,
+}
+
+impl Daemon {
+    fn new(path: &Path, config: Config) -> Result<Daemon, PlugError> {
+        let mut daemon = Daemon {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            config: Config { devices: Vec::new(), ..Config::default() },
+            watcher: Watcher::new(config.interval()),
+            voltage: None,
+            anomaly: None,
+        };
+        daemon.apply(config)?;
+        Ok(daemon)
+    }
+
+    fn alerts(&mut self, alerts: &Alerts) {
+        self.voltage = alerts.voltage.map(|(low, high)| VoltageMonitor::new(low, high));
+        self.anomaly = alerts.anomaly_factor.map(AnomalyDetector::new);
+    }
+
+    fn apply(&mut self, config: Config) -> Result<(), PlugError> {
+        if config.state_file != self.config.state_file {
+            if let Some(path) = &config.state_file {
+                self.watcher.persist_to(path)?;
+            }
+        }
+        if config.alerts != self.config.alerts {
+            self.alerts(&config.alerts);
+        }
+
+        for old in &self.config.devices {
+            if !config.devices.contains(old) && self.watcher.remove(&old.name) {
+                println!("{}: no longer watched", old.name);
+            }
+        }
+        let watched: Vec<String> = self.watcher.names().map(String::from).collect();
+        let added: Vec<&DeviceConfig> = config.devices.iter()
+            .filter(|d| !watched.contains(&d.name))
+            .collect();
+        for (name, device) in resolve(&added, &config.limits) {
+            println!("{}: watching {:#}", name, device);
+            self.watcher.add(&name, device);
+        }
+
+        self.config = config;
+        Ok(())
+    }
+
+    fn reload_if_changed(&mut self) {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match Config::load(&self.path).and_then(|config| self.apply(config)) {
+            Ok(()) => println!("reloaded {}", self.path.display()),
+            Err(e) => eprintln!("not reloaded: {}", e),
+        }
+    }
+
+    fn log(&self, event: &Event) {
+        match (event, &self.config.tariff) {
+            (Event::PowerSample { device, reading }, Some(tariff)) =>
+                println!("{}: {:.1} W, {:.3} kWh ({:.2} {})", device, reading.power_w, reading.total_kwh,
+                         tariff.cost(reading.total_kwh), tariff.currency),
+            _ => println!("{}: {:?}", event.device(), event),
+        }
+    }
+
+    fn poll(&mut self) {
+        for event in self.watcher.poll() {
+            self.log(&event);
+            let mut alerts = Vec::new();
+            if let Some(voltage) = &mut self.voltage {
+                alerts.extend(voltage.handle(&event));
+            }
+            if let Some(anomaly) = &mut self.anomaly {
+                alerts.extend(anomaly.handle(&event));
+            }
+            for alert in &alerts {
+                self.log(alert);
+            }
+        }
+        if let Err(e) = self.watcher.save() {
+            eprintln!("saving state: {}", e);
+        }
+    }
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let path = Path::new(args.require("config")?);
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = args.get("otlp") {
+        let otlp = Arc::new(Otlp::new(endpoint, "hs1x0"));
+        otlp.spawn(Duration::from_secs(60), metrics::metrics());
+        let _ = OTLP.set(otlp);
+    }
+    let mut daemon = Daemon::new(path, Config::load(path)?)?;
+    if let Some(address) = args.get("metrics") {
+        let listener = std::net::TcpListener::bind(address)?;
+        std::thread::spawn(move || metrics::metrics().serve(listener));
+    }
+    lifecycle::handle_signals();
+
+    let mut deadline = Instant::now();
+    let mut ready = false;
+    loop {
+        daemon.reload_if_changed();
+        daemon.poll();
+        if !ready {
+            lifecycle::notify(&format!("READY=1\nSTATUS=Watching {} devices", daemon.watcher.names().count()))?;
+            ready = true;
+        }
+
+        deadline += daemon.config.interval();
+        if deadline < Instant::now() {
+            deadline = Instant::now();
+        }
+        if !lifecycle::sleep_until(deadline) {
+            break;
+        }
+    }
+
+    let _ = lifecycle::notify("STOPPING=1");
+    daemon.watcher.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::config::Config;
+    use super::Daemon;
+
+    #[test]
+    fn test_apply_changes() {
+        let config = |text: &str| Config::parse(text).unwrap();
+        let heater = "[[device]]\nname = \"heater\"\nhost = \"192.0.2.1\"\n";
+        let lamp = "[[device]]\nname = \"lamp\"\nhost = \"192.0.2.2\"\n";
+
+        let mut daemon = Daemon::new(Path::new("unused.toml"), config(heater)).unwrap();
+        assert_eq!(daemon.watcher.names().collect::<Vec<_>>(), ["heater"]);
+        assert!(daemon.voltage.is_none());
+
+        daemon.apply(config(&format!("[alerts]\nvoltage = [207, 253]\n{}{}", heater, lamp))).unwrap();
+        assert_eq!(daemon.watcher.names().collect::<Vec<_>>(), ["heater", "lamp"]);
+        assert!(daemon.voltage.is_some());
+
+        daemon.apply(config(lamp)).unwrap();
+        assert_eq!(daemon.watcher.names().collect::<Vec<_>>(), ["lamp"]);
+        assert!(daemon.voltage.is_none());
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * A live terminal view of some devices, fed by a `Watcher`:
+ *
+ *   hs1x0 dashboard heater=192.168.1.20 lamp=192.168.1.21 [--interval 2]
+ *
+ *    #  NAME                 STATE    POWER        RSSI  HISTORY
+ *    1  heater               on       1203.4 W      -52  ▁▂▃▅▇██▇
+ *
+ * Keys 1-9 toggle the device in that row, r polls now and q quits. The
+ * terminal is put in cbreak mode with stty, so this needs a Unix terminal.
+ */
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+
+use hs110::TpLinkDevice;
+use hs110::events::Event;
+use hs110::types::PlugError;
+use hs110::watcher::Watcher;
+
+use crate::args::Args;
+
+const HISTORY: usize = 40;
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// One bar per value, scaled between the smallest and largest.
+pub fn sparkline(values: &[f64]) -> String {
+    let low = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let high = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    values.iter()
+        .map(|v| match high - low {
+            range if range > 0.0 => BARS[(((v - low) / range) * (BARS.len() - 1) as f64).round() as usize],
+            _ => BARS[0],
+        })
+        .collect()
+}
+
+/// Restores the terminal when dropped.
+struct Cbreak;
+
+impl Cbreak {
+    fn enter() -> Result<Cbreak, PlugError> {
+        stty(&["-icanon", "-echo", "min", "1"])?;
+        print!("\x1b[?25l");
+        Ok(Cbreak)
+    }
+}
+
+impl Drop for Cbreak {
+    fn drop(&mut self) {
+        let _ = stty(&["sane"]);
+        print!("\x1b[?25h");
+    }
+}
+
+fn stty(settings: &[&str]) -> Result<(), PlugError> {
+    let status = Command::new("stty").args(settings).stdin(Stdio::inherit()).status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(PlugError::new("stty failed; is stdin a terminal?")),
+    }
+}
+
+fn keys() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut key = [0; 1];
+        while let Ok(1) = io::stdin().read(&mut key) {
+            if tx.send(key[0]).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+struct Dashboard {
+    watcher: Watcher,
+    devices: Vec<(String, TpLinkDevice)>,
+    history: BTreeMap<String, VecDeque<f64>>,
+    status: String,
+}
+
+impl Dashboard {
+    fn poll(&mut self) {
+        for event in self.watcher.poll() {
+            if let Event::PowerSample { device, reading } = event {
+                let history = self.history.entry(device).or_default();
+                if history.len() == HISTORY {
+                    history.pop_front();
+                }
+                history.push_back(reading.power_w);
+            }
+        }
+    }
+
+    fn toggle(&mut self, row: usize) {
+        let Some((name, device)) = self.devices.get(row) else { return };
+        let on = self.watcher.last_known(name).and_then(|k| k.relay_on).unwrap_or(false);
+        let result = if on { device.off() } else { device.on() };
+        self.status = match result {
+            Ok(_) => format!("switched {} {}", name, if on { "off" } else { "on" }),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    fn render(&self) -> String {
+        let mut screen = String::from("\x1b[H\x1b[2Jhs1x0 dashboard  (1-9 toggle, r refresh, q quit)\n\n");
+        screen += &format!(" {:>2}  {:<20} {:<8} {:>10}  {:>5}  HISTORY\n", "#", "NAME", "STATE", "POWER", "RSSI");
+        for (row, (name, _)) in self.devices.iter().enumerate() {
+            let known = self.watcher.last_known(name).cloned().unwrap_or_default();
+            let state = match (known.online, known.relay_on) {
+                (Some(false), _) => "offline",
+                (_, Some(true)) => "on",
+                (_, Some(false)) => "off",
+                _ => "?",
+            };
+            let power = known.reading.map_or(String::from("-"), |r| format!("{:.1} W", r.power_w));
+            let rssi = known.rssi.map_or(String::from("-"), |r| r.to_string());
+            let history: Vec<f64> = self.history.get(name).map_or(Vec::new(), |h| h.iter().cloned().collect());
+            screen += &format!(" {:>2}  {:<20} {:<8} {:>10}  {:>5}  {}\n", row + 1, name, state, power, rssi, sparkline(&history));
+        }
+        screen += &format!("\n{}\n", self.status);
+        screen
+    }
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    if args.positional.is_empty() {
+        return Err(PlugError::new("Give devices as name=host or host"));
+    }
+    let interval = args.seconds("interval", 2.0)?;
+    let mut watcher = Watcher::new(interval);
+    let mut devices = Vec::new();
+    for spec in &args.positional {
+        let (name, host) = spec.split_once('=').unwrap_or((spec, spec));
+        let device = TpLinkDevice::new(host);
+        watcher.add(name, device.clone());
+        devices.push((String::from(name), device));
+    }
+    let mut dashboard = Dashboard { watcher, devices, history: BTreeMap::new(), status: String::new() };
+
+    let _terminal = Cbreak::enter()?;
+    let keys = keys();
+    loop {
+        dashboard.poll();
+        print!("{}", dashboard.render());
+        io::stdout().flush()?;
+        match keys.recv_timeout(interval) {
+            Ok(b'q') | Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            Ok(key @ b'1'..=b'9') => dashboard.toggle((key - b'1') as usize),
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sparkline;
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 50.0, 100.0]), "▁▅█");
+        assert_eq!(sparkline(&[3.0, 3.0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+use serde_json::json;
+
+use hs110::discovery::{self, Discovered, DiscoveredInfo, DiscoveryMethod, Presence};
+use hs110::inventory;
+use hs110::types::PlugError;
+
+use crate::args::Args;
+
+fn methods(args: &Args) -> Result<Vec<DiscoveryMethod>, PlugError> {
+    args.get("method").unwrap_or("broadcast").split(',')
+        .map(|method| match method.trim() {
+            "broadcast" => Ok(DiscoveryMethod::Broadcast),
+            "neighbors" => Ok(DiscoveryMethod::Neighbors),
+            #[cfg(feature = "mdns")]
+            "mdns" => Ok(DiscoveryMethod::mdns()),
+            other => Err(PlugError::new(format!("Unknown discovery method: {}", other).as_str())),
+        })
+        .collect()
+}
+
+fn alias(found: &Discovered) -> &str {
+    found.sysinfo().map_or("", |s| s.alias.as_str())
+}
+
+fn model(found: &Discovered) -> &str {
+    match &found.info {
+        DiscoveredInfo::Known { sysinfo, .. } => &sysinfo.model,
+        DiscoveredInfo::Unknown { model, .. } => model.as_deref().unwrap_or("?"),
+    }
+}
+
+fn kind(found: &Discovered) -> String {
+    match &found.info {
+        DiscoveredInfo::Known { kind, .. } => format!("{:?}", kind),
+        DiscoveredInfo::Unknown { .. } => String::from("Unknown"),
+    }
+}
+
+/// By alias, case-insensitively, then by address; devices without an alias last.
+fn sort(found: &mut [Discovered]) {
+    found.sort_by_key(|d| (alias(d).is_empty(), alias(d).to_lowercase(), d.address));
+}
+
+fn line(found: &Discovered) -> String {
+    format!("{:<22} {:<24} {:<12} {:<8} {}",
+            found.address, alias(found), model(found), kind(found), found.device_id().unwrap_or(""))
+}
+
+fn to_json(found: &Discovered) -> serde_json::Value {
+    json!({
+        "address": found.address.to_string(),
+        "alias": alias(found),
+        "model": model(found),
+        "type": kind(found),
+        "device_id": found.device_id(),
+    })
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let methods = methods(args)?;
+    let timeout = args.seconds("timeout", 2.0)?;
+    let as_json = args.flag("json");
+
+    if args.flag("watch") {
+        let interval = args.seconds("interval", 30.0)?;
+        for change in discovery::watch(methods, timeout, interval.max(Duration::from_secs(1))) {
+            let (event, found) = match &change {
+                Presence::Joined(found) => ("joined", found),
+                Presence::Left(found) => ("left", found),
+            };
+            if as_json {
+                let mut value = to_json(found);
+                value["event"] = json!(event);
+                println!("{}", value);
+            } else {
+                println!("{:<7} {}", event, line(found));
+            }
+        }
+        return Ok(());
+    }
+
+    let rounds: u32 = args.parsed("rounds", 2)?;
+    let mut found = Vec::new();
+    for _ in 0..rounds.max(1) {
+        found.extend(discovery::discover_with(&methods, timeout)?);
+    }
+    let mut found = discovery::merge(found);
+    sort(&mut found);
+
+    match args.get("format") {
+        Some("ha") => print!("{}", inventory::home_assistant(&found)),
+        Some("ansible") => print!("{}", inventory::ansible(&found)),
+        Some(other) => return Err(PlugError::new(format!("Unknown format: {}", other).as_str())),
+        None if as_json => println!("{}", serde_json::Value::Array(found.iter().map(to_json).collect())),
+        None => {
+            println!("{:<22} {:<24} {:<12} {:<8} DEVICE ID", "ADDRESS", "ALIAS", "MODEL", "TYPE");
+            for found in &found {
+                println!("{}", line(found));
+            }
+        }
+    }
+    Ok(())
+}
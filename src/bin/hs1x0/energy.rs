@@ -0,0 +1,90 @@
+/*
+ * Energy history from the meter, with an optional cost estimate:
+ *
+ *   hs1x0 energy --host 192.168.1.20 --month 2024-11 --tariff 0.32EUR/kWh
+ *   hs1x0 energy --host 192.168.1.20 --year 2024
+ *
+ * A month prints one row per day, a year one row per month. Without either
+ * the current month is shown.
+ */
+
+use chrono::{Datelike, Local};
+
+use hs110::TpLinkDevice;
+use hs110::tariff::Tariff;
+use hs110::types::PlugError;
+
+use crate::args::Args;
+
+/// `0.32EUR/kWh`, `0.32 EUR` or just `0.32`.
+pub fn parse_tariff(spec: &str) -> Result<Tariff, PlugError> {
+    let invalid = || PlugError::new(format!("Invalid tariff (want e.g. 0.32EUR/kWh): {}", spec).as_str());
+    let spec = spec.trim();
+    let spec = spec.strip_suffix("/kWh").or_else(|| spec.strip_suffix("/kwh")).unwrap_or(spec);
+    let split = spec.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(spec.len());
+    let price: f64 = spec[..split].parse().map_err(|_| invalid())?;
+    Ok(Tariff::new(price, spec[split..].trim()))
+}
+
+/// `2024-11` to (year, month).
+pub fn parse_month(spec: &str) -> Result<(i32, u32), PlugError> {
+    let invalid = || PlugError::new(format!("Invalid month (want YYYY-MM): {}", spec).as_str());
+    let (year, month) = spec.split_once('-').ok_or_else(invalid)?;
+    let (year, month): (i32, u32) = (year.parse().map_err(|_| invalid())?, month.parse().map_err(|_| invalid())?);
+    if !(1..=12).contains(&month) {
+        return Err(invalid());
+    }
+    Ok((year, month))
+}
+
+fn print_table(rows: &[(String, Option<f64>)], tariff: Option<&Tariff>) {
+    let cost = |kwh: f64| tariff.map_or(String::new(), |t| format!("{:>10.2} {}", t.cost(kwh), t.currency));
+    let total: f64 = rows.iter().filter_map(|(_, kwh)| *kwh).sum();
+    for (label, kwh) in rows {
+        match kwh {
+            Some(kwh) => println!("{:<10} {:>10.3} kWh {}", label, kwh, cost(*kwh)),
+            None => println!("{:<10} {:>10} kWh", label, "?"),
+        }
+    }
+    println!("{:<10} {:>10.3} kWh {}", "total", total, cost(total));
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let device = TpLinkDevice::new(args.require("host")?);
+    let tariff = args.get("tariff").map(parse_tariff).transpose()?;
+
+    let rows: Vec<(String, Option<f64>)> = match (args.get("month"), args.get("year")) {
+        (Some(_), Some(_)) => return Err(PlugError::new("Give --month or --year, not both")),
+        (None, Some(_)) => {
+            device.monthstat(args.parsed("year", 0)?)?.month_list.iter()
+                .map(|m| (format!("{}-{:02}", m.year, m.month), m.energy_kwh()))
+                .collect()
+        }
+        (month, None) => {
+            let (year, month) = match month {
+                Some(month) => parse_month(month)?,
+                None => (Local::now().year(), Local::now().month()),
+            };
+            device.daystat(year, month)?.iter()
+                .map(|d| (format!("{}-{:02}-{:02}", d.year, d.month, d.day), d.energy_kwh()))
+                .collect()
+        }
+    };
+    print_table(&rows, tariff.as_ref());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_month, parse_tariff};
+
+    #[test]
+    fn test_parse() {
+        let tariff = parse_tariff("0.32EUR/kWh").unwrap();
+        assert_eq!((tariff.price_per_kwh, tariff.currency.as_str()), (0.32, "EUR"));
+        assert_eq!(parse_tariff("0.5").unwrap().currency, "");
+        assert!(parse_tariff("EUR").is_err());
+        assert_eq!(parse_month("2024-11").unwrap(), (2024, 11));
+        assert!(parse_month("2024-13").is_err());
+    }
+}
@@ -0,0 +1,97 @@
+/*
+ * What a service manager expects of the daemon: SIGTERM and SIGINT ask it to
+ * stop at the next opportunity rather than killing it mid-write, and with the
+ * `systemd` feature it reports readiness over $NOTIFY_SOCKET, so that a unit
+ * with `Type=notify` is only started once devices have been polled.
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hs110::types::PlugError;
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: i32) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+/// Makes SIGINT and SIGTERM set the flag `stopping` reads.
+pub fn handle_signals() {
+    #[cfg(unix)]
+    {
+        const SIGINT: i32 = 2;
+        const SIGTERM: i32 = 15;
+        // The handler only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            signal(SIGINT, on_signal);
+            signal(SIGTERM, on_signal);
+        }
+    }
+}
+
+pub fn stopping() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Sleeps until `deadline`, returning early with `false` when asked to stop.
+pub fn sleep_until(deadline: Instant) -> bool {
+    while !stopping() {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(200)));
+    }
+    false
+}
+
+/// Sends `state`, e.g. "READY=1", to the service manager. Does nothing when
+/// not run by one.
+#[cfg(feature = "systemd")]
+pub fn notify(state: &str) -> Result<(), PlugError> {
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else { return Ok(()) };
+    let path = path.to_string_lossy();
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(PlugError::new("Abstract notify sockets are Linux only")),
+        None => {
+            socket.send_to(state.as_bytes(), path.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify(_state: &str) -> Result<(), PlugError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::sleep_until;
+
+    #[test]
+    fn test_sleep_until() {
+        let started = Instant::now();
+        assert!(sleep_until(started + Duration::from_millis(50)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(sleep_until(started));
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * Command line front end:
+ *
+ *   hs1x0 changes --host <host> --file <snapshot.json> [--keep]
+ *   hs1x0 conformance --host <host> [--json]
+ *   hs1x0 dashboard <name=host>... [--interval 2]
+ *   hs1x0 daemon --config hs1x0.toml [--metrics 127.0.0.1:9100] [--otlp http://collector:4318]
+ *   hs1x0 discover [--timeout 2] [--rounds 3] [--method broadcast,neighbors] [--json | --format ha|ansible] [--watch]
+ *   hs1x0 energy --host <host> [--month 2024-11 | --year 2024] [--tariff 0.32EUR/kWh]
+ *   hs1x0 repl
+ *   hs1x0 schedule list|add|rm|export|import|compact --host <host> [--on 07:30] [--days mon-fri]
+ *   hs1x0 upgrade --host <host> --model HS110 --version 1.5.10 --url <url> | --file <image>
+ *
+ * Options are `--name value` or bare `--flag`, in any order after the command.
+ */
+
+mod args;
+mod changes;
+mod conformance;
+#[cfg(feature = "daemon")]
+mod config;
+#[cfg(feature = "daemon")]
+mod daemon;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod discover;
+mod energy;
+#[cfg(feature = "daemon")]
+mod lifecycle;
+mod repl;
+mod schedule;
+mod upgrade;
+
+use std::process::ExitCode;
+
+use args::Args;
+
+const USAGE: &str = "\
+usage: hs1x0 <command> [options]
+
+commands:
+  changes      what changed on a device since a saved snapshot
+  conformance  check how a device speaks the protocol, for support reports
+  daemon       watch the devices in a config file (daemon feature)
+  dashboard    live power, relay state and signal of some devices (dashboard feature)
+  discover     find devices on the local network
+  energy       energy use per day or month, with cost
+  repl         an interactive session
+  schedule     list, add, remove, export, import or compact schedule rules
+  upgrade      install firmware after checking it suits the device";
+
+fn main() -> ExitCode {
+    let mut argv = std::env::args().skip(1);
+    let command = argv.next().unwrap_or_default();
+    let args = match Args::parse(argv) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match command.as_str() {
+        "changes" => changes::run(&args),
+        "conformance" => conformance::run(&args),
+        #[cfg(feature = "daemon")]
+        "daemon" => daemon::run(&args),
+        #[cfg(feature = "dashboard")]
+        "dashboard" => dashboard::run(&args),
+        "discover" => discover::run(&args),
+        "energy" => energy::run(&args),
+        "repl" => repl::run(),
+        "schedule" => schedule::run(&args),
+        "upgrade" => upgrade::run(&args),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("hs1x0 {}: {}", command, e);
+            ExitCode::FAILURE
+        }
+    }
+}
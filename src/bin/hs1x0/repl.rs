@@ -0,0 +1,159 @@
+/*
+ * An interactive session for exploring devices and their firmware:
+ *
+ *   hs1x0> connect 192.168.1.20 heater
+ *   hs1x0> use heater
+ *   heater> info
+ *   heater> raw {"system":{"get_dev_icon":{}}}
+ *   heater> o?
+ *   off  on
+ *
+ * Devices are detected once and kept for the session. A word ending in `?`
+ * lists what it could be completed to: commands, or the names of connected
+ * devices after `use`.
+ */
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use serde_json::Value;
+
+use hs110::TpLinkDevice;
+use hs110::types::PlugError;
+
+const COMMANDS: &[(&str, &str)] = &[
+    ("connect", "<host> [name]  detect a device and keep it"),
+    ("devices", "list connected devices"),
+    ("use", "<name>  send the commands that follow to this device"),
+    ("info", "print sysinfo"),
+    ("on", "switch the relay on"),
+    ("off", "switch the relay off"),
+    ("power", "print a meter reading"),
+    ("raw", "<json>  send a command as is and print the reply"),
+    ("help", "list commands"),
+    ("quit", "leave"),
+];
+
+#[derive(Default)]
+struct Session {
+    devices: BTreeMap<String, TpLinkDevice>,
+    current: Option<String>,
+}
+
+impl Session {
+    fn device(&self) -> Result<&TpLinkDevice, PlugError> {
+        self.current.as_ref().and_then(|name| self.devices.get(name))
+            .ok_or_else(|| PlugError::new("No device selected; connect to one first"))
+    }
+
+    /// Candidates for the last word of `line`.
+    fn complete(&self, line: &str) -> Vec<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (prefix, candidates): (&str, Vec<String>) = match words.as_slice() {
+            [] => ("", COMMANDS.iter().map(|(c, _)| String::from(*c)).collect()),
+            [word] => (word, COMMANDS.iter().map(|(c, _)| String::from(*c)).collect()),
+            ["use", word] => (word, self.devices.keys().cloned().collect()),
+            _ => return Vec::new(),
+        };
+        let mut matches: Vec<String> = candidates.into_iter().filter(|c| c.starts_with(prefix)).collect();
+        matches.sort();
+        matches
+    }
+
+    /// Runs one line; `Ok(false)` to leave.
+    fn execute(&mut self, line: &str) -> Result<bool, PlugError> {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "" => {}
+            "quit" | "exit" => return Ok(false),
+            "help" => {
+                for (name, help) in COMMANDS {
+                    println!("  {:<8} {}", name, help);
+                }
+            }
+            "connect" => {
+                let mut parts = rest.split_whitespace();
+                let host = parts.next().ok_or_else(|| PlugError::new("connect <host> [name]"))?;
+                let device = TpLinkDevice::new(host).detect()?;
+                let name = match parts.next() {
+                    Some(name) => String::from(name),
+                    None => device.sysinfo()?.alias,
+                };
+                println!("{} is a {}", name, device.model().unwrap_or("device"));
+                self.devices.insert(name.clone(), device);
+                self.current = Some(name);
+            }
+            "devices" => {
+                for (name, device) in &self.devices {
+                    println!("  {:<20} {:#}", name, device);
+                }
+            }
+            "use" => {
+                if !self.devices.contains_key(rest) {
+                    return Err(PlugError::new(format!("No device named {}", rest).as_str()));
+                }
+                self.current = Some(String::from(rest));
+            }
+            "info" => print_json(&serde_json::to_value(self.device()?.sysinfo()?)?),
+            "on" => print_json(&serde_json::to_value(self.device()?.on()?)?),
+            "off" => print_json(&serde_json::to_value(self.device()?.off()?)?),
+            "power" => print_json(&serde_json::to_value(self.device()?.power_reading()?)?),
+            "raw" => {
+                let cmd: Value = serde_json::from_str(rest)?;
+                print_json(&self.device()?.send_raw(cmd)?);
+            }
+            other => return Err(PlugError::new(format!("Unknown command {}; try help", other).as_str())),
+        }
+        Ok(true)
+    }
+}
+
+fn print_json(value: &Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+}
+
+pub fn run() -> Result<(), PlugError> {
+    let mut session = Session::default();
+    let stdin = io::stdin();
+    loop {
+        print!("{}> ", session.current.as_deref().unwrap_or("hs1x0"));
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim();
+        if let Some(partial) = line.strip_suffix('?') {
+            println!("{}", session.complete(partial).join("  "));
+            continue;
+        }
+        match session.execute(line) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use hs110::TpLinkDevice;
+    use hs110::types::PlugError;
+    use super::Session;
+
+    #[test]
+    fn test_completion() {
+        let mut session = Session::default();
+        let silent = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> { Ok(Vec::new()) };
+        session.devices.insert(String::from("heater"), TpLinkDevice::with_transport("heater", Arc::new(silent)));
+
+        assert_eq!(session.complete("o"), ["off", "on"]);
+        assert_eq!(session.complete("use h"), ["heater"]);
+        assert!(session.execute("info").is_err());
+        session.execute("use heater").unwrap();
+        assert!(session.execute("raw {not json").is_err());
+        assert!(!session.execute("quit").unwrap());
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * Weekly schedule rules on a device:
+ *
+ *   hs1x0 schedule list --host 192.168.1.20
+ *   hs1x0 schedule add --host 192.168.1.20 --on 07:30 --days mon-fri [--name wake]
+ *   hs1x0 schedule rm --host 192.168.1.20 <id>|--all
+ *   hs1x0 schedule export --host 192.168.1.20 --file rules.json
+ *   hs1x0 schedule import --host 192.168.1.20 --file rules.json [--replace]
+ *   hs1x0 schedule compact --host 192.168.1.20
+ *
+ * Importing adds the rules the device doesn't have yet, so the same file can
+ * be imported again; `--replace` drops the device's rules first. Adding and
+ * importing refuse rules that conflict with each other or don't fit on the
+ * device; `compact` merges duplicates to make room. Files ending in
+ * `.ics` or `.ical` are iCalendar, anything else JSON.
+ *
+ * Days are `daily`, `weekdays`, `weekends`, or a comma separated list of
+ * names and ranges such as `mon-wed,sat`.
+ */
+
+use hs110::TpLinkDevice;
+use hs110::ical;
+use hs110::schedule::{compact_rules, diff_rules, ScheduleRule};
+use hs110::types::PlugError;
+
+use crate::args::Args;
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+fn day(name: &str) -> Result<usize, PlugError> {
+    let name = name.trim().to_lowercase();
+    DAYS.iter().position(|d| name.starts_with(d))
+        .ok_or_else(|| PlugError::new(format!("Unknown day: {}", name).as_str()))
+}
+
+/// Sunday first, as `ScheduleRule::on_days` takes them. Ranges may wrap, as in `fri-mon`.
+pub fn parse_days(spec: &str) -> Result<[bool; 7], PlugError> {
+    match spec {
+        "daily" => return Ok([true; 7]),
+        "weekdays" => return parse_days("mon-fri"),
+        "weekends" => return parse_days("sat,sun"),
+        _ => {}
+    }
+    let mut days = [false; 7];
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                let mut d = from;
+                loop {
+                    days[d] = true;
+                    if d == to {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+/// `HH:MM` to (hour, minute).
+pub fn parse_time(time: &str) -> Result<(u32, u32), PlugError> {
+    let invalid = || PlugError::new(format!("Invalid time (want HH:MM): {}", time).as_str());
+    let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+    let (hour, minute): (u32, u32) = (hour.parse().map_err(|_| invalid())?, minute.parse().map_err(|_| invalid())?);
+    if hour > 23 || minute > 59 {
+        return Err(invalid());
+    }
+    Ok((hour, minute))
+}
+
+fn describe_days(wday: &[i64]) -> String {
+    match wday.iter().filter(|d| **d != 0).count() {
+        0 => String::from("once"),
+        7 => String::from("daily"),
+        _ => DAYS.iter().zip(wday).filter(|(_, on)| **on != 0).map(|(d, _)| *d).collect::<Vec<_>>().join(","),
+    }
+}
+
+fn line(rule: &ScheduleRule) -> String {
+    let time = match rule.stime_opt {
+        1 => String::from("sunrise"),
+        2 => String::from("sunset"),
+        _ => format!("{:02}:{:02}", rule.smin / 60, rule.smin % 60),
+    };
+    format!("{:<34} {:<8} {:<4} {:<28} {}{}",
+            rule.id.as_deref().unwrap_or(""), time, if rule.sact == 1 { "on" } else { "off" },
+            describe_days(&rule.wday), rule.name, if rule.enable == 0 { " (disabled)" } else { "" })
+}
+
+/// Builds the rule `schedule add` asks for.
+pub fn rule(args: &Args) -> Result<ScheduleRule, PlugError> {
+    let (time, turn_on) = match (args.get("on"), args.get("off")) {
+        (Some(time), None) => (time, true),
+        (None, Some(time)) => (time, false),
+        _ => return Err(PlugError::new("Give one of --on HH:MM or --off HH:MM")),
+    };
+    let (hour, minute) = parse_time(time)?;
+    let mut rule = ScheduleRule::at(hour, minute, turn_on).on_days(parse_days(args.get("days").unwrap_or("daily"))?);
+    if let Some(name) = args.get("name") {
+        rule = rule.named(name);
+    }
+    Ok(rule)
+}
+
+/// `existing` followed by those of `imported` it doesn't have already.
+fn merged(existing: Vec<ScheduleRule>, imported: &[ScheduleRule]) -> Vec<ScheduleRule> {
+    let (added, _) = diff_rules(&existing, imported);
+    existing.into_iter().chain(added).collect()
+}
+
+fn is_ical(file: &str) -> bool {
+    file.ends_with(".ics") || file.ends_with(".ical")
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let device = TpLinkDevice::new(args.require("host")?);
+    match args.positional.first().map(String::as_str) {
+        Some("list") | None => {
+            for rule in device.schedule_rules()? {
+                println!("{}", line(&rule));
+            }
+        }
+        Some("add") => println!("{}", device.add_schedule_checked(&rule(args)?)?),
+        Some("rm") => {
+            if args.flag("all") {
+                device.clear_schedules()?;
+            } else {
+                let id = args.positional.get(1).ok_or_else(|| PlugError::new("schedule rm <id> or --all"))?;
+                device.delete_schedule(id)?;
+            }
+        }
+        Some("export") => {
+            let file = args.require("file")?;
+            let rules = device.schedule_rules()?;
+            let text = if is_ical(file) { ical::to_ical(&rules) } else { serde_json::to_string_pretty(&rules)? + "\n" };
+            std::fs::write(file, text)?;
+        }
+        Some("import") => {
+            let file = args.require("file")?;
+            let text = std::fs::read_to_string(file)?;
+            let mut rules: Vec<ScheduleRule> = if is_ical(file) { ical::from_ical(&text)? } else { serde_json::from_str(&text)? };
+            if !args.flag("replace") {
+                rules = merged(device.schedule_rules()?, &rules);
+            }
+            for id in device.set_schedules(&rules)? {
+                println!("{}", id);
+            }
+        }
+        Some("compact") => {
+            let rules = device.schedule_rules()?;
+            let compacted = compact_rules(&rules);
+            if compacted.len() < rules.len() {
+                device.set_schedules(&compacted)?;
+            }
+            println!("{} rules, {} before", compacted.len(), rules.len());
+        }
+        Some(other) => return Err(PlugError::new(format!("Unknown schedule command: {}", other).as_str())),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hs110::schedule::ScheduleRule;
+    use crate::args::Args;
+    use super::{merged, parse_days, parse_time, rule};
+
+    #[test]
+    fn test_parse_days() {
+        assert_eq!(parse_days("mon-fri").unwrap(), [false, true, true, true, true, true, false]);
+        assert_eq!(parse_days("fri-sun,Wed").unwrap(), [true, false, false, true, false, true, true]);
+        assert_eq!(parse_days("weekends").unwrap(), [true, false, false, false, false, false, true]);
+        assert!(parse_days("mon-fry").is_err());
+        assert_eq!(parse_time("07:30").unwrap(), (7, 30));
+        assert!(parse_time("24:00").is_err());
+    }
+
+    #[test]
+    fn test_rule_from_args() {
+        let args = Args::parse(["add", "--off", "22:15", "--days", "sat,sun", "--name", "night"].map(String::from)).unwrap();
+        let rule = rule(&args).unwrap();
+        assert_eq!((rule.smin, rule.sact, rule.name.as_str()), (22 * 60 + 15, 0, "night"));
+        assert_eq!(rule.wday, [1, 0, 0, 0, 0, 0, 1]);
+        assert!(super::rule(&Args::parse(["add"].map(String::from)).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_import_skips_rules_already_there() {
+        let mut existing = ScheduleRule::at(7, 30, true);
+        existing.id = Some(String::from("A1"));
+        let imported = [ScheduleRule::at(7, 30, true), ScheduleRule::at(22, 0, false)];
+        let rules = merged(vec![existing.clone()], &imported);
+        assert_eq!(rules, [existing, ScheduleRule::at(22, 0, false)]);
+        assert_eq!(merged(rules.clone(), &imported), rules);
+    }
+}
@@ -0,0 +1,92 @@
+/*
+ * Firmware upgrades:
+ *
+ *   hs1x0 upgrade --host 192.168.1.20 --model HS110 --version 1.5.10 --url http://10.0.0.2/hs110.bin
+ *   hs1x0 upgrade --host 192.168.1.20 --model HS110 --version 1.5.10 --file hs110.bin
+ *
+ * `--model` and `--version` describe the image and are checked against the
+ * device before anything is sent; `--allow-downgrade` lets an older version
+ * through. `--file` serves the image from this host while the device fetches it.
+ *
+ * `--https-only`, `--allow-hosts fw.example.net,10.0.0.2` and `--sha256 <digest>`
+ * put a `UrlPolicy` on the URL; the digest is checked when built with the
+ * `checksum` feature and refused otherwise.
+ */
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use hs110::TpLinkDevice;
+use hs110::firmware::{FileServer, Image, Progress, Upgrade, UrlPolicy};
+use hs110::types::PlugError;
+
+use crate::args::Args;
+
+const BAR_WIDTH: usize = 30;
+
+pub fn bar(percent: u8) -> String {
+    let filled = BAR_WIDTH * percent.min(100) as usize / 100;
+    format!("[{}{}] {:>3}%", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled), percent)
+}
+
+fn show(progress: Progress) {
+    match progress {
+        Progress::Downloading(percent) => print!("\rdownloading {}", bar(percent)),
+        Progress::Flashing => print!("\nflashing..."),
+        Progress::Rebooting => print!("\nrebooting..."),
+        Progress::Done(version) => println!("\nnow running {}", version),
+    }
+    let _ = io::stdout().flush();
+}
+
+pub fn run(args: &Args) -> Result<(), PlugError> {
+    let device = TpLinkDevice::new(args.require("host")?);
+    let image = Image::new(args.require("model")?, args.require("version")?);
+
+    let server;
+    let url = match (args.get("url"), args.get("file")) {
+        (Some(url), None) => url,
+        (None, Some(file)) => {
+            server = FileServer::serve(Path::new(file), &device)?;
+            println!("serving {} at {}", file, server.url);
+            server.url.as_str()
+        }
+        _ => return Err(PlugError::new("Give one of --url or --file")),
+    };
+
+    let mut upgrade = Upgrade::new(url, image).timeout(args.seconds("timeout", 300.0)?);
+    if args.flag("allow-downgrade") {
+        upgrade = upgrade.allow_downgrade();
+    }
+    let mut policy = UrlPolicy::new();
+    if args.flag("https-only") {
+        policy = policy.https_only();
+    }
+    for host in args.get("allow-hosts").into_iter().flat_map(|hosts| hosts.split(',')) {
+        policy = policy.allow_host(host.trim());
+    }
+    if let Some(digest) = args.get("sha256") {
+        policy = policy.sha256(digest);
+    }
+    if policy != UrlPolicy::new() {
+        upgrade = upgrade.policy(policy);
+    }
+    let checks = upgrade.checks(&device)?;
+    for check in &checks {
+        println!("{:<4} {:<8} {}", if check.passed { "ok" } else { "FAIL" }, check.name, check.detail);
+    }
+    upgrade.run_checked(&checks, &device, show)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bar;
+
+    #[test]
+    fn test_bar() {
+        assert_eq!(bar(0), format!("[{}]   0%", " ".repeat(30)));
+        assert_eq!(bar(50), format!("[{}{}]  50%", "#".repeat(15), " ".repeat(15)));
+        assert_eq!(bar(120), format!("[{}] 120%", "#".repeat(30)));
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ * Stops one dead plug from holding up everything else, such as a poll of the
+ * whole house waiting out a connect timeout for it on every round:
+ *
+ *   let (device, breaker) = device.with_breaker(3, Duration::from_secs(60));
+ *   breaker.spawn_probe(Duration::from_secs(10));
+ *   watcher.add("heater", device);
+ *
+ * After `threshold` failed requests in a row the circuit opens, and requests
+ * fail at once without reaching the device for `cooldown`. Then one request
+ * is let through: if it works the circuit closes, if not it stays open for
+ * another cooldown. A background probe, if spawned, checks open circuits in
+ * the meantime and closes them as soon as the device answers. Only transport
+ * errors count; a device that answers with an `err_code` is alive.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::timing::{self, Clock};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Circuit {
+    /// Requests go through; `failures` in a row so far.
+    Closed { failures: u32 },
+    /// Requests fail fast until `until`.
+    Open { until: Instant },
+    /// The cooldown is over and a trial request is on its way; other requests fail fast until it's back.
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    inner: Arc<dyn Transport>,
+    threshold: u32,
+    cooldown: Duration,
+    probe_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(inner: Arc<dyn Transport>, threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            inner,
+            threshold: threshold.max(1),
+            cooldown,
+            probe_timeout: Duration::from_secs(2),
+            clock: timing::system(),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> CircuitBreaker {
+        self.clock = clock;
+        self
+    }
+
+    pub fn probe_timeout(mut self, timeout: Duration) -> CircuitBreaker {
+        self.probe_timeout = timeout;
+        self
+    }
+
+    pub fn circuit(&self, address: &str) -> Circuit {
+        self.circuits.lock().ok()
+            .and_then(|circuits| circuits.get(address).copied())
+            .unwrap_or(Circuit::Closed { failures: 0 })
+    }
+
+    /// Addresses whose circuit isn't closed.
+    pub fn unhealthy(&self) -> Vec<String> {
+        let mut unhealthy: Vec<String> = self.circuits.lock().map(|circuits| circuits.iter()
+            .filter(|(_, circuit)| !matches!(circuit, Circuit::Closed { .. }))
+            .map(|(address, _)| address.clone())
+            .collect()).unwrap_or_default();
+        unhealthy.sort();
+        unhealthy
+    }
+
+    fn set(&self, address: &str, circuit: Circuit) {
+        if let Ok(mut circuits) = self.circuits.lock() {
+            circuits.insert(String::from(address), circuit);
+        }
+    }
+
+    /// Probes every open circuit once and closes those whose device answers. Returns their addresses.
+    pub fn probe_open(&self) -> Vec<String> {
+        let open: Vec<String> = self.circuits.lock().map(|circuits| circuits.iter()
+            .filter(|(_, circuit)| matches!(circuit, Circuit::Open { .. }))
+            .map(|(address, _)| address.clone())
+            .collect()).unwrap_or_default();
+        open.into_iter()
+            .filter(|address| self.inner.probe(address, self.probe_timeout).is_ok())
+            .inspect(|address| self.set(address, Circuit::Closed { failures: 0 }))
+            .collect()
+    }
+
+    /// Runs `probe_open` every `interval` in a background thread, until the breaker is dropped.
+    pub fn spawn_probe(self: &Arc<CircuitBreaker>, interval: Duration) {
+        let breaker: Weak<CircuitBreaker> = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match breaker.upgrade() {
+                Some(breaker) => breaker.probe_open(),
+                None => return,
+            };
+        });
+    }
+}
+
+impl CircuitBreaker {
+    /// Decides whether a request may go to `address`, and takes the trial if it's due, under one lock
+    /// so that only one caller gets it. Returns the failures so far in a row.
+    fn admit(&self, address: &str) -> Result<u32, PlugError> {
+        let now = self.clock.now();
+        let mut circuits = self.circuits.lock()
+            .map_err(|_| PlugError::new("Circuit breaker state is poisoned"))?;
+        let circuit = circuits.entry(String::from(address)).or_insert(Circuit::Closed { failures: 0 });
+        match *circuit {
+            Circuit::Closed { failures } => Ok(failures),
+            Circuit::Open { until } if now < until => Err(PlugError::new(
+                format!("{} is failing; not trying again for {:.1}s", address,
+                        until.saturating_duration_since(now).as_secs_f64()).as_str())),
+            Circuit::Open { .. } => {
+                *circuit = Circuit::HalfOpen;
+                Ok(self.threshold - 1)
+            }
+            Circuit::HalfOpen => Err(PlugError::new(
+                format!("{} is failing; a trial request is already on its way", address).as_str())),
+        }
+    }
+}
+
+impl Transport for CircuitBreaker {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let failures = self.admit(address)?;
+
+        let response = self.inner.request(address, frame);
+        let circuit = match &response {
+            Ok(_) => Circuit::Closed { failures: 0 },
+            Err(_) if failures + 1 >= self.threshold => Circuit::Open { until: self.clock.now() + self.cooldown },
+            Err(_) => Circuit::Closed { failures: failures + 1 },
+        };
+        self.set(address, circuit);
+        response
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device that fails fast for `cooldown` after `threshold` failures in a row.
+    pub fn with_breaker(&self, threshold: u32, cooldown: Duration) -> (TpLinkDevice, Arc<CircuitBreaker>) {
+        self.with_breaker_and_clock(threshold, cooldown, timing::system())
+    }
+
+    /// Like `with_breaker`, with the cooldown told by `clock`.
+    pub fn with_breaker_and_clock(&self, threshold: u32, cooldown: Duration, clock: Arc<dyn Clock>)
+        -> (TpLinkDevice, Arc<CircuitBreaker>) {
+        let breaker = Arc::new(CircuitBreaker::new(self.transport.clone(), threshold, cooldown).with_clock(clock));
+        (self.with_inner(breaker.clone()), breaker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+    use crate::TpLinkDevice;
+    use crate::protocol::encrypt_payload;
+    use crate::timing::MockClock;
+    use crate::types::PlugError;
+    use super::Circuit;
+
+    #[test]
+    fn test_opens_and_recovers() {
+        let alive = Arc::new(AtomicBool::new(false));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let (up, count) = (alive.clone(), sent.clone());
+        let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            count.fetch_add(1, Ordering::SeqCst);
+            match up.load(Ordering::SeqCst) {
+                true => Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec())),
+                false => Err(PlugError::new("Connection refused")),
+            }
+        };
+        let clock = Arc::new(MockClock::new());
+        let (device, breaker) = TpLinkDevice::with_transport("10.0.0.9", Arc::new(transport))
+            .with_breaker_and_clock(2, Duration::from_secs(60), clock.clone());
+
+        assert!(device.on().is_err());
+        assert_eq!(breaker.circuit("10.0.0.9"), Circuit::Closed { failures: 1 });
+        assert!(device.on().is_err());
+        assert!(matches!(breaker.circuit("10.0.0.9"), Circuit::Open { .. }));
+        // Fails fast without reaching the device.
+        assert!(device.on().unwrap_err().to_string().contains("not trying again"));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        // The trial after the cooldown fails too, so it opens again at once.
+        clock.advance(Duration::from_secs(60));
+        assert!(device.on().is_err());
+        assert!(matches!(breaker.circuit("10.0.0.9"), Circuit::Open { .. }));
+        assert_eq!(breaker.unhealthy(), ["10.0.0.9"]);
+
+        alive.store(true, Ordering::SeqCst);
+        assert_eq!(breaker.probe_open(), ["10.0.0.9"]);
+        device.on().unwrap();
+        assert!(breaker.unhealthy().is_empty());
+    }
+
+    #[test]
+    fn test_one_trial_at_a_time() {
+        let (entered, release) = (Arc::new(Barrier::new(2)), Arc::new(Barrier::new(2)));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let (gate, done, count) = (entered.clone(), release.clone(), sent.clone());
+        let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(PlugError::new("Connection refused"));
+            }
+            gate.wait();
+            done.wait();
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()))
+        };
+        let clock = Arc::new(MockClock::new());
+        let (device, breaker) = TpLinkDevice::with_transport("10.0.0.9", Arc::new(transport))
+            .with_breaker_and_clock(1, Duration::from_secs(60), clock.clone());
+        assert!(device.on().is_err());
+
+        clock.advance(Duration::from_secs(60));
+        let trial = {
+            let device = device.clone();
+            thread::spawn(move || device.on())
+        };
+        entered.wait();
+        assert_eq!(breaker.circuit("10.0.0.9"), Circuit::HalfOpen);
+        // A second caller fails fast instead of sending its own trial.
+        assert!(device.on().unwrap_err().to_string().contains("already on its way"));
+        release.wait();
+        trial.join().unwrap().unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        assert_eq!(breaker.circuit("10.0.0.9"), Circuit::Closed { failures: 0 });
+    }
+}
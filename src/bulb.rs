@@ -0,0 +1,376 @@
+/*
+ * Smart bulbs (LB1x0, KL1x0). The light is driven through the
+ * `smartlife.iot.smartbulb.lightingservice` namespace; what a bulb can do comes
+ * from its sysinfo flags:
+ *
+ *   let bulb = Bulb::connect(TpLinkDevice::with_transport("10.0.0.7:9999", transport))?;
+ *   bulb.set_color_temp(2700)?;
+ *   bulb.set_brightness(40)?;
+ *   bulb.fade_to(&LightState::off(), Duration::from_secs(5))?;
+ *
+ * Energy readings go through `device()`, which knows to use the bulbs' emeter
+ * namespace.
+ *
+ * Setters check values against the bulb's capabilities before anything is sent.
+ * `get_light_details` doesn't report colour temperature limits, so those come
+ * from a per-model table.
+ */
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceType, TpLinkDevice};
+use crate::commands;
+use crate::router::{self, Request};
+use crate::types::{PlugError, SystemGetSysInfoResponse};
+
+/// Colour temperature ranges in K, by model prefix.
+const KELVIN_RANGES: &[(&str, (u32, u32))] = &[
+    ("LB120", (2700, 6500)),
+    ("LB130", (2500, 9000)),
+    ("LB230", (2500, 9000)),
+    ("KB130", (2500, 9000)),
+    ("KL120(EU)", (2700, 6500)),
+    ("KL120(US)", (2700, 5000)),
+    ("KL125", (2500, 6500)),
+    ("KL130", (2500, 9000)),
+    ("KL135", (2500, 6500)),
+    ("KL430", (2500, 9000)),
+];
+
+/// Used for tunable white bulbs missing from the table.
+const DEFAULT_KELVIN_RANGE: (u32, u32) = (2700, 5000);
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LightState {
+    pub on_off: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hue: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<u8>,
+    /// 0 while showing a colour rather than white.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_temp: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
+    /// What the bulb comes back with when switched on, reported while it is off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dft_on_state: Option<alloc::boxed::Box<LightState>>,
+    /// How long the bulb takes to get to this state, in ms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transition_period: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub err_code: Option<i64>,
+}
+
+impl LightState {
+    pub fn on() -> LightState {
+        LightState {
+            on_off: 1,
+            ..LightState::default()
+        }
+    }
+
+    pub fn off() -> LightState {
+        LightState::default()
+    }
+
+    pub fn brightness(mut self, brightness: u8) -> LightState {
+        self.brightness = Some(brightness);
+        self
+    }
+
+    pub fn color_temp(mut self, kelvin: u32) -> LightState {
+        self.color_temp = Some(kelvin);
+        self
+    }
+
+    pub fn hsv(mut self, hue: u16, saturation: u8, value: u8) -> LightState {
+        self.hue = Some(hue);
+        self.saturation = Some(saturation);
+        self.brightness = Some(value);
+        self.color_temp = Some(0);
+        self
+    }
+
+    pub fn transition(mut self, period: Duration) -> LightState {
+        self.transition_period = Some(period.as_millis().min(u32::MAX as u128) as u32);
+        self
+    }
+
+    /// A state that brings the light back to `self`: its colour and brightness,
+    /// and on or off as it was.
+    pub fn restoring(&self) -> LightState {
+        let shown = self.effective();
+        let white = shown.color_temp.is_some_and(|k| k != 0);
+        LightState {
+            on_off: self.on_off,
+            hue: if white { None } else { shown.hue },
+            saturation: if white { None } else { shown.saturation },
+            color_temp: shown.color_temp,
+            brightness: shown.brightness,
+            ..LightState::default()
+        }
+    }
+
+    /// The state the light is actually showing: `dft_on_state` when the bulb is off.
+    pub fn effective(&self) -> &LightState {
+        match &self.dft_on_state {
+            Some(state) if self.on_off == 0 => state,
+            _ => self,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct LightDetails {
+    pub lamp_beam_angle: i64,
+    pub min_voltage: i64,
+    pub max_voltage: i64,
+    pub wattage: i64,
+    pub incandescent_equivalent: i64,
+    pub max_lumens: i64,
+    pub color_rendering_index: i64,
+    pub err_code: i64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct LightingResponse {
+    pub get_light_state: Option<LightState>,
+    /// Light strips answer `set_light_state` instead.
+    #[serde(alias = "set_light_state")]
+    pub transition_light_state: Option<LightState>,
+    pub get_light_details: Option<LightDetails>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LightCapabilities {
+    pub dimmable: bool,
+    pub color: bool,
+    /// Supported colour temperatures in K, `None` if the bulb has a fixed white.
+    pub color_temp: Option<(u32, u32)>,
+}
+
+impl LightCapabilities {
+    pub fn from_sysinfo(sysinfo: &SystemGetSysInfoResponse) -> LightCapabilities {
+        let color_temp = if sysinfo.is_variable_color_temp != 0 {
+            Some(KELVIN_RANGES.iter()
+                .find(|(model, _)| sysinfo.model.starts_with(model))
+                .map(|(_, range)| *range)
+                .unwrap_or(DEFAULT_KELVIN_RANGE))
+        } else {
+            None
+        };
+        LightCapabilities {
+            dimmable: sysinfo.is_dimmable != 0,
+            color: sysinfo.is_color != 0,
+            color_temp,
+        }
+    }
+
+    pub fn check(&self, state: &LightState) -> Result<(), PlugError> {
+        let mut problems: Vec<String> = Vec::new();
+        if let Some(brightness) = state.brightness {
+            if !self.dimmable {
+                problems.push(String::from("bulb is not dimmable"));
+            } else if brightness > 100 {
+                problems.push(format!("brightness {} is outside 0..=100", brightness));
+            }
+        }
+        if state.hue.is_some() || state.saturation.is_some() {
+            if !self.color {
+                problems.push(String::from("bulb has no colour"));
+            }
+            if state.hue.is_some_and(|h| h > 360) {
+                problems.push(format!("hue {} is outside 0..=360", state.hue.unwrap_or_default()));
+            }
+            if state.saturation.is_some_and(|s| s > 100) {
+                problems.push(format!("saturation {} is outside 0..=100", state.saturation.unwrap_or_default()));
+            }
+        }
+        match (state.color_temp, self.color_temp) {
+            (None, _) | (Some(0), _) => {}
+            (Some(_), None) => problems.push(String::from("bulb has no adjustable colour temperature")),
+            (Some(k), Some((min, max))) if k < min || k > max =>
+                problems.push(format!("colour temperature {} K is outside {}..={} K", k, min, max)),
+            _ => {}
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(PlugError::new(problems.join(", ").as_str()))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Bulb {
+    device: TpLinkDevice,
+    capabilities: LightCapabilities,
+}
+
+impl Bulb {
+    /// Reads the bulb's capabilities.
+    pub fn connect(device: TpLinkDevice) -> Result<Bulb, PlugError> {
+        let sysinfo = device.sysinfo()?;
+        let capabilities = LightCapabilities::from_sysinfo(&sysinfo);
+        Ok(Bulb::with_capabilities(device.identified(&sysinfo), capabilities))
+    }
+
+    pub fn with_capabilities(device: TpLinkDevice, capabilities: LightCapabilities) -> Bulb {
+        Bulb {
+            device: device.with_device_type(DeviceType::Bulb),
+            capabilities,
+        }
+    }
+
+    pub fn device(&self) -> &TpLinkDevice {
+        &self.device
+    }
+
+    pub fn capabilities(&self) -> &LightCapabilities {
+        &self.capabilities
+    }
+
+    pub fn light_state(&self) -> Result<LightState, PlugError> {
+        let cmd = commands::get_light_state();
+        let command = commands::name(&cmd);
+        match self.device.send(cmd)?.lighting.and_then(|l| l.get_light_state) {
+            Some(state) => Ok(state),
+            None => Err(self.device.in_context(PlugError::new("Response has no light state"), &command)),
+        }
+    }
+
+    pub fn light_details(&self) -> Result<LightDetails, PlugError> {
+        let cmd = commands::get_light_details();
+        let command = commands::name(&cmd);
+        match self.device.send(cmd)?.lighting.and_then(|l| l.get_light_details) {
+            Some(details) => Ok(details),
+            None => Err(self.device.in_context(PlugError::new("Response has no light details"), &command)),
+        }
+    }
+
+    /// Applies `state` and returns the state the bulb reports back.
+    pub fn set_state(&self, state: &LightState) -> Result<LightState, PlugError> {
+        self.capabilities.check(state)?;
+        let cmd = router::command(self.device.device_type(), self.device.model(), &Request::LightState(state))?;
+        let command = commands::name(&cmd);
+        let response = self.device.send(cmd)?;
+        match response.lighting.and_then(|l| l.transition_light_state) {
+            Some(state) if state.err_code.unwrap_or(0) == 0 => Ok(state),
+            Some(state) => Err(self.device.in_context(PlugError::new(
+                format!("Failed with err_code {}", state.err_code.unwrap_or_default()).as_str()), &command)),
+            None => Err(self.device.in_context(PlugError::new("Response has no light state"), &command)),
+        }
+    }
+
+    /// Lets the bulb move to `state` gradually over `duration`.
+    pub fn fade_to(&self, state: &LightState, duration: Duration) -> Result<LightState, PlugError> {
+        self.set_state(&state.clone().transition(duration))
+    }
+
+    pub fn on(&self) -> Result<LightState, PlugError> {
+        self.set_state(&LightState::on())
+    }
+
+    pub fn off(&self) -> Result<LightState, PlugError> {
+        self.set_state(&LightState::off())
+    }
+
+    pub fn set_brightness(&self, brightness: u8) -> Result<LightState, PlugError> {
+        self.set_state(&LightState::on().brightness(brightness))
+    }
+
+    pub fn set_color_temp(&self, kelvin: u32) -> Result<LightState, PlugError> {
+        self.set_state(&LightState::on().color_temp(kelvin))
+    }
+
+    /// `hue` in degrees, `saturation` and `value` (brightness) in percent.
+    pub fn set_hsv(&self, hue: u16, saturation: u8, value: u8) -> Result<LightState, PlugError> {
+        self.set_state(&LightState::on().hsv(hue, saturation, value))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use crate::DeviceType;
+    use super::{Bulb, LightCapabilities};
+
+    const LIGHTING: &str = "smartlife.iot.smartbulb.lightingservice";
+
+    /// A bulb that echoes transitions back as its new state.
+    pub(crate) fn bulb(model: &str, is_color: u8, requests: Arc<Mutex<Vec<Value>>>) -> TpLinkDevice {
+        let model = String::from(model);
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            requests.lock().unwrap().push(request.clone());
+            let response = if request["system"].get("get_sysinfo").is_some() {
+                json!({"system": {"get_sysinfo": {
+                    "sw_ver": "1.8.8", "hw_ver": "1.0", "model": model, "mic_type": "IOT.SMARTBULB",
+                    "mic_mac": "50C7BF000002", "alias": "Desk lamp", "is_dimmable": 1, "is_color": is_color,
+                    "is_variable_color_temp": 1, "rssi": -55, "err_code": 0
+                }}})
+            } else if request.get("smartlife.iot.common.emeter").is_some() {
+                json!({"smartlife.iot.common.emeter": {"get_realtime": {"power_mw": 7500, "err_code": 0}}})
+            } else if let Some(state) = request[LIGHTING].get("transition_light_state") {
+                let mut state = state.clone();
+                state["err_code"] = json!(0);
+                json!({LIGHTING: {"transition_light_state": state}})
+            } else {
+                json!({LIGHTING: {"get_light_state": {"on_off": 0, "err_code": 0,
+                    "dft_on_state": {"mode": "normal", "hue": 0, "saturation": 0, "color_temp": 2700, "brightness": 80}}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("bulb", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_ranges_per_model() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let bulb = Bulb::connect(bulb("KL120(US)", 0, requests.clone())).unwrap();
+        assert_eq!(*bulb.capabilities(), LightCapabilities { dimmable: true, color: false, color_temp: Some((2700, 5000)) });
+
+        assert!(bulb.set_color_temp(6500).is_err());
+        assert!(bulb.set_hsv(120, 50, 50).is_err());
+        assert!(bulb.set_brightness(101).is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let state = bulb.set_brightness(30).unwrap();
+        assert_eq!((state.on_off, state.brightness), (1, Some(30)));
+        assert_eq!(requests.lock().unwrap()[1][LIGHTING]["transition_light_state"], json!({"on_off": 1, "brightness": 30}));
+    }
+
+    #[test]
+    fn test_state_while_off() {
+        let bulb = Bulb::connect(bulb("KL130(EU)", 1, Arc::new(Mutex::new(Vec::new())))).unwrap();
+        let state = bulb.light_state().unwrap();
+        assert_eq!(state.on_off, 0);
+        assert_eq!(state.effective().color_temp, Some(2700));
+        assert_eq!(bulb.set_hsv(240, 100, 60).unwrap().hue, Some(240));
+    }
+
+    #[test]
+    fn test_emeter_namespace() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let device = bulb("KL130(EU)", 1, requests.clone()).detect().unwrap();
+        assert_eq!(device.device_type(), DeviceType::Bulb);
+        assert_eq!(device.power_reading().unwrap().power_w, 7.5);
+        assert!(requests.lock().unwrap()[1].get("emeter").is_none());
+    }
+}
@@ -0,0 +1,86 @@
+/*
+ * One place for events to go through, so the watcher, detectors, the rules
+ * engine and the webhooks don't each need their own channel plumbing:
+ *
+ *   let bus = EventBus::new();
+ *   let rules = bus.subscribe();
+ *   let hooks = bus.subscribe();
+ *   bus.attach(watcher.spawn().into());
+ *   thread::spawn(move || notifier.forward(hooks));
+ *   engine.publish_to(&bus).run(rules);
+ *
+ * Every subscriber gets every event, in the order it was published.
+ * Subscribers that are dropped are forgotten on the next publish.
+ */
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::events::Event;
+
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// Receives what is published from now on.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+
+    /// Publishes everything from `events` in a background thread, until the sender side hangs up.
+    pub fn attach(&self, events: Receiver<Event>) {
+        let bus = self.clone();
+        thread::spawn(move || {
+            for event in events {
+                bus.publish(event);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use crate::events::Event;
+    use super::EventBus;
+
+    #[test]
+    fn test_fan_out() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        drop(bus.subscribe());
+
+        let (tx, rx) = mpsc::channel();
+        bus.attach(rx);
+        tx.send(Event::DeviceOnline { device: String::from("heater") }).unwrap();
+        bus.publish(Event::CommandFailed {
+            device: String::from("heater"),
+            command: String::from("system.set_relay_state"),
+            reason: String::from("Empty response"),
+        });
+
+        let received = [first.recv().unwrap(), first.recv().unwrap()];
+        assert!(received.iter().any(|e| matches!(e, Event::DeviceOnline { .. })));
+        assert!(received.iter().any(|e| matches!(e, Event::CommandFailed { .. })));
+        assert_eq!(second.iter().take(2).count(), 2);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 2);
+    }
+}
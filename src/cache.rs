@@ -0,0 +1,181 @@
+/*
+ * Keeps sysinfo replies for a while, so that `alias()`, `is_on()` and friends
+ * called in quick succession ask the device once:
+ *
+ *   let (cached, cache) = plug.cached(Duration::from_secs(5));
+ *   println!("{} is {}", cached.sysinfo()?.alias, cached.sysinfo()?.relay_state);
+ *   cache.invalidate();
+ *
+ * Any command that changes the device drops what was kept for it. The device
+ * `cached` was called on still goes to the device every time.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+use crate::TpLinkDevice;
+use crate::commands::is_read_only;
+use crate::protocol::{decrypt_payload, size_from_bytes};
+use crate::timing::{self, Clock};
+use crate::transport::Transport;
+use crate::types::{PlugError, SystemGetSysInfoResponse};
+
+/// Address and request.
+type Key = (String, String);
+
+pub struct SysinfoCache {
+    inner: Arc<dyn Transport>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    /// Response frames and when they arrived. Keyed by request too, since the
+    /// outlets of a strip ask with a context.
+    entries: Mutex<HashMap<Key, (Instant, Vec<u8>)>>,
+}
+
+fn is_sysinfo(cmd: &Value) -> bool {
+    cmd.get("system").and_then(Value::as_object).is_some_and(|s| s.len() == 1 && s.contains_key("get_sysinfo"))
+}
+
+impl SysinfoCache {
+    pub fn new(inner: Arc<dyn Transport>, ttl: Duration) -> SysinfoCache {
+        SysinfoCache {
+            inner,
+            ttl,
+            clock: timing::system(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> SysinfoCache {
+        self.clock = clock;
+        self
+    }
+
+    /// Forgets everything, so the next read of every device goes to the device.
+    pub fn invalidate(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+
+    /// Reads `device`'s sysinfo afresh and keeps it.
+    pub fn refresh(&self, device: &TpLinkDevice) -> Result<SystemGetSysInfoResponse, PlugError> {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|(address, _), _| address != device.address());
+        }
+        device.sysinfo()
+    }
+}
+
+impl Transport for SysinfoCache {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let cmd: Option<Value> = if frame.len() >= 4 && frame.len() >= size_from_bytes(frame) + 4 {
+            serde_json::from_slice(&decrypt_payload(frame)).ok()
+        } else {
+            None
+        };
+        let key = match &cmd {
+            Some(cmd) if is_sysinfo(cmd) => (String::from(address), cmd.to_string()),
+            Some(cmd) if is_read_only(cmd) => return self.inner.request(address, frame),
+            _ => {
+                if let Ok(mut entries) = self.entries.lock() {
+                    entries.retain(|(cached, _), _| cached != address);
+                }
+                return self.inner.request(address, frame);
+            }
+        };
+
+        if let Some((at, response)) = self.entries.lock().ok().and_then(|e| e.get(&key).cloned()) {
+            if self.clock.now().saturating_duration_since(at) < self.ttl {
+                return Ok(response);
+            }
+        }
+        let response = self.inner.request(address, frame)?;
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(key, (self.clock.now(), response.clone()));
+        }
+        Ok(response)
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose sysinfo reads are served from a cache for `ttl`.
+    pub fn cached(&self, ttl: Duration) -> (TpLinkDevice, Arc<SysinfoCache>) {
+        self.cached_with_clock(ttl, timing::system())
+    }
+
+    /// Like `cached`, with entries aged by `clock`.
+    pub fn cached_with_clock(&self, ttl: Duration, clock: Arc<dyn Clock>) -> (TpLinkDevice, Arc<SysinfoCache>) {
+        let cache = Arc::new(SysinfoCache::new(self.transport.clone(), ttl).with_clock(clock));
+        (self.with_inner(cache.clone()), cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::timing::MockClock;
+    use crate::types::PlugError;
+
+    #[test]
+    fn test_reads_cached_until_write() {
+        let sent = Arc::new(Mutex::new(0));
+        let count = sent.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            *count.lock().unwrap() += 1;
+            let response = if request["system"].get("get_sysinfo").is_some() {
+                json!({"system": {"get_sysinfo": {"alias": "Kettle", "relay_state": 1, "err_code": 0}}})
+            } else {
+                json!({"system": {"set_relay_state": {"err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        let plug = TpLinkDevice::with_transport("plug", Arc::new(transport));
+        let (cached, cache) = plug.cached(Duration::from_secs(60));
+
+        assert_eq!(cached.sysinfo().unwrap().alias, "Kettle");
+        assert_eq!(cached.sysinfo().unwrap().relay_state, 1);
+        assert_eq!(*sent.lock().unwrap(), 1);
+
+        cached.off().unwrap();
+        cached.sysinfo().unwrap();
+        assert_eq!(*sent.lock().unwrap(), 3);
+
+        plug.sysinfo().unwrap();
+        cache.refresh(&cached).unwrap();
+        cached.sysinfo().unwrap();
+        assert_eq!(*sent.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let sent = Arc::new(Mutex::new(0));
+        let count = sent.clone();
+        let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            *count.lock().unwrap() += 1;
+            Ok(encrypt_payload(br#"{"system":{"get_sysinfo":{"alias":"Kettle","err_code":0}}}"#.to_vec()))
+        };
+        let clock = Arc::new(MockClock::new());
+        let (cached, _) = TpLinkDevice::with_transport("plug", Arc::new(transport))
+            .cached_with_clock(Duration::from_secs(5), clock.clone());
+
+        cached.sysinfo().unwrap();
+        clock.advance(Duration::from_secs(4));
+        cached.sysinfo().unwrap();
+        assert_eq!(*sent.lock().unwrap(), 1);
+        clock.advance(Duration::from_secs(2));
+        cached.sysinfo().unwrap();
+        assert_eq!(*sent.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * How much CO2 the grid emits per kWh, for reports that show emissions next to
+ * energy and for rules that wait for cleaner power:
+ *
+ *   let grid: Arc<dyn CarbonIntensityProvider> = Arc::new(UkGrid::new());
+ *   engine.carbon(grid.clone()).rule(Rule::new("pool pump")
+ *       .when(Trigger::at(10, 0)?)
+ *       .only_if(Condition::CarbonBelow { g_per_kwh: 150.0 })
+ *       .then(Action::TurnOn("pump".into())));
+ *   let reporter = Reporter::new(&history).with_carbon(grid);
+ *
+ * `StaticIntensity` is one figure for all times, such as a country's yearly
+ * average. `UkGrid`, with the `carbon` feature, asks the National Grid ESO
+ * carbon intensity API, which needs no key, for the half hour in question.
+ */
+
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+
+use crate::types::PlugError;
+
+/// A rough average for European grids, in g CO2 per kWh.
+pub const DEFAULT_G_PER_KWH: f64 = 300.0;
+
+pub trait CarbonIntensityProvider: Send + Sync {
+    /// Grams of CO2 per kWh drawn at `at`.
+    fn intensity(&self, at: DateTime<Utc>) -> Result<f64, PlugError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StaticIntensity(pub f64);
+
+impl Default for StaticIntensity {
+    fn default() -> StaticIntensity {
+        StaticIntensity(DEFAULT_G_PER_KWH)
+    }
+}
+
+impl CarbonIntensityProvider for StaticIntensity {
+    fn intensity(&self, _: DateTime<Utc>) -> Result<f64, PlugError> {
+        Ok(self.0)
+    }
+}
+
+impl<F> CarbonIntensityProvider for F
+    where F: Fn(DateTime<Utc>) -> Result<f64, PlugError> + Send + Sync {
+    fn intensity(&self, at: DateTime<Utc>) -> Result<f64, PlugError> {
+        self(at)
+    }
+}
+
+/// The default provider, `StaticIntensity` at `DEFAULT_G_PER_KWH`.
+pub fn fixed() -> Arc<dyn CarbonIntensityProvider> {
+    Arc::new(StaticIntensity::default())
+}
+
+#[cfg(feature = "carbon")]
+pub use uk::UkGrid;
+
+#[cfg(feature = "carbon")]
+mod uk {
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+    use serde_json::Value;
+
+    use crate::types::PlugError;
+    use super::CarbonIntensityProvider;
+
+    pub const API: &str = "https://api.carbonintensity.org.uk";
+
+    /// Great Britain's grid, by half hour. The measured figure is used once
+    /// there is one, the forecast before that. The last answer is kept so a
+    /// rules engine checking every minute asks once per half hour.
+    pub struct UkGrid {
+        url: String,
+        agent: ureq::Agent,
+        last: Mutex<Option<(DateTime<Utc>, f64)>>,
+    }
+
+    impl Default for UkGrid {
+        fn default() -> UkGrid {
+            UkGrid::new()
+        }
+    }
+
+    impl UkGrid {
+        pub fn new() -> UkGrid {
+            UkGrid::at_url(API)
+        }
+
+        /// For a mirror or a test server.
+        pub fn at_url(url: &str) -> UkGrid {
+            UkGrid {
+                url: String::from(url.trim_end_matches('/')),
+                agent: ureq::Agent::config_builder()
+                    .timeout_global(Some(Duration::from_secs(10)))
+                    .build()
+                    .into(),
+                last: Mutex::new(None),
+            }
+        }
+    }
+
+    pub(super) fn parse(body: &str) -> Result<f64, PlugError> {
+        let value: Value = serde_json::from_str(body)?;
+        let intensity = &value["data"][0]["intensity"];
+        intensity["actual"].as_f64().or_else(|| intensity["forecast"].as_f64())
+            .ok_or_else(|| PlugError::new("Carbon intensity response has no figure"))
+    }
+
+    impl CarbonIntensityProvider for UkGrid {
+        fn intensity(&self, at: DateTime<Utc>) -> Result<f64, PlugError> {
+            let period = at.duration_trunc(TimeDelta::minutes(30)).unwrap_or(at);
+            if let Some((cached, g)) = *self.last.lock().map_err(|_| PlugError::new("Cache lock poisoned"))? {
+                if cached == period {
+                    return Ok(g);
+                }
+            }
+            let url = format!("{}/intensity/{}", self.url, period.format("%Y-%m-%dT%H:%MZ"));
+            let body = self.agent.get(url.as_str()).call()
+                .and_then(|mut response| response.body_mut().read_to_string())
+                .map_err(|e| PlugError::new(format!("Fetching {} failed: {}", url, e).as_str()))?;
+            let g = parse(&body)?;
+            if let Ok(mut last) = self.last.lock() {
+                *last = Some((period, g));
+            }
+            Ok(g)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use crate::types::PlugError;
+    use super::{CarbonIntensityProvider, StaticIntensity};
+
+    #[test]
+    fn test_providers() {
+        assert_eq!(StaticIntensity::default().intensity(Utc::now()).unwrap(), 300.0);
+        let by_hour = |at: chrono::DateTime<Utc>| -> Result<f64, PlugError> {
+            Ok(if chrono::Timelike::hour(&at) < 12 { 120.0 } else { 250.0 })
+        };
+        let morning = chrono::DateTime::from_timestamp(1_717_405_200, 0).unwrap();
+        assert_eq!(by_hour.intensity(morning).unwrap(), 120.0);
+    }
+
+    #[test]
+    #[cfg(feature = "carbon")]
+    fn test_parse_uk_grid() {
+        use super::uk::parse;
+        let body = r#"{"data":[{"from":"2024-06-03T12:00Z","to":"2024-06-03T12:30Z",
+            "intensity":{"forecast":140,"actual":null,"index":"low"}}]}"#;
+        assert_eq!(parse(body).unwrap(), 140.0);
+        assert_eq!(parse(&body.replace("null", "152")).unwrap(), 152.0);
+        assert!(parse(r#"{"data":[]}"#).is_err());
+    }
+}
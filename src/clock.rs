@@ -0,0 +1,459 @@
+/*
+ * The device clock (`time` namespace) with typed replies. The device keeps
+ * local time and a timezone index from TP-Link's table; it doesn't report a
+ * UTC offset. `Timezone` is that table, one zone per index, named after a
+ * city the app lists for it:
+ *
+ *   let time = plug.device_time()?;
+ *   plug.set_timezone(Timezone::EuropeBerlin, Local::now().naive_local())?;
+ *   assert_eq!(plug.timezone()?.zone(), Some(Timezone::EuropeBerlin));
+ *
+ *   let clock = plug.clock()?;
+ *   println!("{} local, {:?} UTC", clock.local, clock.utc);
+ *   println!("{}s off", plug.clock_drift()?.num_seconds());
+ *
+ * The table follows the order of the Windows time zone list the app was
+ * built around. Offsets are those of standard time; the device applies DST
+ * itself, so its local time is the wall-clock time of its zone all year.
+ * `Dst` is the rule each zone follows as of 2024, for the common rules: the
+ * EU's, North America's, and those of south-east Australia and New Zealand.
+ * Zones whose rules change from year to year or follow another calendar are
+ * `Dst::Varies`, and their UTC offset is left unknown rather than guessed.
+ * Local times in the hour repeated when DST ends are read as standard time.
+ */
+
+#[cfg(feature = "time")]
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc, Weekday};
+
+#[cfg(feature = "time")]
+use alloc::format;
+#[cfg(feature = "time")]
+use crate::{TpLinkDevice, types::PlugError};
+
+tplink_command! {
+    /// The device's local time.
+    fn device_time / get_time() = "time"."get_time" -> DeviceTime {
+        year: i64,
+        month: i64,
+        mday: i64,
+        hour: i64,
+        min: i64,
+        sec: i64,
+    }
+}
+
+tplink_command! {
+    fn timezone / get_timezone() = "time"."get_timezone" -> TimezoneInfo {
+        index: i64,
+    }
+}
+
+tplink_command! {
+    /// Sets local time and timezone index together, as the firmware expects.
+    fn set_clock / set_timezone(year: i32, month: u32, mday: u32, hour: u32, min: u32, sec: u32, index: u32)
+        = "time"."set_timezone";
+}
+
+/// When a zone moves its clocks an hour ahead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dst {
+    Never,
+    /// Last Sunday of March to last Sunday of October, at 01:00 UTC.
+    Eu,
+    /// Second Sunday of March to first Sunday of November, at 02:00 local time.
+    NorthAmerica,
+    /// First Sunday of October to first Sunday of April, at 02:00 standard time.
+    Australia,
+    /// Last Sunday of September to first Sunday of April, at 02:00 standard time.
+    NewZealand,
+    /// Not known in advance, or not worked out here.
+    Varies,
+}
+
+#[cfg(feature = "time")]
+impl Dst {
+    /// Whether DST is in effect at `utc` in a zone `standard` minutes east of
+    /// UTC, `None` for `Varies`.
+    pub fn in_effect(self, utc: NaiveDateTime, standard: i32) -> Option<bool> {
+        let year = (utc + Duration::minutes(standard as i64)).year();
+        let sunday = |month: u32, n: u8| -> NaiveDate {
+            NaiveDate::from_weekday_of_month_opt(year, month, Weekday::Sun, n)
+                .or_else(|| NaiveDate::from_weekday_of_month_opt(year, month, Weekday::Sun, n - 1))
+                .unwrap_or_default()
+        };
+        const LAST: u8 = 5;
+        // Changes at `hour` local standard time on `day`, as UTC.
+        let at = |day: NaiveDate, hour: u32| day.and_hms_opt(hour, 0, 0).unwrap_or_default() - Duration::minutes(standard as i64);
+        let (start, end) = match self {
+            Dst::Never => return Some(false),
+            Dst::Varies => return None,
+            Dst::Eu => (sunday(3, LAST).and_hms_opt(1, 0, 0)?, sunday(10, LAST).and_hms_opt(1, 0, 0)?),
+            Dst::NorthAmerica => (at(sunday(3, 2), 2), at(sunday(11, 1), 1)),
+            Dst::Australia => (at(sunday(10, 1), 2), at(sunday(4, 1), 2)),
+            Dst::NewZealand => (at(sunday(9, LAST), 2), at(sunday(4, 1), 2)),
+        };
+        Some(if start < end { start <= utc && utc < end } else { utc >= start || utc < end })
+    }
+}
+
+macro_rules! timezones {
+    ($($zone:ident = $index:literal, $name:literal, $offset:literal, $dst:ident;)*) => {
+        /// TP-Link's timezone indexes.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Timezone {
+            $($zone = $index,)*
+        }
+
+        impl Timezone {
+            pub const ALL: &'static [Timezone] = &[$(Timezone::$zone,)*];
+
+            pub fn from_index(index: i64) -> Option<Timezone> {
+                match index {
+                    $($index => Some(Timezone::$zone),)*
+                    _ => None,
+                }
+            }
+
+            /// The IANA name of a zone with the same rules.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Timezone::$zone => $name,)*
+                }
+            }
+
+            /// Minutes east of UTC outside DST.
+            pub fn utc_offset_minutes(self) -> i32 {
+                match self {
+                    $(Timezone::$zone => $offset,)*
+                }
+            }
+
+            pub fn dst(self) -> Dst {
+                match self {
+                    $(Timezone::$zone => Dst::$dst,)*
+                }
+            }
+        }
+    };
+}
+
+timezones! {
+    UtcMinus12 = 0, "Etc/GMT+12", -720, Never;
+    PacificPagoPago = 1, "Pacific/Pago_Pago", -660, Never;
+    PacificHonolulu = 2, "Pacific/Honolulu", -600, Never;
+    AmericaAnchorage = 3, "America/Anchorage", -540, NorthAmerica;
+    AmericaTijuana = 4, "America/Tijuana", -480, NorthAmerica;
+    UtcMinus08 = 5, "Etc/GMT+8", -480, Never;
+    AmericaLosAngeles = 6, "America/Los_Angeles", -480, NorthAmerica;
+    AmericaPhoenix = 7, "America/Phoenix", -420, Never;
+    AmericaMazatlan = 8, "America/Mazatlan", -420, Never;
+    UtcMinus07 = 9, "Etc/GMT+7", -420, Never;
+    AmericaDenver = 10, "America/Denver", -420, NorthAmerica;
+    AmericaMexicoCity = 11, "America/Mexico_City", -360, Never;
+    AmericaGuatemala = 12, "America/Guatemala", -360, Never;
+    AmericaChicago = 13, "America/Chicago", -360, NorthAmerica;
+    AmericaMonterrey = 14, "America/Monterrey", -360, Never;
+    AmericaRegina = 15, "America/Regina", -360, Never;
+    AmericaBogota = 16, "America/Bogota", -300, Never;
+    AmericaNewYork = 17, "America/New_York", -300, NorthAmerica;
+    AmericaIndianaIndianapolis = 18, "America/Indiana/Indianapolis", -300, NorthAmerica;
+    AmericaCaracas = 19, "America/Caracas", -240, Never;
+    AmericaAsuncion = 20, "America/Asuncion", -240, Varies;
+    AmericaLaPaz = 21, "America/La_Paz", -240, Never;
+    AmericaHalifax = 22, "America/Halifax", -240, NorthAmerica;
+    AmericaCuiaba = 23, "America/Cuiaba", -240, Never;
+    AmericaManaus = 24, "America/Manaus", -240, Never;
+    AmericaSantiago = 25, "America/Santiago", -240, Varies;
+    AmericaStJohns = 26, "America/St_Johns", -210, NorthAmerica;
+    AmericaSaoPaulo = 27, "America/Sao_Paulo", -180, Never;
+    AmericaArgentinaBuenosAires = 28, "America/Argentina/Buenos_Aires", -180, Never;
+    AmericaCayenne = 29, "America/Cayenne", -180, Never;
+    AmericaMiquelon = 30, "America/Miquelon", -180, NorthAmerica;
+    AmericaMontevideo = 31, "America/Montevideo", -180, Never;
+    AmericaPuntaArenas = 32, "America/Punta_Arenas", -180, Never;
+    UtcMinus02 = 33, "Etc/GMT+2", -120, Never;
+    AtlanticAzores = 34, "Atlantic/Azores", -60, Eu;
+    AtlanticCapeVerde = 35, "Atlantic/Cape_Verde", -60, Never;
+    AfricaCasablanca = 36, "Africa/Casablanca", 0, Varies;
+    EtcUTC = 37, "Etc/UTC", 0, Never;
+    EuropeLondon = 38, "Europe/London", 0, Eu;
+    AfricaMonrovia = 39, "Africa/Monrovia", 0, Never;
+    EuropeBerlin = 40, "Europe/Berlin", 60, Eu;
+    EuropeBelgrade = 41, "Europe/Belgrade", 60, Eu;
+    EuropeParis = 42, "Europe/Paris", 60, Eu;
+    EuropeWarsaw = 43, "Europe/Warsaw", 60, Eu;
+    AfricaLagos = 44, "Africa/Lagos", 60, Never;
+    AfricaWindhoek = 45, "Africa/Windhoek", 120, Never;
+    AsiaAmman = 46, "Asia/Amman", 120, Varies;
+    EuropeAthens = 47, "Europe/Athens", 120, Eu;
+    AsiaBeirut = 48, "Asia/Beirut", 120, Varies;
+    AfricaCairo = 49, "Africa/Cairo", 120, Varies;
+    AsiaDamascus = 50, "Asia/Damascus", 120, Varies;
+    EuropeChisinau = 51, "Europe/Chisinau", 120, Varies;
+    AfricaHarare = 52, "Africa/Harare", 120, Never;
+    EuropeHelsinki = 53, "Europe/Helsinki", 120, Eu;
+    EuropeIstanbul = 54, "Europe/Istanbul", 180, Never;
+    AsiaJerusalem = 55, "Asia/Jerusalem", 120, Varies;
+    EuropeKaliningrad = 56, "Europe/Kaliningrad", 120, Never;
+    AfricaTripoli = 57, "Africa/Tripoli", 120, Never;
+    AsiaBaghdad = 58, "Asia/Baghdad", 180, Never;
+    AsiaRiyadh = 59, "Asia/Riyadh", 180, Never;
+    EuropeMinsk = 60, "Europe/Minsk", 180, Never;
+    EuropeMoscow = 61, "Europe/Moscow", 180, Never;
+    AfricaNairobi = 62, "Africa/Nairobi", 180, Never;
+    AsiaTehran = 63, "Asia/Tehran", 210, Never;
+    AsiaDubai = 64, "Asia/Dubai", 240, Never;
+    AsiaBaku = 65, "Asia/Baku", 240, Never;
+    EuropeSamara = 66, "Europe/Samara", 240, Never;
+    IndianMauritius = 67, "Indian/Mauritius", 240, Never;
+    AsiaTbilisi = 68, "Asia/Tbilisi", 240, Never;
+    AsiaYerevan = 69, "Asia/Yerevan", 240, Never;
+    AsiaKabul = 70, "Asia/Kabul", 270, Never;
+    AsiaTashkent = 71, "Asia/Tashkent", 300, Never;
+    AsiaYekaterinburg = 72, "Asia/Yekaterinburg", 300, Never;
+    AsiaKarachi = 73, "Asia/Karachi", 300, Never;
+    AsiaKolkata = 74, "Asia/Kolkata", 330, Never;
+    AsiaColombo = 75, "Asia/Colombo", 330, Never;
+    AsiaKathmandu = 76, "Asia/Kathmandu", 345, Never;
+    AsiaAlmaty = 77, "Asia/Almaty", 360, Never;
+    AsiaDhaka = 78, "Asia/Dhaka", 360, Never;
+    AsiaNovosibirsk = 79, "Asia/Novosibirsk", 420, Never;
+    AsiaYangon = 80, "Asia/Yangon", 390, Never;
+    AsiaBangkok = 81, "Asia/Bangkok", 420, Never;
+    AsiaKrasnoyarsk = 82, "Asia/Krasnoyarsk", 420, Never;
+    AsiaShanghai = 83, "Asia/Shanghai", 480, Never;
+    AsiaIrkutsk = 84, "Asia/Irkutsk", 480, Never;
+    AsiaSingapore = 85, "Asia/Singapore", 480, Never;
+    AustraliaPerth = 86, "Australia/Perth", 480, Never;
+    AsiaTaipei = 87, "Asia/Taipei", 480, Never;
+    AsiaUlaanbaatar = 88, "Asia/Ulaanbaatar", 480, Never;
+    AsiaTokyo = 89, "Asia/Tokyo", 540, Never;
+    AsiaSeoul = 90, "Asia/Seoul", 540, Never;
+    AsiaYakutsk = 91, "Asia/Yakutsk", 540, Never;
+    AustraliaAdelaide = 92, "Australia/Adelaide", 570, Australia;
+    AustraliaDarwin = 93, "Australia/Darwin", 570, Never;
+    AustraliaBrisbane = 94, "Australia/Brisbane", 600, Never;
+    AustraliaSydney = 95, "Australia/Sydney", 600, Australia;
+    PacificGuam = 96, "Pacific/Guam", 600, Never;
+    AustraliaHobart = 97, "Australia/Hobart", 600, Australia;
+    AsiaVladivostok = 98, "Asia/Vladivostok", 600, Never;
+    AsiaMagadan = 99, "Asia/Magadan", 660, Never;
+    AsiaSrednekolymsk = 100, "Asia/Srednekolymsk", 660, Never;
+    PacificGuadalcanal = 101, "Pacific/Guadalcanal", 660, Never;
+    AsiaAnadyr = 102, "Asia/Anadyr", 720, Never;
+    PacificAuckland = 103, "Pacific/Auckland", 720, NewZealand;
+    UtcPlus12 = 104, "Etc/GMT-12", 720, Never;
+    PacificFiji = 105, "Pacific/Fiji", 720, Never;
+    PacificTongatapu = 106, "Pacific/Tongatapu", 780, Never;
+    PacificApia = 107, "Pacific/Apia", 780, Never;
+    PacificKiritimati = 108, "Pacific/Kiritimati", 840, Never;
+}
+
+impl Timezone {
+    pub fn index(self) -> u32 {
+        self as u32
+    }
+
+    pub fn from_name(name: &str) -> Option<Timezone> {
+        Timezone::ALL.iter().copied().find(|zone| zone.name() == name)
+    }
+}
+
+#[cfg(feature = "time")]
+impl Timezone {
+    /// Minutes east of UTC at `utc`, DST included; `None` where the zone's DST `Varies`.
+    pub fn utc_offset_at(self, utc: DateTime<Utc>) -> Option<i32> {
+        let standard = self.utc_offset_minutes();
+        Some(standard + if self.dst().in_effect(utc.naive_utc(), standard)? { 60 } else { 0 })
+    }
+
+    /// The instant `local` wall-clock time in this zone stands for; `None` where
+    /// the zone's DST `Varies`.
+    pub fn to_utc(self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let standard = local - Duration::minutes(self.utc_offset_minutes() as i64);
+        let offset = self.utc_offset_at(Utc.from_utc_datetime(&standard))?;
+        Some(Utc.from_utc_datetime(&(local - Duration::minutes(offset as i64))))
+    }
+}
+
+impl TimezoneInfo {
+    /// `None` for an index outside the table.
+    pub fn zone(&self) -> Option<Timezone> {
+        Timezone::from_index(self.index)
+    }
+}
+
+#[cfg(feature = "time")]
+impl TpLinkDevice {
+    /// Sets the timezone, with `local` the current time there.
+    pub fn set_timezone(&self, zone: Timezone, local: NaiveDateTime) -> Result<(), PlugError> {
+        self.set_clock(local.year(), local.month(), local.day(), local.hour(), local.minute(), local.second(),
+                       zone.index())
+    }
+}
+
+#[cfg(feature = "time")]
+impl DeviceTime {
+    pub fn to_naive(&self) -> Option<NaiveDateTime> {
+        chrono::NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.mday as u32)?
+            .and_hms_opt(self.hour as u32, self.min as u32, self.sec as u32)
+    }
+}
+
+/// The device clock as read by `TpLinkDevice::clock`.
+#[cfg(feature = "time")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DeviceClock {
+    /// The time the device shows.
+    pub local: NaiveDateTime,
+    /// `None` for an index outside the table.
+    pub zone: Option<Timezone>,
+    /// `None` without a zone, or for one whose DST `Varies`.
+    pub utc: Option<DateTime<Utc>>,
+}
+
+#[cfg(feature = "time")]
+impl DeviceClock {
+    /// `None` if the device reports a date that doesn't exist.
+    pub fn new(time: &DeviceTime, timezone: &TimezoneInfo) -> Option<DeviceClock> {
+        let local = time.to_naive()?;
+        let zone = timezone.zone();
+        let utc = zone.and_then(|zone| zone.to_utc(local));
+        Some(DeviceClock { local, zone, utc })
+    }
+
+    /// How far the device is ahead of `host`, `None` without `utc`.
+    pub fn drift_from(&self, host: DateTime<Utc>) -> Option<Duration> {
+        Some(self.utc? - host)
+    }
+}
+
+#[cfg(feature = "time")]
+impl TpLinkDevice {
+    /// Reads the local time and timezone.
+    pub fn clock(&self) -> Result<DeviceClock, PlugError> {
+        let timezone = self.timezone()?;
+        let time = self.device_time()?;
+        DeviceClock::new(&time, &timezone).ok_or_else(|| self.in_context(PlugError::new(
+            format!("Device time {}-{}-{} {}:{}:{} is not a valid date",
+                    time.year, time.month, time.mday, time.hour, time.min, time.sec).as_str()), "time.get_time"))
+    }
+
+    /// How far the device clock is ahead of this host's, to the second the
+    /// device reports. Host time is taken halfway through the exchange.
+    #[cfg(feature = "std")]
+    pub fn clock_drift(&self) -> Result<Duration, PlugError> {
+        let before = crate::now();
+        let clock = self.clock()?;
+        let host = before + (crate::now() - before) / 2;
+        let error = match clock.zone {
+            Some(zone) => format!("DST rules for {} aren't known", zone.name()),
+            None => String::from("Timezone index is not in the table"),
+        };
+        clock.drift_from(host).ok_or_else(|| self.in_context(PlugError::new(error.as_str()), "time.get_timezone"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::{set_timezone, DeviceTime, Timezone};
+
+    fn plug() -> TpLinkDevice {
+        let transport = |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let response = if request["time"].get("get_time").is_some() {
+                json!({"time": {"get_time": {"year": 2024, "month": 6, "mday": 3, "hour": 18, "min": 30, "sec": 5,
+                    "err_code": 0}}})
+            } else if request["time"].get("get_timezone").is_some() {
+                json!({"time": {"get_timezone": {"index": 38, "err_code": 0}}})
+            } else {
+                json!({"time": {"set_timezone": {"err_code": -3, "err_msg": "invalid argument"}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("plug", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_generated_builder() {
+        assert_eq!(set_timezone(2024, 6, 3, 18, 30, 0, 39),
+                   json!({"time": {"set_timezone": {"year": 2024, "month": 6, "mday": 3, "hour": 18, "min": 30,
+                       "sec": 0, "index": 39}}}));
+        assert_eq!(super::get_time(), crate::commands::get_time());
+    }
+
+    #[test]
+    fn test_generated_methods() {
+        let time: DeviceTime = plug().device_time().unwrap();
+        assert_eq!((time.mday, time.hour, time.sec), (3, 18, 5));
+        #[cfg(feature = "time")]
+        assert_eq!(time.to_naive().unwrap().to_string(), "2024-06-03 18:30:05");
+
+        let error = plug().set_clock(2024, 6, 3, 18, 30, 0, 39).unwrap_err();
+        assert_eq!(error.to_string(), "plug, time.set_timezone: Failed with err_code -3 (invalid argument)");
+    }
+
+    #[test]
+    #[cfg(feature = "time")]
+    fn test_device_clock() {
+        use chrono::{Duration, TimeZone, Utc};
+        use super::{DeviceClock, TimezoneInfo};
+
+        let clock = plug().clock().unwrap();
+        assert_eq!((clock.local.to_string(), clock.zone), ("2024-06-03 18:30:05".into(), Some(Timezone::EuropeLondon)));
+        assert_eq!(clock.utc, Some(Utc.with_ymd_and_hms(2024, 6, 3, 17, 30, 5).unwrap()));
+
+        let time = DeviceTime { year: 2024, month: 6, mday: 3, hour: 18, min: 30, sec: 5 };
+        let berlin = DeviceClock::new(&time, &TimezoneInfo { index: 40 }).unwrap();
+        let host = Utc.with_ymd_and_hms(2024, 6, 3, 16, 31, 0).unwrap();
+        assert_eq!(berlin.utc.unwrap().to_string(), "2024-06-03 16:30:05 UTC");
+        assert_eq!(berlin.drift_from(host), Some(Duration::seconds(-55)));
+        let winter = DeviceClock::new(&DeviceTime { month: 1, ..time.clone() }, &TimezoneInfo { index: 40 }).unwrap();
+        assert_eq!(winter.utc.unwrap().to_string(), "2024-01-03 17:30:05 UTC");
+
+        let unknown = DeviceClock::new(&time, &TimezoneInfo { index: 200 }).unwrap();
+        assert_eq!((unknown.zone, unknown.drift_from(host)), (None, None));
+        let varies = DeviceClock::new(&time, &TimezoneInfo { index: Timezone::AfricaCairo.index() as i64 }).unwrap();
+        assert_eq!((varies.zone, varies.utc), (Some(Timezone::AfricaCairo), None));
+        assert_eq!(DeviceClock::new(&DeviceTime { month: 13, ..time }, &TimezoneInfo::default()), None);
+    }
+
+    #[test]
+    #[cfg(feature = "time")]
+    fn test_dst_transitions() {
+        use chrono::{TimeZone, Utc};
+        let offset = |zone: Timezone, y, m, d, h, min| zone.utc_offset_at(Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap());
+
+        // 2024-03-31 01:00 UTC in Europe, 2024-03-10 10:00 UTC (02:00 PST) in Los Angeles.
+        assert_eq!((offset(Timezone::EuropeBerlin, 2024, 3, 31, 0, 59), offset(Timezone::EuropeBerlin, 2024, 3, 31, 1, 0)),
+                   (Some(60), Some(120)));
+        assert_eq!(offset(Timezone::EuropeLondon, 2024, 10, 27, 1, 0), Some(0));
+        assert_eq!((offset(Timezone::AmericaLosAngeles, 2024, 3, 10, 9, 59), offset(Timezone::AmericaLosAngeles, 2024, 3, 10, 10, 0)),
+                   (Some(-480), Some(-420)));
+        assert_eq!(offset(Timezone::AmericaLosAngeles, 2024, 11, 3, 9, 0), Some(-480));
+        // Southern summers span the new year: Sydney from 2023-10-01, Auckland until 2024-04-07.
+        assert_eq!(offset(Timezone::AustraliaSydney, 2024, 1, 15, 0, 0), Some(660));
+        assert_eq!(offset(Timezone::AustraliaSydney, 2024, 7, 15, 0, 0), Some(600));
+        assert_eq!((offset(Timezone::PacificAuckland, 2024, 4, 6, 13, 59), offset(Timezone::PacificAuckland, 2024, 4, 6, 14, 0)),
+                   (Some(780), Some(720)));
+        assert_eq!(offset(Timezone::AsiaTokyo, 2024, 7, 1, 0, 0), Some(540));
+        assert_eq!(offset(Timezone::AsiaJerusalem, 2024, 7, 1, 0, 0), None);
+    }
+
+    #[test]
+    fn test_timezone_table() {
+        assert_eq!(Timezone::ALL.len(), 109);
+        assert!(Timezone::ALL.iter().enumerate().all(|(i, zone)| zone.index() == i as u32));
+        assert_eq!(Timezone::EuropeBerlin.index(), 40);
+        assert_eq!(Timezone::from_index(40), Some(Timezone::EuropeBerlin));
+        assert_eq!(Timezone::from_name("Asia/Kolkata").map(Timezone::utc_offset_minutes), Some(330));
+        assert_eq!(Timezone::from_index(109), None);
+        assert_eq!(set_timezone(2024, 6, 3, 18, 30, 0, Timezone::EuropeLondon.index())["time"]["set_timezone"]["index"], 38);
+    }
+}
@@ -0,0 +1,211 @@
+/*
+ * Whether a device is bound to a TP-Link cloud account, and moving it to
+ * another one:
+ *
+ *   match plug.cloud_status()? {
+ *       CloudStatus::Bound { username } => println!("bound to {}", username),
+ *       status => println!("{:?}", status),
+ *   }
+ *   plug.rebind("me@example.com", "secret")?;
+ *
+ * A device bound to one account refuses to bind to another, so `rebind`
+ * unbinds it first. A non-zero `err_code` from `get_info` usually means the
+ * device can't reach the cloud server and is reported as a status, not an error.
+ *
+ * For devices that should only be used locally, `disable_cloud` points them
+ * at a server that can't be reached and waits until they have disconnected,
+ * and `verify_local_only` checks that they stayed that way:
+ *
+ *   plug.disable_cloud(Duration::from_secs(30))?;
+ *   let problems = plug.verify_local_only()?.problems();
+ *
+ * Neither unbinds the device, so its account keeps it once the server is set back.
+ */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::TpLinkDevice;
+use crate::commands;
+use crate::types::PlugError;
+
+/// The reply to `cnCloud.get_info`.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CloudInfo {
+    pub username: String,
+    pub server: String,
+    pub binded: i64,
+    /// 1 while connected to the server.
+    pub cld_connection: i64,
+    pub err_code: i64,
+}
+
+/// Where `disable_cloud` points devices: their own loopback address, which
+/// firmwares accept where some refuse an empty server.
+pub const LOCAL_ONLY_SERVER: &str = "127.0.0.1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CloudStatus {
+    Unbound,
+    Bound { username: String },
+    /// The device answered with this `err_code`.
+    Error { code: i64 },
+}
+
+impl From<&CloudInfo> for CloudStatus {
+    fn from(info: &CloudInfo) -> CloudStatus {
+        match (info.err_code, info.binded) {
+            (0, 0) => CloudStatus::Unbound,
+            (0, _) => CloudStatus::Bound { username: info.username.clone() },
+            (code, _) => CloudStatus::Error { code },
+        }
+    }
+}
+
+/// What `verify_local_only` found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalOnly {
+    pub server: String,
+    pub connected: bool,
+}
+
+impl LocalOnly {
+    /// What keeps the device from being local only; nothing if it is.
+    pub fn problems(&self) -> Vec<&'static str> {
+        let mut problems = Vec::new();
+        if self.connected {
+            problems.push("connected to the cloud server");
+        }
+        if self.server != LOCAL_ONLY_SERVER {
+            problems.push("the cloud server is reachable if the network allows it");
+        }
+        problems
+    }
+}
+
+impl TpLinkDevice {
+    /// `get_info` as it came, including a non-zero `err_code`.
+    pub fn cloud_info(&self) -> Result<CloudInfo, PlugError> {
+        let mut response = self.send_raw(commands::get_cloud_info())?;
+        match response.get_mut("cnCloud").and_then(|c| c.get_mut("get_info")).map(Value::take) {
+            Some(info) => serde_json::from_value(info).map_err(|e| self.in_context(e.into(), "cnCloud.get_info")),
+            None => Err(self.in_context(PlugError::new("Response has no cloud info"), "cnCloud.get_info")),
+        }
+    }
+
+    pub fn cloud_status(&self) -> Result<CloudStatus, PlugError> {
+        self.cloud_info().map(|info| CloudStatus::from(&info))
+    }
+
+    /// Binds to the account `user`, unbinding from the current one first, and
+    /// returns the status afterwards.
+    pub fn rebind(&self, user: &str, password: &str) -> Result<CloudStatus, PlugError> {
+        if let CloudStatus::Bound { .. } = self.cloud_status()? {
+            self.call::<IgnoredAny>(commands::unregister_device(), "cnCloud", "unbind")?;
+        }
+        self.call::<IgnoredAny>(commands::connect_to_cloud(user, password), "cnCloud", "bind")?;
+        self.cloud_status()
+    }
+
+    pub fn verify_local_only(&self) -> Result<LocalOnly, PlugError> {
+        let info = self.cloud_info()?;
+        Ok(LocalOnly { server: info.server, connected: info.cld_connection != 0 })
+    }
+
+    /// Points the device at `LOCAL_ONLY_SERVER` and waits up to `timeout` for
+    /// it to drop its cloud connection.
+    #[cfg(feature = "std")]
+    pub fn disable_cloud(&self, timeout: std::time::Duration) -> Result<LocalOnly, PlugError> {
+        use std::time::{Duration, Instant};
+
+        self.call::<IgnoredAny>(commands::set_server_url(LOCAL_ONLY_SERVER), "cnCloud", "set_server_url")?;
+        let started = Instant::now();
+        loop {
+            let state = self.verify_local_only()?;
+            if state.server != LOCAL_ONLY_SERVER {
+                return Err(PlugError::new(alloc::format!("Server is still {}", state.server).as_str()));
+            }
+            if !state.connected {
+                return Ok(state);
+            }
+            if started.elapsed() >= timeout {
+                return Err(PlugError::new("Still connected to the cloud"));
+            }
+            std::thread::sleep(Duration::from_millis(500).min(timeout));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::CloudStatus;
+
+    #[test]
+    fn test_rebind() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        let bound = Arc::new(Mutex::new(Some(String::from("old@example.com"))));
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let (method, args) = request["cnCloud"].as_object().unwrap().iter().next().unwrap();
+            seen.lock().unwrap().push(method.clone());
+            let mut bound = bound.lock().unwrap();
+            let reply = match method.as_str() {
+                "unbind" => {
+                    *bound = None;
+                    json!({"err_code": 0})
+                }
+                "bind" if bound.is_some() => json!({"err_code": -10, "err_msg": "already bound"}),
+                "bind" => {
+                    *bound = args["username"].as_str().map(String::from);
+                    json!({"err_code": 0})
+                }
+                _ => json!({"username": bound.clone().unwrap_or_default(), "binded": bound.is_some() as i64, "err_code": 0}),
+            };
+            Ok(encrypt_payload(json!({"cnCloud": {method.as_str(): reply}}).to_string().into_bytes()))
+        };
+        let plug = TpLinkDevice::with_transport("plug", Arc::new(transport));
+
+        let status = plug.rebind("me@example.com", "secret").unwrap();
+        assert_eq!(status, CloudStatus::Bound { username: String::from("me@example.com") });
+        assert_eq!(*calls.lock().unwrap(), ["get_info", "unbind", "bind", "get_info"]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_disable_cloud() {
+        use super::LOCAL_ONLY_SERVER;
+
+        let server = Arc::new(Mutex::new(String::from("n-devs.tplinkcloud.com")));
+        let polls = Arc::new(Mutex::new(0));
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let mut server = server.lock().unwrap();
+            let response = if let Some(set) = request["cnCloud"].get("set_server_url") {
+                *server = String::from(set["server"].as_str().unwrap());
+                json!({"cnCloud": {"set_server_url": {"err_code": 0}}})
+            } else {
+                // The connection drops on the second look after the change.
+                let mut polls = polls.lock().unwrap();
+                *polls += 1;
+                let connected = *server != LOCAL_ONLY_SERVER || *polls < 2;
+                json!({"cnCloud": {"get_info": {"server": *server, "cld_connection": connected as i64, "err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        let plug = TpLinkDevice::with_transport("plug", Arc::new(transport));
+
+        let state = plug.disable_cloud(std::time::Duration::from_secs(5)).unwrap();
+        assert!(state.problems().is_empty());
+        assert_eq!(plug.verify_local_only().unwrap().server, LOCAL_ONLY_SERVER);
+    }
+}
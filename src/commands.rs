@@ -0,0 +1,533 @@
+/*
+ * Request bodies for every supported command, as documented in
+ * https://github.com/softScheck/tplink-smartplug/blob/master/tplink-smarthome-commands.txt
+ *
+ * These only build JSON and are usable without `std`, e.g. from firmware that
+ * encrypts them with `protocol::encrypt_payload` and talks to plugs on its own.
+ * Built with `default-features = false` the crate needs nothing but serde and
+ * serde_json; the `time` feature adds chrono for timestamps, tariffs and
+ * dates, `std` and `net` the rest, and `full` everything.
+ */
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde_json::{json, Value};
+
+use crate::bulb::LightState;
+use crate::schedule::{CountdownRule, ScheduleRule};
+
+/// A command encrypted at compile time, for the ones polling loops send over
+/// and over. `frame` is exactly what `encrypt_payload` makes of the builder's
+/// JSON, so devices and transports can't tell the two apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticCommand {
+    /// "namespace.method", as `name` gives it.
+    pub name: &'static str,
+    pub frame: &'static [u8],
+}
+
+macro_rules! static_command {
+    ($name:literal, $body:literal) => {{
+        const BODY: &[u8] = $body.as_bytes();
+        const FRAME: [u8; BODY.len() + 4] = crate::protocol::encrypt_const(BODY);
+        StaticCommand { name: $name, frame: &FRAME }
+    }};
+}
+
+/// `get_meter_info()`.
+pub const GET_SYSINFO: StaticCommand = static_command!("system.get_sysinfo", r#"{"system":{"get_sysinfo":{}}}"#);
+/// `get_realtime()`.
+pub const GET_REALTIME: StaticCommand = static_command!("emeter.get_realtime", r#"{"emeter":{"get_realtime":{}}}"#);
+/// `set_relay_state(1)`.
+pub const RELAY_ON: StaticCommand =
+    static_command!("system.set_relay_state", r#"{"system":{"set_relay_state":{"state":1}}}"#);
+/// `set_relay_state(0)`.
+pub const RELAY_OFF: StaticCommand =
+    static_command!("system.set_relay_state", r#"{"system":{"set_relay_state":{"state":0}}}"#);
+
+pub fn set_relay_state(state: u8) -> Value {
+    json!({
+        "system": {
+            "set_relay_state": {
+                "state": state
+            }
+        }
+    })
+}
+
+pub fn get_realtime() -> Value {
+    json!({
+        "emeter": {
+            "get_realtime": {}
+        }
+    })
+}
+
+pub fn get_daystat(year: i32, month: u32) -> Value {
+    json!({
+        "emeter": {
+            "get_daystat": {
+                "year": year,
+                "month": month
+            }
+        }
+    })
+}
+
+pub fn reboot() -> Value {
+    json!({
+        "system": {
+            "reboot": {
+                "delay": 1
+            }
+        }
+    })
+}
+
+pub fn reset_to_factory() -> Value {
+    json!({
+        "system": {
+            "reset": {
+                "delay": 1
+            }
+        }
+    })
+}
+
+pub fn turn_led_off() -> Value {
+    set_led_off(1)
+}
+
+pub fn set_led_off(off: u8) -> Value {
+    json!({
+        "system": {
+            "set_led_off": {
+                "off": off
+            }
+        }
+    })
+}
+
+pub fn set_device_alias(name: &str) -> Value {
+    json!({
+        "system": {
+            "set_dev_alias": {
+                "alias": name
+            }
+        }
+    })
+}
+
+pub fn set_mac_address(mac: &str) -> Value {
+    json!({
+        "system": {
+            "set_mac_addr": {
+                "mac": mac
+            }
+        }
+    })
+}
+
+pub fn set_device_id(device_id: &str) -> Value {
+    json!({
+        "system": {
+            "set_device_id": {
+                "deviceId": device_id
+            }
+        }
+    })
+}
+
+pub fn set_hardware_id(hardware_id: &str) -> Value {
+    json!({
+        "system": {
+            "set_hw_id": {
+                "hwId": hardware_id
+            }
+        }
+    })
+}
+
+pub fn set_location(latitude: f64, longitude: f64) -> Value {
+    json!({
+        "system": {
+            "set_dev_location": {
+                "longitude": longitude,
+                "latitude": latitude,
+            }
+        }
+    })
+}
+
+fn ten_thousandths(degrees: f64) -> i64 {
+    let scaled = degrees * 10000.0;
+    (if scaled < 0.0 { scaled - 0.5 } else { scaled + 0.5 }) as i64
+}
+
+/// For firmware that keeps the location in 1/10000 degree.
+pub fn set_location_i(latitude: f64, longitude: f64) -> Value {
+    json!({
+        "system": {
+            "set_dev_location": {
+                "longitude_i": ten_thousandths(longitude),
+                "latitude_i": ten_thousandths(latitude),
+            }
+        }
+    })
+}
+
+pub fn uboot_bootloader_check() -> Value {
+    json!({
+        "system": {
+            "test_check_uboot": null
+        }
+    })
+}
+
+pub fn get_device_icon() -> Value {
+    json!({
+        "system": {
+            "get_dev_icon": null
+        }
+    })
+}
+
+pub fn set_device_icon(icon: &str, hash: &str) -> Value {
+    json!({
+        "system": {
+            "set_dev_icon": {
+                "icon": icon,
+                "hash": hash,
+            }
+        }
+    })
+}
+
+pub fn set_test_mode() -> Value {
+    json!({
+        "system": {
+            "set_test_mode": {
+                "enable": 1
+            }
+        }
+    })
+}
+
+pub fn download_firmware_from_url(url: &str) -> Value {
+    json!({
+        "system": {
+            "download_firmware": {
+                "url": url
+            }
+        }
+    })
+}
+
+pub fn get_download_state() -> Value {
+    json!({
+        "system": {
+            "get_download_state": {}
+        }
+    })
+}
+
+pub fn flash_downloaded_firmware() -> Value {
+    json!({
+        "system": {
+            "flash_firmware": {}
+        }
+    })
+}
+
+pub fn check_config() -> Value {
+    json!({
+        "system": {
+            "check_new_config": null
+        }
+    })
+}
+
+pub fn scan_available_aps() -> Value {
+    json!({
+        "netif": {
+            "get_scaninfo": {
+                "refresh": 1
+            }
+        }
+    })
+}
+
+/// Asks for a fresh scan lasting up to `timeout` seconds, which makes firmwares that
+/// support it report channel and signal strength per AP.
+pub fn deep_scan_aps(timeout: u32) -> Value {
+    json!({
+        "netif": {
+            "get_scaninfo": {
+                "refresh": 1,
+                "timeout": timeout
+            }
+        }
+    })
+}
+
+pub fn get_stainfo() -> Value {
+    json!({
+        "netif": {
+            "get_stainfo": {}
+        }
+    })
+}
+
+pub fn connect_to_ap(ssid: &str, password: &str) -> Value {
+    json!({
+        "netif": {
+            "set_stainfo": {
+                "ssid": ssid,
+                "password": password,
+                "key_type": 3
+            }
+        }
+    })
+}
+
+pub fn get_cloud_info() -> Value {
+    json!({
+        "cnCloud": {
+            "get_info": null
+        }
+    })
+}
+
+pub fn get_firmware_list() -> Value {
+    json!({
+        "cnCloud": {
+            "get_intl_fw_list": {}
+        }
+    })
+}
+
+pub fn set_server_url(server_url: &str) -> Value {
+    json!({
+        "cnCloud": {
+            "set_server_url": {
+                "server": server_url,
+            }
+        }
+    })
+}
+
+pub fn connect_to_cloud(user: &str, password: &str) -> Value {
+    json!({
+        "cnCloud": {
+            "bind": {
+                "username": user,
+                "password": password,
+            }
+        }
+    })
+}
+
+pub fn unregister_device() -> Value {
+    json!({
+        "cnCloud": {
+            "unbind": null
+        }
+    })
+}
+
+pub fn get_time() -> Value {
+    json!({
+        "time": {
+            "get_time": null
+        }
+    })
+}
+
+pub fn get_timezone() -> Value {
+    json!({
+        "time": {
+            "get_timezone": null
+        }
+    })
+}
+
+pub fn set_timezone() -> Value {
+    json!({
+        "time": {
+            "set_timezone": {
+                "year": 1,
+                "month": 2,
+                "mday": 3,
+                "hour": 4,
+                "min": 5,
+                "sec": 6,
+                "index": 42
+            }
+        }
+    })
+}
+
+pub fn get_meter_info() -> Value {
+    json!({
+        "system": {
+             "get_sysinfo": {}
+        }
+    })
+}
+
+pub fn get_countdown_rules() -> Value {
+    json!({
+        "count_down": {
+            "get_rules": null
+        }
+    })
+}
+
+pub fn add_countdown_rule(rule: &CountdownRule) -> Value {
+    json!({
+        "count_down": {
+            "add_rule": rule
+        }
+    })
+}
+
+pub fn delete_countdown_rule(id: &str) -> Value {
+    json!({
+        "count_down": {
+            "delete_rule": {
+                "id": id
+            }
+        }
+    })
+}
+
+pub fn delete_all_countdown_rules() -> Value {
+    json!({
+        "count_down": {
+            "delete_all_rules": null
+        }
+    })
+}
+
+pub fn get_schedule_rules() -> Value {
+    json!({
+        "schedule": {
+            "get_rules": null
+        }
+    })
+}
+
+pub fn add_schedule_rule(rule: &ScheduleRule) -> Value {
+    json!({
+        "schedule": {
+            "add_rule": rule
+        }
+    })
+}
+
+pub fn edit_schedule_rule(rule: &ScheduleRule) -> Value {
+    json!({
+        "schedule": {
+            "edit_rule": rule
+        }
+    })
+}
+
+pub fn delete_schedule_rule(id: &str) -> Value {
+    json!({
+        "schedule": {
+            "delete_rule": {
+                "id": id
+            }
+        }
+    })
+}
+
+pub fn delete_all_schedule_rules() -> Value {
+    json!({
+        "schedule": {
+            "delete_all_rules": null
+        }
+    })
+}
+
+pub fn get_light_state() -> Value {
+    json!({
+        "smartlife.iot.smartbulb.lightingservice": {
+            "get_light_state": null
+        }
+    })
+}
+
+pub fn get_light_details() -> Value {
+    json!({
+        "smartlife.iot.smartbulb.lightingservice": {
+            "get_light_details": null
+        }
+    })
+}
+
+pub fn transition_light_state(state: &LightState) -> Value {
+    json!({
+        "smartlife.iot.smartbulb.lightingservice": {
+            "transition_light_state": state
+        }
+    })
+}
+
+/// "namespace.method" for each method in `cmd`, for messages.
+pub fn name(cmd: &Value) -> String {
+    let mut names = Vec::new();
+    if let Some(namespaces) = cmd.as_object() {
+        for (namespace, methods) in namespaces.iter().filter(|(namespace, _)| *namespace != "context") {
+            match methods.as_object() {
+                Some(methods) => names.extend(methods.keys().map(|m| format!("{}.{}", namespace, m))),
+                None => names.push(namespace.clone()),
+            }
+        }
+    }
+    names.join(", ")
+}
+
+/// Addresses `cmd` to one outlet of a power strip instead of the strip itself.
+pub fn for_child(mut cmd: Value, child_id: &str) -> Value {
+    if let Some(namespaces) = cmd.as_object_mut() {
+        namespaces.insert("context".into(), json!({"child_ids": [child_id]}));
+    }
+    cmd
+}
+
+/// Whether every method in `cmd` only reads state: `get_*` queries and the
+/// `check_*` style self tests. Anything else may change the device.
+pub fn is_read_only(cmd: &Value) -> bool {
+    let namespaces = match cmd.as_object() {
+        Some(namespaces) => namespaces,
+        None => return false,
+    };
+    namespaces.iter()
+        .filter(|(namespace, _)| *namespace != "context")
+        .all(|(_, methods)| match methods.as_object() {
+            Some(methods) => methods.keys().all(|m| m.starts_with("get_") || m.contains("check")),
+            None => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::encrypt_payload;
+    use super::*;
+
+    #[test]
+    fn test_static_commands_match_builders() {
+        for (command, built) in [
+            (GET_SYSINFO, get_meter_info()),
+            (GET_REALTIME, get_realtime()),
+            (RELAY_ON, set_relay_state(1)),
+            (RELAY_OFF, set_relay_state(0)),
+        ] {
+            assert_eq!(command.frame, encrypt_payload(built.to_string().into_bytes()), "{}", command.name);
+            assert_eq!(command.name, name(&built));
+        }
+    }
+}
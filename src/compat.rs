@@ -0,0 +1,360 @@
+/*
+ * The response structs as earlier releases had them, and conversions to and
+ * from the current ones, so code written against an old shape keeps working
+ * while it moves over:
+ *
+ *   let old: compat::v0_1::PlugResponse = serde_json::from_str(&saved)?;
+ *   let response = types::PlugResponse::from(old);
+ *   let sysinfo = compat::v0_1::SystemGetSysInfoResponse::from(plug.sysinfo()?);
+ *
+ * The response structs in `types`, `bulb` and `schedule` are
+ * `#[non_exhaustive]`, so new fields aren't a breaking change: outside this
+ * crate, make them with `Default` and set fields, rather than with a struct
+ * literal. Each module here is named after the release whose shapes it holds
+ * and is kept as it was, serde attributes included, so JSON saved by that
+ * release reads back. Converting to an old shape drops what it has no field
+ * for; converting from one fills what it lacks with defaults.
+ */
+
+use alloc::vec::Vec;
+
+use crate::types;
+
+/// Release 0.1.
+pub mod v0_1 {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Default, Debug, PartialEq, Deserialize, Serialize)]
+    pub struct SystemGetSysInfoResponse {
+        pub errcode: i64,
+        pub sw_ver: String,
+        pub hw_ver: String,
+        #[serde(rename = "type")]
+        pub hw_type: String,
+        pub model: String,
+        pub mac: String,
+        #[serde(rename = "deviceId")]
+        pub device_id: String,
+        #[serde(rename= "hwId")]
+        pub hw_id: String,
+        #[serde(rename = "fwId")]
+        pub fw_id: String,
+        #[serde(rename = "oemId")]
+        pub oem_id: String,
+        pub alias: String,
+        pub dev_name: String,
+        pub icon_hash: String,
+        pub relay_state: i64,
+        pub on_time: i64,
+        pub active_mode: String,
+        pub feature: String,
+        pub updating: i64,
+        pub rssi: i64,
+        pub led_off: i64,
+        pub latitude: f64,
+        pub longitude: f64,
+    }
+
+    /// `get_sysinfo` wasn't optional yet.
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct SystemResponse {
+        pub get_sysinfo: SystemGetSysInfoResponse
+    }
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct EmeterGetRealtimeResponse {
+        pub current: Option<f64>,
+        pub current_ma: Option<f64>,
+        pub voltage: Option<f64>,
+        pub voltage_mv: Option<f64>,
+        pub power: Option<f64>,
+        pub power_mw: Option<f64>,
+        pub total: Option<f64>,
+        pub total_wh: Option<f64>,
+        pub err_code: i64,
+    }
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct EmeterGetVGainIGainResponse {
+        pub vgain: i64,
+        pub igain: i64,
+        pub err_code: i64,
+    }
+
+    /// `energy` is in kWh; firmware reporting Wh wasn't read yet.
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct EmeterGetDaystatItem {
+        pub year: i64,
+        pub month: i64,
+        pub day: i64,
+        pub energy: f64,
+    }
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct EmeterGetDaystatResponse {
+        pub day_list: Vec<EmeterGetDaystatItem>,
+        pub err_code: i64,
+    }
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct EmeterResponse {
+        pub get_realtime: Option<EmeterGetRealtimeResponse>,
+        pub get_vgain_igain: Option<EmeterGetVGainIGainResponse>,
+        pub get_daystat: Option<EmeterGetDaystatResponse>
+    }
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct PlugResponse {
+        pub system: Option<SystemResponse>,
+        pub emeter: Option<EmeterResponse>
+    }
+}
+
+impl From<v0_1::SystemGetSysInfoResponse> for types::SystemGetSysInfoResponse {
+    fn from(old: v0_1::SystemGetSysInfoResponse) -> types::SystemGetSysInfoResponse {
+        types::SystemGetSysInfoResponse {
+            errcode: old.errcode,
+            sw_ver: old.sw_ver,
+            hw_ver: old.hw_ver,
+            hw_type: old.hw_type,
+            model: old.model,
+            mac: old.mac,
+            device_id: old.device_id,
+            hw_id: old.hw_id,
+            fw_id: old.fw_id,
+            oem_id: old.oem_id,
+            alias: old.alias,
+            dev_name: old.dev_name,
+            icon_hash: old.icon_hash,
+            relay_state: old.relay_state,
+            on_time: old.on_time,
+            active_mode: old.active_mode,
+            feature: old.feature,
+            updating: old.updating,
+            rssi: old.rssi,
+            led_off: old.led_off,
+            latitude: old.latitude,
+            longitude: old.longitude,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<types::SystemGetSysInfoResponse> for v0_1::SystemGetSysInfoResponse {
+    fn from(new: types::SystemGetSysInfoResponse) -> v0_1::SystemGetSysInfoResponse {
+        v0_1::SystemGetSysInfoResponse {
+            errcode: new.errcode,
+            sw_ver: new.sw_ver,
+            hw_ver: new.hw_ver,
+            hw_type: new.hw_type,
+            model: new.model,
+            mac: new.mac,
+            device_id: new.device_id,
+            hw_id: new.hw_id,
+            fw_id: new.fw_id,
+            oem_id: new.oem_id,
+            alias: new.alias,
+            dev_name: new.dev_name,
+            icon_hash: new.icon_hash,
+            relay_state: new.relay_state,
+            on_time: new.on_time,
+            active_mode: new.active_mode,
+            feature: new.feature,
+            updating: new.updating,
+            rssi: new.rssi,
+            led_off: new.led_off,
+            // Where only the fixed-point location was reported.
+            latitude: new.latitude_i.map_or(new.latitude, |l| l as f64 / 10_000.0),
+            longitude: new.longitude_i.map_or(new.longitude, |l| l as f64 / 10_000.0),
+        }
+    }
+}
+
+impl From<v0_1::SystemResponse> for types::SystemResponse {
+    fn from(old: v0_1::SystemResponse) -> types::SystemResponse {
+        types::SystemResponse {
+            get_sysinfo: Some(old.get_sysinfo.into()),
+        }
+    }
+}
+
+/// A response without sysinfo becomes the default one.
+impl From<types::SystemResponse> for v0_1::SystemResponse {
+    fn from(new: types::SystemResponse) -> v0_1::SystemResponse {
+        v0_1::SystemResponse {
+            get_sysinfo: new.get_sysinfo.map(Into::into).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<v0_1::EmeterGetRealtimeResponse> for types::EmeterGetRealtimeResponse {
+    fn from(old: v0_1::EmeterGetRealtimeResponse) -> types::EmeterGetRealtimeResponse {
+        types::EmeterGetRealtimeResponse {
+            current: old.current,
+            current_ma: old.current_ma,
+            voltage: old.voltage,
+            voltage_mv: old.voltage_mv,
+            power: old.power,
+            power_mw: old.power_mw,
+            total: old.total,
+            total_wh: old.total_wh,
+            err_code: old.err_code,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<types::EmeterGetRealtimeResponse> for v0_1::EmeterGetRealtimeResponse {
+    fn from(new: types::EmeterGetRealtimeResponse) -> v0_1::EmeterGetRealtimeResponse {
+        v0_1::EmeterGetRealtimeResponse {
+            current: new.current,
+            current_ma: new.current_ma,
+            voltage: new.voltage,
+            voltage_mv: new.voltage_mv,
+            power: new.power,
+            power_mw: new.power_mw,
+            total: new.total,
+            total_wh: new.total_wh,
+            err_code: new.err_code,
+        }
+    }
+}
+
+impl From<v0_1::EmeterGetVGainIGainResponse> for types::EmeterGetVGainIGainResponse {
+    fn from(old: v0_1::EmeterGetVGainIGainResponse) -> types::EmeterGetVGainIGainResponse {
+        types::EmeterGetVGainIGainResponse {
+            vgain: old.vgain,
+            igain: old.igain,
+            err_code: old.err_code,
+        }
+    }
+}
+
+impl From<types::EmeterGetVGainIGainResponse> for v0_1::EmeterGetVGainIGainResponse {
+    fn from(new: types::EmeterGetVGainIGainResponse) -> v0_1::EmeterGetVGainIGainResponse {
+        v0_1::EmeterGetVGainIGainResponse {
+            vgain: new.vgain,
+            igain: new.igain,
+            err_code: new.err_code,
+        }
+    }
+}
+
+impl From<v0_1::EmeterGetDaystatItem> for types::EmeterGetDaystatItem {
+    fn from(old: v0_1::EmeterGetDaystatItem) -> types::EmeterGetDaystatItem {
+        types::EmeterGetDaystatItem {
+            year: old.year,
+            month: old.month,
+            day: old.day,
+            energy: Some(old.energy),
+            energy_wh: None,
+        }
+    }
+}
+
+/// Energy reported in Wh is converted to kWh.
+impl From<types::EmeterGetDaystatItem> for v0_1::EmeterGetDaystatItem {
+    fn from(new: types::EmeterGetDaystatItem) -> v0_1::EmeterGetDaystatItem {
+        v0_1::EmeterGetDaystatItem {
+            year: new.year,
+            month: new.month,
+            day: new.day,
+            energy: new.energy_kwh().unwrap_or_default(),
+        }
+    }
+}
+
+impl From<v0_1::EmeterGetDaystatResponse> for types::EmeterGetDaystatResponse {
+    fn from(old: v0_1::EmeterGetDaystatResponse) -> types::EmeterGetDaystatResponse {
+        types::EmeterGetDaystatResponse {
+            day_list: old.day_list.into_iter().map(Into::into).collect::<Vec<_>>(),
+            err_code: old.err_code,
+        }
+    }
+}
+
+impl From<types::EmeterGetDaystatResponse> for v0_1::EmeterGetDaystatResponse {
+    fn from(new: types::EmeterGetDaystatResponse) -> v0_1::EmeterGetDaystatResponse {
+        v0_1::EmeterGetDaystatResponse {
+            day_list: new.day_list.into_iter().map(Into::into).collect::<Vec<_>>(),
+            err_code: new.err_code,
+        }
+    }
+}
+
+impl From<v0_1::EmeterResponse> for types::EmeterResponse {
+    fn from(old: v0_1::EmeterResponse) -> types::EmeterResponse {
+        types::EmeterResponse {
+            get_realtime: old.get_realtime.map(Into::into),
+            get_vgain_igain: old.get_vgain_igain.map(Into::into),
+            get_daystat: old.get_daystat.map(Into::into),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<types::EmeterResponse> for v0_1::EmeterResponse {
+    fn from(new: types::EmeterResponse) -> v0_1::EmeterResponse {
+        v0_1::EmeterResponse {
+            get_realtime: new.get_realtime.map(Into::into),
+            get_vgain_igain: new.get_vgain_igain.map(Into::into),
+            get_daystat: new.get_daystat.map(Into::into),
+        }
+    }
+}
+
+impl From<v0_1::PlugResponse> for types::PlugResponse {
+    fn from(old: v0_1::PlugResponse) -> types::PlugResponse {
+        types::PlugResponse {
+            system: old.system.map(Into::into),
+            emeter: old.emeter.map(Into::into),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<types::PlugResponse> for v0_1::PlugResponse {
+    fn from(new: types::PlugResponse) -> v0_1::PlugResponse {
+        v0_1::PlugResponse {
+            system: new.system.map(Into::into),
+            emeter: new.emeter.map(Into::into),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types;
+    use super::v0_1;
+
+    #[test]
+    fn test_round_trip() {
+        // As 0.1 serialized it, with `errcode` not yet renamed.
+        let saved = r#"{"system":{"get_sysinfo":{"errcode":0,"sw_ver":"1.0.8","hw_ver":"1.0","type":"IOT.SMARTPLUGSWITCH",
+            "model":"HS110(EU)","mac":"50:C7:BF:00:00:01","deviceId":"D1","hwId":"H1","fwId":"F1","oemId":"O1",
+            "alias":"Heater","dev_name":"Smart Plug","icon_hash":"","relay_state":1,"on_time":60,"active_mode":"none",
+            "feature":"TIM:ENE","updating":0,"rssi":-60,"led_off":0,"latitude":51.5,"longitude":-0.1}},
+            "emeter":{"get_realtime":null,"get_vgain_igain":null,
+            "get_daystat":{"day_list":[{"year":2024,"month":6,"day":3,"energy":1.5}],"err_code":0}}}"#;
+        let old: v0_1::PlugResponse = serde_json::from_str(saved).unwrap();
+        let response = types::PlugResponse::from(old.clone());
+        let sysinfo = response.system.as_ref().unwrap().get_sysinfo.as_ref().unwrap();
+        assert_eq!((sysinfo.alias.as_str(), sysinfo.relay_state), ("Heater", 1));
+        let day = &response.emeter.as_ref().unwrap().get_daystat.as_ref().unwrap().day_list[0];
+        assert_eq!(day.energy_kwh(), Some(1.5));
+        assert_eq!(v0_1::PlugResponse::from(response), old);
+    }
+
+    #[test]
+    fn test_to_old_shape() {
+        let day = types::EmeterGetDaystatItem { energy_wh: Some(2500.0), ..Default::default() };
+        assert_eq!(v0_1::EmeterGetDaystatItem::from(day).energy, 2.5);
+
+        let sysinfo = types::SystemGetSysInfoResponse { latitude_i: Some(515_000), ..Default::default() };
+        assert_eq!(v0_1::SystemGetSysInfoResponse::from(sysinfo).latitude, 51.5);
+        assert_eq!(v0_1::SystemResponse::from(types::SystemResponse::default()).get_sysinfo,
+                   v0_1::SystemGetSysInfoResponse::default());
+    }
+}
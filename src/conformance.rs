@@ -0,0 +1,248 @@
+/*
+ * Read-only checks of how a device speaks the protocol, for telling which
+ * models and firmwares work and reporting those that don't:
+ *
+ *   let report = conformance::run(&TpLinkDevice::new("192.168.1.20"));
+ *   println!("{}", serde_json::to_string_pretty(&report)?);
+ *
+ * Nothing is changed on the device. Each check says whether it passed, why
+ * not, and how long the device took. Features the device doesn't claim, such
+ * as the meter on an HS100, are skipped rather than failed. The sysinfo fields
+ * this crate relies on for the kind of device are listed when missing, since
+ * that is what a new firmware most often gets wrong.
+ */
+
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{DeviceType, TpLinkDevice};
+use crate::commands;
+use crate::types::{EmeterGetRealtimeResponse, SystemGetSysInfoResponse};
+
+/// How many sysinfo requests the timing check makes.
+pub const TIMING_ROUNDS: usize = 5;
+/// A median reply time above this fails the timing check.
+pub const SLOW_REPLY: Duration = Duration::from_secs(1);
+
+/// Sysinfo fields the crate reads from a device of `kind`, each with the names it goes by on the wire.
+/// Bulbs, for instance, say `mic_mac` for `mac` and have no relay or LED, and strips have a relay only
+/// on each outlet.
+fn sysinfo_fields(kind: DeviceType) -> Vec<&'static [&'static str]> {
+    let mut fields: Vec<&'static [&'static str]> = Vec::from([
+        &["model"][..], &["sw_ver"], &["hw_ver"], &["deviceId"], &["alias"], &["rssi"],
+        &["mac", "mic_mac"], &["type", "mic_type"],
+    ]);
+    match kind {
+        DeviceType::Plug => fields.extend([&["relay_state"][..], &["feature"], &["led_off"]]),
+        DeviceType::Dimmer => fields.extend([&["relay_state"][..], &["feature"], &["led_off"], &["brightness"]]),
+        DeviceType::Strip => fields.extend([&["feature"][..], &["led_off"], &["children"]]),
+        DeviceType::Bulb => fields.push(&["light_state"]),
+        DeviceType::Unknown => {}
+    }
+    fields
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", content = "detail", rename_all = "snake_case")]
+pub enum Outcome {
+    Passed(String),
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub address: String,
+    pub model: Option<String>,
+    pub hw_ver: Option<String>,
+    pub sw_ver: Option<String>,
+    pub checks: Vec<CheckResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        !self.checks.iter().any(|check| matches!(check.outcome, Outcome::Failed(_)))
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|check| matches!(check.outcome, Outcome::Failed(_)))
+    }
+}
+
+/// The reply to `namespace`.`method`, or why there is none.
+fn reply(device: &TpLinkDevice, cmd: Value, namespace: &str, method: &str) -> Result<Value, String> {
+    let response = device.send_raw(cmd).map_err(|e| e.to_string())?;
+    let refused = |reply: &Value| match reply.get("err_code").and_then(Value::as_i64) {
+        Some(code) if code != 0 => {
+            let msg = reply.get("err_msg").and_then(Value::as_str).unwrap_or("");
+            Some(format!("err_code {} {}", code, msg).trim_end().to_string())
+        }
+        _ => None,
+    };
+    let Some(ns) = response.get(namespace) else {
+        return Err(format!("no {} in the reply", namespace));
+    };
+    // An unknown namespace is refused on the namespace, not the method.
+    if let Some(why) = refused(ns) {
+        return Err(why);
+    }
+    let Some(reply) = ns.get(method) else {
+        return Err(format!("no {}.{} in the reply", namespace, method));
+    };
+    match refused(reply) {
+        Some(why) => Err(why),
+        None => Ok(reply.clone()),
+    }
+}
+
+fn timed<F: FnOnce() -> Outcome>(name: &'static str, check: F) -> CheckResult {
+    let started = Instant::now();
+    let outcome = check();
+    CheckResult { name, outcome, elapsed_ms: started.elapsed().as_millis() as u64 }
+}
+
+fn available(device: &TpLinkDevice, cmd: Value, namespace: &str, method: &str) -> Outcome {
+    match reply(device, cmd, namespace, method) {
+        Ok(_) => Outcome::Passed(format!("{}.{} answered", namespace, method)),
+        Err(why) => Outcome::Failed(why),
+    }
+}
+
+/// Runs every check against `device`, in order, and reports them all.
+pub fn run(device: &TpLinkDevice) -> Report {
+    let mut report = Report { address: String::from(device.address()), ..Report::default() };
+
+    let (mut raw, mut kind) = (None, DeviceType::Unknown);
+    report.checks.push(timed("sysinfo", || match reply(device, commands::get_meter_info(), "system", "get_sysinfo") {
+        Ok(value) => {
+            raw = Some(value.clone());
+            match serde_json::from_value::<SystemGetSysInfoResponse>(value) {
+                Ok(sysinfo) => {
+                    kind = DeviceType::from_sysinfo(&sysinfo);
+                    Outcome::Passed(format!("{:?}", kind))
+                }
+                Err(e) => Outcome::Failed(format!("doesn't parse: {}", e)),
+            }
+        }
+        Err(why) => Outcome::Failed(why),
+    }));
+    let Some(raw) = raw else { return report };
+    let field = |name: &str| raw.get(name).and_then(Value::as_str).map(String::from);
+    (report.model, report.hw_ver, report.sw_ver) = (field("model"), field("hw_ver"), field("sw_ver"));
+
+    report.checks.push(timed("sysinfo_fields", || {
+        let fields = sysinfo_fields(kind);
+        let missing: Vec<String> = fields.iter()
+            .filter(|names| names.iter().all(|name| raw.get(name).is_none()))
+            .map(|names| names.join("/"))
+            .collect();
+        if missing.is_empty() {
+            Outcome::Passed(format!("all {} present", fields.len()))
+        } else {
+            Outcome::Failed(format!("missing {}", missing.join(", ")))
+        }
+    }));
+
+    let has_meter = raw.get("feature").and_then(Value::as_str).is_some_and(|f| f.contains("ENE"));
+    report.checks.push(timed("emeter", || {
+        if !has_meter {
+            return Outcome::Skipped(String::from("no ENE in feature"));
+        }
+        match reply(device, commands::get_realtime(), "emeter", "get_realtime")
+            .and_then(|value| serde_json::from_value::<EmeterGetRealtimeResponse>(value).map_err(|e| e.to_string())) {
+            Ok(realtime) if realtime.power_mw.is_some() => Outcome::Passed(String::from("milli-units (power_mw)")),
+            Ok(realtime) if realtime.power.is_some() => Outcome::Passed(String::from("units (power)")),
+            Ok(_) => Outcome::Failed(String::from("reading has no power")),
+            Err(why) => Outcome::Failed(why),
+        }
+    }));
+
+    report.checks.push(timed("schedule", || available(device, commands::get_schedule_rules(), "schedule", "get_rules")));
+    report.checks.push(timed("countdown", || available(device, commands::get_countdown_rules(), "count_down", "get_rules")));
+    report.checks.push(timed("time", || available(device, commands::get_time(), "time", "get_time")));
+    report.checks.push(timed("cloud", || available(device, commands::get_cloud_info(), "cnCloud", "get_info")));
+
+    report.checks.push(timed("timing", || {
+        let mut times = Vec::new();
+        for _ in 0..TIMING_ROUNDS {
+            let started = Instant::now();
+            if let Err(why) = reply(device, commands::get_meter_info(), "system", "get_sysinfo") {
+                return Outcome::Failed(format!("request {} of {}: {}", times.len() + 1, TIMING_ROUNDS, why));
+            }
+            times.push(started.elapsed());
+        }
+        times.sort();
+        let median = times[times.len() / 2];
+        let detail = format!("median {} ms, slowest {} ms", median.as_millis(), times[times.len() - 1].as_millis());
+        if median > SLOW_REPLY { Outcome::Failed(detail) } else { Outcome::Passed(detail) }
+    }));
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::emulator::Emulator;
+    use crate::protocol::encrypt_payload;
+    use crate::types::PlugError;
+    use super::{run, Outcome};
+
+    fn answering(sysinfo: Value) -> TpLinkDevice {
+        let reply = json!({"system": {"get_sysinfo": sysinfo}}).to_string();
+        let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(reply.clone().into_bytes()))
+        };
+        TpLinkDevice::with_transport("10.0.0.5", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_emulated_plug() {
+        let (plug, _) = Emulator::new("Plug").device();
+        let report = run(&plug);
+        assert_eq!(report.model.as_deref(), Some("HS110(EU)"));
+
+        let outcome = |name: &str| report.checks.iter().find(|c| c.name == name).map(|c| c.outcome.clone()).unwrap();
+        assert_eq!(outcome("sysinfo"), Outcome::Passed(String::from("Plug")));
+        assert!(matches!(outcome("sysinfo_fields"), Outcome::Passed(_)));
+        assert_eq!(outcome("emeter"), Outcome::Passed(String::from("milli-units (power_mw)")));
+        // The emulator has no schedule.
+        assert_eq!(outcome("schedule"), Outcome::Failed(String::from("err_code -1 module not support")));
+        assert!(matches!(outcome("timing"), Outcome::Passed(_)));
+        assert_eq!(report.failures().count(), 4);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][0]["outcome"], "passed");
+        assert_eq!(json["checks"][0]["name"], "sysinfo");
+    }
+
+    #[test]
+    fn test_fields_by_kind() {
+        let fields = |sysinfo: Value| {
+            let report = run(&answering(sysinfo));
+            report.checks.iter().find(|c| c.name == "sysinfo_fields").map(|c| c.outcome.clone()).unwrap()
+        };
+        let bulb = json!({
+            "sw_ver": "1.8.6", "hw_ver": "1.0", "model": "LB130(EU)", "deviceId": "B1", "alias": "Lamp",
+            "rssi": -60, "mic_type": "IOT.SMARTBULB", "mic_mac": "50C7BF000001", "light_state": {"on_off": 1},
+        });
+        assert_eq!(fields(bulb), Outcome::Passed(String::from("all 9 present")));
+
+        let strip = json!({
+            "sw_ver": "1.0.12", "hw_ver": "1.0", "model": "HS300(EU)", "deviceId": "S1", "alias": "Desk",
+            "rssi": -50, "mic_type": "IOT.SMARTPLUGSWITCH", "mac": "50:C7:BF:00:00:02", "feature": "TIM:ENE",
+            "children": [{"id": "S100", "state": 1, "alias": "Lamp"}],
+        });
+        assert_eq!(fields(strip), Outcome::Failed(String::from("missing led_off")));
+    }
+}
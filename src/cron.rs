@@ -0,0 +1,174 @@
+/*
+ * Five-field cron expressions ("minute hour day-of-month month day-of-week"),
+ * supporting `*`, lists, ranges and steps, plus the @hourly/@daily/@weekly/
+ * @monthly/@yearly shorthands. As in cron(8), when both day fields are
+ * restricted a time matches if either of them does.
+ */
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike};
+
+use crate::types::PlugError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, PlugError> {
+    let invalid = || PlugError::new(format!("Invalid cron field: {}", field).as_str());
+    let mut mask = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?)
+        } else {
+            let start = range.parse().map_err(|_| invalid())?;
+            // "5/15" means every 15 starting at 5.
+            (start, if part.contains('/') { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<CronSchedule, PlugError> {
+        let expr = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        };
+
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(PlugError::new(
+                format!("Invalid cron expression: {}. Expected 5 fields", expr).as_str()));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let dom = self.days_of_month & (1 << date.day()) != 0;
+        let dow = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (false, true) => dom,
+            (true, false) => dow,
+            (false, false) => dom || dow,
+        }
+    }
+
+    /// Returns the first matching minute strictly after `after`, or `None` if no time within
+    /// the next five years matches (e.g. "0 0 30 2 *").
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let tz = after.timezone();
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = start + Duration::days(5 * 366);
+        let mut t = start;
+
+        while t < limit {
+            if self.months & (1 << t.month()) == 0 {
+                let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.matches_day(t.date()) {
+                t = (t.date() + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if self.minutes & (1 << t.minute()) == 0 {
+                t += Duration::minutes(1);
+                continue;
+            }
+
+            // Wall-clock times skipped by a DST transition never happen; keep looking.
+            if let Some(next) = tz.from_local_datetime(&t).earliest() {
+                return Some(next);
+            }
+            t += Duration::minutes(1);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use super::CronSchedule;
+
+    #[test]
+    fn test_step_and_range() {
+        let cron = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        // Saturday 2024-06-01 10:07 -> Monday 09:00.
+        let after = Utc.with_ymd_and_hms(2024, 6, 1, 10, 7, 0).unwrap();
+        assert_eq!(cron.next_after(&after), Some(Utc.with_ymd_and_hms(2024, 6, 3, 9, 0, 0).unwrap()));
+
+        let after = Utc.with_ymd_and_hms(2024, 6, 3, 9, 0, 0).unwrap();
+        assert_eq!(cron.next_after(&after), Some(Utc.with_ymd_and_hms(2024, 6, 3, 9, 15, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_day_fields_are_ored() {
+        // The 13th of each month, or any Friday.
+        let cron = CronSchedule::parse("0 12 13 * 5").unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 6, 8, 0, 0, 0).unwrap();
+        assert_eq!(cron.next_after(&after), Some(Utc.with_ymd_and_hms(2024, 6, 13, 12, 0, 0).unwrap()));
+        let after = Utc.with_ymd_and_hms(2024, 6, 13, 12, 0, 0).unwrap();
+        assert_eq!(cron.next_after(&after), Some(Utc.with_ymd_and_hms(2024, 6, 14, 12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        assert!(CronSchedule::parse("* * * *").is_err());
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert_eq!(CronSchedule::parse("0 0 30 2 *").unwrap().next_after(&Utc::now()), None);
+    }
+}
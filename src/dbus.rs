@@ -0,0 +1,116 @@
+/*
+ * D-Bus interface, enabled with the `dbus` feature.
+ *
+ * Every device is exported at /org/hs110/Device/<n> with the org.hs110.Device
+ * interface, e.g.:
+ *
+ *   busctl --user call org.hs110.Plugs /org/hs110/Device/0 org.hs110.Device On
+ */
+
+use std::thread;
+use std::time::Duration;
+use zbus::blocking::connection;
+use zbus::blocking::Connection;
+use zbus::object_server::SignalEmitter;
+use zbus::{block_on, fdo, interface};
+
+use crate::TpLinkDevice;
+use crate::types::PlugError;
+
+pub const BUS_NAME: &str = "org.hs110.Plugs";
+const OBJECT_ROOT: &str = "/org/hs110/Device";
+
+pub struct DeviceObject {
+    alias: String,
+    device: TpLinkDevice,
+    last_power: Option<f64>,
+}
+
+fn to_fdo_error(e: PlugError) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
+
+#[interface(name = "org.hs110.Device")]
+impl DeviceObject {
+    fn on(&self) -> fdo::Result<()> {
+        self.device.on().map(|_| ()).map_err(to_fdo_error)
+    }
+
+    fn off(&self) -> fdo::Result<()> {
+        self.device.off().map(|_| ()).map_err(to_fdo_error)
+    }
+
+    #[zbus(property)]
+    fn alias(&self) -> String {
+        self.alias.clone()
+    }
+
+    #[zbus(signal)]
+    async fn power_changed(emitter: &SignalEmitter<'_>, watts: f64) -> zbus::Result<()>;
+}
+
+pub struct DbusServer {
+    connection: Connection,
+    paths: Vec<String>,
+}
+
+impl DbusServer {
+    pub fn session(devices: Vec<(String, TpLinkDevice)>) -> zbus::Result<DbusServer> {
+        DbusServer::serve(connection::Builder::session()?, devices)
+    }
+
+    pub fn system(devices: Vec<(String, TpLinkDevice)>) -> zbus::Result<DbusServer> {
+        DbusServer::serve(connection::Builder::system()?, devices)
+    }
+
+    fn serve(mut builder: connection::Builder<'static>, devices: Vec<(String, TpLinkDevice)>)
+        -> zbus::Result<DbusServer> {
+
+        let mut paths = Vec::new();
+        for (idx, (alias, device)) in devices.into_iter().enumerate() {
+            let path = format!("{}/{}", OBJECT_ROOT, idx);
+            builder = builder.serve_at(path.clone(), DeviceObject {
+                alias,
+                device,
+                last_power: None,
+            })?;
+            paths.push(path);
+        }
+
+        Ok(DbusServer {
+            connection: builder.name(BUS_NAME)?.build()?,
+            paths,
+        })
+    }
+
+    /// Reads the meter of every device once, emitting PowerChanged for those whose reading changed.
+    pub fn poll(&self) -> zbus::Result<()> {
+        let object_server = self.connection.object_server();
+        for path in &self.paths {
+            let iface_ref = object_server.interface::<_, DeviceObject>(path.as_str())?;
+            let mut iface = iface_ref.get_mut();
+
+            let watts = match iface.device.get_realtime() {
+                Ok(response) => response.emeter
+                    .and_then(|e| e.get_realtime)
+                    .and_then(|r| r.power_w()),
+                Err(_) => None,
+            };
+
+            if let Some(watts) = watts {
+                if iface.last_power != Some(watts) {
+                    iface.last_power = Some(watts);
+                    block_on(DeviceObject::power_changed(iface_ref.signal_emitter(), watts))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run(&self, interval: Duration) -> zbus::Result<()> {
+        loop {
+            self.poll()?;
+            thread::sleep(interval);
+        }
+    }
+}
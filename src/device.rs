@@ -0,0 +1,146 @@
+/*
+ * One type for every kind of Kasa device, so a mixed fleet can be handled
+ * without looking at model strings:
+ *
+ *   for device in devices {
+ *       let device = SmartDevice::connect(device)?;
+ *       println!("{} ({:?}): {:?} W", device.alias()?, device.device_type(), device.power_w()?);
+ *       device.off()?;
+ *   }
+ *
+ * `from_sysinfo` builds one from a sysinfo that was already read, e.g. a
+ * discovery reply. The variants give access to what only that kind can do.
+ */
+
+use alloc::string::String;
+
+use crate::{DeviceType, TpLinkDevice};
+use crate::bulb::{Bulb, LightCapabilities};
+use crate::strip::Strip;
+use crate::types::{PlugError, PlugResponse, SystemGetSysInfoResponse};
+
+#[derive(Clone)]
+pub enum SmartDevice {
+    Plug { device: TpLinkDevice, metered: bool },
+    Dimmer(TpLinkDevice),
+    Bulb(Bulb),
+    Strip { strip: Strip, metered: bool },
+}
+
+fn has_emeter(sysinfo: &SystemGetSysInfoResponse) -> bool {
+    sysinfo.feature.contains("ENE")
+}
+
+impl SmartDevice {
+    pub fn connect(device: TpLinkDevice) -> Result<SmartDevice, PlugError> {
+        let sysinfo = device.sysinfo()?;
+        Ok(SmartDevice::from_sysinfo(device, &sysinfo))
+    }
+
+    /// Devices that aren't recognised are treated as plain plugs.
+    pub fn from_sysinfo(device: TpLinkDevice, sysinfo: &SystemGetSysInfoResponse) -> SmartDevice {
+        let device = device.identified(sysinfo);
+        match device.device_type() {
+            DeviceType::Bulb => SmartDevice::Bulb(Bulb::with_capabilities(device, LightCapabilities::from_sysinfo(sysinfo))),
+            DeviceType::Strip => SmartDevice::Strip { strip: Strip::new(device), metered: has_emeter(sysinfo) },
+            DeviceType::Dimmer => SmartDevice::Dimmer(device),
+            DeviceType::Plug | DeviceType::Unknown => SmartDevice::Plug { device, metered: has_emeter(sysinfo) },
+        }
+    }
+
+    pub fn device(&self) -> &TpLinkDevice {
+        match self {
+            SmartDevice::Plug { device, .. } | SmartDevice::Dimmer(device) => device,
+            SmartDevice::Bulb(bulb) => bulb.device(),
+            SmartDevice::Strip { strip, .. } => strip.device(),
+        }
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            SmartDevice::Plug { .. } => DeviceType::Plug,
+            SmartDevice::Dimmer(_) => DeviceType::Dimmer,
+            SmartDevice::Bulb(_) => DeviceType::Bulb,
+            SmartDevice::Strip { .. } => DeviceType::Strip,
+        }
+    }
+
+    pub fn has_emeter(&self) -> bool {
+        match self {
+            SmartDevice::Plug { metered, .. } | SmartDevice::Strip { metered, .. } => *metered,
+            SmartDevice::Dimmer(_) => false,
+            SmartDevice::Bulb(_) => true,
+        }
+    }
+
+    pub fn sysinfo(&self) -> Result<SystemGetSysInfoResponse, PlugError> {
+        self.device().sysinfo()
+    }
+
+    pub fn alias(&self) -> Result<String, PlugError> {
+        Ok(self.sysinfo()?.alias)
+    }
+
+    pub fn set_alias(&self, alias: &str) -> Result<PlugResponse, PlugError> {
+        self.device().set_device_alias(alias)
+    }
+
+    /// For a strip, whether any outlet is on.
+    pub fn is_on(&self) -> Result<bool, PlugError> {
+        match self {
+            SmartDevice::Bulb(bulb) => Ok(bulb.light_state()?.on_off != 0),
+            SmartDevice::Strip { strip, .. } => Ok(strip.device().sysinfo()?.children.iter().any(|c| c.state != 0)),
+            _ => self.device().is_on(),
+        }
+    }
+
+    /// Switches a strip's outlets all at once.
+    pub fn on(&self) -> Result<PlugResponse, PlugError> {
+        self.device().on()
+    }
+
+    pub fn off(&self) -> Result<PlugResponse, PlugError> {
+        self.device().off()
+    }
+
+    /// Fails on devices that can't dim; bulbs check the value first.
+    pub fn set_brightness(&self, brightness: u8) -> Result<(), PlugError> {
+        match self {
+            SmartDevice::Bulb(bulb) => bulb.set_brightness(brightness).map(|_| ()),
+            _ => self.device().set_brightness(brightness).map(|_| ()),
+        }
+    }
+
+    /// Current draw in W, summed over the outlets of a strip; `None` without a meter.
+    pub fn power_w(&self) -> Result<Option<f64>, PlugError> {
+        if !self.has_emeter() {
+            return Ok(None);
+        }
+        match self {
+            SmartDevice::Strip { strip, .. } => strip.total_power().map(Some),
+            _ => Ok(Some(self.device().power_reading()?.power_w)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::DeviceType;
+    use crate::bulb::tests::bulb;
+    use crate::strip::tests::hs300;
+    use super::SmartDevice;
+
+    #[test]
+    fn test_mixed_fleet() {
+        let fleet = [hs300(), bulb("KL130(EU)", 1, Arc::new(Mutex::new(Vec::new())))];
+        let devices: Vec<_> = fleet.into_iter().map(|d| SmartDevice::connect(d).unwrap()).collect();
+
+        assert_eq!(devices.iter().map(|d| d.device_type()).collect::<Vec<_>>(), [DeviceType::Strip, DeviceType::Bulb]);
+        assert_eq!(devices[0].power_w().unwrap(), Some(42.5));
+        assert_eq!(devices[1].power_w().unwrap(), Some(7.5));
+        assert!(devices[0].is_on().unwrap());
+        assert!(!devices[1].is_on().unwrap());
+        assert_eq!(devices[1].alias().unwrap(), "Desk lamp");
+    }
+}
@@ -0,0 +1,444 @@
+/*
+ * Finds devices by broadcasting a sysinfo request over UDP, the way the Kasa
+ * app does, and parses whatever answers:
+ *
+ *   for found in discovery::discover(Duration::from_secs(2))? {
+ *       match &found.info {
+ *           DiscoveredInfo::Known { kind, sysinfo } => println!("{} {:?} {}", found.address, kind, sysinfo.alias),
+ *           DiscoveredInfo::Unknown { model, .. } => println!("{} {:?}?", found.address, model),
+ *       }
+ *   }
+ *
+ * Replies come without the length prefix. Plugs, dimmers, bulbs and strips
+ * all answer in `system.get_sysinfo`; cameras with a plug nest theirs one level
+ * deeper. Anything whose sysinfo doesn't parse is still returned, with the raw
+ * reply, so that it isn't silently left out.
+ *
+ * `discover_with` combines methods, e.g. broadcast, the neighbour table and
+ * mDNS (feature "mdns") for networks that filter broadcasts, and lists each
+ * device once. `discover_paced` also limits how fast and how many packets go
+ * out, so that scanning a large network doesn't look like an attack:
+ *
+ *   let pacing = Pacing { per_second: Some(20.0), budget: Some(2000) };
+ *   let subnet = DiscoveryMethod::Subnet { network: Ipv4Addr::new(10, 1, 0, 0), prefix: 22 };
+ *   let found = discovery::discover_paced(&[subnet], Duration::from_millis(300), pacing)?;
+ *
+ * `watch` repeats discovery and reports devices joining and leaving.
+ */
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+use crate::{DeviceType, TpLinkDevice, DEFAULT_PORT};
+use crate::commands;
+use crate::protocol::{decrypt_payload, encrypt_payload, size_to_bytes};
+use crate::service::{ServiceContext, ServiceHandle};
+use crate::transport::{TcpTransport, Transport};
+use crate::types::{PlugError, SystemGetSysInfoResponse};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryMethod {
+    /// UDP broadcast to 255.255.255.255.
+    Broadcast,
+    /// Hosts in the ARP table with a TP-Link MAC, asked for sysinfo one by one.
+    Neighbors,
+    /// Every host of an IPv4 network, asked for sysinfo one by one. At most a /16.
+    Subnet { network: Ipv4Addr, prefix: u8 },
+    /// Hosts announcing the service, asked for sysinfo one by one.
+    #[cfg(feature = "mdns")]
+    Mdns { service: String },
+}
+
+#[cfg(feature = "mdns")]
+impl DiscoveryMethod {
+    pub fn mdns() -> DiscoveryMethod {
+        DiscoveryMethod::Mdns { service: String::from(crate::mdns::KASA_SERVICE) }
+    }
+}
+
+/// Limits on what discovery sends. The default has none.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pacing {
+    /// Packets, or connection attempts, per second.
+    pub per_second: Option<f64>,
+    /// Packets in total, over all methods.
+    pub budget: Option<usize>,
+}
+
+struct Pacer {
+    pacing: Pacing,
+    sent: usize,
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(pacing: Pacing) -> Pacer {
+        Pacer {
+            pacing,
+            sent: 0,
+            next: Instant::now(),
+        }
+    }
+
+    /// Waits until the next packet may go out; `false` once the budget is spent.
+    fn wait(&mut self) -> bool {
+        if self.pacing.budget.is_some_and(|budget| self.sent >= budget) {
+            return false;
+        }
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        }
+        if let Some(rate) = self.pacing.per_second.filter(|r| *r > 0.0) {
+            self.next = self.next.max(now) + Duration::from_secs_f64(1.0 / rate);
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiscoveredInfo {
+    Known { kind: DeviceType, sysinfo: Box<SystemGetSysInfoResponse> },
+    /// Answered, but not with a sysinfo this crate understands.
+    Unknown { model: Option<String>, raw: Value },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Discovered {
+    /// Where the reply came from, with the TCP port rather than the sender's.
+    pub address: SocketAddr,
+    pub info: DiscoveredInfo,
+}
+
+impl Discovered {
+    pub fn sysinfo(&self) -> Option<&SystemGetSysInfoResponse> {
+        match &self.info {
+            DiscoveredInfo::Known { sysinfo, .. } => Some(sysinfo.as_ref()),
+            DiscoveredInfo::Unknown { .. } => None,
+        }
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        match &self.info {
+            DiscoveredInfo::Known { sysinfo, .. } => Some(sysinfo.device_id.as_str()),
+            DiscoveredInfo::Unknown { raw, .. } => sysinfo_of(raw)?.get("deviceId")?.as_str(),
+        }
+    }
+
+    /// A device already identified from the reply, so `detect` isn't needed.
+    pub fn device(&self) -> TpLinkDevice {
+        let device = TpLinkDevice::from_socket_addr(self.address);
+        match self.sysinfo() {
+            Some(sysinfo) => device.identified(sysinfo),
+            None => device,
+        }
+    }
+}
+
+/// `system.get_sysinfo`, or the `system` object cameras nest inside it.
+fn sysinfo_of(reply: &Value) -> Option<&Value> {
+    let sysinfo = reply.get("system")?.get("get_sysinfo")?;
+    match sysinfo.get("system") {
+        Some(nested) if nested.is_object() => Some(nested),
+        _ => Some(sysinfo),
+    }
+}
+
+/// Decrypts a UDP payload, which comes without the length prefix.
+pub(crate) fn decrypt_datagram(datagram: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::from(size_to_bytes(datagram.len() as u32));
+    frame.extend_from_slice(datagram);
+    decrypt_payload(&frame)
+}
+
+/// Parses one discovery datagram from `sender`.
+pub fn parse_reply(sender: SocketAddr, datagram: &[u8]) -> Result<Discovered, PlugError> {
+    let raw: Value = serde_json::from_slice(&decrypt_datagram(datagram))?;
+
+    let info = match sysinfo_of(&raw).map(|s| serde_json::from_value::<SystemGetSysInfoResponse>(s.clone())) {
+        Some(Ok(sysinfo)) if !sysinfo.model.is_empty() => DiscoveredInfo::Known {
+            kind: DeviceType::from_sysinfo(&sysinfo),
+            sysinfo: Box::new(sysinfo),
+        },
+        _ => DiscoveredInfo::Unknown {
+            model: sysinfo_of(&raw).and_then(|s| s.get("model")).and_then(Value::as_str).map(String::from),
+            raw,
+        },
+    };
+    Ok(Discovered {
+        address: SocketAddr::new(sender.ip(), DEFAULT_PORT),
+        info,
+    })
+}
+
+/// Broadcasts on the local network and collects replies for `timeout`. Replies
+/// that aren't valid protocol frames are skipped.
+pub fn discover(timeout: Duration) -> Result<Vec<Discovered>, PlugError> {
+    broadcast(timeout, &mut Pacer::new(Pacing::default()))
+}
+
+fn broadcast(timeout: Duration, pacer: &mut Pacer) -> Result<Vec<Discovered>, PlugError> {
+    if !pacer.wait() {
+        return Ok(Vec::new());
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    let request = encrypt_payload(commands::get_meter_info().to_string().into_bytes());
+    socket.send_to(&request[4..], ("255.255.255.255", DEFAULT_PORT))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<Discovered> = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(found);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, sender)) => {
+                if let Ok(reply) = parse_reply(sender, &buf[..len]) {
+                    if !found.iter().any(|f| f.address == reply.address) {
+                        found.push(reply);
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+                return Ok(found),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Asks one host for sysinfo over TCP and parses the reply like a discovery reply.
+pub fn probe(host: IpAddr, timeout: Duration) -> Result<Discovered, PlugError> {
+    let address = SocketAddr::new(host, DEFAULT_PORT);
+    let request = encrypt_payload(commands::get_meter_info().to_string().into_bytes());
+    let response = TcpTransport::new(timeout).request(address.to_string().as_str(), &request)?;
+    parse_reply(address, &response[4..])
+}
+
+fn probe_all(hosts: impl IntoIterator<Item = IpAddr>, timeout: Duration, pacer: &mut Pacer) -> Vec<Discovered> {
+    let mut found = Vec::new();
+    for host in hosts {
+        if !pacer.wait() {
+            break;
+        }
+        if let Ok(reply) = probe(host, timeout) {
+            found.push(reply);
+        }
+    }
+    found
+}
+
+/// The hosts of `network`/`prefix`, without its network and broadcast addresses.
+fn subnet_hosts(network: Ipv4Addr, prefix: u8) -> Result<impl Iterator<Item = IpAddr>, PlugError> {
+    if !(16..=32).contains(&prefix) {
+        return Err(PlugError::new("Subnets larger than a /16 aren't scanned"));
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    let first = u32::from(network) & mask;
+    let last = first | !mask;
+    let (first, last) = if prefix < 31 { (first + 1, last - 1) } else { (first, last) };
+    Ok((first..=last).map(|ip| IpAddr::V4(Ipv4Addr::from(ip))))
+}
+
+impl Discovered {
+    /// The same device, by device id or, without one, by address.
+    pub fn is_same(&self, other: &Discovered) -> bool {
+        match (self.device_id(), other.device_id()) {
+            (Some(a), Some(b)) if !a.is_empty() => a == b,
+            _ => self.address == other.address,
+        }
+    }
+}
+
+/// Keeps the first of each device; see `Discovered::is_same`.
+pub fn merge(rounds: impl IntoIterator<Item = Discovered>) -> Vec<Discovered> {
+    let mut merged: Vec<Discovered> = Vec::new();
+    for found in rounds {
+        if !merged.iter().any(|m| m.is_same(&found)) {
+            merged.push(found);
+        }
+    }
+    merged
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Presence {
+    Joined(Discovered),
+    /// Missing from a round after having been found.
+    Left(Discovered),
+}
+
+/// The devices found by the latest round of discovery.
+#[derive(Clone, Debug, Default)]
+pub struct Roster {
+    devices: Vec<Discovered>,
+}
+
+impl Roster {
+    pub fn new() -> Roster {
+        Roster::default()
+    }
+
+    pub fn devices(&self) -> &[Discovered] {
+        &self.devices
+    }
+
+    /// Replaces the devices with `round`, returning who joined and who left.
+    pub fn update(&mut self, round: Vec<Discovered>) -> Vec<Presence> {
+        let round = merge(round);
+        let mut changes: Vec<Presence> = self.devices.iter()
+            .filter(|known| !round.iter().any(|found| found.is_same(known)))
+            .map(|known| Presence::Left(known.clone()))
+            .collect();
+        changes.extend(round.iter()
+            .filter(|found| !self.devices.iter().any(|known| known.is_same(found)))
+            .map(|found| Presence::Joined(found.clone())));
+        self.devices = round;
+        changes
+    }
+}
+
+/// Runs discovery every `interval` in a background thread, until the returned
+/// receiver is dropped. Rounds that fail are skipped.
+pub fn watch(methods: Vec<DiscoveryMethod>, timeout: Duration, interval: Duration) -> Receiver<Presence> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || watch_in(methods, timeout, interval, tx, &ServiceContext::default()));
+    rx
+}
+
+/// `watch` as a service, degraded while rounds fail.
+pub fn watch_service(methods: Vec<DiscoveryMethod>, timeout: Duration, interval: Duration)
+    -> (ServiceHandle, Receiver<Presence>) {
+    let (tx, rx) = mpsc::channel();
+    (ServiceHandle::new("discovery", move |context| watch_in(methods, timeout, interval, tx, context)), rx)
+}
+
+fn watch_in(methods: Vec<DiscoveryMethod>, timeout: Duration, interval: Duration, tx: Sender<Presence>,
+            context: &ServiceContext) {
+    let mut roster = Roster::new();
+    loop {
+        match discover_with(&methods, timeout) {
+            Ok(round) => {
+                context.running();
+                for change in roster.update(round) {
+                    if tx.send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => context.degraded(&e),
+        }
+        if !context.sleep_until(Instant::now() + interval) {
+            return;
+        }
+    }
+}
+
+/// Runs each method for `timeout` and merges what they found. Fails only if every method did.
+pub fn discover_with(methods: &[DiscoveryMethod], timeout: Duration) -> Result<Vec<Discovered>, PlugError> {
+    discover_paced(methods, timeout, Pacing::default())
+}
+
+/// Like `discover_with`, sending no faster and no more than `pacing` allows.
+/// `timeout` applies to each probe.
+pub fn discover_paced(methods: &[DiscoveryMethod], timeout: Duration, pacing: Pacing)
+    -> Result<Vec<Discovered>, PlugError> {
+
+    let mut pacer = Pacer::new(pacing);
+    let mut found = Vec::new();
+    let mut last_error = None;
+    for method in methods {
+        let result = match method {
+            DiscoveryMethod::Broadcast => broadcast(timeout, &mut pacer),
+            DiscoveryMethod::Neighbors => crate::neighbors::tplink_hosts()
+                .map(|hosts| probe_all(hosts, timeout, &mut pacer)),
+            DiscoveryMethod::Subnet { network, prefix } => subnet_hosts(*network, *prefix)
+                .map(|hosts| probe_all(hosts, timeout, &mut pacer)),
+            #[cfg(feature = "mdns")]
+            DiscoveryMethod::Mdns { service } => {
+                let asked = pacer.wait();
+                crate::mdns::query(service, timeout)
+                    .map(|hosts| if asked { probe_all(hosts, timeout, &mut pacer) } else { Vec::new() })
+            }
+        };
+        match result {
+            Ok(round) => found.extend(round),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) if found.is_empty() => Err(e),
+        _ => Ok(merge(found)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use serde_json::json;
+    use crate::DeviceType;
+    use crate::protocol::encrypt_payload;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+    use super::{merge, parse_reply, subnet_hosts, DiscoveredInfo, Pacer, Pacing, Presence, Roster};
+
+    fn datagram(reply: serde_json::Value) -> Vec<u8> {
+        encrypt_payload(reply.to_string().into_bytes())[4..].to_vec()
+    }
+
+    #[test]
+    fn test_mixed_household() {
+        let sender: SocketAddr = "192.168.1.20:50123".parse().unwrap();
+        let bulb = parse_reply(sender, &datagram(json!({"system": {"get_sysinfo": {
+            "model": "KL130(EU)", "mic_type": "IOT.SMARTBULB", "deviceId": "B1", "alias": "Desk lamp",
+            "is_color": 1, "light_state": {"on_off": 1}, "err_code": 0}}}))).unwrap();
+        assert_eq!(bulb.address.to_string(), "192.168.1.20:9999");
+        assert!(matches!(&bulb.info, DiscoveredInfo::Known { kind: DeviceType::Bulb, sysinfo } if sysinfo.alias == "Desk lamp"));
+
+        let strip = parse_reply(sender, &datagram(json!({"system": {"get_sysinfo": {
+            "model": "HS300(US)", "type": "IOT.SMARTPLUGSWITCH", "deviceId": "S1",
+            "children": [{"id": "S100", "state": 1, "alias": "TV", "on_time": 5}]}}}))).unwrap();
+        assert!(matches!(strip.info, DiscoveredInfo::Known { kind: DeviceType::Strip, .. }));
+
+        let camera = parse_reply(sender, &datagram(json!({"system": {"get_sysinfo": {"system": {
+            "model": "KC100(US)", "type": "IOT.IPCAMERA", "deviceId": "C1", "latitude": "unknown"}}}}))).unwrap();
+        assert!(matches!(&camera.info, DiscoveredInfo::Unknown { model: Some(m), .. } if m == "KC100(US)"));
+        assert_eq!(camera.device_id(), Some("C1"));
+    }
+
+    #[test]
+    fn test_pacing() {
+        let mut pacer = Pacer::new(Pacing { per_second: Some(100.0), budget: Some(3) });
+        let start = Instant::now();
+        assert_eq!((0..5).map(|_| pacer.wait()).collect::<Vec<_>>(), [true, true, true, false, false]);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let hosts: Vec<_> = subnet_hosts(Ipv4Addr::new(192, 168, 1, 77), 30).unwrap().map(|h| h.to_string()).collect();
+        assert_eq!(hosts, ["192.168.1.77", "192.168.1.78"]);
+        assert!(subnet_hosts(Ipv4Addr::new(10, 0, 0, 0), 8).is_err());
+    }
+
+    #[test]
+    fn test_merge_by_device_id() {
+        let reply = |ip: &str, id: &str| parse_reply(format!("{}:9999", ip).parse().unwrap(), &datagram(json!(
+            {"system": {"get_sysinfo": {"model": "HS110(EU)", "deviceId": id}}}))).unwrap();
+        // The same plug seen on two interfaces, by two methods.
+        let merged = merge([reply("192.168.1.20", "P1"), reply("192.168.1.21", "P2"), reply("10.0.0.20", "P1")]);
+        assert_eq!(merged.iter().map(|d| d.address.to_string()).collect::<Vec<_>>(),
+                   ["192.168.1.20:9999", "192.168.1.21:9999"]);
+
+        let mut roster = Roster::new();
+        assert_eq!(roster.update(vec![reply("192.168.1.20", "P1")]).len(), 1);
+        // Moved to another address but still the same plug; the other one is new.
+        let changes = roster.update(vec![reply("192.168.1.30", "P1"), reply("192.168.1.21", "P2")]);
+        assert!(matches!(&changes[..], [Presence::Joined(d)] if d.device_id() == Some("P2")));
+        assert!(matches!(&roster.update(Vec::new())[..], [Presence::Left(_), Presence::Left(_)]));
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ * Dry-run mode: reads reach the device as usual, while commands that would
+ * change it are recorded and answered with a synthetic success instead:
+ *
+ *   let (device, dry_run) = device.dry_run();
+ *   engine.device("heater", device);
+ *   ...
+ *   for cmd in dry_run.intercepted() { println!("would send {}", cmd); }
+ *
+ * Commands are classified with `commands::is_read_only`.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::{json, Map, Value};
+
+use crate::TpLinkDevice;
+use crate::commands::is_read_only;
+use crate::protocol::{decrypt_payload, encrypt_payload, size_from_bytes};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+pub struct DryRun {
+    inner: Arc<dyn Transport>,
+    intercepted: Mutex<Vec<Value>>,
+}
+
+/// `{"err_code": 0}` for every method in the command, the way the device acknowledges writes.
+pub(crate) fn acknowledge(cmd: &Value) -> Value {
+    let mut response = Map::new();
+    if let Some(namespaces) = cmd.as_object() {
+        for (namespace, methods) in namespaces.iter().filter(|(namespace, _)| *namespace != "context") {
+            let acks: Map<String, Value> = methods.as_object().into_iter().flatten()
+                .map(|(method, _)| (method.clone(), json!({"err_code": 0})))
+                .collect();
+            response.insert(namespace.clone(), Value::Object(acks));
+        }
+    }
+    Value::Object(response)
+}
+
+impl DryRun {
+    pub fn new(inner: Arc<dyn Transport>) -> DryRun {
+        DryRun {
+            inner,
+            intercepted: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Commands that were held back, oldest first.
+    pub fn intercepted(&self) -> Vec<Value> {
+        self.intercepted.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut intercepted) = self.intercepted.lock() {
+            intercepted.clear();
+        }
+    }
+}
+
+impl Transport for DryRun {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        if frame.len() < 4 || frame.len() < size_from_bytes(frame) + 4 {
+            return Err(PlugError::new("Truncated request"));
+        }
+        let cmd: Value = serde_json::from_slice(&decrypt_payload(frame))?;
+        if is_read_only(&cmd) {
+            return self.inner.request(address, frame);
+        }
+
+        let response = acknowledge(&cmd);
+        if let Ok(mut intercepted) = self.intercepted.lock() {
+            intercepted.push(cmd);
+        }
+        Ok(encrypt_payload(response.to_string().into_bytes()))
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device in dry-run mode, and the record of what it held back.
+    pub fn dry_run(&self) -> (TpLinkDevice, Arc<DryRun>) {
+        let dry_run = Arc::new(DryRun::new(self.transport.clone()));
+        (self.with_inner(dry_run.clone()), dry_run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::TpLinkDevice;
+    use crate::commands;
+    use crate::protocol::encrypt_payload;
+    use crate::types::PlugError;
+
+    #[test]
+    fn test_reads_pass_writes_held_back() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"emeter":{"get_realtime":{"power_mw":1500,"err_code":0}}}"#.to_vec()))
+        };
+        let (device, dry_run) = TpLinkDevice::with_transport("plug", Arc::new(transport)).dry_run();
+
+        assert_eq!(device.power_reading().unwrap().power_w, 1.5);
+        device.off().unwrap();
+        device.set_device_alias("Heater").unwrap();
+
+        assert_eq!(dry_run.intercepted(),
+                   [commands::set_relay_state(0), commands::set_device_alias("Heater")]);
+    }
+
+    #[test]
+    fn test_classification() {
+        assert!(commands::is_read_only(&commands::get_meter_info()));
+        assert!(commands::is_read_only(&commands::uboot_bootloader_check()));
+        assert!(!commands::is_read_only(&commands::reboot()));
+        assert!(!commands::is_read_only(&json!({"system": {"get_sysinfo": {}, "reboot": {}}})));
+    }
+}
@@ -0,0 +1,95 @@
+/*
+ * Notification style effects for bulbs, timed from the host. Each step is a
+ * `fade_to`, so the bulb smooths between them itself; afterwards the light is
+ * put back to how it was:
+ *
+ *   bulb.pulse(&LightState::on().hsv(0, 100, 100), 3, Duration::from_secs(1))?;
+ *   bulb.breathe(10, 100, 5, Duration::from_secs(4))?;
+ */
+
+use std::thread;
+use std::time::Duration;
+
+use crate::bulb::{Bulb, LightState};
+use crate::types::PlugError;
+
+impl Bulb {
+    /// Fades through `steps` `rounds` times, spending `step` on each, then restores the light.
+    pub fn play(&self, steps: &[LightState], rounds: u32, step: Duration) -> Result<(), PlugError> {
+        let previous = self.light_state()?.restoring();
+        self.play_from(&previous, steps, rounds, step)
+    }
+
+    fn play_from(&self, previous: &LightState, steps: &[LightState], rounds: u32, step: Duration)
+                 -> Result<(), PlugError> {
+        let mut result = Ok(());
+        'rounds: for _ in 0..rounds {
+            for state in steps {
+                result = self.fade_to(state, step).map(|_| ());
+                if result.is_err() {
+                    break 'rounds;
+                }
+                thread::sleep(step);
+            }
+        }
+
+        if result.is_err() || steps.last() != Some(previous) {
+            let restored = self.fade_to(previous, step);
+            result = result.and(restored.map(|_| ()));
+        }
+        result
+    }
+
+    /// Flashes to `state` and back `times` times, one flash per `period`.
+    pub fn pulse(&self, state: &LightState, times: u32, period: Duration) -> Result<(), PlugError> {
+        let previous = self.light_state()?.restoring();
+        self.play_from(&previous, &[state.clone(), previous.clone()], times, period / 2)
+    }
+
+    /// Swells the current colour between `low` and `high` brightness, one breath per `period`.
+    pub fn breathe(&self, low: u8, high: u8, breaths: u32, period: Duration) -> Result<(), PlugError> {
+        let current = self.light_state()?.restoring();
+        let level = |brightness: u8| LightState { on_off: 1, brightness: Some(brightness), ..current.clone() };
+        self.play_from(&current, &[level(high), level(low)], breaths, period / 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::bulb::{Bulb, LightState};
+    use crate::bulb::tests::bulb;
+
+    fn transitions(requests: &Mutex<Vec<Value>>) -> Vec<Value> {
+        requests.lock().unwrap().iter()
+            .filter_map(|r| r["smartlife.iot.smartbulb.lightingservice"].get("transition_light_state").cloned())
+            .collect()
+    }
+
+    #[test]
+    fn test_pulse_ends_as_it_started() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let bulb = Bulb::connect(bulb("KL130(EU)", 1, requests.clone())).unwrap();
+        bulb.pulse(&LightState::on().hsv(0, 100, 100), 2, Duration::from_millis(20)).unwrap();
+
+        let sent = transitions(&requests);
+        assert_eq!(sent.len(), 4);
+        assert_eq!(sent[0]["saturation"], 100);
+        assert_eq!(sent[0]["transition_period"], 10);
+        assert_eq!(sent[3], json!({"on_off": 0, "color_temp": 2700, "brightness": 80, "transition_period": 10}));
+    }
+
+    #[test]
+    fn test_breathe_keeps_colour() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let bulb = Bulb::connect(bulb("KL120(EU)", 0, requests.clone())).unwrap();
+        bulb.breathe(10, 90, 1, Duration::from_millis(20)).unwrap();
+
+        let sent = transitions(&requests);
+        let levels: Vec<_> = sent.iter().map(|s| (s["on_off"].clone(), s["brightness"].clone())).collect();
+        assert_eq!(levels, [(json!(1), json!(90)), (json!(1), json!(10)), (json!(0), json!(80))]);
+        assert!(sent.iter().all(|s| s["color_temp"] == 2700));
+    }
+}
@@ -0,0 +1,321 @@
+/*
+ * A plug with an energy meter that lives in the process, drawing power the
+ * way a real appliance would, for testing alerting, anomaly detection and
+ * reports end to end without hardware:
+ *
+ *   let clock = Arc::new(MockClock::new());
+ *   let (fridge, emulator) = Emulator::new("Fridge")
+ *       .profile(LoadProfile::compressor(90.0, Duration::from_secs(600), Duration::from_secs(1200)))
+ *       .clock(clock.clone())
+ *       .device();
+ *   clock.advance(Duration::from_secs(300));
+ *   assert_eq!(fridge.power_reading()?.power_w, 90.0);
+ *
+ * A profile starts over whenever the relay is switched on; while it's off the
+ * plug draws nothing. Energy is added up exactly from the profile, so the
+ * meter's total matches the power over time. The emulator answers the sysinfo,
+ * relay, alias, LED and realtime meter commands of an HS110 and refuses
+ * others the way the device does.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::{json, Map, Value};
+
+use crate::TpLinkDevice;
+use crate::protocol::{decrypt_payload, encrypt_payload};
+use crate::timing::{self, Clock};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+/// Mains voltage the emulated meter reports.
+pub const VOLTAGE_V: f64 = 230.0;
+
+/// Power drawn over time since the appliance was switched on, as steps of a
+/// constant power each.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadProfile {
+    steps: Vec<(Duration, f64)>,
+    repeat: bool,
+    /// Drawn after the last step of a profile that doesn't repeat.
+    after: f64,
+}
+
+impl LoadProfile {
+    pub fn constant(watts: f64) -> LoadProfile {
+        LoadProfile { steps: Vec::new(), repeat: false, after: watts }
+    }
+
+    /// A fridge or freezer: the compressor runs at `watts` for `on`, then rests
+    /// for `off` at a couple of watts for the thermostat and light.
+    pub fn compressor(watts: f64, on: Duration, off: Duration) -> LoadProfile {
+        LoadProfile { steps: Vec::from([(on, watts), (off, 2.0)]), repeat: true, after: 0.0 }
+    }
+
+    /// A 40 °C cotton programme taking 75 minutes: filling, heating the water,
+    /// washing, rinsing and spinning, then standby.
+    pub fn washing_machine() -> LoadProfile {
+        const MINUTE: u64 = 60;
+        LoadProfile {
+            steps: Vec::from([
+                (Duration::from_secs(3 * MINUTE), 15.0),
+                (Duration::from_secs(15 * MINUTE), 2100.0),
+                (Duration::from_secs(32 * MINUTE), 180.0),
+                (Duration::from_secs(15 * MINUTE), 120.0),
+                (Duration::from_secs(10 * MINUTE), 450.0),
+            ]),
+            repeat: false,
+            after: 1.0,
+        }
+    }
+
+    /// Any steps; after the last one it starts over if `repeat`, or draws `after` watts.
+    pub fn steps(steps: Vec<(Duration, f64)>, repeat: bool, after: f64) -> LoadProfile {
+        LoadProfile { steps, repeat, after }
+    }
+
+    fn period(&self) -> Duration {
+        self.steps.iter().map(|(length, _)| *length).sum()
+    }
+
+    /// The power `t` after switching on.
+    pub fn power_at(&self, t: Duration) -> f64 {
+        let period = self.period();
+        let mut t = t;
+        if self.repeat && !period.is_zero() {
+            t = Duration::from_nanos((t.as_nanos() % period.as_nanos()) as u64);
+        }
+        let mut start = Duration::ZERO;
+        for (length, watts) in &self.steps {
+            start += *length;
+            if t < start {
+                return *watts;
+            }
+        }
+        self.after
+    }
+
+    /// Watt-hours drawn in the first `t` after switching on.
+    pub fn energy_wh(&self, t: Duration) -> f64 {
+        let hours = |d: Duration| d.as_secs_f64() / 3600.0;
+        let period = self.period();
+        let (mut total, mut left) = (0.0, t);
+        if self.repeat && !period.is_zero() {
+            let cycles = (t.as_nanos() / period.as_nanos()) as f64;
+            total += cycles * self.steps.iter().map(|(length, watts)| watts * hours(*length)).sum::<f64>();
+            left = Duration::from_nanos((t.as_nanos() % period.as_nanos()) as u64);
+        }
+        for (length, watts) in &self.steps {
+            let part = left.min(*length);
+            total += watts * hours(part);
+            left -= part;
+        }
+        total + self.after * hours(left)
+    }
+}
+
+struct State {
+    alias: String,
+    led_off: bool,
+    /// When the relay was last switched on, while it is.
+    on_since: Option<Instant>,
+    /// Energy used before the relay was last switched on.
+    used_wh: f64,
+}
+
+pub struct Emulator {
+    profile: LoadProfile,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+fn reply(namespace: &str, method: &str, body: Value) -> Value {
+    json!({namespace: {method: body}})
+}
+
+impl Emulator {
+    /// A plug named `alias` that is on and draws nothing until given a profile.
+    pub fn new(alias: &str) -> Emulator {
+        let clock = timing::system();
+        Emulator {
+            profile: LoadProfile::constant(0.0),
+            state: Mutex::new(State {
+                alias: String::from(alias),
+                led_off: false,
+                on_since: Some(clock.now()),
+                used_wh: 0.0,
+            }),
+            clock,
+        }
+    }
+
+    pub fn profile(mut self, profile: LoadProfile) -> Emulator {
+        self.profile = profile;
+        self
+    }
+
+    /// Takes the time from `clock`, restarting the profile on it.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Emulator {
+        if let Ok(state) = self.state.get_mut() {
+            state.on_since = state.on_since.map(|_| clock.now());
+        }
+        self.clock = clock;
+        self
+    }
+
+    /// A device that talks to this emulator, and the emulator for a look inside.
+    pub fn device(self) -> (TpLinkDevice, Arc<Emulator>) {
+        let emulator = Arc::new(self);
+        (TpLinkDevice::with_transport("emulator", emulator.clone()), emulator)
+    }
+
+    /// What the plug draws right now, in W.
+    pub fn power_w(&self) -> f64 {
+        let now = self.clock.now();
+        self.state.lock().ok()
+            .and_then(|state| state.on_since)
+            .map_or(0.0, |since| self.profile.power_at(now.saturating_duration_since(since)))
+    }
+
+    /// Everything the plug has used, in Wh.
+    pub fn total_wh(&self) -> f64 {
+        let now = self.clock.now();
+        self.state.lock().map_or(0.0, |state| {
+            state.used_wh + state.on_since.map_or(0.0, |since| self.profile.energy_wh(now.saturating_duration_since(since)))
+        })
+    }
+
+    fn sysinfo(&self, state: &State) -> Value {
+        json!({
+            "err_code": 0, "sw_ver": "1.5.4 Build 180815 Rel.121440", "hw_ver": "2.0",
+            "type": "IOT.SMARTPLUGSWITCH", "model": "HS110(EU)", "mac": "50:C7:BF:00:00:00",
+            "deviceId": "EMULATOR", "hwId": "EMULATOR", "fwId": "EMULATOR", "oemId": "EMULATOR",
+            "alias": state.alias, "dev_name": "Smart Wi-Fi Plug With Energy Monitoring", "icon_hash": "",
+            "relay_state": state.on_since.is_some() as i64, "led_off": state.led_off as i64,
+            "on_time": state.on_since.map_or(0, |since| self.clock.now().saturating_duration_since(since).as_secs()),
+            "active_mode": "none", "feature": "TIM:ENE", "updating": 0, "rssi": -55,
+            "latitude": 0.0, "longitude": 0.0,
+        })
+    }
+
+    fn answer(&self, namespace: &str, method: &str, args: &Value) -> Value {
+        let ok = json!({"err_code": 0});
+        match (namespace, method) {
+            ("emeter", "get_realtime") => {
+                let power = self.power_w();
+                reply(namespace, method, json!({
+                    "voltage_mv": (VOLTAGE_V * 1000.0) as i64,
+                    "current_ma": (power / VOLTAGE_V * 1000.0).round() as i64,
+                    "power_mw": (power * 1000.0).round() as i64,
+                    "total_wh": self.total_wh().round() as i64,
+                    "err_code": 0,
+                }))
+            }
+            ("system" | "emeter", _) => {
+                let now = self.clock.now();
+                let Ok(mut state) = self.state.lock() else {
+                    return reply(namespace, method, json!({"err_code": -1, "err_msg": "busy"}));
+                };
+                match (namespace, method) {
+                    ("system", "get_sysinfo") => reply(namespace, method, self.sysinfo(&state)),
+                    ("system", "set_relay_state") => {
+                        let on = args.get("state").and_then(Value::as_i64).unwrap_or(0) != 0;
+                        match (on, state.on_since) {
+                            (true, None) => state.on_since = Some(now),
+                            (false, Some(since)) => {
+                                state.used_wh += self.profile.energy_wh(now.saturating_duration_since(since));
+                                state.on_since = None;
+                            }
+                            _ => {}
+                        }
+                        reply(namespace, method, ok)
+                    }
+                    ("system", "set_dev_alias") => {
+                        state.alias = String::from(args.get("alias").and_then(Value::as_str).unwrap_or(""));
+                        reply(namespace, method, ok)
+                    }
+                    ("system", "set_led_off") => {
+                        state.led_off = args.get("off").and_then(Value::as_i64).unwrap_or(0) != 0;
+                        reply(namespace, method, ok)
+                    }
+                    _ => reply(namespace, method, json!({"err_code": -2, "err_msg": "member not support"})),
+                }
+            }
+            _ => json!({namespace: {"err_code": -1, "err_msg": "module not support"}}),
+        }
+    }
+}
+
+impl Transport for Emulator {
+    fn request(&self, _: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let request: Value = serde_json::from_slice(&decrypt_payload(frame))?;
+        let mut response = Map::new();
+        for (namespace, methods) in request.as_object().into_iter().flatten() {
+            for (method, args) in methods.as_object().into_iter().flatten() {
+                if let Value::Object(answer) = self.answer(namespace, method, args) {
+                    for (namespace, methods) in answer {
+                        match (response.get_mut(&namespace), methods) {
+                            (Some(Value::Object(existing)), Value::Object(methods)) => existing.extend(methods),
+                            (_, methods) => {
+                                response.insert(namespace, methods);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(encrypt_payload(Value::Object(response).to_string().into_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::timing::MockClock;
+    use super::{Emulator, LoadProfile};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_compressor_cycles() {
+        let clock = Arc::new(MockClock::new());
+        let (fridge, emulator) = Emulator::new("Fridge")
+            .profile(LoadProfile::compressor(90.0, 10 * MINUTE, 20 * MINUTE))
+            .clock(clock.clone())
+            .device();
+
+        let mut powers = Vec::new();
+        for _ in 0..6 {
+            powers.push(fridge.power_reading().unwrap().power_w);
+            clock.advance(5 * MINUTE);
+        }
+        assert_eq!(powers, [90.0, 90.0, 2.0, 2.0, 2.0, 2.0]);
+        // 10 minutes at 90 W and 20 at 2 W per half hour.
+        assert!((emulator.total_wh() - 15.0 - 2.0 / 3.0).abs() < 1e-9);
+
+        fridge.off().unwrap();
+        clock.advance(60 * MINUTE);
+        assert_eq!(fridge.power_reading().unwrap().power_w, 0.0);
+        assert_eq!(fridge.sysinfo().unwrap().relay_state, 0);
+        fridge.on().unwrap();
+        assert_eq!(fridge.power_reading().unwrap().power_w, 90.0);
+    }
+
+    #[test]
+    fn test_washing_machine_program() {
+        let clock = Arc::new(MockClock::new());
+        let (washer, _) = Emulator::new("Washer").profile(LoadProfile::washing_machine()).clock(clock.clone()).device();
+        washer.set_device_alias("Laundry").unwrap();
+        assert_eq!(washer.sysinfo().unwrap().alias, "Laundry");
+
+        clock.advance(10 * MINUTE);
+        assert_eq!(washer.power_reading().unwrap().power_w, 2100.0);
+        clock.advance(70 * MINUTE);
+        let done = washer.power_reading().unwrap();
+        assert_eq!(done.power_w, 1.0);
+        // 727 Wh for the programme and 5 minutes of standby, in whole Wh as the meter reports.
+        assert_eq!(done.total_kwh, 0.727);
+        assert!(washer.daystat(2024, 1).is_err());
+    }
+}
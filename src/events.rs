@@ -0,0 +1,60 @@
+use alloc::string::String;
+use core::time::Duration;
+
+use crate::reading::PowerReading;
+use crate::snapshot::FieldChange;
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExcursionKind {
+    Sag,
+    Swell,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Alert {
+    /// Power has stayed away from what is usual for this hour of the day.
+    PowerAnomaly { power_w: f64, baseline_w: f64, deviation: f64 },
+    /// Voltage has left the allowed band.
+    VoltageExcursion { kind: ExcursionKind, voltage_v: f64 },
+    /// Voltage is back in the band; `extreme_v` is the furthest it went.
+    VoltageRestored { kind: ExcursionKind, extreme_v: f64, duration: Duration },
+    /// The relay has switched `limit` times, the configured soft limit.
+    RelayWear { switches: u64, limit: u64 },
+}
+
+/// Something observed about a device, named as it was registered with the watcher.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    PowerSample { device: String, reading: PowerReading },
+    RelayChanged { device: String, on: bool },
+    /// Anything but the relay changed in the device's sysinfo.
+    FieldChanged { device: String, change: FieldChange },
+    DeviceOnline { device: String },
+    DeviceOffline { device: String, reason: String },
+    AlertRaised { device: String, alert: Alert },
+    /// A command or other action taken on the device's behalf, e.g. by the rules engine, failed.
+    CommandFailed { device: String, command: String, reason: String },
+}
+
+impl Event {
+    pub fn device(&self) -> &str {
+        match self {
+            Event::PowerSample { device, .. } => device,
+            Event::RelayChanged { device, .. } => device,
+            Event::FieldChanged { device, .. } => device,
+            Event::DeviceOnline { device } => device,
+            Event::DeviceOffline { device, .. } => device,
+            Event::AlertRaised { device, .. } => device,
+            Event::CommandFailed { device, .. } => device,
+        }
+    }
+
+    pub fn command_failed(device: &str, error: &PlugError) -> Event {
+        Event::CommandFailed {
+            device: String::from(device),
+            command: String::from(error.command().unwrap_or_default()),
+            reason: error.message(),
+        }
+    }
+}
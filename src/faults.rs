@@ -0,0 +1,254 @@
+/*
+ * Makes any transport misbehave on purpose, the ways a flaky plug or network
+ * does, so the handling of broken replies can be tested one fault at a time:
+ *
+ *   let (device, faults) = emulated.with_faults();
+ *   faults.next(Fault::Refuse).next(Fault::Truncate(6));
+ *   assert!(matches!(device.sysinfo(), Err(PlugError::Io { .. })));
+ *   assert!(matches!(device.sysinfo(), Err(PlugError::ConnectionClosed { .. })));
+ *   device.sysinfo()?;                    // fine again
+ *
+ * Faults queued with `next` hit one request each, in order; one set with
+ * `always` hits every request after the queue is empty. Except for `Refuse`,
+ * the request reaches the device before its reply is spoiled, as when a reply
+ * is lost on the way back, so a command that was "lost" may still have been
+ * carried out. `Delay` sleeps on the injector's clock, a `MockClock` in tests.
+ *
+ * The spoiled reply goes over a connection to a throwaway listener on
+ * localhost and is read with a `TcpTransport`, so each fault ends up as the
+ * error a device doing the same would cause: a refused connection, one closed
+ * too early, a frame over the size limit, or a payload that isn't JSON.
+ */
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::TpLinkDevice;
+use crate::protocol::{size_from_bytes, size_to_bytes};
+use crate::timing::{self, Clock};
+use crate::transport::{TcpTransport, Transport};
+use crate::types::PlugError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The connection is refused and nothing is sent.
+    Refuse,
+    /// The device closes the connection after the first half of its reply.
+    DropMidFrame,
+    /// The reply arrives this much later.
+    Delay(Duration),
+    /// Only the first bytes of the reply arrive, length prefix included, before the connection closes.
+    Truncate(usize),
+    /// The payload is replaced with as many random bytes.
+    Garbage,
+    /// The length prefix says this instead of the payload's length.
+    LengthPrefix(u32),
+}
+
+#[derive(Default)]
+struct Plan {
+    queue: VecDeque<Fault>,
+    always: Option<Fault>,
+    injected: usize,
+    rng: u64,
+}
+
+pub struct FaultInjector {
+    inner: Arc<dyn Transport>,
+    /// Reads the spoiled replies.
+    tcp: TcpTransport,
+    clock: Arc<dyn Clock>,
+    plan: Mutex<Plan>,
+}
+
+impl FaultInjector {
+    pub fn new(inner: Arc<dyn Transport>) -> FaultInjector {
+        FaultInjector {
+            inner,
+            tcp: TcpTransport::default(),
+            clock: timing::system(),
+            plan: Mutex::new(Plan { rng: 0x2545_f491_4f6c_dd1d, ..Plan::default() }),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> FaultInjector {
+        self.clock = clock;
+        self
+    }
+
+    /// Spoils the next request not already claimed by a queued fault.
+    pub fn next(&self, fault: Fault) -> &FaultInjector {
+        if let Ok(mut plan) = self.plan.lock() {
+            plan.queue.push_back(fault);
+        }
+        self
+    }
+
+    /// Spoils every request once the queue is empty, until `clear`.
+    pub fn always(&self, fault: Fault) -> &FaultInjector {
+        if let Ok(mut plan) = self.plan.lock() {
+            plan.always = Some(fault);
+        }
+        self
+    }
+
+    /// Drops queued faults and `always`, so requests pass through again.
+    pub fn clear(&self) {
+        if let Ok(mut plan) = self.plan.lock() {
+            plan.queue.clear();
+            plan.always = None;
+        }
+    }
+
+    /// How many requests were spoiled so far.
+    pub fn injected(&self) -> usize {
+        self.plan.lock().map(|plan| plan.injected).unwrap_or(0)
+    }
+
+    /// The fault for this request, and random bytes should it need them.
+    fn take(&self, len: usize) -> (Option<Fault>, Vec<u8>) {
+        let Ok(mut plan) = self.plan.lock() else { return (None, Vec::new()) };
+        let fault = plan.queue.pop_front().or_else(|| plan.always.clone());
+        let mut noise = Vec::new();
+        if fault.is_some() {
+            plan.injected += 1;
+        }
+        if fault == Some(Fault::Garbage) {
+            for _ in 0..len {
+                // xorshift64, as in the scheduler's jitter.
+                plan.rng ^= plan.rng << 13;
+                plan.rng ^= plan.rng >> 7;
+                plan.rng ^= plan.rng << 17;
+                noise.push(plan.rng as u8);
+            }
+        }
+        (fault, noise)
+    }
+
+    /// Plays the device on the one connection to `listener`: passes the request on
+    /// to the wrapped transport and writes back its reply as `fault` spoils it.
+    fn answer(&self, listener: &TcpListener, address: &str, fault: Option<Fault>, noise: Vec<u8>) -> Result<(), PlugError> {
+        let (mut stream, _) = listener.accept()?;
+        let mut request = vec![0u8; 4];
+        stream.read_exact(&mut request)?;
+        request.resize(4 + size_from_bytes(&request), 0);
+        stream.read_exact(&mut request[4..])?;
+
+        let mut response = self.inner.request(address, &request)?;
+        match fault {
+            None | Some(Fault::Refuse) => {}
+            Some(Fault::DropMidFrame) => response.truncate(response.len() / 2),
+            Some(Fault::Delay(delay)) => self.clock.sleep(delay),
+            Some(Fault::Truncate(len)) => response.truncate(len),
+            Some(Fault::Garbage) => {
+                response = Vec::from(size_to_bytes(noise.len() as u32));
+                response.extend(noise);
+            }
+            Some(Fault::LengthPrefix(size)) if response.len() >= 4 => response[..4].copy_from_slice(&size_to_bytes(size)),
+            Some(Fault::LengthPrefix(_)) => {}
+        }
+        // Dropping the stream closes the connection, short of what the prefix promised or not.
+        stream.write_all(&response)?;
+        Ok(())
+    }
+}
+
+impl Transport for FaultInjector {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let (fault, noise) = self.take(frame.len().max(16));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let local = listener.local_addr()?;
+        if fault == Some(Fault::Refuse) {
+            // Nothing listens there any more, so connecting is refused for real.
+            drop(listener);
+            TcpStream::connect(local)?;
+            return Err(PlugError::new("Connection to a closed listener wasn't refused"));
+        }
+
+        let mut stream = TcpStream::connect(local)?;
+        thread::scope(|scope| {
+            let device = scope.spawn(|| self.answer(&listener, address, fault, noise));
+            let reply = self.tcp.exchange(&mut stream, frame);
+            drop(stream);
+            match device.join() {
+                // The wrapped transport failing is the error, not the connection it left closed.
+                Ok(Err(error)) => Err(error),
+                _ => Ok(reply?),
+            }
+        })
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose requests can be made to fail; see `FaultInjector`.
+    pub fn with_faults(&self) -> (TpLinkDevice, Arc<FaultInjector>) {
+        self.with_faults_on(timing::system())
+    }
+
+    /// Like `with_faults`, with delays slept on `clock`.
+    pub fn with_faults_on(&self, clock: Arc<dyn Clock>) -> (TpLinkDevice, Arc<FaultInjector>) {
+        let injector = Arc::new(FaultInjector::new(self.transport.clone()).with_clock(clock));
+        (self.with_inner(injector.clone()), injector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::emulator::Emulator;
+    use crate::timing::{Clock, MockClock};
+    use crate::types::PlugError;
+    use super::Fault;
+
+    #[test]
+    fn test_each_fault() {
+        let (plug, _) = Emulator::new("Plug").device();
+        let (device, faults) = plug.with_faults();
+        faults.next(Fault::Refuse)
+            .next(Fault::Truncate(6))
+            .next(Fault::Garbage)
+            .next(Fault::LengthPrefix(0xffff_ffff))
+            .next(Fault::LengthPrefix(0x8000))
+            .next(Fault::LengthPrefix(3));
+
+        assert!(matches!(device.sysinfo(), Err(PlugError::Io { source, .. }) if source.kind() == ErrorKind::ConnectionRefused));
+        assert!(matches!(device.sysinfo(), Err(PlugError::ConnectionClosed { .. })));
+        assert!(matches!(device.sysinfo(), Err(PlugError::Utf8 { .. })));
+        assert!(device.sysinfo().unwrap_err().message().contains("over the limit"));
+        assert!(matches!(device.sysinfo(), Err(PlugError::ConnectionClosed { .. })));
+        assert!(matches!(device.sysinfo(), Err(PlugError::Json { .. })));
+        assert_eq!(device.sysinfo().unwrap().alias, "Plug");
+        assert_eq!(faults.injected(), 6);
+    }
+
+    #[test]
+    fn test_lost_reply_still_switches() {
+        let clock = Arc::new(MockClock::new());
+        let (plug, emulator) = Emulator::new("Plug").clock(clock.clone()).device();
+        let (device, faults) = plug.with_faults_on(clock.clone());
+
+        faults.next(Fault::DropMidFrame);
+        assert!(matches!(device.off(), Err(PlugError::ConnectionClosed { .. })));
+        assert_eq!(emulator.power_w(), 0.0);
+        assert_eq!(plug.sysinfo().unwrap().relay_state, 0);
+
+        faults.always(Fault::Delay(Duration::from_secs(3)));
+        let before = clock.now();
+        device.on().unwrap();
+        device.on().unwrap();
+        assert_eq!(clock.now() - before, Duration::from_secs(6));
+        faults.clear();
+        device.on().unwrap();
+        assert_eq!(faults.injected(), 3);
+    }
+}
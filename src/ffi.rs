@@ -0,0 +1,142 @@
+/*
+ * C API, enabled with the `ffi` feature. The header lives in include/hs110.h and
+ * is regenerated with:
+ *
+ *   cbindgen --config cbindgen.toml --output include/hs110.h
+ *
+ * Build a linkable library with:
+ *
+ *   cargo rustc --release --features ffi --crate-type cdylib   (or staticlib)
+ */
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use crate::TpLinkDevice;
+use crate::reading::PowerReading;
+
+pub const HS110_OK: c_int = 0;
+pub const HS110_ERR_NULL_POINTER: c_int = -1;
+pub const HS110_ERR_COMMAND_FAILED: c_int = -2;
+pub const HS110_ERR_NO_EMETER: c_int = -3;
+
+/// Meter reading normalized to V, A, W and kWh regardless of the hardware revision.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Hs110Realtime {
+    pub voltage_v: f64,
+    pub current_a: f64,
+    pub power_w: f64,
+    pub total_kwh: f64,
+}
+
+/// Creates a device for `address`, a host with an optional ":port" (9999 if omitted). Returns NULL if `address` is NULL or not UTF-8.
+/// The returned handle must be released with `hs110_device_free`.
+///
+/// # Safety
+///
+/// `address` must be NULL or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hs110_device_new(address: *const c_char) -> *mut TpLinkDevice {
+    if address.is_null() {
+        return ptr::null_mut();
+    }
+
+    match CStr::from_ptr(address).to_str() {
+        Ok(address) => Box::into_raw(Box::new(TpLinkDevice::new(address))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `device` must be NULL or a handle returned by `hs110_device_new`.
+#[no_mangle]
+pub unsafe extern "C" fn hs110_device_on(device: *const TpLinkDevice) -> c_int {
+    match device.as_ref() {
+        Some(device) => match device.on() {
+            Ok(_) => HS110_OK,
+            Err(_) => HS110_ERR_COMMAND_FAILED,
+        },
+        None => HS110_ERR_NULL_POINTER,
+    }
+}
+
+/// # Safety
+///
+/// `device` must be NULL or a handle returned by `hs110_device_new`.
+#[no_mangle]
+pub unsafe extern "C" fn hs110_device_off(device: *const TpLinkDevice) -> c_int {
+    match device.as_ref() {
+        Some(device) => match device.off() {
+            Ok(_) => HS110_OK,
+            Err(_) => HS110_ERR_COMMAND_FAILED,
+        },
+        None => HS110_ERR_NULL_POINTER,
+    }
+}
+
+/// Reads the meter into `out`. `out` is left untouched unless `HS110_OK` is returned.
+///
+/// # Safety
+///
+/// `device` must be NULL or a handle returned by `hs110_device_new`, and `out` must be NULL or
+/// point to writable memory for one `Hs110Realtime`.
+#[no_mangle]
+pub unsafe extern "C" fn hs110_device_get_realtime(device: *const TpLinkDevice,
+                                                   out: *mut Hs110Realtime) -> c_int {
+    let (device, out) = match (device.as_ref(), out.as_mut()) {
+        (Some(device), Some(out)) => (device, out),
+        _ => return HS110_ERR_NULL_POINTER,
+    };
+
+    let realtime = match device.get_realtime() {
+        Ok(response) => match response.emeter.and_then(|e| e.get_realtime) {
+            Some(realtime) => PowerReading::from(&realtime),
+            None => return HS110_ERR_NO_EMETER,
+        },
+        Err(_) => return HS110_ERR_COMMAND_FAILED,
+    };
+
+    *out = Hs110Realtime {
+        voltage_v: realtime.voltage_v,
+        current_a: realtime.current_a,
+        power_w: realtime.power_w,
+        total_kwh: realtime.total_kwh,
+    };
+    HS110_OK
+}
+
+/// # Safety
+///
+/// `device` must be NULL or a handle returned by `hs110_device_new` that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn hs110_device_free(device: *mut TpLinkDevice) {
+    if !device.is_null() {
+        drop(Box::from_raw(device));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::ptr;
+    use super::*;
+
+    #[test]
+    fn test_null_arguments() {
+        unsafe {
+            assert!(hs110_device_new(ptr::null()).is_null());
+            assert_eq!(hs110_device_on(ptr::null()), HS110_ERR_NULL_POINTER);
+            assert_eq!(hs110_device_off(ptr::null()), HS110_ERR_NULL_POINTER);
+
+            let address = CString::new("127.0.0.1:9999").unwrap();
+            let device = hs110_device_new(address.as_ptr());
+            assert!(!device.is_null());
+            assert_eq!(hs110_device_get_realtime(device, ptr::null_mut()), HS110_ERR_NULL_POINTER);
+            hs110_device_free(device);
+            hs110_device_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,572 @@
+/*
+ * Firmware upgrades: checks that an image suits the device, has it download
+ * the image, flashes it and waits for the device to come back.
+ *
+ *   let upgrade = Upgrade::new("http://10.0.0.2/hs110v2.bin", Image::new("HS110(EU)", "1.5.10"));
+ *   let checks = upgrade.checks(&plug)?;
+ *   for check in &checks {
+ *       println!("{}: {}", check.name, check.detail);
+ *   }
+ *   let version = upgrade.run_checked(&checks, &plug, |progress| println!("{:?}", progress))?;
+ *
+ * `run` makes the checks itself; either refuses to start when a check fails. Older devices only download over
+ * plain HTTP; `FileServer` serves a local image to them for the duration of an
+ * upgrade.
+ *
+ * A `UrlPolicy` guards against typos and tampering on the way: it can insist on
+ * HTTPS, limit the hosts images come from, and with the `checksum` feature
+ * fetch the image first to compare its SHA-256 with the expected one:
+ *
+ *   let upgrade = upgrade.policy(UrlPolicy::new().allow_host("fw.example.net").sha256(DIGEST));
+ *
+ * The digest is of the file as this host received it; the device fetches it
+ * again on its own.
+ */
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::TpLinkDevice;
+use crate::commands;
+use crate::quirks::compare_firmware;
+use crate::types::PlugError;
+
+/// Where a firmware download stands, from the `status` of `get_download_state`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "i64", into = "i64")]
+pub enum DownloadStatus {
+    #[default]
+    Idle,
+    Downloading,
+    /// Ready to be flashed.
+    Downloaded,
+    /// A status this crate doesn't know.
+    Other(i64),
+}
+
+impl From<i64> for DownloadStatus {
+    fn from(status: i64) -> DownloadStatus {
+        match status {
+            0 => DownloadStatus::Idle,
+            2 => DownloadStatus::Downloading,
+            3 => DownloadStatus::Downloaded,
+            other => DownloadStatus::Other(other),
+        }
+    }
+}
+
+impl From<DownloadStatus> for i64 {
+    fn from(status: DownloadStatus) -> i64 {
+        match status {
+            DownloadStatus::Idle => 0,
+            DownloadStatus::Downloading => 2,
+            DownloadStatus::Downloaded => 3,
+            DownloadStatus::Other(other) => other,
+        }
+    }
+}
+
+/// The reply to `system.get_download_state`.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadState {
+    pub status: DownloadStatus,
+    /// Percent downloaded.
+    pub ratio: i64,
+    /// Seconds the device expects to take rebooting, and flashing.
+    pub reboot_time: i64,
+    pub flash_time: i64,
+}
+
+impl DownloadState {
+    pub fn is_done(&self) -> bool {
+        self.ratio >= 100 || self.status == DownloadStatus::Downloaded
+    }
+}
+
+impl TpLinkDevice {
+    pub fn download_state(&self) -> Result<DownloadState, PlugError> {
+        self.call(commands::get_download_state(), "system", "get_download_state")
+    }
+
+    /// Asks every `interval` how far a download got and tells `progress`,
+    /// until it is done or `progress` returns false. Returns the last state.
+    pub fn poll_download(&self, interval: Duration, mut progress: impl FnMut(&DownloadState) -> bool)
+        -> Result<DownloadState, PlugError> {
+        loop {
+            let state = self.download_state()?;
+            if !progress(&state) {
+                return Err(PlugError::new(format!("Stopped waiting for the download at {}%", state.ratio).as_str()));
+            }
+            if state.is_done() {
+                return Ok(state);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// What a firmware image is for, and its version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    /// A model prefix, e.g. "HS110" or "HS110(EU)".
+    pub model: String,
+    pub version: String,
+}
+
+impl Image {
+    pub fn new(model: &str, version: &str) -> Image {
+        Image {
+            model: String::from(model),
+            version: String::from(version),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Progress {
+    /// Percent downloaded.
+    Downloading(u8),
+    Flashing,
+    Rebooting,
+    /// The version the device reports afterwards.
+    Done(String),
+}
+
+/// Where an image may be fetched from, and what it must hash to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UrlPolicy {
+    pub https_only: bool,
+    /// Hosts the URL may point at; any if empty.
+    pub allowed_hosts: Vec<String>,
+    /// The image's SHA-256 in hex. Checking it needs the `checksum` feature.
+    pub sha256: Option<String>,
+}
+
+impl UrlPolicy {
+    pub fn new() -> UrlPolicy {
+        UrlPolicy::default()
+    }
+
+    pub fn https_only(mut self) -> UrlPolicy {
+        self.https_only = true;
+        self
+    }
+
+    pub fn allow_host(mut self, host: &str) -> UrlPolicy {
+        self.allowed_hosts.push(host.to_lowercase());
+        self
+    }
+
+    pub fn sha256(mut self, digest: &str) -> UrlPolicy {
+        self.sha256 = Some(digest.trim().to_lowercase());
+        self
+    }
+
+    /// Whether `url` may be used, and why not.
+    pub fn check_url(&self, url: &str) -> Check {
+        let refuse = |detail: String| Check { name: "url", passed: false, detail };
+        let Some((scheme, host)) = scheme_and_host(url) else {
+            return refuse(format!("{} is not an http or https URL", url));
+        };
+        if self.https_only && scheme != "https" {
+            return refuse(format!("{} is not https", url));
+        }
+        if !self.allowed_hosts.is_empty() && !self.allowed_hosts.contains(&host) {
+            return refuse(format!("{} is not one of {}", host, self.allowed_hosts.join(", ")));
+        }
+        Check { name: "url", passed: true, detail: format!("{} from {}", scheme, host) }
+    }
+
+    /// Fetches `url` and compares its digest with the expected one, if there is one.
+    pub fn check_digest(&self, url: &str) -> Option<Check> {
+        let expected = self.sha256.as_ref()?;
+        #[cfg(feature = "checksum")]
+        let check = match sha256_of(url) {
+            Ok(digest) if digest == *expected => Check { name: "sha256", passed: true, detail: digest },
+            Ok(digest) => Check { name: "sha256", passed: false, detail: format!("{} is not {}", digest, expected) },
+            Err(e) => Check { name: "sha256", passed: false, detail: e.to_string() },
+        };
+        #[cfg(not(feature = "checksum"))]
+        let check = Check {
+            name: "sha256",
+            passed: false,
+            detail: format!("can't check {} of {}: built without the checksum feature", expected, url),
+        };
+        Some(check)
+    }
+}
+
+/// The lowercase scheme and host of an http or https URL.
+fn scheme_and_host(url: &str) -> Option<(String, String)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let scheme = scheme.to_lowercase();
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| (scheme, host.to_lowercase()))
+}
+
+#[cfg(feature = "checksum")]
+fn sha256_of(url: &str) -> Result<String, PlugError> {
+    use sha2::{Digest, Sha256};
+
+    let mut response = ureq::get(url).call()
+        .map_err(|e| PlugError::new(format!("Fetching {} failed: {}", url, e).as_str()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut response.body_mut().as_reader(), &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[derive(Clone, Debug)]
+pub struct Upgrade {
+    pub url: String,
+    pub image: Image,
+    pub allow_downgrade: bool,
+    /// Checked along with the image when set.
+    pub policy: Option<UrlPolicy>,
+    /// How often to ask the device how far it got.
+    pub poll: Duration,
+    /// How long each of downloading and rebooting may take.
+    pub timeout: Duration,
+}
+
+impl Upgrade {
+    pub fn new(url: &str, image: Image) -> Upgrade {
+        Upgrade {
+            url: String::from(url),
+            image,
+            allow_downgrade: false,
+            policy: None,
+            poll: Duration::from_secs(1),
+            timeout: Duration::from_secs(300),
+        }
+    }
+
+    pub fn allow_downgrade(mut self) -> Upgrade {
+        self.allow_downgrade = true;
+        self
+    }
+
+    pub fn policy(mut self, policy: UrlPolicy) -> Upgrade {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn poll_every(mut self, poll: Duration) -> Upgrade {
+        self.poll = poll;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Upgrade {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether the image suits `device`: the model matches and, unless allowed,
+    /// the version is not older than the installed one. With a policy, also
+    /// whether the URL and the image's digest pass it.
+    pub fn checks(&self, device: &TpLinkDevice) -> Result<Vec<Check>, PlugError> {
+        let sysinfo = device.sysinfo()?;
+        let model = Check {
+            name: "model",
+            passed: sysinfo.model.starts_with(self.image.model.as_str()),
+            detail: format!("device is {}, image is for {}", sysinfo.model, self.image.model),
+        };
+        let order = compare_firmware(&self.image.version, &sysinfo.sw_ver);
+        let version = Check {
+            name: "version",
+            passed: order.is_ge() || self.allow_downgrade,
+            detail: format!("{} to {}{}", sysinfo.sw_ver, self.image.version,
+                            if order.is_lt() { " is a downgrade" } else { "" }),
+        };
+        let mut checks = vec![model, version];
+        if let Some(policy) = &self.policy {
+            checks.push(policy.check_url(&self.url));
+            checks.extend(policy.check_digest(&self.url));
+        }
+        Ok(checks)
+    }
+
+    /// Downloads and flashes the image, reporting progress, and returns the
+    /// version the device reports once it is back. The device counts as back
+    /// once it has dropped off and answers again, or reports another version;
+    /// anything but the image's version then is an error.
+    pub fn run(&self, device: &TpLinkDevice, progress: impl FnMut(Progress)) -> Result<String, PlugError> {
+        self.run_checked(&self.checks(device)?, device, progress)
+    }
+
+    /// Like `run`, with `checks` already made for `device`, so a caller showing
+    /// them doesn't have them made twice, which fetches the image again when
+    /// the policy has a digest.
+    pub fn run_checked(&self, checks: &[Check], device: &TpLinkDevice, mut progress: impl FnMut(Progress))
+        -> Result<String, PlugError> {
+        let failed: Vec<String> = checks.iter()
+            .filter(|c| !c.passed)
+            .map(|c| format!("{} ({})", c.name, c.detail))
+            .collect();
+        if !failed.is_empty() {
+            return Err(PlugError::new(format!("Refusing to upgrade: {}", failed.join(", ")).as_str()));
+        }
+
+        device.call::<serde::de::IgnoredAny>(commands::download_firmware_from_url(&self.url), "system", "download_firmware")?;
+        let started = Instant::now();
+        let mut timed_out = false;
+        let polled = device.poll_download(self.poll, |state| {
+            progress(Progress::Downloading(state.ratio.clamp(0, 100) as u8));
+            timed_out = !state.is_done() && started.elapsed() > self.timeout;
+            !timed_out
+        });
+        if timed_out {
+            return Err(PlugError::new("Timed out downloading the firmware"));
+        }
+        polled?;
+
+        progress(Progress::Flashing);
+        let installed = device.sysinfo()?.sw_ver;
+        device.call::<serde::de::IgnoredAny>(commands::flash_downloaded_firmware(), "system", "flash_firmware")?;
+
+        progress(Progress::Rebooting);
+        let started = Instant::now();
+        let mut dropped = false;
+        let version = loop {
+            thread::sleep(self.poll);
+            // Until it reboots, the device may still answer with the old firmware.
+            match device.sysinfo() {
+                Ok(sysinfo) if dropped || sysinfo.sw_ver != installed => break sysinfo.sw_ver,
+                Ok(_) => {}
+                Err(_) => dropped = true,
+            }
+            if started.elapsed() >= self.timeout {
+                return Err(PlugError::new(if dropped {
+                    "Timed out waiting for the device to come back"
+                } else {
+                    "Timed out waiting for the device to reboot"
+                }));
+            }
+        };
+        if compare_firmware(&version, &self.image.version).is_ne() {
+            return Err(PlugError::new(
+                format!("Device came back with firmware {}, not {}", version, self.image.version).as_str()));
+        }
+        progress(Progress::Done(version.clone()));
+        Ok(version)
+    }
+}
+
+/// Serves one file over HTTP until dropped, on the local address that
+/// `device` reaches us at.
+pub struct FileServer {
+    pub url: String,
+    stop: Arc<AtomicBool>,
+    address: std::net::SocketAddr,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FileServer {
+    pub fn serve(path: &Path, device: &TpLinkDevice) -> Result<FileServer, PlugError> {
+        let image = fs::read(path)?;
+        // The route to the device tells which of our addresses it can reach.
+        let probe = UdpSocket::bind("0.0.0.0:0")?;
+        probe.connect(device.address())?;
+        let listener = TcpListener::bind((probe.local_addr()?.ip(), 0))?;
+        let address = listener.local_addr()?;
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("firmware.bin");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                if let Ok(stream) = stream {
+                    let _ = respond(stream, &image);
+                }
+            }
+        });
+        Ok(FileServer {
+            url: format!("http://{}/{}", address, name),
+            stop,
+            address,
+            handle: Some(handle),
+        })
+    }
+}
+
+fn respond(mut stream: TcpStream, body: &[u8]) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+    write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n", body.len())?;
+    stream.write_all(body)?;
+    stream.shutdown(Shutdown::Both)
+}
+
+impl Drop for FileServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes the accepting thread so it sees the flag.
+        let _ = TcpStream::connect(self.address);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::{DownloadStatus, Image, Progress, Upgrade, UrlPolicy};
+
+    fn plug() -> TpLinkDevice {
+        flashing_to("1.5.10 Build 191125 Rel.094314", 0)
+    }
+
+    /// A plug that comes back with `flashed` after not answering `down` times.
+    fn flashing_to(flashed: &'static str, down: u32) -> TpLinkDevice {
+        let version = Arc::new(Mutex::new(String::from("1.5.4 Build 180815 Rel.121440")));
+        let ratio = Arc::new(Mutex::new(0));
+        let rebooting = Arc::new(Mutex::new(0));
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let system = &request["system"];
+            let mut rebooting = rebooting.lock().unwrap();
+            if *rebooting > 0 {
+                *rebooting -= 1;
+                if *rebooting == 0 {
+                    *version.lock().unwrap() = String::from(flashed);
+                }
+                return Err(PlugError::new("Connection refused"));
+            }
+            let response = if system.get("get_sysinfo").is_some() {
+                json!({"system": {"get_sysinfo": {"model": "HS110(EU)", "sw_ver": *version.lock().unwrap(), "err_code": 0}}})
+            } else if system.get("get_download_state").is_some() {
+                let mut ratio = ratio.lock().unwrap();
+                *ratio += 50;
+                json!({"system": {"get_download_state": {"status": 2, "ratio": *ratio, "err_code": 0}}})
+            } else if system.get("flash_firmware").is_some() {
+                if down == 0 {
+                    *version.lock().unwrap() = String::from(flashed);
+                }
+                *rebooting = down;
+                json!({"system": {"flash_firmware": {"err_code": 0}}})
+            } else {
+                json!({"system": {"download_firmware": {"err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("plug", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let plug = plug();
+        let mut seen = Vec::new();
+        let version = Upgrade::new("http://10.0.0.2/fw.bin", Image::new("HS110", "1.5.10"))
+            .poll_every(Duration::ZERO)
+            .run(&plug, |p| seen.push(p))
+            .unwrap();
+        assert!(version.starts_with("1.5.10"));
+        assert_eq!(seen, [Progress::Downloading(50), Progress::Downloading(100), Progress::Flashing,
+                          Progress::Rebooting, Progress::Done(version)]);
+    }
+
+    #[test]
+    fn test_upgrade_waits_for_the_new_firmware() {
+        let upgrade = Upgrade::new("http://10.0.0.2/fw.bin", Image::new("HS110", "1.5.10"))
+            .poll_every(Duration::ZERO)
+            .timeout(Duration::from_millis(50));
+        assert!(upgrade.run(&flashing_to("1.5.10 Build 191125 Rel.094314", 3), |_| {}).unwrap().starts_with("1.5.10"));
+
+        let error = upgrade.run(&flashing_to("1.5.4 Build 180815 Rel.121440", 2), |_| {}).unwrap_err();
+        assert_eq!(error.to_string(), "Device came back with firmware 1.5.4 Build 180815 Rel.121440, not 1.5.10");
+        let error = upgrade.run(&flashing_to("1.5.4 Build 180815 Rel.121440", 0), |_| {}).unwrap_err();
+        assert_eq!(error.to_string(), "Timed out waiting for the device to reboot");
+    }
+
+    #[test]
+    fn test_poll_download() {
+        let plug = plug();
+        let mut statuses = Vec::new();
+        let state = plug.poll_download(Duration::ZERO, |state| {
+            statuses.push(state.status);
+            true
+        }).unwrap();
+        assert_eq!((state.ratio, statuses), (100, vec![DownloadStatus::Downloading; 2]));
+
+        let stopped = plug.poll_download(Duration::ZERO, |_| false).unwrap_err();
+        assert_eq!(stopped.to_string(), "Stopped waiting for the download at 150%");
+        assert_eq!(serde_json::from_value::<DownloadStatus>(json!(7)).unwrap(), DownloadStatus::Other(7));
+    }
+
+    #[test]
+    fn test_checks_refuse() {
+        let plug = plug();
+        let downgrade = Upgrade::new("http://10.0.0.2/fw.bin", Image::new("HS110", "1.2.5"));
+        let checks = downgrade.checks(&plug).unwrap();
+        assert!(checks[0].passed && !checks[1].passed);
+        assert!(downgrade.clone().allow_downgrade().checks(&plug).unwrap().iter().all(|c| c.passed));
+
+        let error = Upgrade::new("http://10.0.0.2/fw.bin", Image::new("HS100", "1.5.10"))
+            .run(&plug, |_| {}).unwrap_err();
+        assert!(error.to_string().starts_with("Refusing to upgrade: model"));
+
+        let checks = downgrade.checks(&plug).unwrap();
+        let error = downgrade.run_checked(&checks, &plug, |_| {}).unwrap_err();
+        assert!(error.to_string().starts_with("Refusing to upgrade: version"));
+    }
+
+    #[test]
+    fn test_url_policy() {
+        let policy = UrlPolicy::new().https_only().allow_host("fw.example.net");
+        assert!(policy.check_url("https://FW.example.net:8443/hs110.bin").passed);
+        assert!(!policy.check_url("http://fw.example.net/hs110.bin").passed);
+        assert!(!policy.check_url("https://fw.example.net.evil.test/hs110.bin").passed);
+        assert!(!policy.check_url("https://user@fw.exmaple.net/hs110.bin").passed);
+        assert!(!policy.check_url("ftp://fw.example.net/hs110.bin").passed);
+
+        let upgrade = Upgrade::new("http://10.0.0.2/fw.bin", Image::new("HS110", "1.5.10")).policy(policy);
+        let error = upgrade.run(&plug(), |_| {}).unwrap_err();
+        assert!(error.to_string().contains("url (http://10.0.0.2/fw.bin is not https)"));
+    }
+
+    #[test]
+    #[cfg(feature = "checksum")]
+    fn test_digest_checked() {
+        let path = std::env::temp_dir().join(format!("hs110-fw-{}.bin", std::process::id()));
+        std::fs::write(&path, b"hello").unwrap();
+        let server = super::FileServer::serve(&path, &TpLinkDevice::new("127.0.0.1")).unwrap();
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        assert!(UrlPolicy::new().sha256(hello).check_digest(&server.url).unwrap().passed);
+        assert!(!UrlPolicy::new().sha256(&hello.replace('2', "3")).check_digest(&server.url).unwrap().passed);
+        assert!(UrlPolicy::new().check_digest(&server.url).is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
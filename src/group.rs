@@ -0,0 +1,239 @@
+/*
+ * Switches many plugs with UDP datagrams from a single socket instead of one
+ * TCP connection each, for "all off" buttons that have to be quick:
+ *
+ *   let results = Broadcast::new(Duration::from_millis(500)).switch(&devices, false);
+ *
+ * Plugs answer a command over UDP the way they answer discovery. Each device
+ * in the group gets its own datagram; those that don't confirm within the
+ * timeout are switched over TCP, one by one, and the result says which way
+ * each device went.
+ *
+ * `lan_wide` sends one broadcast instead, which is quicker still but switches
+ * every plug that hears it, whether it is in the group or not. Only use it when
+ * the group is the whole segment.
+ *
+ * Heaters, pumps and other loads that draw a surge when switched on can trip
+ * a shared breaker if they all start together. With a stagger, switching on
+ * skips UDP and closes the relays one at a time, waiting between each and the
+ * next; devices that were on already don't wait:
+ *
+ *   Broadcast::new(Duration::from_millis(500)).stagger(Duration::from_secs(2)).switch(&heaters, true);
+ */
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+use crate::{TpLinkDevice, DEFAULT_PORT};
+use crate::commands;
+use crate::discovery::decrypt_datagram;
+use crate::protocol::encrypt_payload;
+use crate::types::PlugError;
+
+/// How a device was switched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Over UDP, confirmed by the device.
+    Broadcast,
+    Unicast,
+}
+
+pub struct Broadcast {
+    /// A broadcast address, or `None` to send to each device.
+    target: Option<SocketAddr>,
+    timeout: Duration,
+    stagger: Option<Duration>,
+}
+
+impl Broadcast {
+    /// Sends one datagram to each device and waits `timeout` for confirmations.
+    pub fn new(timeout: Duration) -> Broadcast {
+        Broadcast {
+            target: None,
+            timeout,
+            stagger: None,
+        }
+    }
+
+    /// Broadcasts to `target`, e.g. 255.255.255.255:9999 or a subnet's broadcast
+    /// address. This switches every plug that hears it, not just the devices passed to `switch`.
+    pub fn lan_wide(target: SocketAddr, timeout: Duration) -> Broadcast {
+        Broadcast {
+            target: Some(target),
+            ..Broadcast::new(timeout)
+        }
+    }
+
+    /// Switches on one device at a time, `delay` apart.
+    pub fn stagger(mut self, delay: Duration) -> Broadcast {
+        self.stagger = Some(delay);
+        self
+    }
+
+    fn switch_on_staggered(&self, devices: &[TpLinkDevice], delay: Duration) -> Vec<Result<Delivery, PlugError>> {
+        let mut last_switched: Option<Instant> = None;
+        devices.iter()
+            .map(|device| {
+                if let Some(at) = last_switched {
+                    thread::sleep(delay.saturating_sub(at.elapsed()));
+                }
+                // Asking first costs a round trip but saves a wait for plugs that were on.
+                if device.ensure_on()? {
+                    last_switched = Some(Instant::now());
+                }
+                Ok(Delivery::Unicast)
+            })
+            .collect()
+    }
+
+    /// Where the command goes: the broadcast address, or each device that resolves.
+    fn targets(&self, devices: &[TpLinkDevice]) -> Vec<SocketAddr> {
+        match self.target {
+            Some(target) => vec![target],
+            None => devices.iter()
+                .filter_map(|device| (device.host(), device.port().unwrap_or(DEFAULT_PORT)).to_socket_addrs().ok()?.next())
+                .collect(),
+        }
+    }
+
+    /// Hosts that confirmed the command within the timeout.
+    fn send(&self, cmd: &Value, targets: &[SocketAddr]) -> Result<Vec<String>, PlugError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(self.target.is_some())?;
+        let datagram = &encrypt_payload(cmd.to_string().into_bytes())[4..];
+        for target in targets {
+            // A device that can't be reached this way is switched over TCP below.
+            let _ = socket.send_to(datagram, target);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut confirmed = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+            socket.set_read_timeout(Some(left))?;
+            let (len, sender) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => break,
+            };
+            let reply: Value = match serde_json::from_slice(&decrypt_datagram(&buf[..len])) {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+            if reply["system"]["set_relay_state"]["err_code"] == 0 {
+                confirmed.push(sender.ip().to_string());
+            }
+        }
+        Ok(confirmed)
+    }
+
+    /// Switches every device, in the order given. If the datagrams can't be
+    /// sent, every device is switched over TCP, as they are when switching on
+    /// staggered.
+    pub fn switch(&self, devices: &[TpLinkDevice], on: bool) -> Vec<Result<Delivery, PlugError>> {
+        if let (true, Some(delay)) = (on, self.stagger) {
+            return self.switch_on_staggered(devices, delay);
+        }
+        let confirmed = self.send(&commands::set_relay_state(on as u8), &self.targets(devices)).unwrap_or_default();
+        devices.iter()
+            .map(|device| {
+                if confirmed.iter().any(|host| host == device.host()) {
+                    return Ok(Delivery::Broadcast);
+                }
+                if on { device.on() } else { device.off() }.map(|_| Delivery::Unicast)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use serde_json::Value;
+    use crate::TpLinkDevice;
+    use crate::protocol::decrypt_payload;
+    use crate::protocol::encrypt_payload;
+    use crate::transport::Transport;
+    use crate::types::PlugError;
+    use super::{Broadcast, Delivery};
+
+    /// A plug answering one UDP command, and how many commands went over TCP.
+    fn udp_plug() -> (SocketAddr, Arc<dyn Transport>, Arc<Mutex<u32>>) {
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = device.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (_, sender) = device.recv_from(&mut buf).unwrap();
+            let reply = encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec());
+            device.send_to(&reply[4..], sender).unwrap();
+        });
+
+        let unicast = Arc::new(Mutex::new(0));
+        let count = unicast.clone();
+        let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            *count.lock().unwrap() += 1;
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()))
+        };
+        (address, Arc::new(transport), unicast)
+    }
+
+    #[test]
+    fn test_datagram_per_member() {
+        let (address, transport, unicast) = udp_plug();
+        let devices = [
+            TpLinkDevice::with_transport(&address.to_string(), transport.clone()),
+            TpLinkDevice::with_transport("127.0.0.2:9", transport),
+        ];
+
+        let results = Broadcast::new(Duration::from_millis(300)).switch(&devices, false);
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+                   [Delivery::Broadcast, Delivery::Unicast]);
+        assert_eq!(*unicast.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_lan_wide_unconfirmed_fall_back_to_unicast() {
+        let (target, transport, unicast) = udp_plug();
+        let devices = [
+            TpLinkDevice::with_transport("127.0.0.1:9999", transport.clone()),
+            TpLinkDevice::with_transport("10.0.0.2:9999", transport),
+        ];
+
+        let results = Broadcast::lan_wide(target, Duration::from_millis(300)).switch(&devices, false);
+        assert_eq!(results.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+                   [Delivery::Broadcast, Delivery::Unicast]);
+        assert_eq!(*unicast.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_staggered_on() {
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let plug = |host: &str, on: bool| {
+            let closed = closed.clone();
+            let transport = move |address: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+                let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+                if request["system"].get("set_relay_state").is_some() {
+                    closed.lock().unwrap().push((String::from(address), Instant::now()));
+                    return Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()));
+                }
+                let sysinfo = format!(r#"{{"system":{{"get_sysinfo":{{"relay_state":{},"err_code":0}}}}}}"#, on as u8);
+                Ok(encrypt_payload(sysinfo.into_bytes()))
+            };
+            TpLinkDevice::with_transport(host, Arc::new(transport))
+        };
+        let devices = [plug("10.0.0.1", false), plug("10.0.0.2", true), plug("10.0.0.3", false)];
+
+        let started = Instant::now();
+        let results = Broadcast::new(Duration::from_millis(100)).stagger(Duration::from_millis(80)).switch(&devices, true);
+        assert!(results.iter().all(Result::is_ok));
+
+        let closed = closed.lock().unwrap();
+        assert_eq!(closed.iter().map(|(a, _)| a.as_str()).collect::<Vec<_>>(), ["10.0.0.1", "10.0.0.3"]);
+        assert!(closed[1].1 - closed[0].1 >= Duration::from_millis(80));
+        assert!(started.elapsed() < Duration::from_millis(300));
+    }
+}
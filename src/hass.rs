@@ -0,0 +1,198 @@
+/*
+ * Stored history as Home Assistant long-term statistics, so moving to Home
+ * Assistant doesn't start the energy dashboard from nothing:
+ *
+ *   let history = store.history(since, Utc::now())?;
+ *   let export = Statistics::from_history(&history, "heater", "sensor.heater_energy");
+ *   fs::write("heater.json", export.to_json())?;
+ *
+ * There is one row per hour, starting on the hour in UTC, with the energy used
+ * so far in `sum` and the meter reading at the end of the hour in `state`. The
+ * JSON is the data for the `recorder.import_statistics` action; `to_csv` gives
+ * the columns the statistics import integrations read. Energy comes from the
+ * difference in the meter's running total where samples carry one, spread
+ * evenly over the time between them, and from integrating power otherwise, in
+ * which case `state` follows `sum`. Hours with no energy are kept, so that
+ * the sum continues across them.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use chrono::{DateTime, Duration, DurationRound, SecondsFormat, Utc};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::history::History;
+use crate::integrator::DEFAULT_MAX_GAP;
+use crate::reading::PowerReading;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HourlyStatistic {
+    pub start: DateTime<Utc>,
+    /// The meter reading at the end of the hour, in kWh.
+    pub state: f64,
+    /// Energy used from the first sample to the end of the hour, in kWh.
+    pub sum: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statistics {
+    pub statistic_id: String,
+    pub name: String,
+    pub rows: Vec<HourlyStatistic>,
+}
+
+fn hour_of(t: DateTime<Utc>) -> DateTime<Utc> {
+    t.duration_trunc(Duration::hours(1)).unwrap_or(t)
+}
+
+/// Adds `kwh`, used evenly from `t0` to `t1`, to the hours it falls in.
+fn spread(hours: &mut BTreeMap<DateTime<Utc>, f64>, t0: DateTime<Utc>, t1: DateTime<Utc>,
+          kwh: impl Fn(DateTime<Utc>, DateTime<Utc>) -> f64) {
+    let mut start = t0;
+    while start < t1 {
+        let end = (hour_of(start) + Duration::hours(1)).min(t1);
+        *hours.entry(hour_of(start)).or_default() += kwh(start, end);
+        start = end;
+    }
+}
+
+/// Energy per UTC hour in kWh and the last meter total seen in each, from samples in time order.
+fn energy_by_hour(samples: &[(DateTime<Utc>, PowerReading)], max_gap: Duration)
+    -> (BTreeMap<DateTime<Utc>, f64>, BTreeMap<DateTime<Utc>, f64>) {
+    let mut hours = BTreeMap::new();
+    let mut totals = BTreeMap::new();
+    for (t, reading) in samples {
+        hours.entry(hour_of(*t)).or_insert(0.0);
+        if reading.total_kwh > 0.0 {
+            totals.insert(hour_of(*t), reading.total_kwh);
+        }
+    }
+    for pair in samples.windows(2) {
+        let ((t0, r0), (t1, r1)) = (&pair[0], &pair[1]);
+        if t1 <= t0 {
+            continue;
+        }
+        let ms = |a: DateTime<Utc>, b: DateTime<Utc>| (b - a).num_milliseconds() as f64;
+        let metered = r0.total_kwh > 0.0 && r1.total_kwh >= r0.total_kwh;
+        if metered {
+            let used = r1.total_kwh - r0.total_kwh;
+            spread(&mut hours, *t0, *t1, |a, b| used * ms(a, b) / ms(*t0, *t1));
+        } else if *t1 - *t0 <= max_gap {
+            let power_at = |t| r0.power_w + (r1.power_w - r0.power_w) * ms(*t0, t) / ms(*t0, *t1);
+            spread(&mut hours, *t0, *t1, |a, b| (power_at(a) + power_at(b)) / 2.0 * ms(a, b) / 3_600_000_000.0);
+        }
+    }
+    (hours, totals)
+}
+
+/// Hourly rows from samples in time order, see the module documentation.
+pub fn hourly(samples: &[(DateTime<Utc>, PowerReading)], max_gap: Duration) -> Vec<HourlyStatistic> {
+    let (hours, totals) = energy_by_hour(samples, max_gap);
+    let (Some(first), Some(last)) = (hours.keys().next().copied(), hours.keys().next_back().copied()) else {
+        return Vec::new();
+    };
+
+    let mut rows = Vec::new();
+    let (mut sum, mut state) = (0.0, None);
+    let mut start = first;
+    while start <= last {
+        sum += hours.get(&start).copied().unwrap_or(0.0);
+        state = totals.get(&start).copied().or(state);
+        rows.push(HourlyStatistic { start, state: state.unwrap_or(sum), sum });
+        start += Duration::hours(1);
+    }
+    rows
+}
+
+impl Statistics {
+    /// `statistic_id` is the energy sensor the rows are for, e.g. `sensor.heater_energy`.
+    pub fn new(statistic_id: &str, name: &str, rows: Vec<HourlyStatistic>) -> Statistics {
+        Statistics {
+            statistic_id: String::from(statistic_id),
+            name: String::from(name),
+            rows,
+        }
+    }
+
+    /// `device` is the device's name in the history.
+    pub fn from_history(history: &History, device: &str, statistic_id: &str) -> Statistics {
+        Statistics::new(statistic_id, device, hourly(history.samples(device), DEFAULT_MAX_GAP))
+    }
+
+    /// The data for Home Assistant's `recorder.import_statistics` action.
+    pub fn to_json(&self) -> String {
+        let stats: Vec<_> = self.rows.iter()
+            .map(|row| json!({
+                "start": row.start.to_rfc3339_opts(SecondsFormat::Secs, false),
+                "state": row.state,
+                "sum": row.sum,
+            }))
+            .collect();
+        let data = json!({
+            "statistic_id": self.statistic_id,
+            "source": "recorder",
+            "name": self.name,
+            "unit_of_measurement": "kWh",
+            "has_mean": false,
+            "has_sum": true,
+            "stats": stats,
+        });
+        serde_json::to_string_pretty(&data).unwrap_or_default()
+    }
+
+    /// One line per hour: `statistic_id,unit,start,state,sum`.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("statistic_id,unit,start,state,sum\n");
+        for row in &self.rows {
+            let _ = writeln!(csv, "{},kWh,{},{:.3},{:.3}", self.statistic_id,
+                             row.start.to_rfc3339_opts(SecondsFormat::Secs, true), row.state, row.sum);
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::history::History;
+    use crate::reading::PowerReading;
+    use super::{hourly, Statistics};
+
+    #[test]
+    fn test_hourly_from_meter_totals() {
+        let at = |h, m| Utc.with_ymd_and_hms(2024, 6, 3, h, m, 0).unwrap();
+        let reading = |total_kwh| PowerReading { total_kwh, ..PowerReading::default() };
+        let mut history = History::new();
+        // 1 kWh used evenly from 10:30 to 11:30, then nothing until 13:00.
+        history.record("heater", at(10, 30), reading(100.0));
+        history.record("heater", at(11, 30), reading(101.0));
+        history.record("heater", at(13, 0), reading(101.0));
+
+        let export = Statistics::from_history(&history, "heater", "sensor.heater_energy");
+        let rows: Vec<_> = export.rows.iter().map(|r| (r.start, r.state, r.sum)).collect();
+        assert_eq!(rows, [
+            (at(10, 0), 100.0, 0.5),
+            (at(11, 0), 101.0, 1.0),
+            (at(12, 0), 101.0, 1.0),
+            (at(13, 0), 101.0, 1.0),
+        ]);
+        assert!(export.to_csv().contains("sensor.heater_energy,kWh,2024-06-03T11:00:00Z,101.000,1.000\n"));
+        let json: serde_json::Value = serde_json::from_str(&export.to_json()).unwrap();
+        assert_eq!(json["has_sum"], true);
+        assert_eq!(json["stats"][0]["start"], "2024-06-03T10:00:00+00:00");
+    }
+
+    #[test]
+    fn test_hourly_from_power() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 3, 9, 45, 0).unwrap();
+        // 2 kW for half an hour, sampled every 5 minutes and without a meter total.
+        let samples: Vec<_> = (0..=6)
+            .map(|i| (start + Duration::minutes(5 * i), PowerReading { power_w: 2000.0, ..PowerReading::default() }))
+            .collect();
+        let rows = hourly(&samples, Duration::minutes(15));
+        assert_eq!(rows.len(), 2);
+        assert!((rows[0].sum - 0.5).abs() < 1e-9 && (rows[1].sum - 1.0).abs() < 1e-9, "{:?}", rows);
+        assert_eq!(rows[1].state, rows[1].sum);
+    }
+}
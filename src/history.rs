@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+
+use crate::reading::PowerReading;
+use crate::scheduler::Sample;
+use crate::sink::Sink;
+
+/// In-memory power samples per device, in the order they were taken.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    samples: HashMap<String, Vec<(DateTime<Utc>, PowerReading)>>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub fn record(&mut self, device: &str, taken_at: DateTime<Utc>, reading: PowerReading) {
+        let samples = self.samples.entry(String::from(device)).or_default();
+        let idx = samples.partition_point(|(t, _)| *t <= taken_at);
+        samples.insert(idx, (taken_at, reading));
+    }
+
+    pub fn samples(&self, device: &str) -> &[(DateTime<Utc>, PowerReading)] {
+        self.samples.get(device).map(|s| s.as_slice()).unwrap_or(&[])
+    }
+
+    /// Samples taken in `[from, to)`.
+    pub fn between(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>)
+        -> &[(DateTime<Utc>, PowerReading)] {
+
+        let samples = self.samples(device);
+        let start = samples.partition_point(|(t, _)| *t < from);
+        let end = samples.partition_point(|(t, _)| *t < to);
+        &samples[start..end.max(start)]
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        self.samples.keys().map(|k| k.as_str())
+    }
+}
+
+/// Shared as `Arc<Mutex<History>>`, the scheduler can fill a history while reports read from it.
+impl Sink for History {
+    fn write(&mut self, sample: &Sample) {
+        if let Ok(reading) = &sample.reading {
+            self.record(&sample.device, sample.taken_at, *reading);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * Schedule rules as iCalendar, so they can be looked at and edited in a
+ * calendar app and pushed back:
+ *
+ *   fs::write("plug.ics", ical::to_ical(&plug.schedule_rules()?))?;
+ *   plug.set_schedules(&ical::from_ical(&fs::read_to_string("plug.ics")?)?)?;
+ *
+ * Each rule is an event, weekly ones with `RRULE:FREQ=WEEKLY;BYDAY=...` from
+ * the first week of 2024, one-off ones on their date. Rules with an end action
+ * last until it, others take no time. Times are floating, that is device local
+ * time; a `TZID` or a trailing `Z` on import is ignored. What to do is kept in
+ * `X-TPLINK-ACTION` and `X-TPLINK-END-ACTION`; events without them, as made in
+ * a calendar app, switch on, or off when their summary says "off". Disabled
+ * rules are cancelled events. Sunrise and sunset rules have no fixed time and
+ * are left out.
+ */
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{Datelike, NaiveDate};
+
+use crate::schedule::ScheduleRule;
+use crate::types::PlugError;
+
+const DAYS: [&str; 7] = ["SU", "MO", "TU", "WE", "TH", "FR", "SA"];
+
+/// Sunday 31 December 2023, so that day `n` of the week is `n` days later.
+fn first_week(day: usize) -> NaiveDate {
+    NaiveDate::from_ymd_opt(2023, 12, 31).unwrap() + chrono::Duration::days(day as i64)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+fn action(act: i64) -> &'static str {
+    if act == 1 { "on" } else { "off" }
+}
+
+fn stamp(date: NaiveDate, minutes: i64) -> String {
+    format!("{}T{:02}{:02}00", date.format("%Y%m%d"), minutes / 60, minutes % 60)
+}
+
+/// A calendar with one event per rule that has a fixed time.
+pub fn to_ical(rules: &[ScheduleRule]) -> String {
+    let mut lines = Vec::from([
+        String::from("BEGIN:VCALENDAR"),
+        String::from("VERSION:2.0"),
+        String::from("PRODID:-//hs1x0//schedule//EN"),
+    ]);
+    for (idx, rule) in rules.iter().enumerate() {
+        if rule.stime_opt != 0 {
+            continue;
+        }
+        let days: Vec<usize> = rule.wday.iter().enumerate().filter(|(_, on)| **on != 0).map(|(d, _)| d).collect();
+        let date = if rule.repeat == 0 && rule.year > 0 {
+            NaiveDate::from_ymd_opt(rule.year as i32, rule.month as u32, rule.day as u32)
+        } else {
+            days.first().map(|d| first_week(*d))
+        };
+        let Some(date) = date else { continue };
+
+        lines.push(String::from("BEGIN:VEVENT"));
+        match &rule.id {
+            Some(id) => lines.push(format!("UID:{}@hs1x0", escape(id))),
+            None => lines.push(format!("UID:rule-{}@hs1x0", idx)),
+        }
+        lines.push(format!("SUMMARY:{}", escape(&rule.name)));
+        lines.push(format!("DTSTART:{}", stamp(date, rule.smin)));
+        if rule.etime_opt == 0 {
+            let end_date = if rule.emin < rule.smin { date.succ_opt().unwrap_or(date) } else { date };
+            lines.push(format!("DTEND:{}", stamp(end_date, rule.emin)));
+            lines.push(format!("X-TPLINK-END-ACTION:{}", action(rule.eact)));
+        }
+        if rule.repeat != 0 {
+            let byday: Vec<&str> = days.iter().map(|d| DAYS[*d]).collect();
+            lines.push(format!("RRULE:FREQ=WEEKLY;BYDAY={}", byday.join(",")));
+        }
+        lines.push(format!("X-TPLINK-ACTION:{}", action(rule.sact)));
+        if rule.enable == 0 {
+            lines.push(String::from("STATUS:CANCELLED"));
+        }
+        lines.push(String::from("END:VEVENT"));
+    }
+    lines.push(String::from("END:VCALENDAR"));
+    lines.join("\r\n") + "\r\n"
+}
+
+/// The date and minutes after midnight of e.g. `20240101T073000`.
+fn parse_stamp(value: &str) -> Result<(NaiveDate, i64), PlugError> {
+    let invalid = || PlugError::new(format!("Invalid date-time: {}", value).as_str());
+    let (date, time) = value.trim_end_matches('Z').split_once('T').ok_or_else(invalid)?;
+    let date = NaiveDate::parse_from_str(date, "%Y%m%d").map_err(|_| invalid())?;
+    let hour: i64 = time.get(0..2).and_then(|h| h.parse().ok()).ok_or_else(invalid)?;
+    let minute: i64 = time.get(2..4).and_then(|m| m.parse().ok()).ok_or_else(invalid)?;
+    Ok((date, hour * 60 + minute))
+}
+
+fn parse_action(value: &str) -> Result<i64, PlugError> {
+    match value.to_lowercase().as_str() {
+        "on" => Ok(1),
+        "off" => Ok(0),
+        other => Err(PlugError::new(format!("Unknown action: {}", other).as_str())),
+    }
+}
+
+/// Days of the week an `RRULE` repeats on, Sunday first.
+fn parse_rrule(value: &str, start: NaiveDate) -> Result<Vec<i64>, PlugError> {
+    let mut freq = "";
+    let mut byday = None;
+    for part in value.split(';') {
+        match part.split_once('=') {
+            Some(("FREQ", f)) => freq = f,
+            Some(("BYDAY", days)) => byday = Some(days),
+            _ => {}
+        }
+    }
+    let mut wday = alloc::vec![0; 7];
+    match (freq, byday) {
+        ("DAILY", None) => wday = alloc::vec![1; 7],
+        ("WEEKLY", None) => wday[start.weekday().num_days_from_sunday() as usize] = 1,
+        ("WEEKLY" | "DAILY", Some(days)) => {
+            for day in days.split(',') {
+                let idx = DAYS.iter().position(|d| day.ends_with(d))
+                    .ok_or_else(|| PlugError::new(format!("Unknown day in RRULE: {}", day).as_str()))?;
+                wday[idx] = 1;
+            }
+        }
+        _ => return Err(PlugError::new(format!("Only daily and weekly repeats are supported: {}", value).as_str())),
+    }
+    Ok(wday)
+}
+
+fn event(properties: &[(String, String)]) -> Result<ScheduleRule, PlugError> {
+    let get = |name: &str| properties.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+    let (date, smin) = parse_stamp(get("DTSTART").ok_or_else(|| PlugError::new("Event has no DTSTART"))?)?;
+    let name = unescape(get("SUMMARY").unwrap_or(""));
+    let sact = match get("X-TPLINK-ACTION") {
+        Some(action) => parse_action(action)?,
+        None => if name.to_lowercase().contains("off") { 0 } else { 1 },
+    };
+    let mut rule = ScheduleRule::at(0, 0, sact == 1);
+    rule.smin = smin;
+    if !name.is_empty() {
+        rule.name = name;
+    }
+    if get("STATUS") == Some("CANCELLED") {
+        rule.enable = 0;
+    }
+    match get("RRULE") {
+        Some(rrule) => rule.wday = parse_rrule(rrule, date)?,
+        None => {
+            rule.repeat = 0;
+            rule.wday = alloc::vec![0; 7];
+            (rule.year, rule.month, rule.day) = (date.year() as i64, date.month() as i64, date.day() as i64);
+        }
+    }
+    if let Some(end) = get("DTEND") {
+        let (end_date, emin) = parse_stamp(end)?;
+        if (end_date, emin) != (date, smin) {
+            rule.etime_opt = 0;
+            rule.emin = emin;
+            rule.eact = match get("X-TPLINK-END-ACTION") {
+                Some(action) => parse_action(action)?,
+                None => 1 - sact,
+            };
+        }
+    }
+    Ok(rule)
+}
+
+/// The rules for every event in `text`. Ids aren't kept, the device hands out new ones.
+pub fn from_ical(text: &str) -> Result<Vec<ScheduleRule>, PlugError> {
+    // Long lines are folded onto following lines that start with a space or tab.
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(String::from(line)),
+        }
+    }
+
+    let mut rules = Vec::new();
+    let mut properties: Option<Vec<(String, String)>> = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        // Parameters such as TZID come after a semicolon in the name.
+        let name = name.split(';').next().unwrap_or(name).to_uppercase();
+        match (name.as_str(), value, &mut properties) {
+            ("BEGIN", "VEVENT", _) => properties = Some(Vec::new()),
+            ("END", "VEVENT", Some(event_properties)) => {
+                rules.push(event(event_properties)?);
+                properties = None;
+            }
+            (_, _, Some(event_properties)) => event_properties.push((name, String::from(value))),
+            _ => {}
+        }
+    }
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schedule::ScheduleRule;
+    use super::{from_ical, to_ical};
+
+    #[test]
+    fn test_round_trip() {
+        let weekdays = [false, true, true, true, true, true, false];
+        let wake = ScheduleRule::at(7, 30, true).on_days(weekdays).named("wake, then coffee");
+        let night = ScheduleRule { etime_opt: 0, emin: 6 * 60, eact: 0, enable: 0, ..ScheduleRule::at(22, 0, true) };
+        let once = ScheduleRule { repeat: 0, wday: vec![0; 7], year: 2024, month: 12, day: 24,
+                                  ..ScheduleRule::at(18, 0, false) };
+        let sunset = ScheduleRule { stime_opt: 2, ..ScheduleRule::at(0, 0, true) };
+
+        let ical = to_ical(&[wake.clone(), night.clone(), once.clone(), sunset]);
+        assert!(ical.contains("RRULE:FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR\r\n"));
+        assert!(ical.contains("SUMMARY:wake\\, then coffee\r\n"));
+        assert_eq!(from_ical(&ical).unwrap(), [wake, night, once]);
+    }
+
+    #[test]
+    fn test_from_calendar_app() {
+        let ical = "BEGIN:VCALENDAR\nBEGIN:VEVENT\nSUMMARY:Lamp off\nDTSTART;TZID=Europe/Berlin:20240106T233000\n\
+                    RRULE:FREQ=WEEKLY;BY\n DAY=SA,SU\nEND:VEVENT\nEND:VCALENDAR\n";
+        let rules = from_ical(ical).unwrap();
+        assert_eq!((rules[0].smin, rules[0].sact), (23 * 60 + 30, 0));
+        assert_eq!(rules[0].wday, [1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(rules[0].name, "Lamp off");
+    }
+}
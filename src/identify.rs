@@ -0,0 +1,113 @@
+/*
+ * Helps tell which physical plug is which when there are many identical ones:
+ * the status LED blinks for a while and is then put back as it was.
+ *
+ * Pulsing the relay is more visible, since whatever is plugged in flickers too,
+ * but it also cuts power to it. `identify_by_relay` therefore refuses while the
+ * plug draws more than the given load, or while it is on and has no meter to tell.
+ */
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::types::PlugError;
+
+pub const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the relay is flipped for on each pulse.
+pub const RELAY_PULSE: Duration = Duration::from_millis(300);
+
+impl TpLinkDevice {
+    /// Blinks the status LED for `duration`, then restores it.
+    pub fn identify_physically(&self, duration: Duration) -> Result<(), PlugError> {
+        let led_was_off = self.sysinfo()?.led_off != 0;
+
+        let start = Instant::now();
+        let mut off = !led_was_off;
+        let mut result = Ok(());
+        while result.is_ok() && start.elapsed() < duration {
+            result = if off { self.turn_led_off() } else { self.turn_led_on() }.map(|_| ());
+            off = !off;
+            thread::sleep(BLINK_INTERVAL);
+        }
+
+        let restored = if led_was_off { self.turn_led_off() } else { self.turn_led_on() };
+        result.and(restored.map(|_| ()))
+    }
+
+    /// Briefly flips the relay once per second for `duration`, leaving it as it was.
+    pub fn identify_by_relay(&self, duration: Duration, max_load_w: f64) -> Result<(), PlugError> {
+        let was_on = self.is_on()?;
+        if was_on {
+            match self.power_reading() {
+                Ok(reading) if reading.power_w <= max_load_w => {}
+                Ok(reading) => return Err(PlugError::new(
+                    format!("Refusing to pulse the relay with {} W connected", reading.power_w).as_str())),
+                Err(_) => return Err(PlugError::new("Refusing to pulse the relay without a power reading")),
+            }
+        }
+
+        let start = Instant::now();
+        let mut result = Ok(());
+        while result.is_ok() && start.elapsed() < duration {
+            result = if was_on { self.off() } else { self.on() }.map(|_| ());
+            thread::sleep(RELAY_PULSE);
+            let back = if was_on { self.on() } else { self.off() };
+            result = result.and(back.map(|_| ()));
+            thread::sleep(Duration::from_secs(1).saturating_sub(RELAY_PULSE));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+
+    fn plug(relay_state: u8, power_mw: u32, log: Arc<Mutex<Vec<Value>>>) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            log.lock().unwrap().push(request.clone());
+            let response = if request["system"].get("get_sysinfo").is_some() {
+                json!({"system": {"get_sysinfo": {
+                    "err_code": 0, "sw_ver": "1.0.8", "hw_ver": "1.0", "type": "IOT.SMARTPLUGSWITCH",
+                    "model": "HS110(EU)", "mac": "50:C7:BF:00:00:01", "deviceId": "D1", "hwId": "H1",
+                    "fwId": "F1", "oemId": "O1", "alias": "Heater", "dev_name": "Wi-Fi Smart Plug",
+                    "icon_hash": "", "relay_state": relay_state, "on_time": 0, "active_mode": "none",
+                    "feature": "TIM:ENE", "updating": 0, "rssi": -60, "led_off": 0,
+                    "latitude": 0.0, "longitude": 0.0
+                }}})
+            } else if request.get("emeter").is_some() {
+                json!({"emeter": {"get_realtime": {"power_mw": power_mw, "err_code": 0}}})
+            } else {
+                json!({"system": {}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("plug", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_led_restored() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        plug(1, 0, log.clone()).identify_physically(Duration::from_millis(200)).unwrap();
+
+        let leds: Vec<_> = log.lock().unwrap().iter()
+            .filter_map(|r| r["system"]["set_led_off"]["off"].as_u64())
+            .collect();
+        assert_eq!(leds, [1, 0]);
+    }
+
+    #[test]
+    fn test_relay_guard() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let result = plug(1, 1_500_000, log.clone()).identify_by_relay(Duration::from_secs(3), 60.0);
+        assert!(result.is_err());
+        assert!(!log.lock().unwrap().iter().any(|r| r["system"].get("set_relay_state").is_some()));
+    }
+}
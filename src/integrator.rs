@@ -0,0 +1,198 @@
+/*
+ * Estimates energy use from power samples for devices without daystat (HS100,
+ * some firmwares), using trapezoidal integration. Stretches between samples that
+ * are further apart than `max_gap` are left out rather than guessed. Energy is
+ * attributed to host-local days, splitting intervals that span midnight.
+ *
+ * `by_bucket` integrates the same way but splits by the windows of a
+ * time-of-use tariff instead of by day:
+ *
+ *   let kwh = integrator::by_bucket(history.samples("heater"), &tariff, DEFAULT_MAX_GAP);
+ *   println!("{:.2} kWh at peak, costing {:.2}", kwh["peak"], tariff.cost(&kwh));
+ */
+
+use std::collections::BTreeMap;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+use crate::reading::PowerReading;
+use crate::tariff::TimeOfUse;
+use crate::types::EmeterGetDaystatItem;
+
+pub const DEFAULT_MAX_GAP: Duration = Duration::minutes(15);
+
+#[derive(Clone, Debug)]
+pub struct EnergyIntegrator {
+    max_gap: Duration,
+    days: BTreeMap<NaiveDate, f64>,
+    last: Option<(DateTime<Utc>, f64)>,
+}
+
+impl Default for EnergyIntegrator {
+    fn default() -> EnergyIntegrator {
+        EnergyIntegrator::new(DEFAULT_MAX_GAP)
+    }
+}
+
+fn next_local_midnight(t: DateTime<Utc>) -> DateTime<Utc> {
+    let date = t.with_timezone(&Local).date_naive() + Duration::days(1);
+    from_local(date.and_hms_opt(0, 0, 0).unwrap())
+}
+
+fn from_local(t: NaiveDateTime) -> DateTime<Utc> {
+    match Local.from_local_datetime(&t).earliest() {
+        Some(t) => t.with_timezone(&Utc),
+        None => Utc.from_utc_datetime(&t),
+    }
+}
+
+/// Energy in kWh per bucket of `tariff`, from samples in time order, with
+/// intervals split where the bucket changes. Every bucket is listed, with 0
+/// when nothing fell in it; gaps over `max_gap` count towards none.
+pub fn by_bucket(samples: &[(DateTime<Utc>, PowerReading)], tariff: &TimeOfUse, max_gap: Duration)
+    -> BTreeMap<String, f64> {
+    let mut buckets: BTreeMap<String, f64> = tariff.windows.iter().map(|w| w.name.clone())
+        .chain([tariff.otherwise.clone()])
+        .map(|name| (name, 0.0))
+        .collect();
+    for pair in samples.windows(2) {
+        let ((t0, r0), (t1, r1)) = (&pair[0], &pair[1]);
+        if t1 <= t0 || *t1 - *t0 > max_gap {
+            continue;
+        }
+        let total_ms = (*t1 - *t0).num_milliseconds() as f64;
+        let power_at = |t: DateTime<Utc>| {
+            r0.power_w + (r1.power_w - r0.power_w) * (t - *t0).num_milliseconds() as f64 / total_ms
+        };
+
+        let mut start = *t0;
+        while start < *t1 {
+            let local = start.with_timezone(&Local).naive_local();
+            let next = from_local(tariff.next_change(local));
+            // A change inside a skipped DST hour can map back onto `start`.
+            let end = if next > start { next.min(*t1) } else { *t1 };
+            let hours = (end - start).num_milliseconds() as f64 / 3_600_000.0;
+            *buckets.entry(String::from(tariff.bucket(local).0)).or_default() +=
+                (power_at(start) + power_at(end)) / 2.0 * hours / 1000.0;
+            start = end;
+        }
+    }
+    buckets
+}
+
+impl EnergyIntegrator {
+    pub fn new(max_gap: Duration) -> EnergyIntegrator {
+        EnergyIntegrator {
+            max_gap,
+            days: BTreeMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn from_samples(samples: &[(DateTime<Utc>, PowerReading)], max_gap: Duration) -> EnergyIntegrator {
+        let mut integrator = EnergyIntegrator::new(max_gap);
+        for (t, reading) in samples {
+            integrator.add(*t, reading.power_w);
+        }
+        integrator
+    }
+
+    /// Adds a sample. Samples must arrive in time order; older ones are ignored.
+    pub fn add(&mut self, at: DateTime<Utc>, power_w: f64) {
+        if let Some((t0, p0)) = self.last {
+            if at <= t0 {
+                return;
+            }
+            if at - t0 <= self.max_gap {
+                self.integrate(t0, p0, at, power_w);
+            }
+        }
+        self.last = Some((at, power_w));
+    }
+
+    fn integrate(&mut self, t0: DateTime<Utc>, p0: f64, t1: DateTime<Utc>, p1: f64) {
+        let total_ms = (t1 - t0).num_milliseconds() as f64;
+        let power_at = |t: DateTime<Utc>| p0 + (p1 - p0) * (t - t0).num_milliseconds() as f64 / total_ms;
+
+        let mut start = t0;
+        while start < t1 {
+            let end = next_local_midnight(start).min(t1);
+            let hours = (end - start).num_milliseconds() as f64 / 3_600_000.0;
+            let kwh = (power_at(start) + power_at(end)) / 2.0 * hours / 1000.0;
+            *self.days.entry(start.with_timezone(&Local).date_naive()).or_default() += kwh;
+            start = end;
+        }
+    }
+
+    pub fn energy_kwh(&self, date: NaiveDate) -> Option<f64> {
+        self.days.get(&date).copied()
+    }
+
+    /// The estimate in the same shape as the device's `get_daystat` response.
+    pub fn daystat(&self, year: i32, month: u32) -> Vec<EmeterGetDaystatItem> {
+        self.days.iter()
+            .filter(|(date, _)| date.year() == year && date.month() == month)
+            .map(|(date, kwh)| EmeterGetDaystatItem {
+                year: date.year() as i64,
+                month: date.month() as i64,
+                day: date.day() as i64,
+                energy: Some(*kwh),
+                energy_wh: None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Local, NaiveDate, NaiveTime, TimeZone, Utc};
+    use crate::reading::PowerReading;
+    use crate::tariff::{TimeOfUse, Window};
+    use super::{by_bucket, EnergyIntegrator};
+
+    fn at(day: u32, h: u32, m: u32) -> chrono::DateTime<Utc> {
+        Local.with_ymd_and_hms(2024, 6, day, h, m, 0).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_trapezoid_with_gap() {
+        let mut integrator = EnergyIntegrator::default();
+        // 1 kW for an hour, ramp to 2 kW over 10 minutes, then an hour without samples.
+        for m in (0..=60).step_by(5) {
+            integrator.add(at(3, 10, 0) + Duration::minutes(m), 1000.0);
+        }
+        integrator.add(at(3, 11, 10), 2000.0);
+        integrator.add(at(3, 12, 10), 2000.0);
+
+        let kwh = integrator.energy_kwh(NaiveDate::from_ymd_opt(2024, 6, 3).unwrap()).unwrap();
+        assert!((kwh - (1.0 + 1.5 / 6.0)).abs() < 1e-9, "{}", kwh);
+    }
+
+    #[test]
+    fn test_split_at_midnight() {
+        let mut integrator = EnergyIntegrator::new(Duration::hours(1));
+        integrator.add(at(3, 23, 30), 600.0);
+        integrator.add(at(4, 0, 30), 600.0);
+
+        let daystat = integrator.daystat(2024, 6);
+        assert_eq!(daystat.len(), 2);
+        assert!((daystat[0].energy_kwh().unwrap() - 0.3).abs() < 1e-9);
+        assert!((daystat[1].energy_kwh().unwrap() - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_by_bucket() {
+        let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let tariff = TimeOfUse::new("off_peak", 0.20, "EUR")
+            .window(Window::new("peak", hm(17, 0), hm(21, 0), 0.40));
+        // 2 kW from 16:30 to 17:30, then 3 hours without samples.
+        let reading = |power_w| PowerReading { power_w, ..PowerReading::default() };
+        let samples: Vec<_> = (0..=4).map(|i| (at(3, 16, 30) + Duration::minutes(15 * i), reading(2000.0)))
+            .chain([(at(3, 20, 30), reading(0.0))])
+            .collect();
+
+        let kwh = by_bucket(&samples, &tariff, Duration::minutes(15));
+        assert!((kwh["peak"] - 1.0).abs() < 1e-9, "{:?}", kwh);
+        assert!((kwh["off_peak"] - 1.0).abs() < 1e-9, "{:?}", kwh);
+        assert!((tariff.cost(&kwh) - 0.6).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * Discovered devices as YAML for other tools to start from:
+ *
+ *   let found = discovery::discover(Duration::from_secs(2))?;
+ *   fs::write("configuration.yaml", inventory::home_assistant(&found))?;
+ *   fs::write("hosts.yml", inventory::ansible(&found))?;
+ *
+ * `home_assistant` is the `tplink:` section of Home Assistant's manual
+ * configuration, with discovery off since every host is listed. Devices of a
+ * type it doesn't know are left out. `ansible` is an inventory with a group
+ * per device type, hosts named after their aliases.
+ *
+ * Strings are written as JSON strings, which YAML reads as double-quoted
+ * scalars, so aliases with colons or quotes in them come out as they are.
+ */
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::DeviceType;
+use crate::discovery::{Discovered, DiscoveredInfo};
+
+fn quote(text: &str) -> String {
+    serde_json::Value::from(text).to_string()
+}
+
+fn kind(found: &Discovered) -> DeviceType {
+    match &found.info {
+        DiscoveredInfo::Known { kind, .. } => *kind,
+        DiscoveredInfo::Unknown { .. } => DeviceType::Unknown,
+    }
+}
+
+/// The `tplink:` section of a Home Assistant `configuration.yaml`.
+pub fn home_assistant(devices: &[Discovered]) -> String {
+    let mut yaml = String::from("tplink:\n  discovery: false\n");
+    let sections = [
+        ("switch", DeviceType::Plug),
+        ("strip", DeviceType::Strip),
+        ("dimmer", DeviceType::Dimmer),
+        ("light", DeviceType::Bulb),
+    ];
+    for (section, of_kind) in sections {
+        let hosts: Vec<_> = devices.iter().filter(|d| kind(d) == of_kind).collect();
+        if hosts.is_empty() {
+            continue;
+        }
+        let _ = writeln!(yaml, "  {}:", section);
+        for found in hosts {
+            let _ = writeln!(yaml, "    - host: {}", quote(&found.address.ip().to_string()));
+        }
+    }
+    yaml
+}
+
+/// A lowercase host name made of letters, digits and underscores.
+fn host_name(found: &Discovered) -> String {
+    let alias = found.sysinfo().map_or("", |s| s.alias.as_str());
+    let mut name = String::new();
+    for c in alias.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    let name = name.trim_end_matches('_');
+    if name.is_empty() {
+        format!("tplink_{}", found.address.ip().to_string().replace(['.', ':'], "_"))
+    } else {
+        String::from(name)
+    }
+}
+
+/// An Ansible inventory with the devices under `all.children.tplink`.
+pub fn ansible(devices: &[Discovered]) -> String {
+    let groups = [
+        ("plugs", DeviceType::Plug),
+        ("strips", DeviceType::Strip),
+        ("dimmers", DeviceType::Dimmer),
+        ("bulbs", DeviceType::Bulb),
+        ("unknown", DeviceType::Unknown),
+    ];
+    let mut yaml = String::from("all:\n  children:\n    tplink:\n      children:\n");
+    let mut taken = HashSet::new();
+    for (group, of_kind) in groups {
+        let hosts: Vec<_> = devices.iter().filter(|d| kind(d) == of_kind).collect();
+        if hosts.is_empty() {
+            continue;
+        }
+        let _ = writeln!(yaml, "        {}:\n          hosts:", group);
+        for found in hosts {
+            // Two devices with the same alias get numbered names.
+            let base = host_name(found);
+            let mut name = base.clone();
+            let mut n = 2;
+            while !taken.insert(name.clone()) {
+                name = format!("{}_{}", base, n);
+                n += 1;
+            }
+            let _ = writeln!(yaml, "            {}:", name);
+            let _ = writeln!(yaml, "              ansible_host: {}", quote(&found.address.ip().to_string()));
+            if found.address.port() != 9999 {
+                let _ = writeln!(yaml, "              tplink_port: {}", found.address.port());
+            }
+            if let Some(sysinfo) = found.sysinfo() {
+                let _ = writeln!(yaml, "              tplink_alias: {}", quote(&sysinfo.alias));
+                let _ = writeln!(yaml, "              tplink_model: {}", quote(&sysinfo.model));
+            }
+            if let Some(id) = found.device_id() {
+                let _ = writeln!(yaml, "              tplink_device_id: {}", quote(id));
+            }
+        }
+    }
+    yaml
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::DeviceType;
+    use crate::discovery::{Discovered, DiscoveredInfo};
+    use crate::types::SystemGetSysInfoResponse;
+    use super::{ansible, home_assistant};
+
+    fn found(address: &str, kind: DeviceType, alias: &str) -> Discovered {
+        let sysinfo = SystemGetSysInfoResponse {
+            alias: String::from(alias), model: String::from("HS110(EU)"), device_id: String::from("D1"),
+            ..Default::default()
+        };
+        Discovered { address: address.parse().unwrap(), info: DiscoveredInfo::Known { kind, sysinfo: Box::new(sysinfo) } }
+    }
+
+    fn household() -> Vec<Discovered> {
+        vec![
+            found("192.168.1.20:9999", DeviceType::Plug, "Heater: \"bath\""),
+            found("192.168.1.21:9999", DeviceType::Bulb, "Desk lamp"),
+            found("192.168.1.22:9999", DeviceType::Plug, "heater bath"),
+            Discovered {
+                address: "192.168.1.30:9999".parse().unwrap(),
+                info: DiscoveredInfo::Unknown { model: None, raw: json!({}) },
+            },
+        ]
+    }
+
+    #[test]
+    fn test_home_assistant() {
+        assert_eq!(home_assistant(&household()), "tplink:\n  discovery: false\n  switch:\n    \
+            - host: \"192.168.1.20\"\n    - host: \"192.168.1.22\"\n  light:\n    - host: \"192.168.1.21\"\n");
+    }
+
+    #[test]
+    fn test_ansible() {
+        let yaml = ansible(&household());
+        assert!(yaml.starts_with("all:\n  children:\n    tplink:\n      children:\n        plugs:\n          hosts:\n"));
+        assert!(yaml.contains("            heater_bath:\n              ansible_host: \"192.168.1.20\"\n"));
+        assert!(yaml.contains("              tplink_alias: \"Heater: \\\"bath\\\"\"\n"));
+        assert!(yaml.contains("            heater_bath_2:\n"));
+        assert!(yaml.contains("        unknown:\n          hosts:\n            tplink_192_168_1_30:\n"));
+    }
+}
@@ -0,0 +1,396 @@
+/*
+ * The JSON encoder and decoder on the request path, behind a trait so another
+ * implementation can take serde_json's place where parsing replies shows up in
+ * profiles, such as a daemon polling hundreds of plugs every second.
+ *
+ * `send_command` encodes every request and decodes every reply through
+ * `Backend`. A backend only has to write a `serde_json::Value` out and parse
+ * one back; the typed reply is then read from that tree, unless the backend
+ * decodes into it directly, as serde_json does. Parsing takes the decrypted
+ * reply mutably, so one that works in place, as simd-json does, needn't copy
+ * it first. Backends turn their errors into `PlugError::Json`.
+ *
+ *   serde_json   the default
+ *   json-lite    a small parser of its own: one non-generic function instead
+ *                of a serde_json deserializer for every reply type, which
+ *                keeps small builds smaller
+ */
+
+use alloc::vec::Vec;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::types::PlugError;
+
+pub(crate) trait JsonBackend {
+    fn to_vec(value: &Value) -> Result<Vec<u8>, PlugError>;
+
+    /// Parses `bytes`, which the backend may overwrite while it does.
+    fn parse(bytes: &mut [u8]) -> Result<Value, PlugError>;
+
+    /// Decodes `bytes` into `T`, by default by way of `parse`.
+    fn from_slice<T: DeserializeOwned>(bytes: &mut [u8]) -> Result<T, PlugError> {
+        Ok(serde_json::from_value(Self::parse(bytes)?)?)
+    }
+}
+
+#[cfg(any(not(feature = "json-lite"), test))]
+pub(crate) struct SerdeJson;
+
+#[cfg(any(not(feature = "json-lite"), test))]
+impl JsonBackend for SerdeJson {
+    fn to_vec(value: &Value) -> Result<Vec<u8>, PlugError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn parse(bytes: &mut [u8]) -> Result<Value, PlugError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &mut [u8]) -> Result<T, PlugError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[cfg(any(feature = "json-lite", test))]
+pub(crate) struct Lite;
+
+#[cfg(any(feature = "json-lite", test))]
+impl JsonBackend for Lite {
+    fn to_vec(value: &Value) -> Result<Vec<u8>, PlugError> {
+        let mut out = alloc::string::String::new();
+        lite::write(value, &mut out);
+        Ok(out.into_bytes())
+    }
+
+    fn parse(bytes: &mut [u8]) -> Result<Value, PlugError> {
+        lite::parse(bytes)
+    }
+}
+
+/// The backend in use.
+#[cfg(not(feature = "json-lite"))]
+pub(crate) type Backend = SerdeJson;
+#[cfg(feature = "json-lite")]
+pub(crate) type Backend = Lite;
+
+#[cfg(any(feature = "json-lite", test))]
+mod lite {
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use core::fmt::Write;
+    use serde::de::Error as _;
+    use serde_json::{Map, Number, Value};
+
+    use crate::types::PlugError;
+
+    /// As deep as serde_json nests before giving up.
+    const MAX_DEPTH: usize = 128;
+
+    pub(super) fn parse(bytes: &[u8]) -> Result<Value, PlugError> {
+        let mut parser = Parser { bytes, at: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => Ok(value),
+            Some(_) => Err(parser.error("trailing characters")),
+        }
+    }
+
+    struct Parser<'a> {
+        bytes: &'a [u8],
+        at: usize,
+    }
+
+    impl Parser<'_> {
+        fn error(&self, what: &str) -> PlugError {
+            serde_json::Error::custom(format!("{} at byte {}", what, self.at)).into()
+        }
+
+        fn peek(&self) -> Option<u8> {
+            self.bytes.get(self.at).copied()
+        }
+
+        fn skip_whitespace(&mut self) {
+            while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+                self.at += 1;
+            }
+        }
+
+        fn expect(&mut self, byte: u8) -> Result<(), PlugError> {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b) if b == byte => {
+                    self.at += 1;
+                    Ok(())
+                }
+                _ => Err(self.error(&format!("expected `{}`", byte as char))),
+            }
+        }
+
+        fn value(&mut self, depth: usize) -> Result<Value, PlugError> {
+            if depth > MAX_DEPTH {
+                return Err(self.error("nested too deeply"));
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b'{') => self.object(depth),
+                Some(b'[') => self.array(depth),
+                Some(b'"') => Ok(Value::String(self.string()?)),
+                Some(b'-' | b'0'..=b'9') => self.number(),
+                Some(b't') => self.literal("true", Value::Bool(true)),
+                Some(b'f') => self.literal("false", Value::Bool(false)),
+                Some(b'n') => self.literal("null", Value::Null),
+                _ => Err(self.error("expected value")),
+            }
+        }
+
+        fn literal(&mut self, word: &str, value: Value) -> Result<Value, PlugError> {
+            if !self.bytes[self.at..].starts_with(word.as_bytes()) {
+                return Err(self.error("expected value"));
+            }
+            self.at += word.len();
+            Ok(value)
+        }
+
+        fn object(&mut self, depth: usize) -> Result<Value, PlugError> {
+            self.at += 1;
+            let mut object = Map::new();
+            self.skip_whitespace();
+            if self.peek() == Some(b'}') {
+                self.at += 1;
+                return Ok(Value::Object(object));
+            }
+            loop {
+                self.skip_whitespace();
+                if self.peek() != Some(b'"') {
+                    return Err(self.error("expected a key"));
+                }
+                let key = self.string()?;
+                self.expect(b':')?;
+                let value = self.value(depth + 1)?;
+                object.insert(key, value);
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') => self.at += 1,
+                    Some(b'}') => {
+                        self.at += 1;
+                        return Ok(Value::Object(object));
+                    }
+                    _ => return Err(self.error("expected `,` or `}`")),
+                }
+            }
+        }
+
+        fn array(&mut self, depth: usize) -> Result<Value, PlugError> {
+            self.at += 1;
+            let mut array = Vec::new();
+            self.skip_whitespace();
+            if self.peek() == Some(b']') {
+                self.at += 1;
+                return Ok(Value::Array(array));
+            }
+            loop {
+                array.push(self.value(depth + 1)?);
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') => self.at += 1,
+                    Some(b']') => {
+                        self.at += 1;
+                        return Ok(Value::Array(array));
+                    }
+                    _ => return Err(self.error("expected `,` or `]`")),
+                }
+            }
+        }
+
+        fn string(&mut self) -> Result<String, PlugError> {
+            self.at += 1;
+            let mut string = String::new();
+            loop {
+                let start = self.at;
+                while matches!(self.peek(), Some(b) if b != b'"' && b != b'\\' && b >= 0x20) {
+                    self.at += 1;
+                }
+                let run = core::str::from_utf8(&self.bytes[start..self.at]).map_err(|_| self.error("invalid UTF-8"))?;
+                string.push_str(run);
+                match self.peek() {
+                    Some(b'"') => {
+                        self.at += 1;
+                        return Ok(string);
+                    }
+                    Some(b'\\') => {
+                        self.at += 1;
+                        string.push(self.escape()?);
+                    }
+                    Some(_) => return Err(self.error("control character in string")),
+                    None => return Err(self.error("unterminated string")),
+                }
+            }
+        }
+
+        fn escape(&mut self) -> Result<char, PlugError> {
+            let escaped = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.at += 1;
+            Ok(match escaped {
+                b'"' => '"',
+                b'\\' => '\\',
+                b'/' => '/',
+                b'b' => '\u{8}',
+                b'f' => '\u{c}',
+                b'n' => '\n',
+                b'r' => '\r',
+                b't' => '\t',
+                b'u' => {
+                    let high = self.hex4()?;
+                    let code = if (0xd800..0xdc00).contains(&high) {
+                        if !self.bytes[self.at..].starts_with(b"\\u") {
+                            return Err(self.error("unpaired surrogate"));
+                        }
+                        self.at += 2;
+                        let low = self.hex4()?;
+                        if !(0xdc00..0xe000).contains(&low) {
+                            return Err(self.error("unpaired surrogate"));
+                        }
+                        0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                    } else {
+                        high
+                    };
+                    char::from_u32(code).ok_or_else(|| self.error("invalid escape"))?
+                }
+                _ => return Err(self.error("invalid escape")),
+            })
+        }
+
+        fn hex4(&mut self) -> Result<u32, PlugError> {
+            let digits = self.bytes.get(self.at..self.at + 4).ok_or_else(|| self.error("invalid escape"))?;
+            let code = core::str::from_utf8(digits).ok().and_then(|d| u32::from_str_radix(d, 16).ok())
+                .ok_or_else(|| self.error("invalid escape"))?;
+            self.at += 4;
+            Ok(code)
+        }
+
+        fn number(&mut self) -> Result<Value, PlugError> {
+            let start = self.at;
+            let mut integer = true;
+            while let Some(b) = self.peek() {
+                match b {
+                    b'0'..=b'9' | b'-' | b'+' => {}
+                    b'.' | b'e' | b'E' => integer = false,
+                    _ => break,
+                }
+                self.at += 1;
+            }
+            // Only ASCII was consumed above.
+            let text = core::str::from_utf8(&self.bytes[start..self.at]).unwrap_or_default();
+            let number = if integer && text.starts_with('-') {
+                text.parse::<i64>().ok().map(Number::from)
+            } else if integer {
+                text.parse::<u64>().ok().map(Number::from)
+            } else {
+                None
+            };
+            match number.or_else(|| text.parse::<f64>().ok().and_then(Number::from_f64)) {
+                Some(number) => Ok(Value::Number(number)),
+                None => Err(self.error("invalid number")),
+            }
+        }
+    }
+
+    pub(super) fn write(value: &Value, out: &mut String) {
+        match value {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            Value::String(s) => write_string(s, out),
+            Value::Array(array) => {
+                out.push('[');
+                for (i, item) in array.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write(item, out);
+                }
+                out.push(']');
+            }
+            Value::Object(object) => {
+                out.push('{');
+                for (i, (key, item)) in object.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_string(key, out);
+                    out.push(':');
+                    write(item, out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    fn write_string(s: &str, out: &mut String) {
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                '\u{8}' => out.push_str("\\b"),
+                '\u{c}' => out.push_str("\\f"),
+                c if (c as u32) < 0x20 => {
+                    let _ = write!(out, "\\u{:04x}", c as u32);
+                }
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::types::{PlugError, PlugResponse};
+    use super::{Backend, JsonBackend, Lite, SerdeJson};
+
+    #[test]
+    fn test_round_trip() {
+        let request = json!({"system": {"get_sysinfo": {}}});
+        let mut bytes = Backend::to_vec(&request).unwrap();
+        assert_eq!(bytes, br#"{"system":{"get_sysinfo":{}}}"#);
+        let response: PlugResponse = Backend::from_slice(&mut bytes).unwrap();
+        assert!(response.system.is_some());
+
+        let error = Backend::from_slice::<PlugResponse>(&mut b"{\"system\":".to_vec()).unwrap_err();
+        assert!(matches!(error, PlugError::Json { .. }));
+    }
+
+    #[test]
+    fn test_lite_agrees_with_serde_json() {
+        let replies = [
+            r#"{"system":{"get_sysinfo":{"alias":"Kitchen \"main\"","rssi":-61,"latitude":51.5,"err_code":0}}}"#,
+            r#"{"emeter":{"get_realtime":{"power_mw":12345,"total_wh":18446744073709551615,"err_code":0}}}"#,
+            r#" { "a" : [ 1 , -2.5e3 , true , false , null , "tab\tnew\nline é 😀 \/" ] , "b" : { } } "#,
+        ];
+        for reply in replies {
+            let expected = SerdeJson::parse(&mut reply.as_bytes().to_vec()).unwrap();
+            assert_eq!(Lite::parse(&mut reply.as_bytes().to_vec()).unwrap(), expected, "{}", reply);
+            assert_eq!(Lite::to_vec(&expected).unwrap(), SerdeJson::to_vec(&expected).unwrap());
+        }
+
+        let sysinfo: PlugResponse = Lite::from_slice(&mut replies[0].as_bytes().to_vec()).unwrap();
+        assert_eq!(sysinfo.system.unwrap().get_sysinfo.unwrap().alias, "Kitchen \"main\"");
+        for broken in [r#"{"system":"#, r#"{"a":1,}"#, r#"[1 2]"#, r#""\x""#, r#"{"a":1} x"#, "\"\u{1}\"", "-"] {
+            let error = Lite::parse(&mut broken.as_bytes().to_vec()).unwrap_err();
+            assert!(matches!(error, PlugError::Json { .. }), "{}", broken);
+        }
+        let deep = "[".repeat(200) + &"]".repeat(200);
+        assert!(Lite::parse(&mut deep.into_bytes()).is_err());
+        assert_eq!(Lite::to_vec(&Value::String("\u{1}".into())).unwrap(), br#""\u0001""#);
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * One request at a time per device, with interactive commands ahead of
+ * background polling:
+ *
+ *   let lanes = Arc::new(Lanes::new(Duration::from_millis(200)));
+ *   watcher.add("heater", heater.in_lane(&lanes, Priority::Background));
+ *   let button = heater.in_lane(&lanes, Priority::Interactive);
+ *   button.off()?;   // next in line, whatever the watcher has queued
+ *
+ * Plugs answer one connection at a time and slowly, so requests to the same
+ * address through the same `Lanes` wait for each other, and are spaced at
+ * least `min_interval` apart. Of the requests waiting, interactive ones go
+ * first, then the rest in the order they came. A request already under way is
+ * never interrupted.
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    #[default]
+    Background,
+    Interactive,
+}
+
+#[derive(Debug, Default)]
+struct Lane {
+    busy: bool,
+    last: Option<Instant>,
+    /// Waiting requests by priority and ticket.
+    waiting: Vec<(Priority, u64)>,
+}
+
+impl Lane {
+    fn next(&self) -> Option<(Priority, u64)> {
+        self.waiting.iter().copied().min_by_key(|(priority, ticket)| (std::cmp::Reverse(*priority), *ticket))
+    }
+}
+
+#[derive(Debug)]
+pub struct Lanes {
+    min_interval: Duration,
+    lanes: Mutex<(u64, HashMap<String, Lane>)>,
+    changed: Condvar,
+}
+
+impl Lanes {
+    pub fn new(min_interval: Duration) -> Lanes {
+        Lanes {
+            min_interval,
+            lanes: Mutex::new((0, HashMap::new())),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Requests waiting for `address`, by priority.
+    pub fn waiting(&self, address: &str) -> HashMap<Priority, usize> {
+        let mut counts = HashMap::new();
+        if let Ok(lanes) = self.lanes.lock() {
+            for (priority, _) in lanes.1.get(address).map(|lane| lane.waiting.as_slice()).unwrap_or_default() {
+                *counts.entry(*priority).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Waits until the request is first in line and the lane is free, then takes the lane.
+    fn enter(&self, address: &str, priority: Priority) -> Result<(), PlugError> {
+        fn poisoned<T>(_: T) -> PlugError {
+            PlugError::new("Lane lock poisoned")
+        }
+        let mut guard = self.lanes.lock().map_err(poisoned)?;
+        let ticket = guard.0;
+        guard.0 += 1;
+        guard.1.entry(String::from(address)).or_default().waiting.push((priority, ticket));
+
+        loop {
+            let lane = guard.1.entry(String::from(address)).or_default();
+            let wait = lane.last.map_or(Duration::ZERO, |last| self.min_interval.saturating_sub(last.elapsed()));
+            if !lane.busy && lane.next() == Some((priority, ticket)) {
+                if wait.is_zero() {
+                    lane.waiting.retain(|(_, t)| *t != ticket);
+                    lane.busy = true;
+                    return Ok(());
+                }
+                guard = self.changed.wait_timeout(guard, wait).map_err(poisoned)?.0;
+            } else {
+                guard = self.changed.wait(guard).map_err(poisoned)?;
+            }
+        }
+    }
+
+    fn leave(&self, address: &str) {
+        if let Ok(mut lanes) = self.lanes.lock() {
+            if let Some(lane) = lanes.1.get_mut(address) {
+                lane.busy = false;
+                lane.last = Some(Instant::now());
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+pub struct InLane {
+    inner: Arc<dyn Transport>,
+    lanes: Arc<Lanes>,
+    priority: Priority,
+}
+
+impl InLane {
+    pub fn new(inner: Arc<dyn Transport>, lanes: Arc<Lanes>, priority: Priority) -> InLane {
+        InLane {
+            inner,
+            lanes,
+            priority,
+        }
+    }
+}
+
+impl Transport for InLane {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        self.lanes.enter(address, self.priority)?;
+        let response = self.inner.request(address, frame);
+        self.lanes.leave(address);
+        response
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose requests queue in `lanes` with `priority`.
+    pub fn in_lane(&self, lanes: &Arc<Lanes>, priority: Priority) -> TpLinkDevice {
+        self.with_inner(Arc::new(InLane::new(self.transport.clone(), lanes.clone(), priority)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use serde_json::Value;
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::{Lanes, Priority};
+
+    #[test]
+    fn test_interactive_goes_first() {
+        let (release, held) = mpsc::channel::<()>();
+        let held = Mutex::new(held);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let log = order.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let command = request.as_object().unwrap().values().next().unwrap().as_object().unwrap()
+                .keys().next().unwrap().clone();
+            if command == "get_sysinfo" && log.lock().unwrap().is_empty() {
+                // The first poll holds the lane until the test lets it go.
+                held.lock().unwrap().recv().unwrap();
+            }
+            log.lock().unwrap().push(command);
+            Ok(encrypt_payload(br#"{"system":{"err_code":0}}"#.to_vec()))
+        };
+        let plug = TpLinkDevice::with_transport("10.0.0.1", Arc::new(transport));
+        let lanes = Arc::new(Lanes::new(Duration::ZERO));
+        let poller = plug.in_lane(&lanes, Priority::Background);
+        let button = plug.in_lane(&lanes, Priority::Interactive);
+
+        let queued = |priority, n| {
+            let started = Instant::now();
+            while lanes.waiting("10.0.0.1").get(&priority).copied().unwrap_or(0) < n {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+        let mut threads = Vec::new();
+        for _ in 0..3 {
+            let poller = poller.clone();
+            threads.push(thread::spawn(move || { let _ = poller.sysinfo(); }));
+        }
+        queued(Priority::Background, 2);
+        threads.push(thread::spawn(move || { let _ = button.off(); }));
+        queued(Priority::Interactive, 1);
+        release.send(()).unwrap();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), ["get_sysinfo", "set_relay_state", "get_sysinfo", "get_sysinfo"]);
+    }
+
+    #[test]
+    fn test_spacing() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()))
+        };
+        let lanes = Arc::new(Lanes::new(Duration::from_millis(50)));
+        let plug = TpLinkDevice::with_transport("10.0.0.1", Arc::new(transport)).in_lane(&lanes, Priority::Interactive);
+        let started = Instant::now();
+        plug.on().unwrap();
+        plug.off().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
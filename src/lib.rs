@@ -1,11 +1,156 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "std")]
+pub mod anomaly;
+#[cfg(feature = "std")]
+pub mod audit;
+pub mod bindings;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod breaker;
+pub mod bulb;
+#[cfg(feature = "std")]
+pub mod bus;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod carbon;
+pub mod clock;
+pub mod cloud;
+pub mod commands;
+pub mod compat;
+#[cfg(feature = "std")]
+pub mod conformance;
+#[cfg(feature = "std")]
+pub mod cron;
+pub mod device;
+#[cfg(feature = "net")]
+pub mod discovery;
+#[cfg(feature = "std")]
+pub mod dryrun;
+#[cfg(feature = "std")]
+pub mod effects;
+#[cfg(feature = "std")]
+pub mod emulator;
+pub mod events;
+#[cfg(feature = "net")]
+pub mod faults;
+#[cfg(feature = "net")]
+pub mod firmware;
+#[cfg(feature = "net")]
+pub mod group;
+#[cfg(feature = "std")]
+pub mod hass;
+#[cfg(feature = "std")]
+pub mod history;
+#[cfg(feature = "time")]
+pub mod ical;
+#[cfg(feature = "std")]
+pub mod identify;
+#[cfg(feature = "std")]
+pub mod integrator;
+#[cfg(feature = "net")]
+pub mod inventory;
+mod json;
+#[cfg(feature = "std")]
+pub mod lanes;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "std")]
+pub mod meter;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "net")]
+pub mod neighbors;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "net")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod protection;
+pub mod protocol;
+pub mod quirks;
+pub mod reading;
+#[cfg(feature = "std")]
+pub mod reconcile;
+#[cfg(feature = "std")]
+pub mod relay;
+#[cfg(feature = "std")]
+pub mod reports;
+pub mod router;
+#[cfg(feature = "std")]
+pub mod rules;
+pub mod schedule;
+#[cfg(feature = "std")]
+pub mod scheduler;
+#[cfg(feature = "std")]
+pub mod service;
+#[cfg(feature = "std")]
+pub mod shedding;
+#[cfg(feature = "std")]
+pub mod sink;
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod standby;
+#[cfg(feature = "std")]
+pub mod state;
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod store;
+pub mod strip;
+#[cfg(feature = "std")]
+pub mod surplus;
+#[cfg(feature = "time")]
+pub mod tariff;
+#[cfg(feature = "std")]
+pub mod template;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "std")]
+pub mod throttle;
+#[cfg(feature = "std")]
+pub mod timing;
+pub mod transport;
 pub mod types;
-
-use chrono::{Date, Utc};
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::time::Duration;
-use serde_json::json;
-
+#[cfg(feature = "std")]
+pub mod voltage;
+#[cfg(feature = "std")]
+pub mod wallclock;
+#[cfg(feature = "std")]
+pub mod watcher;
+#[cfg(feature = "std")]
+pub mod wear;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+pub mod wifi;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+#[cfg(feature = "time")]
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+#[cfg(feature = "time")]
+use chrono::{Datelike, Months, NaiveDate};
+use core::fmt;
+use core::ops::Range;
+use serde_json::Value;
+
+use json::JsonBackend;
+use protocol::{decrypt_payload, encrypt_payload, size_from_bytes};
+use quirks::{Quirk, QuirkRegistry, Quirks};
+use reading::PowerReading;
+use transport::Transport;
 use types::*;
 
 /*
@@ -13,480 +158,893 @@ use types::*;
  *   https://github.com/softScheck/tplink-smartplug/blob/master/tplink-smarthome-commands.txt
  */
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum DeviceType {
     Plug,
+    Dimmer,
     Bulb,
     Strip,
+    #[default]
     Unknown,
 }
 
-fn size_to_bytes(size: u32) -> [u8;4] {
-    let b1 = ((size >> 24) & 0xff) as u8;
-    let b2 = ((size >> 16) & 0xff) as u8;
-    let b3 = ((size >> 8) & 0xff) as u8;
-    let b4 = (size & 0xff) as u8;
+impl DeviceType {
+    pub fn from_sysinfo(sysinfo: &SystemGetSysInfoResponse) -> DeviceType {
+        if !sysinfo.children.is_empty() {
+            DeviceType::Strip
+        } else if sysinfo.hw_type.contains("SMARTBULB") {
+            DeviceType::Bulb
+        } else if sysinfo.hw_type.contains("SMARTPLUG") && sysinfo.brightness.is_some() {
+            DeviceType::Dimmer
+        } else if sysinfo.hw_type.contains("SMARTPLUG") {
+            DeviceType::Plug
+        } else {
+            DeviceType::Unknown
+        }
+    }
+}
 
-    return [b1, b2, b3, b4];
+#[derive(Clone)]
+pub struct TpLinkDevice {
+    ip: String,
+    transport: Arc<dyn Transport>,
+    kind: DeviceType,
+    model: Option<String>,
+    alias: Option<String>,
+    quirks: Quirks,
 }
 
-fn size_from_bytes(size: &[u8]) -> usize {
-    return ((size[0] as usize) << 24) |
-        ((size[1] as usize) << 16) |
-        ((size[2] as usize) << 8) |
-        size[3] as usize;
+fn send_command<T>(transport: &dyn Transport, ip: &str, quirks: Quirks, cmd: Value) -> Result<T, PlugError>
+where
+    T: serde::de::DeserializeOwned
+{
+    send_frame(transport, ip, quirks, &encrypt_payload(json::Backend::to_vec(&cmd)?))
 }
 
-fn encrypt_payload(data: Vec<u8>) -> Vec<u8> {
-    let it = data.iter();
-    let mut v2 = Vec::new();
-    let mut key = 171;
+/// Sends an encrypted request and decodes the reply.
+fn send_frame<T>(transport: &dyn Transport, ip: &str, quirks: Quirks, frame: &[u8]) -> Result<T, PlugError>
+where
+    T: serde::de::DeserializeOwned
+{
+    if quirks.contains(Quirk::RequiresKlap) {
+        return Err(PlugError::new("Device requires the KLAP protocol, which is not supported"));
+    }
+
+    let mut response = transport.request(ip, frame)?;
+
+    let prefixed = response.len() >= 4 && response.len() == size_from_bytes(&response[0..4]) + 4;
+    if quirks.contains(Quirk::UnprefixedReplies) && !prefixed {
+        let mut framed = Vec::from(protocol::size_to_bytes(response.len() as u32));
+        framed.append(&mut response);
+        response = framed;
+    }
 
-    size_to_bytes(data.len() as u32).map(|x| v2.push(x));
+    if response.is_empty() {
+        return Err(PlugError::EmptyResponse { context: Default::default() });
+    }
+    if response.len() < 4 || response.len() < size_from_bytes(&response[0..4]) + 4 {
+        return Err(PlugError::new("Truncated response"));
+    }
 
-    for b in it {
-        let tmp = *b ^ key;
-        v2.push(tmp);
-        key = tmp;
+    let mut decrypted = String::from_utf8(decrypt_payload(&response))?.into_bytes();
+    if decrypted.trim_ascii().is_empty() {
+        return Err(PlugError::EmptyResponse { context: Default::default() });
     }
 
-    v2
+    json::Backend::from_slice(&mut decrypted)
 }
 
-fn decrypt_payload(data: &[u8]) -> Vec<u8> {
+/// `name`, or with `{:#}` the alias and address together, e.g. "Heater (192.168.1.20:9999)".
+impl fmt::Display for TpLinkDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.alias {
+            Some(alias) if f.alternate() => write!(f, "{} ({})", alias, self.shown_address()),
+            _ if f.alternate() => write!(f, "{}", self.shown_address()),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Leaves out the transport, which may hold keys or tokens.
+impl fmt::Debug for TpLinkDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TpLinkDevice")
+            .field("address", &self.shown_address())
+            .field("alias", &self.alias)
+            .field("kind", &self.kind)
+            .field("model", &self.model)
+            .field("quirks", &self.quirks)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The port devices listen on for the TCP protocol.
+pub const DEFAULT_PORT: u16 = 9999;
 
-    let payload_size = size_from_bytes(&data[0..4]);
-    let mut v2 = Vec::new();
-    let mut key = 171u8;
+/// `address` without any `user:password@` a custom transport may have put in it.
+pub(crate) fn shown_address(address: &str) -> &str {
+    address.rsplit_once('@').map_or(address, |(_, address)| address)
+}
 
-    for idx in 4..payload_size+4 {
-        let tmp = data[idx] ^ key;
-        v2.push(tmp);
-        key = data[idx];
+/// Splits "host:port", "[v6]:port" or a bare host.
+fn split_address(address: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = address.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':').and_then(|p| p.parse().ok())),
+            None => (address, None),
+        };
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (address, None),
+        },
+        _ => (address, None),
     }
+}
 
-    v2
+fn join_address(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
 }
 
-pub struct TpLinkDevice {
-    ip: String
+/// Stamps for readings; without a clock they are left at the epoch.
+#[cfg(feature = "std")]
+fn now() -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now()
 }
 
-fn send_command<T>(ip: &str, s: String) -> Result<T, PlugError>
-where
-    T: serde::de::DeserializeOwned
-{
-    match TcpStream::connect(ip) {
-        Ok(mut stream) => {
-            stream.set_read_timeout(Some(Duration::from_millis(5000))).unwrap();
+#[cfg(all(feature = "time", not(feature = "std")))]
+fn now() -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::default()
+}
 
-            let payload = encrypt_payload(s.as_bytes().to_vec());
-            match stream.write(payload.as_slice()) {
-                Ok(_v) => 0,
-                Err(e) => return Err(PlugError::new("Write failed"))
-            };
+/// The reading, stamped with `now` if there is a clock to read.
+#[cfg(feature = "time")]
+fn stamped(realtime: &EmeterGetRealtimeResponse) -> PowerReading {
+    PowerReading::from_realtime(realtime, now())
+}
 
-            let mut buf = [0u8; 2048];
-            let size = match stream.read(&mut buf) {
-                Ok(v) => v,
-                Err(e) => return Err(PlugError::new("Read failed"))
-            };
+#[cfg(not(feature = "time"))]
+fn stamped(realtime: &EmeterGetRealtimeResponse) -> PowerReading {
+    PowerReading::from(realtime)
+}
 
-            let decrypted = match String::from_utf8(decrypt_payload(&buf[0..size])) {
-                Ok(v) => v,
-                Err(e) => return Err(PlugError::new("Decoding failed"))
-            };
+impl TpLinkDevice {
+    /// Connects to `host` on `DEFAULT_PORT`; see `with_port` for others. The host
+    /// may come from anywhere at runtime, e.g. a config file or discovery. An
+    /// old style "host:port" is still understood.
+    #[cfg(feature = "net")]
+    pub fn new(host: impl Into<String>) -> TpLinkDevice {
+        let host = host.into();
+        let (host, port) = split_address(&host);
+        TpLinkDevice::with_transport(&join_address(host, port.unwrap_or(DEFAULT_PORT)),
+                                     Arc::new(transport::TcpTransport::default()))
+    }
 
-            match serde_json::from_str(decrypted.as_str()) {
-                Ok(result) => Ok(result),
-                Err(e) => return Err(PlugError::new(
-                    format!("Deserialization failed. Reason: {}", e.to_string()).as_str()))
-            }
+    #[cfg(feature = "net")]
+    pub fn from_socket_addr(address: std::net::SocketAddr) -> TpLinkDevice {
+        TpLinkDevice::new(address.to_string())
+    }
+
+    /// The same device over `transport`, e.g. one with other limits.
+    #[cfg(feature = "net")]
+    pub fn over_tcp(&self, transport: transport::TcpTransport) -> TpLinkDevice {
+        self.with_inner(Arc::new(transport))
+    }
+
+    pub fn with_port(mut self, port: u16) -> TpLinkDevice {
+        self.ip = join_address(self.host(), port);
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        split_address(&self.ip).0
+    }
+
+    /// `None` for custom transports addressed without a port.
+    pub fn port(&self) -> Option<u16> {
+        split_address(&self.ip).1
+    }
+
+    pub fn with_transport(ip: &str, transport: Arc<dyn Transport>) -> TpLinkDevice {
+        TpLinkDevice {
+            ip: String::from(ip),
+            transport,
+            kind: DeviceType::Unknown,
+            model: None,
+            alias: None,
+            quirks: Quirks::default(),
         }
-        Err(_) => Err(PlugError::new("Connection error")),
     }
-}
 
-impl TpLinkDevice {
-    pub fn new(ip: &'static str) -> TpLinkDevice {
+    /// Skips `detect` when the kind of device is already known.
+    pub fn with_device_type(mut self, kind: DeviceType) -> TpLinkDevice {
+        self.kind = kind;
+        self
+    }
+
+    pub fn address(&self) -> &str {
+        &self.ip
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.kind
+    }
+
+    /// The model reported by `detect`, if it has been run.
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    /// The alias the device had when it was detected; `sysinfo` has the current one.
+    pub fn known_alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    /// The address without any `user:password@` a custom transport may have put in it.
+    fn shown_address(&self) -> &str {
+        shown_address(&self.ip)
+    }
+
+    /// What to call the device in logs and output: its alias once detected, its host before.
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or_else(|| split_address(self.shown_address()).0)
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> TpLinkDevice {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// How long the device took to answer the transport's cheapest check; over
+    /// TCP that is just opening a connection. Nothing is parsed.
+    #[cfg(feature = "std")]
+    pub fn ping(&self, timeout: core::time::Duration) -> Result<core::time::Duration, PlugError> {
+        let start = std::time::Instant::now();
+        self.transport.probe(&self.ip, timeout).map_err(|e| self.in_context(e, "ping"))?;
+        Ok(start.elapsed())
+    }
+
+    /// Reads sysinfo to learn what kind of device this is, so that operations
+    /// go to the namespaces it answers in (see `router`), and which of the
+    /// built-in quirks apply to it.
+    pub fn detect(self) -> Result<TpLinkDevice, PlugError> {
+        self.detect_with(&QuirkRegistry::default())
+    }
+
+    pub fn detect_with(self, registry: &QuirkRegistry) -> Result<TpLinkDevice, PlugError> {
+        let sysinfo = self.sysinfo()?;
+        Ok(self.identified_with(&sysinfo, registry))
+    }
+
+    fn identified(self, sysinfo: &SystemGetSysInfoResponse) -> TpLinkDevice {
+        self.identified_with(sysinfo, &QuirkRegistry::default())
+    }
+
+    fn identified_with(mut self, sysinfo: &SystemGetSysInfoResponse, registry: &QuirkRegistry) -> TpLinkDevice {
+        self.kind = DeviceType::from_sysinfo(sysinfo);
+        self.model = Some(sysinfo.model.clone());
+        self.alias = Some(sysinfo.alias.clone()).filter(|a| !a.is_empty());
+        self.quirks = self.quirks.union(registry.lookup(&sysinfo.model, &sysinfo.sw_ver));
+        self
+    }
+
+    /// The same device reached through another transport.
+    #[cfg(feature = "std")]
+    fn with_inner(&self, transport: Arc<dyn Transport>) -> TpLinkDevice {
         TpLinkDevice {
-            ip: String::from(ip)
+            transport,
+            ..self.clone()
         }
     }
 
-    fn set_relay_state(&self, state: u8) -> Result<PlugResponse, PlugError> {
-        let cmd = json!({
-            "system": {
-                "set_relay_state": {
-                    "state": state
-                }
+    /// Tags `error` with this device and the command it came from.
+    fn in_context(&self, error: PlugError, command: &str) -> PlugError {
+        error.in_context(self.shown_address(), self.alias.as_deref(), command)
+    }
+
+    fn send(&self, cmd: Value) -> Result<PlugResponse, PlugError> {
+        let command = commands::name(&cmd);
+        send_command(self.transport.as_ref(), &self.ip, self.quirks, cmd).map_err(|e| self.in_context(e, &command))
+    }
+
+    /// Sends a precomputed frame, without building or encrypting anything.
+    fn send_static(&self, command: commands::StaticCommand) -> Result<PlugResponse, PlugError> {
+        send_frame(self.transport.as_ref(), &self.ip, self.quirks, command.frame)
+            .map_err(|e| self.in_context(e, command.name))
+    }
+
+    fn send_routed(&self, request: &router::Request) -> Result<PlugResponse, PlugError> {
+        if let Some(command) = router::static_command(self.kind, self.model(), request) {
+            return self.send_static(command);
+        }
+        let cmd = router::command(self.kind, self.model(), request).map_err(|e| self.in_context(e, ""))?;
+        self.send(cmd)
+    }
+
+    /// Sends any command and returns the reply as it came, for commands this
+    /// crate doesn't know yet.
+    pub fn send_raw(&self, cmd: Value) -> Result<Value, PlugError> {
+        let command = commands::name(&cmd);
+        send_command(self.transport.as_ref(), &self.ip, self.quirks, cmd).map_err(|e| self.in_context(e, &command))
+    }
+
+    /// Sends `cmd` and returns the reply to `namespace`.`method`, failing on a non-zero `err_code`.
+    fn call<T>(&self, cmd: Value, namespace: &str, method: &str) -> Result<T, PlugError>
+    where
+        T: serde::de::DeserializeOwned
+    {
+        let command = format!("{}.{}", namespace, method);
+        let mut response: Value = send_command(self.transport.as_ref(), &self.ip, self.quirks, cmd)
+            .map_err(|e| self.in_context(e, &command))?;
+        let reply = match response.get_mut(namespace).and_then(|n| n.get_mut(method)) {
+            Some(reply) => reply.take(),
+            None => return Err(self.in_context(PlugError::new("Response has no reply to the command"), &command)),
+        };
+        let result = match reply.get("err_code").and_then(Value::as_i64) {
+            Some(code) if code != 0 => {
+                let reason = reply.get("err_msg").and_then(Value::as_str).map(|m| format!(" ({})", m));
+                Err(PlugError::new(format!("Failed with err_code {}{}", code, reason.unwrap_or_default()).as_str()))
             }
-        });
-        send_command(&self.ip, cmd.to_string())
+            _ => serde_json::from_value(reply).map_err(PlugError::from),
+        };
+        result.map_err(|e| self.in_context(e, &command))
     }
 
     pub fn on(&self) -> Result<PlugResponse, PlugError> {
-        self.set_relay_state(1)
+        self.send_routed(&router::Request::Power(true))
     }
 
     pub fn off(&self) -> Result<PlugResponse, PlugError> {
-        self.set_relay_state(0)
+        self.send_routed(&router::Request::Power(false))
+    }
+
+    /// Switches on unless already on, going by `sysinfo`, which a `cached`
+    /// device may answer without asking. Returns whether anything was sent.
+    pub fn ensure_on(&self) -> Result<bool, PlugError> {
+        self.ensure(true)
+    }
+
+    pub fn ensure_off(&self) -> Result<bool, PlugError> {
+        self.ensure(false)
+    }
+
+    /// Whether the relay, or a bulb's light, is on, going by `sysinfo`. Fails
+    /// for strips, whose outlets each have their own.
+    pub fn is_on(&self) -> Result<bool, PlugError> {
+        self.power_state(&self.sysinfo()?)
+    }
+
+    pub(crate) fn power_state(&self, sysinfo: &SystemGetSysInfoResponse) -> Result<bool, PlugError> {
+        sysinfo.is_on().ok_or_else(|| self.in_context(PlugError::new("Device has no on/off state of its own"),
+                                                        "system.get_sysinfo"))
+    }
+
+    fn ensure(&self, on: bool) -> Result<bool, PlugError> {
+        if self.is_on()? == on {
+            return Ok(false);
+        }
+        self.send_routed(&router::Request::Power(on))?;
+        Ok(true)
+    }
+
+    /// Dimmers and bulbs only.
+    pub fn set_brightness(&self, brightness: u8) -> Result<PlugResponse, PlugError> {
+        self.send_routed(&router::Request::Brightness(brightness))
     }
 
     pub fn get_realtime(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "emeter": {
-                "get_realtime": {}
+        self.send_routed(&router::Request::Realtime)
+    }
+
+    pub fn sysinfo(&self) -> Result<SystemGetSysInfoResponse, PlugError> {
+        match self.get_meter_info()?.system.and_then(|s| s.get_sysinfo) {
+            Some(mut sysinfo) => {
+                if self.quirks.contains(Quirk::IntegerLocation) {
+                    sysinfo.latitude = sysinfo.latitude_i.map_or(sysinfo.latitude, |l| l as f64 / 10000.0);
+                    sysinfo.longitude = sysinfo.longitude_i.map_or(sysinfo.longitude, |l| l as f64 / 10000.0);
+                }
+                Ok(sysinfo)
             }
-        });
+            None => Err(self.in_context(PlugError::new("Response has no sysinfo"), "system.get_sysinfo")),
+        }
+    }
 
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+    pub fn power_reading(&self) -> Result<PowerReading, PlugError> {
+        match self.get_realtime()?.emeter.and_then(|e| e.get_realtime) {
+            Some(realtime) => Ok(stamped(&realtime)),
+            None => Err(self.in_context(PlugError::new("Response has no emeter reading"), "emeter.get_realtime")),
+        }
     }
 
-    pub fn reboot(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "reboot": {
-                    "delay": 1
-                }
+    pub fn get_daystat(&self, year: i32, month: u32) -> Result<PlugResponse, PlugError> {
+        self.send_routed(&router::Request::Daystat { year, month })
+    }
+
+    pub fn daystat(&self, year: i32, month: u32) -> Result<Vec<EmeterGetDaystatItem>, PlugError> {
+        match self.get_daystat(year, month)?.emeter.and_then(|e| e.get_daystat) {
+            Some(daystat) => Ok(daystat.day_list),
+            None => Err(self.in_context(PlugError::new("Response has no daystat"), "emeter.get_daystat")),
+        }
+    }
+
+    /// Energy in kWh for every day of the month, `None` for days the device has
+    /// nothing for, including those still to come.
+    #[cfg(feature = "time")]
+    pub fn daily_energy(&self, year: i32, month: u32) -> Result<BTreeMap<NaiveDate, Option<f64>>, PlugError> {
+        NaiveDate::from_ymd_opt(year, month, 1)
+            .ok_or_else(|| PlugError::new(format!("{}-{} is not a month", year, month).as_str()))?;
+        let mut days: BTreeMap<NaiveDate, Option<f64>> =
+            (1..=31).filter_map(|day| NaiveDate::from_ymd_opt(year, month, day)).map(|d| (d, None)).collect();
+        for item in self.daystat(year, month)? {
+            let date = NaiveDate::from_ymd_opt(item.year as i32, item.month as u32, item.day as u32);
+            if let Some(energy) = date.and_then(|d| days.get_mut(&d)) {
+                *energy = item.energy_kwh();
             }
-        });
+        }
+        Ok(days)
+    }
 
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+    /// Like `daily_energy`, for the days from `start` to `end` inclusive, with
+    /// one `get_daystat` per month they touch.
+    #[cfg(feature = "time")]
+    pub fn daily_energy_between(&self, start: NaiveDate, end: NaiveDate)
+        -> Result<BTreeMap<NaiveDate, Option<f64>>, PlugError> {
+        let mut days = BTreeMap::new();
+        let mut month = start.with_day(1).unwrap_or(start);
+        while month <= end {
+            let month_days = self.daily_energy(month.year(), month.month())?;
+            days.extend(month_days.into_iter().filter(|(d, _)| (start..=end).contains(d)));
+            let Some(next) = month.checked_add_months(Months::new(1)) else { break };
+            month = next;
+        }
+        Ok(days)
     }
 
-    pub fn reset_to_factory(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "reset": {
-                    "delay": 1
+    /// Energy per month over `years`, oldest first, one `get_monthstat` per year.
+    /// The series runs from the first month the device still keeps to the last
+    /// one it has; months in between that it left out have no energy. Years
+    /// outside its retention window, which it refuses or answers empty, add
+    /// nothing rather than failing.
+    pub fn energy_history(&self, years: Range<i32>) -> Result<Vec<EmeterGetMonthstatItem>, PlugError> {
+        let mut months = Vec::new();
+        for year in years {
+            let monthstat = self.send_routed(&router::Request::Monthstat { year })?.emeter.and_then(|e| e.get_monthstat)
+                .ok_or_else(|| self.in_context(PlugError::new("Response has no monthstat"), "emeter.get_monthstat"))?;
+            if monthstat.err_code == 0 {
+                months.extend(monthstat.month_list.into_iter().filter(|m| (1..=12).contains(&m.month)));
+            }
+        }
+        months.sort_by_key(|m| (m.year, m.month));
+        months.dedup_by_key(|m| (m.year, m.month));
+
+        let mut series: Vec<EmeterGetMonthstatItem> = Vec::with_capacity(months.len());
+        for month in months {
+            if let Some(last) = series.last() {
+                let (mut year, mut next) = (last.year, last.month + 1);
+                loop {
+                    if next > 12 {
+                        (year, next) = (year + 1, 1);
+                    }
+                    if (year, next) == (month.year, month.month) {
+                        break;
+                    }
+                    series.push(EmeterGetMonthstatItem { year, month: next, ..EmeterGetMonthstatItem::default() });
+                    next += 1;
                 }
             }
-        });
+            series.push(month);
+        }
+        Ok(series)
+    }
 
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+    pub fn reboot(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::reboot())
+    }
+
+    pub fn reset_to_factory(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::reset_to_factory())
     }
 
     pub fn turn_led_off(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_led_off": {
-                    "off": 1
-                }
-            }
-        });
+        self.send(commands::turn_led_off())
+    }
 
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+    pub fn turn_led_on(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::set_led_off(0))
     }
 
     pub fn set_device_alias(&self, name: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_dev_alias": {
-                    "alias": name
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send_routed(&router::Request::Alias(name))
     }
 
     pub fn set_mac_address(&self, mac: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_mac_addr": {
-                    "mac": mac
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::set_mac_address(mac))
     }
 
     pub fn set_device_id(&self, device_id: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_device_id": {
-                    "deviceId": device_id
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::set_device_id(device_id))
     }
 
     pub fn set_hardware_id(&self, hardware_id: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_hw_id": {
-                    "hwId": hardware_id
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::set_hardware_id(hardware_id))
     }
 
     pub fn set_location(&self, latitude: f64, longitude: f64) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_dev_location": {
-                    "longitude": longitude,
-                    "latitude": latitude,
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        if self.quirks.contains(Quirk::IntegerLocation) {
+            self.send(commands::set_location_i(latitude, longitude))
+        } else {
+            self.send(commands::set_location(latitude, longitude))
+        }
     }
 
     pub fn uboot_bootloader_check(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "test_check_uboot": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::uboot_bootloader_check())
     }
 
     pub fn get_device_icon(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "get_dev_icon": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::get_device_icon())
     }
 
     pub fn set_device_icon(&self, icon: &str, hash: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_dev_icon": {
-                    "icon": icon,
-                    "hash": hash,
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::set_device_icon(icon, hash))
     }
 
     pub fn set_test_mode(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "set_test_mode": {
-                    "enable": 1
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::set_test_mode())
     }
 
     pub fn download_firmware_from_url(&self, url: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "download_firmware": {
-                    "url": url
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::download_firmware_from_url(url))
     }
 
     pub fn get_download_state(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "get_download_state": {}
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::get_download_state())
     }
 
     pub fn flash_downloaded_firmware(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "flash_firmware": {}
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::flash_downloaded_firmware())
     }
 
     pub fn check_config(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                "check_new_config": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::check_config())
     }
 
     pub fn scan_available_aps(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "netif": {
-                "get_scaninfo": {
-                    "refresh": 1
-                }
-            }
-        });
+        self.send(commands::scan_available_aps())
+    }
 
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+    pub fn access_points(&self, timeout: u32) -> Result<Vec<NetifGetScaninfoItem>, PlugError> {
+        match self.send(commands::deep_scan_aps(timeout))?.netif.and_then(|n| n.get_scaninfo) {
+            Some(scan) => Ok(scan.ap_list),
+            None => Err(self.in_context(PlugError::new("Response has no scan info"), "netif.get_scaninfo")),
+        }
     }
 
-    pub fn connect_to_ap(&self, ssid: &str, password: &str)
-        -> Result<PlugResponse, PlugError> {
+    pub fn get_stainfo(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::get_stainfo())
+    }
 
-        let v = json!({
-            "netif": {
-                "set_stainfo": {
-                    "ssid": ssid,
-                    "password": password,
-                    "key_type": 3
-                }
-            }
-        });
+    /// Not every firmware answers `get_stainfo`; those that don't yield an error.
+    pub fn current_wifi(&self) -> Result<NetifGetStainfoResponse, PlugError> {
+        match self.get_stainfo()?.netif.and_then(|n| n.get_stainfo) {
+            Some(stainfo) if stainfo.err_code == 0 => Ok(stainfo),
+            Some(stainfo) => Err(self.in_context(
+                PlugError::new(format!("Failed with err_code {}", stainfo.err_code).as_str()), "netif.get_stainfo")),
+            None => Err(self.in_context(PlugError::new("Response has no station info"), "netif.get_stainfo")),
+        }
+    }
 
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+    pub fn connect_to_ap(&self, ssid: &str, password: &str)
+        -> Result<PlugResponse, PlugError> {
+        self.send(commands::connect_to_ap(ssid, password))
     }
 
     pub fn get_cloud_info(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "cnCloud": {
-                "get_info": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::get_cloud_info())
     }
 
     pub fn get_firmware_list(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "cnCloud": {
-                "get_intl_fw_list": {}
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::get_firmware_list())
     }
 
     pub fn set_server_url(&self, server_url: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "cnCloud": {
-                "set_server_url": {
-                    "server": server_url,
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::set_server_url(server_url))
     }
 
     pub fn connect_to_cloud(&self, user: &str, password: &str) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "cnCloud": {
-                "bind": {
-                    "username": user,
-                    "password": password,
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::connect_to_cloud(user, password))
     }
 
     pub fn unregister_device(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "cnCloud": {
-                "unbind": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::unregister_device())
     }
 
     pub fn get_time(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "time": {
-                "get_time": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::get_time())
     }
 
     pub fn get_timezone(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "time": {
-                "get_timezone": null
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
-    }
-
-    pub fn set_timezone(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "time": {
-                "set_timezone": {
-                    "year": 1,
-                    "month": 2,
-                    "mday": 3,
-                    "hour": 4,
-                    "min": 5,
-                    "sec": 6,
-                    "index": 42
-                }
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send(commands::get_timezone())
     }
 
     pub fn get_meter_info(&self) -> Result<PlugResponse, PlugError> {
-        let v = json!({
-            "system": {
-                 "get_sysinfo": {}
-            }
-        });
-
-        send_command::<PlugResponse>(&self.ip, v.to_string())
+        self.send_static(commands::GET_SYSINFO)
     }
 
     pub fn get_realtime_current_voltage() -> (f32, f32) {
-        let cmd = json!({
-            "emeter": {
-                "get_realtime": {}
-            }
-        });
-        (1 as f32, 1 as f32)
+        let _cmd = commands::get_realtime();
+        (1.0, 1.0)
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "net")]
     use std::io::{Read, Write};
+    #[cfg(feature = "net")]
     use std::net::TcpStream;
+    #[cfg(feature = "net")]
     use std::time::Duration;
+    use std::sync::Arc;
     use serde_json::json;
-    use crate::{decrypt_payload, encrypt_payload, TpLinkDevice};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
 
     #[test]
     fn test_encrypt_payload() {
         let ep = encrypt_payload(
             String::from("{\"system\":{\"set_relay_state\":{\"state\":0}}}").as_bytes().to_vec());
         let dp = decrypt_payload(ep.as_slice());
-        // TODO: test input and output strings are equal.
+        assert_eq!(dp, b"{\"system\":{\"set_relay_state\":{\"state\":0}}}".to_vec());
+    }
+
+    #[test]
+    fn test_custom_transport() {
+        let transport = |address: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            assert_eq!(address, "plug.local");
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            assert_eq!(request, json!({"system": {"set_relay_state": {"state": 1}}}));
+            Ok(encrypt_payload(b"{\"system\":{\"set_relay_state\":{\"err_code\":0}}}".to_vec()))
+        };
+
+        let device = TpLinkDevice::with_transport("plug.local", Arc::new(transport));
+        assert!(device.on().unwrap().system.is_some());
+    }
+
+    #[test]
+    fn test_identity() {
+        let transport = |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let response = match request["system"].get("get_sysinfo") {
+                Some(_) => json!({"system": {"get_sysinfo": {"alias": "Heater", "type": "IOT.SMARTPLUGSWITCH",
+                                                             "model": "HS110(EU)", "err_code": 0}}}),
+                None => return Err(PlugError::new("Connection reset")),
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        let device = TpLinkDevice::with_transport("admin:secret@10.0.0.7:9999", Arc::new(transport));
+        assert_eq!((format!("{}", device), format!("{:#}", device)), ("10.0.0.7".into(), "10.0.0.7:9999".into()));
+
+        let device = device.detect().unwrap();
+        assert_eq!(device.known_alias(), Some("Heater"));
+        assert_eq!((format!("{}", device), format!("{:#}", device)),
+                   ("Heater".into(), "Heater (10.0.0.7:9999)".into()));
+        let debug = format!("{:?}", device);
+        assert!(debug.contains("Heater") && !debug.contains("secret"), "{}", debug);
+        let error = device.on().unwrap_err().to_string();
+        assert!(error.starts_with("10.0.0.7:9999 (Heater)") && !error.contains("secret"), "{}", error);
+    }
+
+    #[test]
+    fn test_ensure_skips_redundant_writes() {
+        let writes = Arc::new(std::sync::Mutex::new(0));
+        let count = writes.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let response = match request["system"].get("set_relay_state") {
+                Some(_) => {
+                    *count.lock().unwrap() += 1;
+                    json!({"system": {"set_relay_state": {"err_code": 0}}})
+                }
+                None => json!({"system": {"get_sysinfo": {"relay_state": 1, "err_code": 0}}}),
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+
+        let device = TpLinkDevice::with_transport("plug", Arc::new(transport));
+        assert!(!device.ensure_on().unwrap());
+        assert!(device.ensure_off().unwrap());
+        assert_eq!(*writes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_ensure_on_bulbs_and_strips() {
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = sent.clone();
+        let transport = move |address: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let response = match (address, request["system"].get("get_sysinfo")) {
+                ("bulb", Some(_)) => json!({"system": {"get_sysinfo": {"mic_type": "IOT.SMARTBULB", "model": "KL110(EU)",
+                                                                    "light_state": {"on_off": 1}, "err_code": 0}}}),
+                ("strip", Some(_)) => json!({"system": {"get_sysinfo": {"type": "IOT.SMARTPLUGSWITCH", "model": "HS300(US)",
+                                                                     "children": [{"id": "00", "state": 1}], "err_code": 0}}}),
+                _ => {
+                    seen.lock().unwrap().push(request.clone());
+                    json!({"smartlife.iot.smartbulb.lightingservice": {"transition_light_state": {"on_off": 0, "err_code": 0}}})
+                }
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        let transport = Arc::new(transport);
+
+        let bulb = TpLinkDevice::with_transport("bulb", transport.clone()).detect().unwrap();
+        assert!(!bulb.ensure_on().unwrap());
+        assert!(bulb.ensure_off().unwrap());
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        let strip = TpLinkDevice::with_transport("strip", transport);
+        assert!(strip.ensure_off().unwrap_err().to_string().contains("no on/off state"));
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_energy_history() {
+        // Keeps March 2023 to February 2024, with nothing recorded for May 2023.
+        let transport = |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let reply = match request["emeter"]["get_monthstat"]["year"].as_i64().unwrap() {
+                2023 => json!({"month_list": [{"year": 2023, "month": 6, "energy_wh": 900},
+                                              {"year": 2023, "month": 3, "energy_wh": 1200},
+                                              {"year": 2023, "month": 4, "energy_wh": 1100},
+                                              {"year": 2023, "month": 12, "energy_wh": 2000}], "err_code": 0}),
+                2024 => json!({"month_list": [{"year": 2024, "month": 2, "energy_wh": 1500}], "err_code": 0}),
+                _ => json!({"err_code": -10, "err_msg": "no data"}),
+            };
+            Ok(encrypt_payload(json!({"emeter": {"get_monthstat": reply}}).to_string().into_bytes()))
+        };
+
+        let device = TpLinkDevice::with_transport("plug", Arc::new(transport));
+        let history = device.energy_history(2021..2025).unwrap();
+        let months: Vec<(i64, i64)> = history.iter().map(|m| (m.year, m.month)).collect();
+        assert_eq!(months.first(), Some(&(2023, 3)));
+        assert_eq!(months.last(), Some(&(2024, 2)));
+        assert_eq!(months.len(), 12);
+        assert_eq!(history[1].energy_kwh(), Some(1.1));
+        assert_eq!(history[2].energy_kwh(), None);
+        assert_eq!(history[10].energy_kwh(), None);
+        assert!(device.energy_history(2010..2020).unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "time")]
+    fn test_daily_energy_between() {
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let (year, month) = (request["emeter"]["get_daystat"]["year"].clone(),
+                                 request["emeter"]["get_daystat"]["month"].clone());
+            seen.lock().unwrap().push(month.as_u64().unwrap());
+            let day_list = match month.as_u64() {
+                Some(1) => json!([{"year": year, "month": 1, "day": 30, "energy_wh": 400},
+                                  {"year": year, "month": 1, "day": 31, "energy_wh": 500}]),
+                _ => json!([{"year": year, "month": month, "day": 2, "energy_wh": 250}]),
+            };
+            let response = json!({"emeter": {"get_daystat": {"day_list": day_list, "err_code": 0}}});
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+
+        let device = TpLinkDevice::with_transport("plug", Arc::new(transport));
+        let date = |m, d| chrono::NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+        let days = device.daily_energy_between(date(1, 30), date(2, 3)).unwrap();
+        assert_eq!(days.into_iter().collect::<Vec<_>>(), vec![
+            (date(1, 30), Some(0.4)), (date(1, 31), Some(0.5)),
+            (date(2, 1), None), (date(2, 2), Some(0.25)), (date(2, 3), None),
+        ]);
+        assert_eq!(*requests.lock().unwrap(), vec![1, 2]);
+        assert_eq!(device.daily_energy(2024, 2).unwrap().len(), 29);
+        assert!(device.daily_energy(2024, 13).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "time")]
+    fn test_daily_energy_up_to_the_last_date() {
+        let months = Arc::new(std::sync::Mutex::new(0));
+        let count = months.clone();
+        let device = crate::testing::answering("plug", move |request| {
+            *count.lock().unwrap() += 1;
+            let year = request["emeter"]["get_daystat"]["year"].clone();
+            json!({"emeter": {"get_daystat": {"day_list": [
+                {"year": year, "month": 12, "day": 31, "energy_wh": 600}], "err_code": 0}}})
+        });
+        let end = chrono::NaiveDate::MAX;
+        let days = device.daily_energy_between(end.pred_opt().unwrap(), end).unwrap();
+        assert_eq!(days.into_iter().collect::<Vec<_>>(), vec![(end.pred_opt().unwrap(), None), (end, Some(0.6))]);
+        assert_eq!(*months.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_empty_response() {
+        let silent = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> { Ok(Vec::new()) };
+        let error = TpLinkDevice::with_transport("plug", Arc::new(silent)).get_download_state().unwrap_err();
+        assert!(matches!(error, PlugError::EmptyResponse { .. }));
+        assert_eq!(error.to_string(), "plug, system.get_download_state: Empty response");
+
+        let closed = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Err(PlugError::ConnectionClosed { context: Default::default() })
+        };
+        let error = TpLinkDevice::with_transport("plug", Arc::new(closed)).reboot().unwrap_err();
+        assert_eq!(error.command(), Some("system.reboot"));
+
+        let error = PlugError::new("Truncated response").in_context("10.0.0.5:9999", Some("Kettle"), "emeter.get_realtime");
+        assert_eq!(error.to_string(), "10.0.0.5:9999 (Kettle), emeter.get_realtime: Truncated response");
+        assert_eq!(error.message(), "Truncated response");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_error_sources_kept() {
+        let garbled = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(b"{\"system\":".to_vec()))
+        };
+        let error = TpLinkDevice::with_transport("plug", Arc::new(garbled)).reboot().unwrap_err();
+        assert!(matches!(error, PlugError::Json { .. }));
+        assert!(std::error::Error::source(&error).unwrap().is::<serde_json::Error>());
+
+        let refused = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into())
+        };
+        let error = TpLinkDevice::with_transport("plug", Arc::new(refused)).reboot().unwrap_err();
+        assert!(matches!(&error, PlugError::Io { source, .. } if source.kind() == std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(error.command(), Some("system.reboot"));
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn test_ping() {
+        use std::time::Duration;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let plug = TpLinkDevice::from_socket_addr(listener.local_addr().unwrap());
+        assert!(plug.ping(Duration::from_secs(1)).unwrap() < Duration::from_secs(1));
+
+        drop(listener);
+        assert!(matches!(plug.ping(Duration::from_secs(1)).unwrap_err(), PlugError::Io { .. }));
     }
 
     #[test]
+    #[cfg(feature = "net")]
+    fn test_runtime_addresses() {
+        let configured = String::from("10.0.0.5");
+        assert_eq!(TpLinkDevice::new(configured).address(), "10.0.0.5:9999");
+        let discovered: std::net::SocketAddr = "[fe80::1]:9999".parse().unwrap();
+        assert_eq!(TpLinkDevice::from_socket_addr(discovered).address(), "[fe80::1]:9999");
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn test_host_and_port() {
+        let plug = TpLinkDevice::new("plug.local").with_port(10000);
+        assert_eq!((plug.host(), plug.port(), plug.address()), ("plug.local", Some(10000), "plug.local:10000"));
+        assert_eq!(TpLinkDevice::new("fe80::1").address(), "[fe80::1]:9999");
+        assert_eq!(TpLinkDevice::new("10.0.0.5:9999").port(), Some(9999));
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    #[ignore = "requires a plug at 192.168.1.115"]
     fn test_get_realtime() {
-        let device = TpLinkDevice::new("192.168.1.115:9999");
+        let device = TpLinkDevice::new("192.168.1.115");
         match device.get_realtime() {
             Ok(result) => { println!("{}", result.emeter.unwrap().get_realtime.unwrap()) },
             Err(e) => { eprintln!("{}", e) }
@@ -494,6 +1052,8 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "net")]
+    #[ignore = "requires a plug at 192.168.1.115"]
     fn test_comm() {
         let v = json!({
             "emeter": {
@@ -503,22 +1063,19 @@ mod tests {
 
         let ev = encrypt_payload(v.to_string().as_bytes().to_vec());
 
-        match TcpStream::connect("192.168.1.115:9999") {
-            Ok(mut stream) => {
-                println!("{}", v.to_string());
-                let size = stream.write(ev.as_slice()).unwrap();
-                println!("{:?}", ev.as_slice());
-                println!("Size = {}", size);
-                let mut buf = [0u8; 2048];
-                stream.set_read_timeout(Some(Duration::from_millis(5000))).unwrap();
-                let size = stream.read(&mut buf).unwrap();
-                println!("Size = {}", size);
-                println!("Response = {}", String::from_utf8(
-                    decrypt_payload(&buf[0..size])).unwrap());
-
-                // Ok(String::from_utf8(buf[0..size].to_vec()).unwrap())
-            }
-            Err(_) => (), //Err(String::from("Failed connecting")),
+        if let Ok(mut stream) = TcpStream::connect("192.168.1.115:9999") {
+            println!("{}", v);
+            let size = stream.write(ev.as_slice()).unwrap();
+            println!("{:?}", ev.as_slice());
+            println!("Size = {}", size);
+            let mut buf = [0u8; 2048];
+            stream.set_read_timeout(Some(Duration::from_millis(5000))).unwrap();
+            let size = stream.read(&mut buf).unwrap();
+            println!("Size = {}", size);
+            println!("Response = {}", String::from_utf8(
+                decrypt_payload(&buf[0..size])).unwrap());
+
+            // Ok(String::from_utf8(buf[0..size].to_vec()).unwrap())
         }
     }
 }
@@ -0,0 +1,87 @@
+/*
+ * `tplink_command!` declares a command once and generates its request builder,
+ * its typed response and the `TpLinkDevice` method that sends it:
+ *
+ *   tplink_command! {
+ *       /// The device's clock.
+ *       fn device_time / get_time() = "time"."get_time" -> DeviceTime {
+ *           year: i64, month: i64, mday: i64, hour: i64, min: i64, sec: i64,
+ *       }
+ *   }
+ *
+ * gives `get_time() -> Value`, `struct DeviceTime` and
+ * `TpLinkDevice::device_time() -> Result<DeviceTime, PlugError>`. Arguments
+ * become the JSON keys of the same name, except for a single `(..rule: &Rule)`
+ * argument, which is sent as the whole body. `-> SomeType;` reuses an existing
+ * response type, and a bare `;` is for commands whose reply is just an
+ * `err_code`. A non-zero `err_code` is returned as an error.
+ */
+
+macro_rules! tplink_command {
+    (@args) => {
+        ::serde_json::Value::Null
+    };
+    (@args $($arg:ident),+) => {{
+        let mut args = ::serde_json::Map::new();
+        $(args.insert(::alloc::string::String::from(stringify!($arg)), ::serde_json::json!($arg));)+
+        ::serde_json::Value::Object(args)
+    }};
+
+    (@emit [$(#[$doc:meta])*] $method:ident $builder:ident [$($arg:ident : $argty:ty),*] [$args:expr]
+        $namespace:literal $command:literal -> $response:ty) => {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $builder($($arg: $argty),*) -> ::serde_json::Value {
+            ::serde_json::json!({ $namespace: { $command: $args } })
+        }
+
+        impl $crate::TpLinkDevice {
+            $(#[$doc])*
+            #[allow(clippy::too_many_arguments)]
+            pub fn $method(&self, $($arg: $argty),*) -> Result<$response, $crate::types::PlugError> {
+                self.call($builder($($arg),*), $namespace, $command)
+            }
+        }
+    };
+    (@emit [$(#[$doc:meta])*] $method:ident $builder:ident [$($arg:ident : $argty:ty),*] [$args:expr]
+        $namespace:literal $command:literal) => {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $builder($($arg: $argty),*) -> ::serde_json::Value {
+            ::serde_json::json!({ $namespace: { $command: $args } })
+        }
+
+        impl $crate::TpLinkDevice {
+            $(#[$doc])*
+            #[allow(clippy::too_many_arguments)]
+            pub fn $method(&self, $($arg: $argty),*) -> Result<(), $crate::types::PlugError> {
+                self.call::<::serde::de::IgnoredAny>($builder($($arg),*), $namespace, $command).map(|_| ())
+            }
+        }
+    };
+
+    ($(#[$doc:meta])* fn $method:ident / $builder:ident (.. $body:ident : $body_ty:ty)
+        = $namespace:literal . $command:literal $(-> $response:ty)?;) => {
+        tplink_command!(@emit [$(#[$doc])*] $method $builder [$body: $body_ty] [::serde_json::json!($body)]
+            $namespace $command $(-> $response)?);
+    };
+
+    ($(#[$doc:meta])* fn $method:ident / $builder:ident ($($arg:ident : $argty:ty),* $(,)?)
+        = $namespace:literal . $command:literal -> $response:ident {
+            $($(#[$field_attr:meta])* $field:ident : $field_ty:ty),* $(,)?
+        }) => {
+        #[derive(Clone, Default, Debug, PartialEq, ::serde::Serialize, ::serde::Deserialize)]
+        #[serde(default)]
+        pub struct $response {
+            $($(#[$field_attr])* pub $field: $field_ty,)*
+        }
+
+        tplink_command! {
+            $(#[$doc])* fn $method / $builder ($($arg: $argty),*) = $namespace . $command -> $response;
+        }
+    };
+
+    ($(#[$doc:meta])* fn $method:ident / $builder:ident ($($arg:ident : $argty:ty),* $(,)?)
+        = $namespace:literal . $command:literal $(-> $response:ty)?;) => {
+        tplink_command!(@emit [$(#[$doc])*] $method $builder [$($arg: $argty),*] [tplink_command!(@args $($arg),*)]
+            $namespace $command $(-> $response)?);
+    };
+}
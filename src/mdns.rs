@@ -0,0 +1,107 @@
+/*
+ * Just enough mDNS to find hosts announcing a service: one PTR question to the
+ * multicast group, asking for unicast replies, and the addresses of whoever
+ * answers. `discovery` then asks each of them for sysinfo over TCP.
+ *
+ *   let hosts = mdns::query(mdns::KASA_SERVICE, Duration::from_secs(2))?;
+ *
+ * Replies are not parsed beyond checking that they answer the question.
+ */
+
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::types::PlugError;
+
+/// The service newer Kasa firmware announces.
+pub const KASA_SERVICE: &str = "_tplink._tcp.local";
+
+const GROUP: ([u8; 4], u16) = ([224, 0, 0, 251], 5353);
+const TYPE_PTR: u16 = 12;
+/// Class IN with the "unicast response" bit set.
+const CLASS_IN_QU: u16 = 0x8001;
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.trim_end_matches('.').split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+pub(crate) fn question(service: &str) -> Vec<u8> {
+    // ID 0, no flags, one question.
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    encode_name(service, &mut packet);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN_QU.to_be_bytes());
+    packet
+}
+
+/// A response with at least one answer that mentions the service.
+pub(crate) fn answers(packet: &[u8], service: &str) -> bool {
+    if packet.len() < 12 || packet[2] & 0x80 == 0 {
+        return false;
+    }
+    let answer_count = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut name = Vec::new();
+    encode_name(service, &mut name);
+    // Compression may split the name; its first label always appears whole.
+    let first_label = &name[..1 + name[0] as usize];
+    answer_count > 0 && packet.windows(first_label.len()).any(|w| w == first_label)
+}
+
+/// Hosts that answered for `service` within `timeout`, each once.
+pub fn query(service: &str, timeout: Duration) -> Result<Vec<IpAddr>, PlugError> {
+    query_at(SocketAddr::from(GROUP), service, timeout)
+}
+
+pub(crate) fn query_at(target: SocketAddr, service: &str, timeout: Duration) -> Result<Vec<IpAddr>, PlugError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.send_to(&question(service), target)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut hosts = Vec::new();
+    let mut buf = [0u8; 1500];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+        socket.set_read_timeout(Some(left))?;
+        let (len, sender) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => break,
+        };
+        if answers(&buf[..len], service) && !hosts.contains(&sender.ip()) {
+            hosts.push(sender.ip());
+        }
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+    use super::{answers, query_at, question, KASA_SERVICE};
+
+    #[test]
+    fn test_query() {
+        assert_eq!(&question("_a._tcp.local")[12..], b"\x02_a\x04_tcp\x05local\x00\x00\x0c\x80\x01");
+
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = responder.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, sender) = responder.recv_from(&mut buf).unwrap();
+            // Echo the question back as a response with one answer.
+            let mut reply = buf[..len].to_vec();
+            reply[2] = 0x84;
+            reply[7] = 1;
+            responder.send_to(b"not dns", sender).unwrap();
+            responder.send_to(&reply, sender).unwrap();
+        });
+
+        let hosts = query_at(target, KASA_SERVICE, Duration::from_millis(300)).unwrap();
+        assert_eq!(hosts, ["127.0.0.1".parse::<std::net::IpAddr>().unwrap()]);
+        assert!(!answers(&question(KASA_SERVICE), KASA_SERVICE));
+    }
+}
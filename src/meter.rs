@@ -0,0 +1,225 @@
+/*
+ * One meter made of several, such as three plugs on the three phases of a
+ * heat pump or everything in a room:
+ *
+ *   let (room, meter) = VirtualMeter::new("Office")
+ *       .member("desk", desk)
+ *       .member("heater", heater)
+ *       .device();
+ *   let reading = room.power_reading()?;
+ *   watcher.add("office", room);
+ *
+ * The meter is a transport, so the device it makes reads, streams through a
+ * watcher and reports like any single plug with a meter. Power, current and
+ * energy are summed over the members and the voltage is their average. Days
+ * and months are summed from what each member has for them. If any member
+ * can't be read the reading fails, since a sum without it would look like a
+ * drop in consumption. The relay shows as on while any member is on; it can't
+ * be switched through the meter.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use serde_json::{json, Map, Value};
+
+use crate::TpLinkDevice;
+use crate::protocol::{decrypt_payload, encrypt_payload};
+use crate::reading::PowerReading;
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+pub struct VirtualMeter {
+    alias: String,
+    members: Vec<(String, TpLinkDevice)>,
+}
+
+fn reply(namespace: &str, method: &str, body: Value) -> Value {
+    json!({namespace: {method: body}})
+}
+
+impl VirtualMeter {
+    pub fn new(alias: &str) -> VirtualMeter {
+        VirtualMeter {
+            alias: String::from(alias),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn member(mut self, name: &str, device: TpLinkDevice) -> VirtualMeter {
+        self.members.push((String::from(name), device));
+        self
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// A device that reads this meter, and the meter itself.
+    pub fn device(self) -> (TpLinkDevice, Arc<VirtualMeter>) {
+        let meter = Arc::new(self);
+        (TpLinkDevice::with_transport("virtual", meter.clone()), meter)
+    }
+
+    /// Every member's latest reading, by name.
+    pub fn readings(&self) -> Result<Vec<(String, PowerReading)>, PlugError> {
+        self.members.iter()
+            .map(|(name, device)| Ok((name.clone(), device.power_reading()?)))
+            .collect()
+    }
+
+    /// The members' readings summed up, stamped with the latest of them.
+    pub fn reading(&self) -> Result<PowerReading, PlugError> {
+        let readings = self.readings()?;
+        if readings.is_empty() {
+            return Err(PlugError::new("Virtual meter has no members"));
+        }
+        let mut sum = PowerReading::default();
+        for (_, reading) in &readings {
+            sum.voltage_v += reading.voltage_v / readings.len() as f64;
+            sum.current_a += reading.current_a;
+            sum.power_w += reading.power_w;
+            sum.total_kwh += reading.total_kwh;
+            sum.taken_at = sum.taken_at.max(reading.taken_at);
+        }
+        Ok(sum)
+    }
+
+    /// Energy per day of the month in kWh, summed over the members.
+    pub fn daily_kwh(&self, year: i32, month: u32) -> Result<BTreeMap<u32, f64>, PlugError> {
+        let mut days = BTreeMap::new();
+        for (_, device) in &self.members {
+            for day in device.daystat(year, month)? {
+                *days.entry(day.day as u32).or_default() += day.energy_kwh().unwrap_or(0.0);
+            }
+        }
+        Ok(days)
+    }
+
+    /// Energy per month of the year in kWh, summed over the members.
+    pub fn monthly_kwh(&self, year: i32) -> Result<BTreeMap<u32, f64>, PlugError> {
+        let mut months = BTreeMap::new();
+        for (_, device) in &self.members {
+            for month in device.monthstat(year)?.month_list {
+                *months.entry(month.month as u32).or_default() += month.energy_kwh().unwrap_or(0.0);
+            }
+        }
+        Ok(months)
+    }
+
+    fn sysinfo(&self) -> Result<Value, PlugError> {
+        let mut on = false;
+        for (_, device) in &self.members {
+            let sysinfo = device.sysinfo()?;
+            on |= sysinfo.is_on().unwrap_or_else(|| sysinfo.children.iter().any(|c| c.state != 0));
+        }
+        Ok(json!({
+            "err_code": 0, "alias": self.alias, "model": "Virtual meter", "type": "IOT.SMARTPLUGSWITCH",
+            "dev_name": "Virtual meter", "deviceId": "VIRTUAL", "relay_state": on as i64,
+            "feature": "TIM:ENE", "active_mode": "none",
+        }))
+    }
+
+    fn answer(&self, namespace: &str, method: &str, args: &Value) -> Result<Value, PlugError> {
+        let year = args.get("year").and_then(Value::as_i64).unwrap_or(0) as i32;
+        let month = args.get("month").and_then(Value::as_i64).unwrap_or(0) as u32;
+        let answer = match (namespace, method) {
+            ("system", "get_sysinfo") => self.sysinfo(),
+            ("emeter", "get_realtime") => self.reading().map(|r| json!({
+                "voltage": r.voltage_v, "current": r.current_a, "power": r.power_w, "total": r.total_kwh, "err_code": 0,
+            })),
+            ("emeter", "get_daystat") => self.daily_kwh(year, month).map(|days| json!({
+                "day_list": days.into_iter()
+                    .map(|(day, energy)| json!({"year": year, "month": month, "day": day, "energy": energy}))
+                    .collect::<Vec<_>>(),
+                "err_code": 0,
+            })),
+            ("emeter", "get_monthstat") => self.monthly_kwh(year).map(|months| json!({
+                "month_list": months.into_iter()
+                    .map(|(month, energy)| json!({"year": year, "month": month, "energy": energy}))
+                    .collect::<Vec<_>>(),
+                "err_code": 0,
+            })),
+            ("system" | "emeter", _) => Ok(json!({"err_code": -2, "err_msg": "member not support"})),
+            _ => return Ok(json!({namespace: {"err_code": -1, "err_msg": "module not support"}})),
+        };
+        answer.map(|body| reply(namespace, method, body))
+    }
+}
+
+impl Transport for VirtualMeter {
+    fn request(&self, _: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let request: Value = serde_json::from_slice(&decrypt_payload(frame))?;
+        let mut response = Map::new();
+        for (namespace, methods) in request.as_object().into_iter().flatten() {
+            for (method, args) in methods.as_object().into_iter().flatten() {
+                if let Some(Value::Object(answer)) = self.answer(namespace, method, args)?.get(namespace) {
+                    let entry = response.entry(namespace.clone()).or_insert_with(|| json!({}));
+                    if let Value::Object(existing) = entry {
+                        existing.extend(answer.clone());
+                    }
+                }
+            }
+        }
+        Ok(encrypt_payload(Value::Object(response).to_string().into_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::VirtualMeter;
+
+    fn phase(power_w: f64, voltage_v: f64, relay_state: i64) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let response = if request["emeter"].get("get_realtime").is_some() {
+                json!({"emeter": {"get_realtime": {"power": power_w, "voltage": voltage_v,
+                                                   "current": power_w / voltage_v, "total": 10.0, "err_code": 0}}})
+            } else if request["emeter"].get("get_daystat").is_some() {
+                json!({"emeter": {"get_daystat": {"day_list": [
+                    {"year": 2024, "month": 6, "day": 2, "energy_wh": 1000},
+                    {"year": 2024, "month": 6, "day": 3, "energy_wh": power_w}], "err_code": 0}}})
+            } else {
+                json!({"system": {"get_sysinfo": {"relay_state": relay_state, "err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("phase", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_sums_phases() {
+        let (heat_pump, _) = VirtualMeter::new("Heat pump")
+            .member("L1", phase(1000.0, 230.0, 1))
+            .member("L2", phase(1100.0, 232.0, 0))
+            .member("L3", phase(900.0, 228.0, 0))
+            .device();
+
+        let reading = heat_pump.power_reading().unwrap();
+        assert_eq!(reading.power_w, 3000.0);
+        assert_eq!(reading.voltage_v, 230.0);
+        assert_eq!(reading.total_kwh, 30.0);
+        let sysinfo = heat_pump.sysinfo().unwrap();
+        assert_eq!((sysinfo.alias.as_str(), sysinfo.relay_state, sysinfo.feature.as_str()), ("Heat pump", 1, "TIM:ENE"));
+
+        let days = heat_pump.daystat(2024, 6).unwrap();
+        assert_eq!(days.iter().map(|d| d.energy_kwh().unwrap()).collect::<Vec<_>>(), [3.0, 3.0]);
+        let refused = heat_pump.send_raw(json!({"system": {"set_relay_state": {"state": 0}}})).unwrap();
+        assert_eq!(refused["system"]["set_relay_state"]["err_code"], -2);
+    }
+
+    #[test]
+    fn test_fails_with_a_member() {
+        let broken = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> { Err(PlugError::new("unreachable")) };
+        let (room, meter) = VirtualMeter::new("Office")
+            .member("desk", phase(60.0, 230.0, 1))
+            .member("heater", TpLinkDevice::with_transport("heater", Arc::new(broken)))
+            .device();
+        assert!(room.power_reading().is_err());
+        assert_eq!(meter.members().collect::<Vec<_>>(), ["desk", "heater"]);
+    }
+}
@@ -0,0 +1,258 @@
+/*
+ * How long each device takes to answer each command, and how often it fails:
+ *
+ *   let plug = TpLinkDevice::new("192.168.1.20").metered(metrics::metrics());
+ *   plug.on()?;
+ *   print!("{}", metrics::metrics().prometheus());
+ *
+ * Latencies go into a histogram per device and command, with the buckets in
+ * `LATENCY_BUCKETS`. A command counts as failed if the transport failed or the
+ * reply has a non-zero `err_code`, like in the audit trail. `metrics()` is the
+ * registry shared by the whole process; `Metrics::new` makes a separate one.
+ *
+ * `prometheus` renders the registry in Prometheus' text format, and `serve`
+ * answers every HTTP request on a listener with it, for a scrape target.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+use crate::{shown_address, TpLinkDevice};
+use crate::audit::check;
+use crate::commands;
+use crate::protocol::{decrypt_payload, size_from_bytes};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+/// Upper bounds of the latency buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// What one device did with one command.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandStats {
+    /// How many answers took at most each of `LATENCY_BUCKETS`, not cumulative.
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub errors: u64,
+    pub total: Duration,
+}
+
+impl CommandStats {
+    fn record(&mut self, latency: Duration, failed: bool) {
+        let seconds = latency.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.total += latency;
+        if failed {
+            self.errors += 1;
+        }
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.errors as f64 / self.count as f64 }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// By device address, then command.
+    stats: Mutex<BTreeMap<(String, String), CommandStats>>,
+}
+
+/// The registry shared by the whole process.
+pub fn metrics() -> Arc<Metrics> {
+    static METRICS: OnceLock<Arc<Metrics>> = OnceLock::new();
+    METRICS.get_or_init(|| Arc::new(Metrics::new())).clone()
+}
+
+pub(crate) fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record(&self, device: &str, command: &str, latency: Duration, failed: bool) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.entry((String::from(device), String::from(command))).or_default().record(latency, failed);
+        }
+    }
+
+    /// A copy of the stats, by device address and command.
+    pub fn snapshot(&self) -> BTreeMap<(String, String), CommandStats> {
+        self.stats.lock().map(|stats| stats.clone()).unwrap_or_default()
+    }
+
+    /// Every command of every device summed up, by device address.
+    pub fn by_device(&self) -> BTreeMap<String, CommandStats> {
+        let mut devices: BTreeMap<String, CommandStats> = BTreeMap::new();
+        for ((device, _), stats) in self.snapshot() {
+            let sum = devices.entry(device).or_default();
+            for (total, n) in sum.buckets.iter_mut().zip(stats.buckets) {
+                *total += n;
+            }
+            sum.count += stats.count;
+            sum.errors += stats.errors;
+            sum.total += stats.total;
+        }
+        devices
+    }
+
+    pub fn reset(&self) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.clear();
+        }
+    }
+
+    /// The registry in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let stats = self.snapshot();
+        let mut out = String::new();
+        out.push_str("# HELP hs1x0_command_duration_seconds Time from sending a command to its answer.\n");
+        out.push_str("# TYPE hs1x0_command_duration_seconds histogram\n");
+        for ((device, command), stats) in &stats {
+            let labels = format!("device=\"{}\",command=\"{}\"", label(device), label(command));
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+                cumulative += n;
+                let _ = writeln!(out, "hs1x0_command_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+            }
+            let _ = writeln!(out, "hs1x0_command_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, stats.count);
+            let _ = writeln!(out, "hs1x0_command_duration_seconds_sum{{{}}} {}", labels, stats.total.as_secs_f64());
+            let _ = writeln!(out, "hs1x0_command_duration_seconds_count{{{}}} {}", labels, stats.count);
+        }
+        out.push_str("# HELP hs1x0_command_errors_total Commands that failed or were refused.\n");
+        out.push_str("# TYPE hs1x0_command_errors_total counter\n");
+        for ((device, command), stats) in &stats {
+            let _ = writeln!(out, "hs1x0_command_errors_total{{device=\"{}\",command=\"{}\"}} {}",
+                             label(device), label(command), stats.errors);
+        }
+        out
+    }
+
+    /// Answers every request on `listener` with `prometheus`, for as long as the process runs.
+    /// A connection that fails before it's accepted is dropped, and the next one waited for.
+    pub fn serve(&self, listener: TcpListener) -> ! {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => {
+                    // Out of file descriptors, say: give it a moment rather than spin.
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            // Read up to the end of the headers, of no more than 8 KiB and not
+            // for long; which path was asked for doesn't matter.
+            if stream.set_read_timeout(Some(Duration::from_secs(5))).is_err() {
+                continue;
+            }
+            let mut reader = BufReader::new((&stream).take(8 * 1024));
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line.trim_end() != "" {
+                line.clear();
+            }
+            let body = self.prometheus();
+            let _ = write!(&stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                                     Content-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        }
+    }
+}
+
+/// The name of the command in `frame`, if it holds a whole one.
+pub(crate) fn command_of(frame: &[u8]) -> Option<String> {
+    if frame.len() >= 4 && frame.len() >= size_from_bytes(frame) + 4 {
+        serde_json::from_slice::<Value>(&decrypt_payload(frame)).ok().map(|cmd| commands::name(&cmd))
+    } else {
+        None
+    }
+}
+
+pub struct Metered {
+    inner: Arc<dyn Transport>,
+    metrics: Arc<Metrics>,
+}
+
+impl Metered {
+    pub fn new(inner: Arc<dyn Transport>, metrics: Arc<Metrics>) -> Metered {
+        Metered {
+            inner,
+            metrics,
+        }
+    }
+}
+
+impl Transport for Metered {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let command = command_of(frame);
+        let started = Instant::now();
+        let response = self.inner.request(address, frame);
+        let latency = started.elapsed();
+        self.metrics.record(shown_address(address), command.as_deref().unwrap_or("?"), latency, check(&response).is_err());
+        response
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose commands are timed and counted in `metrics`.
+    pub fn metered(&self, metrics: Arc<Metrics>) -> TpLinkDevice {
+        self.with_inner(Arc::new(Metered::new(self.transport.clone(), metrics)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::TpLinkDevice;
+    use crate::protocol::encrypt_payload;
+    use crate::types::PlugError;
+    use super::Metrics;
+
+    #[test]
+    fn test_counts_errors_per_command() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":-3}}}"#.to_vec()))
+        };
+        let metrics = Arc::new(Metrics::new());
+        let plug = TpLinkDevice::with_transport("10.0.0.1", Arc::new(transport)).metered(metrics.clone());
+        let broken = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> { Err(PlugError::new("unreachable")) };
+        let gone = TpLinkDevice::with_transport("admin:secret@10.0.0.2", Arc::new(broken)).metered(metrics.clone());
+
+        let _ = plug.on();
+        let _ = plug.on();
+        let _ = gone.on();
+        let stats = metrics.snapshot();
+        let on = &stats[&(String::from("10.0.0.1"), String::from("system.set_relay_state"))];
+        assert_eq!((on.count, on.errors), (2, 2));
+        assert_eq!(on.buckets[0], 2);
+        assert_eq!(metrics.by_device()["10.0.0.2"].error_rate(), 1.0);
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = Metrics::new();
+        metrics.record("10.0.0.1", "system.get_sysinfo", Duration::from_millis(40), false);
+        metrics.record("10.0.0.1", "system.get_sysinfo", Duration::from_millis(300), true);
+        let text = metrics.prometheus();
+        let labels = "device=\"10.0.0.1\",command=\"system.get_sysinfo\"";
+        assert!(text.contains(&format!("hs1x0_command_duration_seconds_bucket{{{},le=\"0.05\"}} 1\n", labels)));
+        assert!(text.contains(&format!("hs1x0_command_duration_seconds_bucket{{{},le=\"0.5\"}} 2\n", labels)));
+        assert!(text.contains(&format!("hs1x0_command_duration_seconds_count{{{}}} 2\n", labels)));
+        assert!(text.contains(&format!("hs1x0_command_errors_total{{{}}} 1\n", labels)));
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * Candidates for discovery from the host's neighbour (ARP) table: addresses
+ * whose MAC has a TP-Link prefix. Probing just those is far gentler than
+ * scanning a subnet and works where broadcasts are filtered, as long as the
+ * host has talked to the devices recently enough to have them in the table.
+ *
+ *   for host in neighbors::tplink_hosts()? {
+ *       println!("{:?}", discovery::probe(host, Duration::from_secs(1)));
+ *   }
+ *
+ * Only Linux's /proc/net/arp is read.
+ */
+
+use std::net::IpAddr;
+
+use crate::types::PlugError;
+
+/// Organizationally unique identifiers registered to TP-Link, upper case.
+pub const TPLINK_OUIS: &[&str] = &[
+    "00:31:92", "10:27:F5", "1C:3B:F3", "1C:61:B4", "3C:84:6A", "50:C7:BF", "54:AF:97", "5C:A6:E6",
+    "60:32:B1", "68:FF:7B", "98:DA:C4", "AC:84:C6", "B0:95:75", "B0:A7:B9", "D8:07:B6", "E8:48:B8",
+];
+
+pub fn is_tplink(mac: &str) -> bool {
+    let prefix = mac.get(..8).unwrap_or("").replace('-', ":").to_uppercase();
+    TPLINK_OUIS.contains(&prefix.as_str())
+}
+
+/// (address, MAC) of the complete entries in a /proc/net/arp listing.
+pub fn parse_arp_table(table: &str) -> Vec<(IpAddr, String)> {
+    table.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // IP address, HW type, flags, HW address, mask, device; flag 0x2 means complete.
+            let complete = fields.get(2).and_then(|f| u32::from_str_radix(f.trim_start_matches("0x"), 16).ok())
+                .is_some_and(|flags| flags & 0x2 != 0);
+            match (fields.first()?.parse().ok(), fields.get(3)) {
+                (Some(ip), Some(mac)) if complete => Some((ip, String::from(*mac))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+pub fn tplink_hosts() -> Result<Vec<IpAddr>, PlugError> {
+    let table = std::fs::read_to_string("/proc/net/arp")?;
+    Ok(parse_arp_table(&table).into_iter()
+        .filter(|(_, mac)| is_tplink(mac))
+        .map(|(ip, _)| ip)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_tplink, parse_arp_table};
+
+    #[test]
+    fn test_arp_table() {
+        let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.20     0x1         0x2         50:c7:bf:12:34:56     *        wlan0
+192.168.1.1      0x1         0x2         a4:91:b1:00:00:01     *        wlan0
+192.168.1.30     0x1         0x0         00:00:00:00:00:00     *        wlan0
+";
+        let entries = parse_arp_table(table);
+        assert_eq!(entries.len(), 2);
+        let tplink: Vec<_> = entries.iter().filter(|(_, mac)| is_tplink(mac)).map(|(ip, _)| ip.to_string()).collect();
+        assert_eq!(tplink, ["192.168.1.20"]);
+        assert!(is_tplink("B0-95-75-AA-BB-CC"));
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * Sends command spans and the metrics registry to an OpenTelemetry collector
+ * over OTLP/HTTP, in its JSON encoding:
+ *
+ *   let otlp = Arc::new(Otlp::new("http://collector:4318", "hs1x0"));
+ *   let plug = TpLinkDevice::new("192.168.1.20").metered(metrics::metrics()).traced(otlp.clone());
+ *   otlp.spawn(Duration::from_secs(60), metrics::metrics());
+ *
+ * Every command sent through a `traced` device becomes a client span named
+ * after the command, with the device's address and the error if it failed,
+ * the same way `Metered` counts failures. Spans wait in memory until the next
+ * export; if the collector can't be reached they are dropped rather than kept
+ * piling up, and `last_error` says why. The registry goes out as the `hs1x0.command.duration` histogram
+ * and the `hs1x0.command.errors` counter, both cumulative.
+ */
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use crate::{shown_address, TpLinkDevice};
+use crate::audit::check;
+use crate::metrics::{command_of, Metrics, LATENCY_BUCKETS};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+/// Spans kept between exports at most; older ones are dropped first.
+pub const MAX_PENDING_SPANS: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub attributes: Vec<(String, String)>,
+    /// Why the command failed, `None` if it didn't.
+    pub error: Option<String>,
+}
+
+/// Random enough for trace ids, without a dependency on a random number crate.
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+fn nanos(t: DateTime<Utc>) -> String {
+    t.timestamp_nanos_opt().unwrap_or_default().to_string()
+}
+
+fn attributes<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Value {
+    Value::Array(pairs.into_iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect())
+}
+
+pub struct Otlp {
+    endpoint: String,
+    service: String,
+    started: DateTime<Utc>,
+    agent: ureq::Agent,
+    spans: Mutex<Vec<Span>>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Otlp {
+    /// `endpoint` is the collector's OTLP/HTTP base URL, without `/v1/...`.
+    pub fn new(endpoint: &str, service: &str) -> Otlp {
+        Otlp {
+            endpoint: String::from(endpoint.trim_end_matches('/')),
+            service: String::from(service),
+            started: Utc::now(),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(10)))
+                .build()
+                .into(),
+            spans: Mutex::new(Vec::new()),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, span: Span) {
+        if let Ok(mut spans) = self.spans.lock() {
+            if spans.len() >= MAX_PENDING_SPANS {
+                spans.remove(0);
+            }
+            spans.push(span);
+        }
+    }
+
+    /// Why the last export by `spawn` failed, until one succeeds.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().ok().and_then(|error| error.clone())
+    }
+
+    /// Spans waiting for the next export.
+    pub fn pending(&self) -> Vec<Span> {
+        self.spans.lock().map(|spans| spans.clone()).unwrap_or_default()
+    }
+
+    fn resource(&self) -> Value {
+        json!({"attributes": attributes([("service.name", self.service.as_str())])})
+    }
+
+    /// The spans as an OTLP `ExportTraceServiceRequest`.
+    pub fn traces_json(&self, spans: &[Span]) -> Value {
+        let spans: Vec<Value> = spans.iter()
+            .map(|span| json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                "kind": 3,
+                "startTimeUnixNano": nanos(span.start),
+                "endTimeUnixNano": nanos(span.end),
+                "attributes": attributes(span.attributes.iter().map(|(k, v)| (k.as_str(), v.as_str()))),
+                "status": match &span.error {
+                    Some(message) => json!({"code": 2, "message": message}),
+                    None => json!({"code": 1}),
+                },
+            }))
+            .collect();
+        json!({"resourceSpans": [{
+            "resource": self.resource(),
+            "scopeSpans": [{"scope": {"name": "hs110"}, "spans": spans}],
+        }]})
+    }
+
+    /// The registry as an OTLP `ExportMetricsServiceRequest`, as of `now`.
+    pub fn metrics_json(&self, metrics: &Metrics, now: DateTime<Utc>) -> Value {
+        let stats = metrics.snapshot();
+        let point = |device: &str, command: &str| json!({
+            "attributes": attributes([("device", device), ("command", command)]),
+            "startTimeUnixNano": nanos(self.started),
+            "timeUnixNano": nanos(now),
+        });
+        let durations: Vec<Value> = stats.iter()
+            .map(|((device, command), stats)| {
+                let mut point = point(device, command);
+                let overflow = stats.count - stats.buckets.iter().sum::<u64>();
+                let counts: Vec<String> = stats.buckets.iter().chain([&overflow]).map(u64::to_string).collect();
+                point["count"] = json!(stats.count.to_string());
+                point["sum"] = json!(stats.total.as_secs_f64());
+                point["bucketCounts"] = json!(counts);
+                point["explicitBounds"] = json!(LATENCY_BUCKETS);
+                point
+            })
+            .collect();
+        let errors: Vec<Value> = stats.iter()
+            .map(|((device, command), stats)| {
+                let mut point = point(device, command);
+                point["asInt"] = json!(stats.errors.to_string());
+                point
+            })
+            .collect();
+        json!({"resourceMetrics": [{
+            "resource": self.resource(),
+            "scopeMetrics": [{"scope": {"name": "hs110"}, "metrics": [
+                {"name": "hs1x0.command.duration", "unit": "s",
+                 "description": "Time from sending a command to its answer.",
+                 "histogram": {"aggregationTemporality": 2, "dataPoints": durations}},
+                {"name": "hs1x0.command.errors", "unit": "1",
+                 "description": "Commands that failed or were refused.",
+                 "sum": {"aggregationTemporality": 2, "isMonotonic": true, "dataPoints": errors}},
+            ]}],
+        }]})
+    }
+
+    fn post(&self, path: &str, body: &Value) -> Result<(), PlugError> {
+        let url = format!("{}{}", self.endpoint, path);
+        self.agent.post(url.as_str())
+            .header("Content-Type", "application/json")
+            .send(body.to_string().as_str())
+            .map(|_| ())
+            .map_err(|e| PlugError::new(format!("Exporting to {} failed: {}", url, e).as_str()))
+    }
+
+    /// Sends the pending spans, if there are any. They are gone either way.
+    pub fn export_spans(&self) -> Result<(), PlugError> {
+        let spans = self.spans.lock().map(|mut spans| std::mem::take(&mut *spans)).unwrap_or_default();
+        if spans.is_empty() {
+            return Ok(());
+        }
+        self.post("/v1/traces", &self.traces_json(&spans))
+    }
+
+    pub fn export_metrics(&self, metrics: &Metrics) -> Result<(), PlugError> {
+        self.post("/v1/metrics", &self.metrics_json(metrics, Utc::now()))
+    }
+
+    /// Exports spans and `metrics` every `interval` in a background thread, for as long as the process runs.
+    pub fn spawn(self: &Arc<Otlp>, interval: Duration, metrics: Arc<Metrics>) {
+        let otlp = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let result = otlp.export_spans().and(otlp.export_metrics(&metrics));
+            if let Ok(mut last_error) = otlp.last_error.lock() {
+                *last_error = result.err().map(|e| e.to_string());
+            }
+        });
+    }
+}
+
+pub struct Traced {
+    inner: Arc<dyn Transport>,
+    otlp: Arc<Otlp>,
+}
+
+impl Traced {
+    pub fn new(inner: Arc<dyn Transport>, otlp: Arc<Otlp>) -> Traced {
+        Traced {
+            inner,
+            otlp,
+        }
+    }
+}
+
+impl Transport for Traced {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let command = command_of(frame).unwrap_or_else(|| String::from("?"));
+        let start = Utc::now();
+        let response = self.inner.request(address, frame);
+        self.otlp.record(Span {
+            trace_id: ((random_u64() as u128) << 64) | random_u64() as u128,
+            span_id: random_u64(),
+            name: command.clone(),
+            start,
+            end: Utc::now(),
+            attributes: Vec::from([
+                (String::from("device"), String::from(shown_address(address))),
+                (String::from("command"), command),
+            ]),
+            error: check(&response).err(),
+        });
+        response
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose commands are recorded as spans for `otlp` to export.
+    pub fn traced(&self, otlp: Arc<Otlp>) -> TpLinkDevice {
+        self.with_inner(Arc::new(Traced::new(self.transport.clone(), otlp)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::TpLinkDevice;
+    use crate::metrics::Metrics;
+    use crate::protocol::encrypt_payload;
+    use crate::types::PlugError;
+    use super::Otlp;
+
+    #[test]
+    fn test_spans() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":-3}}}"#.to_vec()))
+        };
+        let otlp = Arc::new(Otlp::new("http://127.0.0.1:4318/", "test"));
+        let plug = TpLinkDevice::with_transport("admin:secret@10.0.0.1", Arc::new(transport)).traced(otlp.clone());
+        let _ = plug.on();
+
+        let spans = otlp.pending();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "system.set_relay_state");
+        let json = otlp.traces_json(&spans);
+        let span = &json["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(span["status"]["code"], 2);
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "10.0.0.1");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.record("10.0.0.1", "system.get_sysinfo", Duration::from_millis(40), false);
+        metrics.record("10.0.0.1", "system.get_sysinfo", Duration::from_secs(20), true);
+        let otlp = Otlp::new("http://127.0.0.1:4318", "test");
+
+        let json = otlp.metrics_json(&metrics, Utc::now());
+        let exported = &json["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        let histogram = &exported[0]["histogram"]["dataPoints"][0];
+        assert_eq!(histogram["count"], "2");
+        assert_eq!(histogram["bucketCounts"].as_array().unwrap().len(), 11);
+        // 20 s is past the last bound.
+        assert_eq!(histogram["bucketCounts"][10], "1");
+        assert_eq!(exported[1]["sum"]["dataPoints"][0]["asInt"], "1");
+    }
+
+    #[test]
+    fn test_failed_export() {
+        // Nothing listens on the discard port.
+        let otlp = Arc::new(Otlp::new("http://127.0.0.1:9", "test"));
+        assert_eq!(otlp.last_error(), None);
+        otlp.spawn(Duration::from_millis(1), Arc::new(Metrics::new()));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while otlp.last_error().is_none() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(otlp.last_error().unwrap().contains("127.0.0.1:9/v1/metrics"));
+    }
+}
@@ -0,0 +1,296 @@
+/*
+ * Keeps connections to devices open between requests, for setups polling
+ * enough plugs that connecting every time adds up:
+ *
+ *   let pool = Arc::new(ConnectionPool::new(TcpTransport::default())
+ *       .max_idle(64)
+ *       .idle_timeout(Duration::from_secs(30)));
+ *   for address in addresses {
+ *       scheduler.add(address, TpLinkDevice::new(address).pooled(&pool), schedule.clone());
+ *   }
+ *   println!("{}", pool.prometheus());
+ *
+ * There is at most one idle connection per address, and no more than
+ * `max_idle` over all of them; past that the one unused the longest is
+ * closed. Connections idle for `idle_timeout` are closed on the next request
+ * or by `evict_idle`. A request that finds a connection uses it, and if the
+ * device had closed it in the meantime, which plugs do after a while, sends
+ * again over a new one. It does so only when the request couldn't be written
+ * or the connection ended before a byte of the reply came back; after a
+ * timeout or a partial reply the device may have acted on the command, and
+ * sending `add_rule` or `reboot` twice isn't harmless, so the error is
+ * returned instead. Concurrent requests to the same address each get their
+ * own connection; only one is kept afterwards.
+ */
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::timing::{self, Clock};
+use crate::transport::{TcpTransport, Transport};
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Requests sent over a connection that was already open.
+    pub hits: u64,
+    /// Requests that opened a connection.
+    pub misses: u64,
+    /// Open connections the device had closed before answering, so the request was sent again.
+    pub reconnects: u64,
+    /// Connections closed for being idle too long or for room.
+    pub evictions: u64,
+    /// Connections open and waiting now.
+    pub idle: usize,
+}
+
+pub struct ConnectionPool {
+    tcp: TcpTransport,
+    max_idle: usize,
+    idle_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    /// Idle connections by address, with when they were last used.
+    idle: Mutex<HashMap<String, (TcpStream, Instant)>>,
+    stats: Mutex<PoolStats>,
+}
+
+impl ConnectionPool {
+    /// Requests go over connections made and read with `tcp`'s limits.
+    pub fn new(tcp: TcpTransport) -> ConnectionPool {
+        ConnectionPool {
+            tcp,
+            max_idle: 64,
+            idle_timeout: Duration::from_secs(30),
+            clock: timing::system(),
+            idle: Mutex::new(HashMap::new()),
+            stats: Mutex::new(PoolStats::default()),
+        }
+    }
+
+    pub fn max_idle(mut self, connections: usize) -> ConnectionPool {
+        self.max_idle = connections;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> ConnectionPool {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ConnectionPool {
+        self.clock = clock;
+        self
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            idle: self.idle.lock().map(|idle| idle.len()).unwrap_or_default(),
+            ..self.stats.lock().map(|stats| *stats).unwrap_or_default()
+        }
+    }
+
+    fn count(&self, update: impl FnOnce(&mut PoolStats)) {
+        if let Ok(mut stats) = self.stats.lock() {
+            update(&mut stats);
+        }
+    }
+
+    /// Closes the connections idle for longer than `idle_timeout`. Returns how many.
+    pub fn evict_idle(&self) -> usize {
+        let now = self.clock.now();
+        let evicted = self.idle.lock().map(|mut idle| {
+            let before = idle.len();
+            idle.retain(|_, (_, used)| now.saturating_duration_since(*used) < self.idle_timeout);
+            before - idle.len()
+        }).unwrap_or_default();
+        self.count(|stats| stats.evictions += evicted as u64);
+        evicted
+    }
+
+    fn take(&self, address: &str) -> Option<TcpStream> {
+        self.evict_idle();
+        self.idle.lock().ok()?.remove(address).map(|(stream, _)| stream)
+    }
+
+    fn keep(&self, address: &str, stream: TcpStream) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let mut evicted = 0;
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.insert(String::from(address), (stream, self.clock.now()));
+            while idle.len() > self.max_idle {
+                let Some(oldest) = idle.iter().min_by_key(|(_, (_, used))| *used).map(|(a, _)| a.clone()) else {
+                    break;
+                };
+                idle.remove(&oldest);
+                evicted += 1;
+            }
+        }
+        self.count(|stats| stats.evictions += evicted);
+    }
+
+    /// Closes every idle connection.
+    pub fn clear(&self) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.clear();
+        }
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let stats = self.stats();
+        let mut out = String::new();
+        out.push_str("# HELP hs1x0_pool_requests_total Requests by whether an open connection was reused.\n");
+        out.push_str("# TYPE hs1x0_pool_requests_total counter\n");
+        let _ = writeln!(out, "hs1x0_pool_requests_total{{result=\"hit\"}} {}", stats.hits);
+        let _ = writeln!(out, "hs1x0_pool_requests_total{{result=\"miss\"}} {}", stats.misses);
+        out.push_str("# HELP hs1x0_pool_reconnects_total Reused connections the device had closed.\n");
+        out.push_str("# TYPE hs1x0_pool_reconnects_total counter\n");
+        let _ = writeln!(out, "hs1x0_pool_reconnects_total {}", stats.reconnects);
+        out.push_str("# HELP hs1x0_pool_evictions_total Idle connections closed by the pool.\n");
+        out.push_str("# TYPE hs1x0_pool_evictions_total counter\n");
+        let _ = writeln!(out, "hs1x0_pool_evictions_total {}", stats.evictions);
+        out.push_str("# HELP hs1x0_pool_idle_connections Connections open and waiting.\n");
+        out.push_str("# TYPE hs1x0_pool_idle_connections gauge\n");
+        let _ = writeln!(out, "hs1x0_pool_idle_connections {}", stats.idle);
+        out
+    }
+}
+
+impl Transport for ConnectionPool {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        if let Some(mut stream) = self.take(address) {
+            self.count(|stats| stats.hits += 1);
+            match self.tcp.exchange(&mut stream, frame) {
+                Ok(response) => {
+                    self.keep(address, stream);
+                    return Ok(response);
+                }
+                Err(failed) if failed.stale => self.count(|stats| stats.reconnects += 1),
+                Err(failed) => return Err(failed.into()),
+            }
+        } else {
+            self.count(|stats| stats.misses += 1);
+        }
+
+        let mut stream = TcpStream::connect(address)?;
+        let response = self.tcp.exchange(&mut stream, frame)?;
+        self.keep(address, stream);
+        Ok(response)
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.tcp.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// The same device over `pool`'s connections.
+    pub fn pooled(&self, pool: &Arc<ConnectionPool>) -> TpLinkDevice {
+        self.with_inner(pool.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use crate::TpLinkDevice;
+    use crate::protocol::encrypt_payload;
+    use crate::timing::MockClock;
+    use crate::transport::TcpTransport;
+    use super::{ConnectionPool, PoolStats};
+
+    /// A plug that answers up to `per_connection` requests on each connection, then hangs up.
+    fn plug(per_connection: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                for _ in 0..per_connection {
+                    let mut prefix = [0u8; 4];
+                    if stream.read_exact(&mut prefix).is_err() {
+                        break;
+                    }
+                    let mut request = vec![0u8; u32::from_be_bytes(prefix) as usize];
+                    stream.read_exact(&mut request).unwrap();
+                    let reply = encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec());
+                    stream.write_all(&reply).unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_reuses_connections() {
+        let clock = Arc::new(MockClock::new());
+        let pool = Arc::new(ConnectionPool::new(TcpTransport::new(Duration::from_secs(2)))
+            .idle_timeout(Duration::from_secs(30))
+            .with_clock(clock.clone()));
+        let device = TpLinkDevice::with_transport(&plug(usize::MAX), pool.clone());
+
+        for _ in 0..3 {
+            device.on().unwrap();
+        }
+        assert_eq!(pool.stats(), PoolStats { hits: 2, misses: 1, reconnects: 0, evictions: 0, idle: 1 });
+
+        clock.advance(Duration::from_secs(31));
+        device.on().unwrap();
+        assert_eq!((pool.stats().misses, pool.stats().evictions), (2, 1));
+        assert!(pool.prometheus().contains("hs1x0_pool_requests_total{result=\"hit\"} 2\n"));
+    }
+
+    #[test]
+    fn test_reconnects_and_bounds() {
+        let pool = Arc::new(ConnectionPool::new(TcpTransport::new(Duration::from_secs(2))).max_idle(1));
+        let hangs_up = TpLinkDevice::with_transport(&plug(1), pool.clone());
+        hangs_up.on().unwrap();
+        hangs_up.on().unwrap();
+        assert_eq!((pool.stats().hits, pool.stats().reconnects), (1, 1));
+
+        let other = TpLinkDevice::with_transport(&plug(usize::MAX), pool.clone());
+        other.on().unwrap();
+        assert_eq!((pool.stats().evictions, pool.stats().idle), (1, 1));
+    }
+
+    #[test]
+    fn test_no_resend_after_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(AtomicUsize::new(0));
+        let count = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut prefix = [0u8; 4];
+                while stream.read_exact(&mut prefix).is_ok() {
+                    let mut request = vec![0u8; u32::from_be_bytes(prefix) as usize];
+                    stream.read_exact(&mut request).unwrap();
+                    // Answers the first request only, then stays silent, like a plug busy rebooting.
+                    if count.fetch_add(1, Ordering::SeqCst) == 0 {
+                        stream.write_all(&encrypt_payload(br#"{"system":{"reboot":{"err_code":0}}}"#.to_vec())).unwrap();
+                    }
+                }
+            }
+        });
+
+        let pool = Arc::new(ConnectionPool::new(TcpTransport::new(Duration::from_millis(100))));
+        let device = TpLinkDevice::with_transport(&address, pool.clone());
+        device.reboot().unwrap();
+        assert!(device.reboot().is_err());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!((pool.stats().hits, pool.stats().reconnects, pool.stats().idle), (1, 0, 0));
+    }
+}
@@ -0,0 +1,263 @@
+/*
+ * Minimum on and off times for loads that suffer from short cycling, such as a
+ * fridge compressor that must rest before it starts again:
+ *
+ *   let protection = Protection::new().min_off(Duration::from_secs(300));
+ *   let (device, guard) = device.protected(protection);
+ *   engine.device("freezer", device);
+ *   ...
+ *   guard.override_next();   // the next switch goes through regardless
+ *   device.off()?;
+ *
+ * Switching on within `min_off` of the relay going off, or off within `min_on`
+ * of it going on, is refused, or with `Hold::Delay` waits until the time is up.
+ * The relay's state is only known from switches made through the guard, so the
+ * first one always goes through. Asking for the state the relay is already in
+ * passes, as does everything other than `set_relay_state`. Strip outlets are
+ * tracked one by one, and switches of the same relay go through one at a time.
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::Value;
+
+use crate::TpLinkDevice;
+use crate::protocol::decrypt_payload;
+use crate::throttle::{outlets, requested_state, Switches};
+use crate::timing::{self, Clock};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+/// What to do with a switch that comes too early.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Hold {
+    #[default]
+    Reject,
+    /// Sleep in the caller's thread until the switch is allowed.
+    Delay,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Protection {
+    pub min_on: Duration,
+    pub min_off: Duration,
+    pub hold: Hold,
+}
+
+impl Protection {
+    pub fn new() -> Protection {
+        Protection::default()
+    }
+
+    /// How long the relay stays on once switched on.
+    pub fn min_on(mut self, min_on: Duration) -> Protection {
+        self.min_on = min_on;
+        self
+    }
+
+    /// How long the relay stays off once switched off.
+    pub fn min_off(mut self, min_off: Duration) -> Protection {
+        self.min_off = min_off;
+        self
+    }
+
+    pub fn hold(mut self, hold: Hold) -> Protection {
+        self.hold = hold;
+        self
+    }
+}
+
+/// Per address and outlets, held from deciding on a switch until it's recorded.
+type Turns = HashMap<(String, String), Arc<Mutex<()>>>;
+
+pub struct ProtectedLoad {
+    inner: Arc<dyn Transport>,
+    protection: Protection,
+    clock: Arc<dyn Clock>,
+    switches: Mutex<Switches>,
+    turns: Mutex<Turns>,
+    override_next: AtomicBool,
+}
+
+impl ProtectedLoad {
+    pub fn new(inner: Arc<dyn Transport>, protection: Protection) -> ProtectedLoad {
+        ProtectedLoad {
+            inner,
+            protection,
+            clock: timing::system(),
+            switches: Mutex::new(HashMap::new()),
+            turns: Mutex::new(HashMap::new()),
+            override_next: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ProtectedLoad {
+        self.clock = clock;
+        self
+    }
+
+    /// Lets the next switch through whatever the minimum times say.
+    pub fn override_next(&self) {
+        self.override_next.store(true, Ordering::SeqCst);
+    }
+
+    /// How long until the relay may be switched to `on`, zero if it may now.
+    fn wait(&self, key: &(String, String), on: bool) -> Duration {
+        let Some((was_on, at)) = self.switches.lock().ok().and_then(|s| s.get(key).copied()) else {
+            return Duration::ZERO;
+        };
+        if was_on == on {
+            return Duration::ZERO;
+        }
+        let minimum = if was_on { self.protection.min_on } else { self.protection.min_off };
+        minimum.saturating_sub(self.clock.now().saturating_duration_since(at))
+    }
+}
+
+impl Transport for ProtectedLoad {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let cmd: Value = serde_json::from_slice(&decrypt_payload(frame))?;
+        let Some(on) = requested_state(&cmd) else { return self.inner.request(address, frame) };
+
+        let key = (String::from(address), outlets(&cmd));
+        // Otherwise a switch the other way could pass while this one sleeps or is on its way.
+        let turn = self.turns.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(key.clone()).or_default().clone();
+        let _turn = turn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let wait = self.wait(&key, on);
+        if !wait.is_zero() && !self.override_next.swap(false, Ordering::SeqCst) {
+            match self.protection.hold {
+                Hold::Reject => return Err(PlugError::new(
+                    format!("Relay may not be switched {} for another {:.1}s",
+                            if on { "on" } else { "off" }, wait.as_secs_f64()).as_str())),
+                Hold::Delay => self.clock.sleep(wait),
+            }
+        }
+
+        let response = self.inner.request(address, frame)?;
+        if let Ok(mut switches) = self.switches.lock() {
+            let switched = switches.get(&key).is_none_or(|(was_on, _)| *was_on != on);
+            if switched {
+                switches.insert(key, (on, self.clock.now()));
+            }
+        }
+        Ok(response)
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose relay keeps to the minimum times in `protection`.
+    pub fn protected(&self, protection: Protection) -> (TpLinkDevice, Arc<ProtectedLoad>) {
+        self.protected_with_clock(protection, timing::system())
+    }
+
+    /// Like `protected`, with time since the last switch told by `clock`.
+    pub fn protected_with_clock(&self, protection: Protection, clock: Arc<dyn Clock>)
+        -> (TpLinkDevice, Arc<ProtectedLoad>) {
+        let guard = Arc::new(ProtectedLoad::new(self.transport.clone(), protection).with_clock(clock));
+        (self.with_inner(guard.clone()), guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::timing::{Clock, MockClock};
+    use crate::types::PlugError;
+    use super::{Hold, Protection};
+
+    fn compressor(sent: Arc<Mutex<Vec<i64>>>) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            if let Some(state) = request["system"]["set_relay_state"]["state"].as_i64() {
+                sent.lock().unwrap().push(state);
+            }
+            Ok(encrypt_payload(json!({"system": {"set_relay_state": {"err_code": 0}}}).to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("fridge", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_rejects_early_restart() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(MockClock::new());
+        let protection = Protection::new().min_on(Duration::from_secs(60)).min_off(Duration::from_secs(300));
+        let (device, guard) = compressor(sent.clone()).protected_with_clock(protection, clock.clone());
+
+        device.on().unwrap();
+        assert!(device.off().is_err());
+        clock.advance(Duration::from_secs(60));
+        device.off().unwrap();
+        clock.advance(Duration::from_secs(120));
+        let refused = device.on().unwrap_err();
+        assert!(refused.to_string().contains("another 180.0s"), "{}", refused);
+        // Already off: nothing to protect.
+        device.off().unwrap();
+
+        guard.override_next();
+        device.on().unwrap();
+        assert!(device.off().is_err());
+        assert_eq!(*sent.lock().unwrap(), [1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_delays_until_allowed() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(MockClock::new());
+        let protection = Protection::new().min_off(Duration::from_secs(300)).hold(Hold::Delay);
+        let (device, _) = compressor(sent.clone()).protected_with_clock(protection, clock.clone());
+
+        device.off().unwrap();
+        let before = clock.now();
+        device.on().unwrap();
+        assert_eq!(clock.now() - before, Duration::from_secs(300));
+        assert_eq!(*sent.lock().unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn test_delays_a_switch_made_while_another_is_on_its_way() {
+        let clock = Arc::new(MockClock::new());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let (entered, on_the_wire) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (log, now, released) = (sent.clone(), clock.clone(), Mutex::new(released));
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let state = request["system"]["set_relay_state"]["state"].as_i64().unwrap();
+            log.lock().unwrap().push((state, now.now()));
+            if state == 0 {
+                entered.send(()).unwrap();
+                released.lock().unwrap().recv().unwrap();
+            }
+            Ok(encrypt_payload(json!({"system": {"set_relay_state": {"err_code": 0}}}).to_string().into_bytes()))
+        };
+        let protection = Protection::new().min_off(Duration::from_secs(300)).hold(Hold::Delay);
+        let (device, _) = TpLinkDevice::with_transport("fridge", Arc::new(transport))
+            .protected_with_clock(protection, clock.clone());
+
+        let off = std::thread::spawn({
+            let device = device.clone();
+            move || device.off()
+        });
+        on_the_wire.recv().unwrap();
+        let on = std::thread::spawn(move || device.on());
+        std::thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+        off.join().unwrap().unwrap();
+        on.join().unwrap().unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.iter().map(|(state, _)| *state).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(sent[1].1 - sent[0].1, Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * TP-Link Smart Home protocol codec. Payloads are XOR-ed with an autokey cipher
+ * (initial key 171) and, over TCP, prefixed with their big-endian length.
+ */
+
+use alloc::vec::Vec;
+
+pub const INITIAL_KEY: u8 = 171;
+
+pub const fn size_to_bytes(size: u32) -> [u8;4] {
+    let b1 = ((size >> 24) & 0xff) as u8;
+    let b2 = ((size >> 16) & 0xff) as u8;
+    let b3 = ((size >> 8) & 0xff) as u8;
+    let b4 = (size & 0xff) as u8;
+
+    [b1, b2, b3, b4]
+}
+
+pub fn size_from_bytes(size: &[u8]) -> usize {
+    ((size[0] as usize) << 24) |
+        ((size[1] as usize) << 16) |
+        ((size[2] as usize) << 8) |
+        size[3] as usize
+}
+
+/// Encrypts `data` into a length-prefixed frame.
+pub fn encrypt_payload(data: Vec<u8>) -> Vec<u8> {
+    let it = data.iter();
+    let mut v2 = Vec::new();
+    let mut key = INITIAL_KEY;
+
+    v2.extend_from_slice(&size_to_bytes(data.len() as u32));
+
+    for b in it {
+        let tmp = *b ^ key;
+        v2.push(tmp);
+        key = tmp;
+    }
+
+    v2
+}
+
+/// `encrypt_payload` at compile time, for bodies known in advance. `N` must be `body.len() + 4`.
+pub const fn encrypt_const<const N: usize>(body: &[u8]) -> [u8; N] {
+    assert!(N == body.len() + 4, "frame length must be the body's plus 4");
+    let mut frame = [0u8; N];
+    let size = size_to_bytes(body.len() as u32);
+    let mut i = 0;
+    while i < 4 {
+        frame[i] = size[i];
+        i += 1;
+    }
+
+    let mut key = INITIAL_KEY;
+    let mut i = 0;
+    while i < body.len() {
+        key ^= body[i];
+        frame[i + 4] = key;
+        i += 1;
+    }
+
+    frame
+}
+
+/// Decrypts a length-prefixed frame. `data` must hold at least as many bytes as its prefix says.
+pub fn decrypt_payload(data: &[u8]) -> Vec<u8> {
+
+    let payload_size = size_from_bytes(&data[0..4]);
+    let mut v2 = Vec::new();
+    let mut key = INITIAL_KEY;
+
+    for b in &data[4..payload_size+4] {
+        let tmp = *b ^ key;
+        v2.push(tmp);
+        key = *b;
+    }
+
+    v2
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use super::*;
+
+    #[test]
+    fn test_size_round_trip() {
+        assert_eq!(size_to_bytes(0x01020304), [1, 2, 3, 4]);
+        assert_eq!(size_from_bytes(&[1, 2, 3, 4]), 0x01020304);
+    }
+
+    #[test]
+    fn test_known_ciphertext() {
+        assert_eq!(encrypt_payload(b"{}".to_vec()), vec![0, 0, 0, 2, 0xd0, 0xad]);
+        assert_eq!(decrypt_payload(&[0, 0, 0, 2, 0xd0, 0xad]), b"{}".to_vec());
+        const FRAME: [u8; 6] = encrypt_const(b"{}");
+        assert_eq!(FRAME, [0, 0, 0, 2, 0xd0, 0xad]);
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * Known oddities of particular models and firmware versions. `detect` looks the
+ * device up here and the send path and parsers adjust to what it finds:
+ *
+ *   let mut registry = QuirkRegistry::default();
+ *   registry.register("HS103(JP)", Some("1.2.0"), Quirk::IntegerLocation);
+ *   let plug = TpLinkDevice::new("10.0.0.9").detect_with(&registry)?;
+ *
+ * A rule applies to models starting with its prefix, from its firmware version
+ * on (every version if none is given). Quirks that get in the way of reading
+ * sysinfo in the first place have to be given up front with `with_quirks`.
+ */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quirk {
+    /// Answers with the bare encrypted payload, like discovery replies over UDP.
+    UnprefixedReplies,
+    /// Only speaks the newer KLAP protocol, which this crate doesn't implement.
+    RequiresKlap,
+    /// Reports and expects `latitude_i`/`longitude_i` in 1/10000 degree.
+    IntegerLocation,
+}
+
+impl Quirk {
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// The quirks that apply to one device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks(u32);
+
+impl Quirks {
+    pub fn contains(&self, quirk: Quirk) -> bool {
+        self.0 & quirk.bit() != 0
+    }
+
+    pub fn insert(&mut self, quirk: Quirk) {
+        self.0 |= quirk.bit();
+    }
+
+    pub fn union(self, other: Quirks) -> Quirks {
+        Quirks(self.0 | other.0)
+    }
+}
+
+impl From<Quirk> for Quirks {
+    fn from(quirk: Quirk) -> Quirks {
+        let mut quirks = Quirks::default();
+        quirks.insert(quirk);
+        quirks
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuirkRule {
+    pub model: String,
+    pub since_firmware: Option<String>,
+    pub quirk: Quirk,
+}
+
+const BUILT_IN: &[(&str, Option<&str>, Quirk)] = &[
+    ("HS100(UK)", Some("1.1.0"), Quirk::RequiresKlap),
+    ("KP125M", None, Quirk::RequiresKlap),
+    ("HS110", Some("1.5.0"), Quirk::IntegerLocation),
+    ("HS300", None, Quirk::IntegerLocation),
+    ("KP115", None, Quirk::IntegerLocation),
+];
+
+/// Orders the leading dotted numbers of e.g. "1.0.12 Build 210329 Rel.123456".
+pub(crate) fn compare_firmware(a: &str, b: &str) -> Ordering {
+    let numbers = |v: &str| -> Vec<u32> {
+        v.split_whitespace().next().unwrap_or("")
+            .split('.')
+            .map(|n| n.parse().unwrap_or(0))
+            .collect()
+    };
+    numbers(a).cmp(&numbers(b))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuirkRegistry {
+    rules: Vec<QuirkRule>,
+}
+
+impl Default for QuirkRegistry {
+    /// The built-in rules.
+    fn default() -> QuirkRegistry {
+        let mut registry = QuirkRegistry::empty();
+        for (model, since, quirk) in BUILT_IN {
+            registry.register(model, *since, *quirk);
+        }
+        registry
+    }
+}
+
+impl QuirkRegistry {
+    pub fn empty() -> QuirkRegistry {
+        QuirkRegistry {
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, model: &str, since_firmware: Option<&str>, quirk: Quirk) -> &mut Self {
+        self.rules.push(QuirkRule {
+            model: String::from(model),
+            since_firmware: since_firmware.map(String::from),
+            quirk,
+        });
+        self
+    }
+
+    pub fn rules(&self) -> &[QuirkRule] {
+        &self.rules
+    }
+
+    pub fn lookup(&self, model: &str, firmware: &str) -> Quirks {
+        let mut quirks = Quirks::default();
+        for rule in &self.rules {
+            let since = rule.since_firmware.as_deref()
+                .is_none_or(|since| compare_firmware(firmware, since) != Ordering::Less);
+            if model.starts_with(rule.model.as_str()) && since {
+                quirks.insert(rule.quirk);
+            }
+        }
+        quirks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::{Quirk, QuirkRegistry};
+
+    #[test]
+    fn test_lookup() {
+        let registry = QuirkRegistry::default();
+        assert!(!registry.lookup("HS100(UK)", "1.0.9 Build 200310 Rel.090206").contains(Quirk::RequiresKlap));
+        assert!(registry.lookup("HS100(UK)", "1.1.0 Build 201016 Rel.175121").contains(Quirk::RequiresKlap));
+        assert!(registry.lookup("HS110(EU)", "1.5.4 Build 180815 Rel.121440").contains(Quirk::IntegerLocation));
+        assert_eq!(registry.lookup("HS110(EU)", "1.2.5 Build 171213 Rel.101523"), Default::default());
+    }
+
+    #[test]
+    fn test_custom_quirks_applied() {
+        let last = Arc::new(Mutex::new(Value::Null));
+        let seen = last.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            *seen.lock().unwrap() = request.clone();
+            let response = if request["system"].get("get_sysinfo").is_some() {
+                json!({"system": {"get_sysinfo": {"model": "HS103(JP)", "sw_ver": "1.2.1 Build 1",
+                    "type": "IOT.SMARTPLUGSWITCH", "latitude_i": 356895, "longitude_i": 1396917, "err_code": 0}}})
+            } else {
+                json!({"system": {}})
+            };
+            // Drop the length prefix, as the quirk says this device does.
+            Ok(encrypt_payload(response.to_string().into_bytes())[4..].to_vec())
+        };
+
+        let mut registry = QuirkRegistry::empty();
+        registry.register("HS103(JP)", Some("1.2.0"), Quirk::IntegerLocation)
+            .register("HS103(JP)", Some("1.3.0"), Quirk::RequiresKlap);
+        let device = TpLinkDevice::with_transport("plug", Arc::new(transport))
+            .with_quirks(Quirk::UnprefixedReplies.into())
+            .detect_with(&registry).unwrap();
+        assert!(device.quirks().contains(Quirk::UnprefixedReplies));
+        assert!(!device.quirks().contains(Quirk::RequiresKlap));
+
+        let sysinfo = device.sysinfo().unwrap();
+        assert_eq!((sysinfo.latitude, sysinfo.longitude), (35.6895, 139.6917));
+        device.set_location(51.5, -0.1).unwrap();
+        assert_eq!(*last.lock().unwrap(),
+                   json!({"system": {"set_dev_location": {"latitude_i": 515000, "longitude_i": -1000}}}));
+    }
+}
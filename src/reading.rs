@@ -0,0 +1,139 @@
+#[cfg(feature = "time")]
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::types::EmeterGetRealtimeResponse;
+
+/// A meter reading in V, A, W and kWh, whichever units the hardware revision reports in.
+///
+/// `taken_at` only exists with the `time` feature, so the struct is built
+/// through `new` or `from_realtime` outside this crate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct PowerReading {
+    pub voltage_v: f64,
+    pub current_a: f64,
+    pub power_w: f64,
+    pub total_kwh: f64,
+    /// When the reading was received; the Unix epoch if it was never stamped.
+    #[cfg(feature = "time")]
+    pub taken_at: DateTime<Utc>,
+}
+
+impl PowerReading {
+    /// An unstamped reading.
+    pub fn new(voltage_v: f64, current_a: f64, power_w: f64, total_kwh: f64) -> PowerReading {
+        PowerReading { voltage_v, current_a, power_w, total_kwh, ..PowerReading::default() }
+    }
+
+    #[cfg(feature = "time")]
+    pub fn from_realtime(realtime: &EmeterGetRealtimeResponse, taken_at: DateTime<Utc>) -> PowerReading {
+        PowerReading {
+            taken_at,
+            ..PowerReading::from(realtime)
+        }
+    }
+
+    /// Voltage times current, in VA.
+    pub fn apparent_power_va(&self) -> f64 {
+        self.voltage_v * self.current_a
+    }
+
+    /// Real over apparent power, `None` without current. Inductive loads such as
+    /// motors sit well below 1; values above 1 mean the reading itself is off.
+    pub fn power_factor(&self) -> Option<f64> {
+        let apparent = self.apparent_power_va();
+        if apparent > 0.0 { Some(self.power_w / apparent) } else { None }
+    }
+}
+
+/// The reading as SI quantities, so downstream code can't mix up V and mV.
+#[cfg(feature = "uom")]
+impl PowerReading {
+    pub fn voltage(&self) -> uom::si::f64::ElectricPotential {
+        uom::si::f64::ElectricPotential::new::<uom::si::electric_potential::volt>(self.voltage_v)
+    }
+
+    pub fn current(&self) -> uom::si::f64::ElectricCurrent {
+        uom::si::f64::ElectricCurrent::new::<uom::si::electric_current::ampere>(self.current_a)
+    }
+
+    pub fn power(&self) -> uom::si::f64::Power {
+        uom::si::f64::Power::new::<uom::si::power::watt>(self.power_w)
+    }
+
+    pub fn total_energy(&self) -> uom::si::f64::Energy {
+        uom::si::f64::Energy::new::<uom::si::energy::kilowatt_hour>(self.total_kwh)
+    }
+}
+
+/// An unstamped reading, see `from_realtime`.
+impl From<&EmeterGetRealtimeResponse> for PowerReading {
+    fn from(realtime: &EmeterGetRealtimeResponse) -> PowerReading {
+        PowerReading::new(realtime.voltage_v().unwrap_or(0.0), realtime.current_a().unwrap_or(0.0),
+                          realtime.power_w().unwrap_or(0.0), realtime.total_kwh().unwrap_or(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::EmeterGetRealtimeResponse;
+    use super::PowerReading;
+
+    #[test]
+    fn test_hardware_revisions_agree() {
+        let v1: EmeterGetRealtimeResponse = serde_json::from_str(
+            r#"{"current":0.5,"voltage":230.0,"power":115.0,"total":1.5,"err_code":0}"#).unwrap();
+        let v2: EmeterGetRealtimeResponse = serde_json::from_str(
+            r#"{"current_ma":500,"voltage_mv":230000,"power_mw":115000,"total_wh":1500,"err_code":0}"#).unwrap();
+
+        assert_eq!(PowerReading::from(&v1), PowerReading::from(&v2));
+    }
+
+    #[test]
+    fn test_firmware_variants() {
+        let strip: EmeterGetRealtimeResponse = serde_json::from_str(
+            r#"{"slot_id":2,"current_ma":20,"voltage_mv":120500,"power_mw":1800,"total_wh":7,"err_code":0,"shunt":3}"#)
+            .unwrap();
+        assert_eq!((strip.slot_id, strip.power_w()), (Some(2), Some(1.8)));
+        assert_eq!(strip.extra["shunt"], 3);
+        assert!(serde_json::to_string(&strip).unwrap().contains(r#""shunt":3"#));
+
+        let partial: EmeterGetRealtimeResponse = serde_json::from_str(r#"{"power":12.5,"err_msg":"ok"}"#).unwrap();
+        assert_eq!((partial.err_code, partial.err_msg.as_deref(), partial.total_kwh()), (0, Some("ok"), None));
+        assert_eq!(PowerReading::from(&partial).power_w, 12.5);
+    }
+
+    #[test]
+    #[cfg(feature = "time")]
+    fn test_serialize() {
+        use chrono::{TimeZone, Utc};
+        let realtime: EmeterGetRealtimeResponse = serde_json::from_str(
+            r#"{"current_ma":500,"voltage_mv":230000,"power_mw":115000,"total_wh":1500,"err_code":0}"#).unwrap();
+        let reading = PowerReading::from_realtime(&realtime, Utc.timestamp_opt(1700000000, 0).unwrap());
+
+        let json = serde_json::to_string(&reading).unwrap();
+        assert_eq!(json, r#"{"voltage_v":230.0,"current_a":0.5,"power_w":115.0,"total_kwh":1.5,"taken_at":"2023-11-14T22:13:20Z"}"#);
+        assert_eq!(serde_json::from_str::<PowerReading>(&json).unwrap(), reading);
+    }
+
+    #[test]
+    fn test_power_factor() {
+        let motor = PowerReading { voltage_v: 230.0, current_a: 2.0, power_w: 322.0, ..PowerReading::default() };
+        assert_eq!(motor.apparent_power_va(), 460.0);
+        assert_eq!(motor.power_factor(), Some(0.7));
+        assert_eq!(PowerReading::default().power_factor(), None);
+    }
+
+    #[cfg(feature = "uom")]
+    #[test]
+    fn test_quantities() {
+        use uom::si::{electric_potential::millivolt, energy::watt_hour, power::milliwatt};
+
+        let reading = PowerReading { voltage_v: 230.0, current_a: 0.5, power_w: 115.0, total_kwh: 1.5, ..PowerReading::default() };
+        assert_eq!(reading.voltage().get::<millivolt>(), 230_000.0);
+        assert_eq!(reading.power().get::<milliwatt>(), 115_000.0);
+        assert_eq!(reading.total_energy().get::<watt_hour>(), 1500.0);
+        assert!((reading.voltage() * reading.current() - reading.power()).value.abs() < 1e-9);
+    }
+}
@@ -0,0 +1,265 @@
+/*
+ * Keeps devices in a declared state: the relay, alias, LED and schedule are
+ * compared with what each device reports and put right where they drifted,
+ * for example after someone used the app or a device was reset:
+ *
+ *   let mut controller = Controller::new();
+ *   controller.device("heater", heater, DesiredState::new().on().alias("Heater").led(false));
+ *   for outcome in controller.spawn(Duration::from_secs(60)) {
+ *       println!("{}: {:?}", outcome.device, outcome.drift);
+ *   }
+ *
+ * Only what is set in a `DesiredState` is looked at. Schedules are compared
+ * rule by rule, ignoring ids, and replaced as a whole when they differ. With
+ * `observe_only` drift is reported but nothing is changed.
+ */
+
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::schedule::{diff_rules, ScheduleRule};
+use crate::types::PlugError;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DesiredState {
+    pub relay_on: Option<bool>,
+    pub alias: Option<String>,
+    pub led_on: Option<bool>,
+    pub schedule: Option<Vec<ScheduleRule>>,
+}
+
+impl DesiredState {
+    pub fn new() -> DesiredState {
+        DesiredState::default()
+    }
+
+    pub fn on(mut self) -> DesiredState {
+        self.relay_on = Some(true);
+        self
+    }
+
+    pub fn off(mut self) -> DesiredState {
+        self.relay_on = Some(false);
+        self
+    }
+
+    pub fn alias(mut self, alias: &str) -> DesiredState {
+        self.alias = Some(String::from(alias));
+        self
+    }
+
+    pub fn led(mut self, on: bool) -> DesiredState {
+        self.led_on = Some(on);
+        self
+    }
+
+    pub fn schedule(mut self, rules: Vec<ScheduleRule>) -> DesiredState {
+        self.schedule = Some(rules);
+        self
+    }
+}
+
+/// A difference between the desired and the actual state.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Drift {
+    Relay { want: bool, was: bool },
+    Alias { want: String, was: String },
+    Led { want: bool, was: bool },
+    /// How many of the desired rules were missing, and how many others were there.
+    Schedule { missing: usize, extra: usize },
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub device: String,
+    pub drift: Vec<Drift>,
+    /// The first failure, after which the device was left for the next round.
+    pub result: Result<(), PlugError>,
+}
+
+struct Managed {
+    name: String,
+    device: TpLinkDevice,
+    desired: DesiredState,
+}
+
+#[derive(Default)]
+pub struct Controller {
+    devices: Vec<Managed>,
+    observe_only: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn device(&mut self, name: &str, device: TpLinkDevice, desired: DesiredState) -> &mut Controller {
+        self.devices.push(Managed { name: String::from(name), device, desired });
+        self
+    }
+
+    pub fn observe_only(&mut self) -> &mut Controller {
+        self.observe_only = true;
+        self
+    }
+
+    fn reconcile_one(&self, managed: &Managed, drift: &mut Vec<Drift>) -> Result<(), PlugError> {
+        let (device, desired) = (&managed.device, &managed.desired);
+        let sysinfo = device.sysinfo()?;
+        let fix = !self.observe_only;
+
+        if let Some(want) = desired.relay_on {
+            let was = device.power_state(&sysinfo)?;
+            if was != want {
+                drift.push(Drift::Relay { want, was });
+                if fix {
+                    if want { device.on()?; } else { device.off()?; }
+                }
+            }
+        }
+        if let Some(want) = &desired.alias {
+            if sysinfo.alias != *want {
+                drift.push(Drift::Alias { want: want.clone(), was: sysinfo.alias.clone() });
+                if fix {
+                    device.set_device_alias(want)?;
+                }
+            }
+        }
+        if let Some(want) = desired.led_on {
+            let was = sysinfo.led_off == 0;
+            if was != want {
+                drift.push(Drift::Led { want, was });
+                if fix {
+                    if want { device.turn_led_on()?; } else { device.turn_led_off()?; }
+                }
+            }
+        }
+        if let Some(want) = &desired.schedule {
+            let (missing, extra) = diff_rules(&device.schedule_rules()?, want);
+            if !missing.is_empty() || !extra.is_empty() {
+                drift.push(Drift::Schedule { missing: missing.len(), extra: extra.len() });
+                if fix {
+                    device.set_schedules(want)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares and fixes every device once.
+    pub fn reconcile(&self) -> Vec<Outcome> {
+        self.devices.iter()
+            .map(|managed| {
+                let mut drift = Vec::new();
+                let result = self.reconcile_one(managed, &mut drift);
+                Outcome { device: managed.name.clone(), drift, result }
+            })
+            .collect()
+    }
+
+    /// Reconciles every `interval` in a background thread until the returned
+    /// receiver is dropped. Devices that were already as desired aren't reported.
+    pub fn spawn(self, interval: Duration) -> Receiver<Outcome> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut deadline = Instant::now();
+            loop {
+                for outcome in self.reconcile() {
+                    if outcome.drift.is_empty() && outcome.result.is_ok() {
+                        continue;
+                    }
+                    if tx.send(outcome).is_err() {
+                        return;
+                    }
+                }
+                deadline += interval;
+                let now = Instant::now();
+                if deadline > now {
+                    thread::sleep(deadline - now);
+                } else {
+                    deadline = now;
+                }
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::schedule::ScheduleRule;
+    use crate::types::PlugError;
+    use super::{Controller, DesiredState, Drift};
+
+    /// Relay, alias, LED off flag and schedule of a simulated plug.
+    type Plug = Arc<Mutex<(i64, String, i64, Vec<Value>)>>;
+
+    fn plug(state: Plug) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let mut plug = state.lock().unwrap();
+            let system = &request["system"];
+            let response = if let Some(set) = system.get("set_relay_state") {
+                plug.0 = set["state"].as_i64().unwrap();
+                json!({"system": {"set_relay_state": {"err_code": 0}}})
+            } else if let Some(set) = system.get("set_dev_alias") {
+                plug.1 = String::from(set["alias"].as_str().unwrap());
+                json!({"system": {"set_dev_alias": {"err_code": 0}}})
+            } else if let Some(set) = system.get("set_led_off") {
+                plug.2 = set["off"].as_i64().unwrap();
+                json!({"system": {"set_led_off": {"err_code": 0}}})
+            } else if request["schedule"].get("get_rules").is_some() {
+                json!({"schedule": {"get_rules": {"rule_list": plug.3, "err_code": 0}}})
+            } else if request["schedule"].get("delete_all_rules").is_some() {
+                plug.3.clear();
+                json!({"schedule": {"delete_all_rules": {"err_code": 0}}})
+            } else if let Some(rule) = request["schedule"].get("add_rule") {
+                plug.3.push(rule.clone());
+                json!({"schedule": {"add_rule": {"id": "R1", "err_code": 0}}})
+            } else {
+                json!({"system": {"get_sysinfo": {"relay_state": plug.0, "alias": plug.1, "led_off": plug.2, "err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("plug", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_drift_fixed() {
+        let state: Plug = Arc::new(Mutex::new((0, String::from("Plug"), 0, Vec::new())));
+        let desired = DesiredState::new().on().alias("Heater").led(false)
+            .schedule(vec![ScheduleRule::at(7, 0, true)]);
+        let mut controller = Controller::new();
+        controller.device("heater", plug(state.clone()), desired);
+
+        let outcome = controller.reconcile().remove(0);
+        outcome.result.unwrap();
+        assert_eq!(outcome.drift, [
+            Drift::Relay { want: true, was: false },
+            Drift::Alias { want: String::from("Heater"), was: String::from("Plug") },
+            Drift::Led { want: false, was: true },
+            Drift::Schedule { missing: 1, extra: 0 },
+        ]);
+        assert_eq!(state.lock().unwrap().0, 1);
+
+        let again = controller.reconcile().remove(0);
+        assert!(again.drift.is_empty() && again.result.is_ok());
+    }
+
+    #[test]
+    fn test_observe_only() {
+        let state: Plug = Arc::new(Mutex::new((1, String::from("Plug"), 0, Vec::new())));
+        let mut controller = Controller::new();
+        controller.device("heater", plug(state.clone()), DesiredState::new().off()).observe_only();
+
+        assert_eq!(controller.reconcile()[0].drift, [Drift::Relay { want: false, was: true }]);
+        assert_eq!(state.lock().unwrap().0, 1);
+    }
+}
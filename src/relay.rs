@@ -0,0 +1,358 @@
+/*
+ * The relay as a small state machine, for automation that needs to tell a
+ * switch still under way or a countdown yet to fire from a settled relay:
+ *
+ *   let relay = Relay::new(plug);
+ *   relay.refresh()?;                                   // Off or On
+ *   let Transition { from, to } = relay.turn_on()?;    // Off -> TurningOn -> On
+ *   relay.turn_off_in(Duration::from_secs(1800))?;      // On -> OffPending
+ *   relay.cancel()?;                                    // OffPending -> On
+ *
+ *   Unknown --refresh--> Off | On
+ *   Off --turn_on--> TurningOn --> On --turn_off--> TurningOff --> Off
+ *   Off --turn_on_in--> OnPending --due--> On    (and the other way round)
+ *
+ * `Relay` is shared by reference, so other threads see `TurningOn` and
+ * `TurningOff` while a command is in flight and are refused with `Busy` if
+ * they try to switch too. Switching to the state the relay is in sends
+ * nothing, unless a countdown is pending to switch it away, in which case the
+ * countdown is cleared. A failed command leaves the state `Unknown`, since the
+ * relay may or may not have switched. Pending states are countdown rules on the
+ * device, replacing whatever countdown was there; they settle by themselves
+ * once due.
+ */
+
+use std::error::Error;
+use std::fmt::{self, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::schedule::CountdownRule;
+use crate::timing::{self, Clock};
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RelayState {
+    /// Not read yet, or a command failed.
+    #[default]
+    Unknown,
+    Off,
+    TurningOn,
+    On,
+    TurningOff,
+    /// Off, with a countdown switching it on at `due`.
+    OnPending { due: Instant },
+    /// On, with a countdown switching it off at `due`.
+    OffPending { due: Instant },
+}
+
+impl RelayState {
+    /// Whether the relay is on right now; `None` while unknown or switching.
+    pub fn is_on(&self) -> Option<bool> {
+        match self {
+            RelayState::Off | RelayState::OnPending { .. } => Some(false),
+            RelayState::On | RelayState::OffPending { .. } => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn is_in_flight(&self) -> bool {
+        matches!(self, RelayState::TurningOn | RelayState::TurningOff)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self, RelayState::OnPending { .. } | RelayState::OffPending { .. })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub from: RelayState,
+    pub to: RelayState,
+}
+
+impl Transition {
+    /// Whether anything was sent to the device.
+    pub fn changed(&self) -> bool {
+        self.from != self.to
+    }
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    /// Another switch is in flight.
+    Busy(RelayState),
+    /// The transition doesn't start from this state, e.g. a countdown from `Unknown`.
+    Invalid { from: RelayState, transition: &'static str },
+    /// The device didn't take the command; the state is `Unknown` now.
+    Failed(PlugError),
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::Busy(state) => write!(f, "Relay is busy ({:?})", state),
+            TransitionError::Invalid { from, transition } => write!(f, "Can't {} from {:?}", transition, from),
+            TransitionError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for TransitionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransitionError::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransitionError> for PlugError {
+    fn from(error: TransitionError) -> PlugError {
+        match error {
+            TransitionError::Failed(e) => e,
+            e => PlugError::new(e.to_string().as_str()),
+        }
+    }
+}
+
+pub struct Relay {
+    device: TpLinkDevice,
+    clock: Arc<dyn Clock>,
+    state: Mutex<RelayState>,
+}
+
+impl Relay {
+    pub fn new(device: TpLinkDevice) -> Relay {
+        Relay {
+            device,
+            clock: timing::system(),
+            state: Mutex::new(RelayState::Unknown),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Relay {
+        self.clock = clock;
+        self
+    }
+
+    pub fn device(&self) -> &TpLinkDevice {
+        &self.device
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RelayState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Settles a pending state whose countdown is due.
+    fn settle(&self, state: &mut RelayState) {
+        let now = self.clock.now();
+        *state = match *state {
+            RelayState::OnPending { due } if due <= now => RelayState::On,
+            RelayState::OffPending { due } if due <= now => RelayState::Off,
+            state => state,
+        };
+    }
+
+    pub fn state(&self) -> RelayState {
+        let mut state = self.lock();
+        self.settle(&mut state);
+        *state
+    }
+
+    /// Reads the relay from the device's sysinfo. A pending countdown is kept
+    /// while the relay is still on its near side.
+    pub fn refresh(&self) -> Result<RelayState, TransitionError> {
+        let from = self.state();
+        if from.is_in_flight() {
+            return Err(TransitionError::Busy(from));
+        }
+        let on = self.device.is_on().map_err(TransitionError::Failed)?;
+        let mut state = self.lock();
+        if !state.is_in_flight() && state.is_on() != Some(on) {
+            *state = if on { RelayState::On } else { RelayState::Off };
+        }
+        Ok(*state)
+    }
+
+    /// Marks the relay as switching, or says why it can't.
+    fn begin(&self, on: bool) -> Result<Option<RelayState>, TransitionError> {
+        let mut state = self.lock();
+        self.settle(&mut state);
+        let from = *state;
+        if from.is_in_flight() {
+            return Err(TransitionError::Busy(from));
+        }
+        if from.is_on() == Some(on) && !from.is_pending() {
+            return Ok(None);
+        }
+        *state = if on { RelayState::TurningOn } else { RelayState::TurningOff };
+        Ok(Some(from))
+    }
+
+    fn switch(&self, on: bool) -> Result<Transition, TransitionError> {
+        let Some(from) = self.begin(on)? else {
+            let state = self.state();
+            return Ok(Transition { from: state, to: state });
+        };
+        // Staying where a pending countdown would switch away from means dropping the countdown.
+        let result = if from.is_on() == Some(on) {
+            self.device.clear_countdowns()
+        } else if on {
+            self.device.on()
+        } else {
+            self.device.off()
+        };
+        let mut state = self.lock();
+        match result {
+            Ok(_) => {
+                *state = if on { RelayState::On } else { RelayState::Off };
+                Ok(Transition { from, to: *state })
+            }
+            Err(e) => {
+                *state = RelayState::Unknown;
+                Err(TransitionError::Failed(e))
+            }
+        }
+    }
+
+    /// In `OffPending`, clears the countdown so the relay stays on.
+    pub fn turn_on(&self) -> Result<Transition, TransitionError> {
+        self.switch(true)
+    }
+
+    /// In `OnPending`, clears the countdown so the relay stays off.
+    pub fn turn_off(&self) -> Result<Transition, TransitionError> {
+        self.switch(false)
+    }
+
+    fn countdown(&self, on: bool, delay: Duration) -> Result<Transition, TransitionError> {
+        let transition = if on { "turn on later" } else { "turn off later" };
+        let from = self.state();
+        if from.is_in_flight() {
+            return Err(TransitionError::Busy(from));
+        }
+        if from.is_on() != Some(!on) {
+            return Err(TransitionError::Invalid { from, transition });
+        }
+
+        let rule = CountdownRule::new(delay.as_secs().min(u32::MAX as u64) as u32, on);
+        self.device.clear_countdowns()
+            .and_then(|_| self.device.add_countdown(&rule))
+            .map_err(TransitionError::Failed)?;
+        let due = self.clock.now() + Duration::from_secs(rule.delay as u64);
+        let mut state = self.lock();
+        *state = if on { RelayState::OnPending { due } } else { RelayState::OffPending { due } };
+        Ok(Transition { from, to: *state })
+    }
+
+    /// Off -> `OnPending`, through a countdown rule on the device.
+    pub fn turn_on_in(&self, delay: Duration) -> Result<Transition, TransitionError> {
+        self.countdown(true, delay)
+    }
+
+    /// On -> `OffPending`, through a countdown rule on the device.
+    pub fn turn_off_in(&self, delay: Duration) -> Result<Transition, TransitionError> {
+        self.countdown(false, delay)
+    }
+
+    /// Drops a pending countdown, leaving the relay where it is.
+    pub fn cancel(&self) -> Result<Transition, TransitionError> {
+        let from = self.state();
+        let to = match from {
+            RelayState::OnPending { .. } => RelayState::Off,
+            RelayState::OffPending { .. } => RelayState::On,
+            _ => return Err(TransitionError::Invalid { from, transition: "cancel" }),
+        };
+        self.device.clear_countdowns().map_err(TransitionError::Failed)?;
+        *self.lock() = to;
+        Ok(Transition { from, to })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::timing::MockClock;
+    use crate::types::PlugError;
+    use super::{Relay, RelayState, Transition, TransitionError};
+
+    fn plug(relay_state: i64, sent: Arc<Mutex<Vec<String>>>) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let (namespace, methods) = request.as_object().unwrap().iter().next().unwrap();
+            let method = methods.as_object().unwrap().keys().next().unwrap();
+            sent.lock().unwrap().push(format!("{}.{}", namespace, method));
+            let response = match method.as_str() {
+                "get_sysinfo" => json!({"system": {"get_sysinfo": {"relay_state": relay_state, "err_code": 0}}}),
+                "add_rule" => json!({"count_down": {"add_rule": {"id": "1", "err_code": 0}}}),
+                "set_relay_state" if namespace == "system" => json!({"system": {"set_relay_state": {"err_code": 0}}}),
+                _ => json!({namespace.as_str(): {method.as_str(): {"err_code": 0}}}),
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("plug", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_switching() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let relay = Relay::new(plug(0, sent.clone()));
+        assert!(matches!(relay.turn_off_in(Duration::from_secs(60)),
+                         Err(TransitionError::Invalid { from: RelayState::Unknown, .. })));
+
+        assert_eq!(relay.refresh().unwrap(), RelayState::Off);
+        assert_eq!(relay.turn_on().unwrap(), Transition { from: RelayState::Off, to: RelayState::On });
+        let again = relay.turn_on().unwrap();
+        assert!(!again.changed());
+        assert_eq!(*sent.lock().unwrap(), ["system.get_sysinfo", "system.set_relay_state"]);
+    }
+
+    #[test]
+    fn test_countdown_settles_when_due() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(MockClock::new());
+        let relay = Relay::new(plug(1, sent.clone())).with_clock(clock.clone());
+        relay.refresh().unwrap();
+
+        let pending = relay.turn_off_in(Duration::from_secs(1800)).unwrap();
+        assert!(matches!(pending.to, RelayState::OffPending { .. }));
+        assert_eq!(relay.state().is_on(), Some(true));
+        // Still on as far as the device is concerned.
+        assert!(matches!(relay.refresh().unwrap(), RelayState::OffPending { .. }));
+        assert_eq!(relay.cancel().unwrap().to, RelayState::On);
+        assert!(matches!(relay.cancel(), Err(TransitionError::Invalid { .. })));
+
+        relay.turn_off_in(Duration::from_secs(1800)).unwrap();
+        clock.advance(Duration::from_secs(1800));
+        assert_eq!(relay.state(), RelayState::Off);
+        assert_eq!(sent.lock().unwrap().iter().filter(|c| *c == "count_down.delete_all_rules").count(), 3);
+    }
+
+    #[test]
+    fn test_switching_to_the_near_side_clears_countdown() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let relay = Relay::new(plug(1, sent.clone()));
+        relay.refresh().unwrap();
+        let pending = relay.turn_off_in(Duration::from_secs(1800)).unwrap().to;
+
+        sent.lock().unwrap().clear();
+        assert_eq!(relay.turn_on().unwrap(), Transition { from: pending, to: RelayState::On });
+        assert_eq!(*sent.lock().unwrap(), ["count_down.delete_all_rules"]);
+        assert!(!relay.turn_on().unwrap().changed());
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_failure_leaves_state_unknown() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> { Err(PlugError::new("unreachable")) };
+        let relay = Relay::new(TpLinkDevice::with_transport("plug", Arc::new(transport)));
+        assert!(matches!(relay.turn_on(), Err(TransitionError::Failed(_))));
+        assert_eq!(relay.state(), RelayState::Unknown);
+    }
+}
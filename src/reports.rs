@@ -0,0 +1,319 @@
+/*
+ * Daily and monthly energy reports, combining the device's own daystat counters
+ * with the power samples kept in a `History`:
+ *
+ *   let reporter = Reporter::new(&history).with_tariff(Tariff::new(0.32, "EUR"));
+ *   println!("{}", reporter.daily_summary("kitchen", &device, date).to_markdown());
+ *
+ * Days are local to the host. When the device can't provide daystat (no meter,
+ * unsupported firmware, unreachable) energy is integrated from the samples
+ * instead and the report is flagged `estimated`.
+ *
+ * With a carbon intensity provider the report also gives the emissions, at the
+ * intensity for the middle of the period. With relay wear counts it gives how
+ * often the relay has switched so far, not only during the period.
+ */
+
+use std::fmt::Write;
+use std::sync::Arc;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::TpLinkDevice;
+use crate::carbon::CarbonIntensityProvider;
+use crate::history::History;
+use crate::integrator::{EnergyIntegrator, DEFAULT_MAX_GAP};
+use crate::reading::PowerReading;
+use crate::tariff::Tariff;
+use crate::wear::RelayWear;
+
+/// Power above which a device counts as switched on for `hours_on`.
+pub const ON_THRESHOLD_W: f64 = 1.0;
+/// Sample gaps longer than this (device offline, poller stopped) don't count towards `hours_on`.
+pub const MAX_SAMPLE_GAP: Duration = DEFAULT_MAX_GAP;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Period {
+    Day { date: NaiveDate },
+    Month { year: i32, month: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Cost {
+    pub amount: f64,
+    pub currency: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EnergyReport {
+    pub device: String,
+    pub period: Period,
+    pub energy_kwh: Option<f64>,
+    /// `energy_kwh` was integrated from samples rather than read from the device.
+    pub estimated: bool,
+    pub min_w: Option<f64>,
+    pub avg_w: Option<f64>,
+    pub peak_w: Option<f64>,
+    pub hours_on: Option<f64>,
+    pub cost: Option<Cost>,
+    /// Grams of CO2 for `energy_kwh`.
+    pub carbon_g: Option<f64>,
+    pub relay_switches: Option<u64>,
+    pub samples: usize,
+}
+
+fn local_midnight(date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    match Local.from_local_datetime(&midnight).earliest() {
+        Some(t) => t.with_timezone(&Utc),
+        None => Utc.from_utc_datetime(&midnight),
+    }
+}
+
+fn first_of_next_month(year: i32, month: u32) -> NaiveDate {
+    if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1).unwrap()
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1).unwrap()
+    }
+}
+
+impl EnergyReport {
+    fn from_samples(device: &str, period: Period, samples: &[(DateTime<Utc>, PowerReading)]) -> EnergyReport {
+        let powers = samples.iter().map(|(_, r)| r.power_w);
+
+        let hours_on = samples.windows(2)
+            .filter(|w| w[0].1.power_w > ON_THRESHOLD_W && w[1].0 - w[0].0 <= MAX_SAMPLE_GAP)
+            .map(|w| (w[1].0 - w[0].0).num_milliseconds() as f64 / 3_600_000.0)
+            .sum();
+
+        EnergyReport {
+            device: String::from(device),
+            period,
+            energy_kwh: None,
+            estimated: false,
+            min_w: powers.clone().reduce(f64::min),
+            avg_w: if samples.is_empty() { None } else { Some(powers.clone().sum::<f64>() / samples.len() as f64) },
+            peak_w: powers.reduce(f64::max),
+            hours_on: if samples.len() < 2 { None } else { Some(hours_on) },
+            cost: None,
+            carbon_g: None,
+            relay_switches: None,
+            samples: samples.len(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let period = match &self.period {
+            Period::Day { date } => date.to_string(),
+            Period::Month { year, month } => format!("{}-{:02}", year, month),
+        };
+        let value = |v: Option<f64>, unit: &str| match v {
+            Some(v) => format!("{:.3} {}", v, unit),
+            None => String::from("n/a"),
+        };
+
+        let mut md = String::new();
+        let _ = writeln!(md, "## {} — {}\n", self.device, period);
+        let _ = writeln!(md, "| Metric | Value |");
+        let _ = writeln!(md, "| --- | --- |");
+        let _ = writeln!(md, "| Energy | {}{} |", value(self.energy_kwh, "kWh"),
+                         if self.estimated { " (estimated)" } else { "" });
+        let _ = writeln!(md, "| Min power | {} |", value(self.min_w, "W"));
+        let _ = writeln!(md, "| Average power | {} |", value(self.avg_w, "W"));
+        let _ = writeln!(md, "| Peak power | {} |", value(self.peak_w, "W"));
+        let _ = writeln!(md, "| Hours on | {} |", value(self.hours_on, "h"));
+        if let Some(cost) = &self.cost {
+            let _ = writeln!(md, "| Cost | {:.2} {} |", cost.amount, cost.currency);
+        }
+        if let Some(carbon_g) = self.carbon_g {
+            let _ = writeln!(md, "| Emissions | {:.0} g CO2 |", carbon_g);
+        }
+        if let Some(switches) = self.relay_switches {
+            let _ = writeln!(md, "| Relay switches | {} |", switches);
+        }
+        let _ = writeln!(md, "| Samples | {} |", self.samples);
+        md
+    }
+}
+
+pub struct Reporter<'a> {
+    history: &'a History,
+    tariff: Option<Tariff>,
+    carbon: Option<Arc<dyn CarbonIntensityProvider>>,
+    wear: Option<&'a RelayWear>,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(history: &'a History) -> Reporter<'a> {
+        Reporter {
+            history,
+            tariff: None,
+            carbon: None,
+            wear: None,
+        }
+    }
+
+    pub fn with_tariff(mut self, tariff: Tariff) -> Reporter<'a> {
+        self.tariff = Some(tariff);
+        self
+    }
+
+    pub fn with_carbon(mut self, provider: Arc<dyn CarbonIntensityProvider>) -> Reporter<'a> {
+        self.carbon = Some(provider);
+        self
+    }
+
+    pub fn with_wear(mut self, wear: &'a RelayWear) -> Reporter<'a> {
+        self.wear = Some(wear);
+        self
+    }
+
+    fn estimate(&self, report: &mut EnergyReport, samples: &[(DateTime<Utc>, PowerReading)]) {
+        if report.energy_kwh.is_none() && samples.len() >= 2 {
+            let integrator = EnergyIntegrator::from_samples(samples, MAX_SAMPLE_GAP);
+            report.energy_kwh = Some(match report.period {
+                Period::Day { date } => integrator.energy_kwh(date).unwrap_or(0.0),
+                Period::Month { year, month } =>
+                    integrator.daystat(year, month).iter().filter_map(|d| d.energy_kwh()).sum(),
+            });
+            report.estimated = true;
+        }
+    }
+
+    fn price(&self, report: &mut EnergyReport) {
+        if let (Some(tariff), Some(energy_kwh)) = (&self.tariff, report.energy_kwh) {
+            report.cost = Some(Cost {
+                amount: tariff.cost(energy_kwh),
+                currency: tariff.currency.clone(),
+            });
+        }
+        if let (Some(carbon), Some(energy_kwh)) = (&self.carbon, report.energy_kwh) {
+            let (start, end) = match report.period {
+                Period::Day { date } => (date, date + Duration::days(1)),
+                Period::Month { year, month } =>
+                    (NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_default(), first_of_next_month(year, month)),
+            };
+            let (start, end) = (local_midnight(start), local_midnight(end));
+            report.carbon_g = carbon.intensity(start + (end - start) / 2).ok().map(|g| g * energy_kwh);
+        }
+    }
+
+    /// `name` is the device's name in the history.
+    pub fn daily_summary(&self, name: &str, device: &TpLinkDevice, date: NaiveDate) -> EnergyReport {
+        let samples = self.history.between(name, local_midnight(date),
+                                           local_midnight(date + Duration::days(1)));
+        let mut report = EnergyReport::from_samples(name, Period::Day { date }, samples);
+
+        report.energy_kwh = device.daystat(date.year(), date.month()).ok()
+            .and_then(|days| days.into_iter().find(|d| d.day == date.day() as i64))
+            .and_then(|d| d.energy_kwh());
+        self.estimate(&mut report, samples);
+        self.price(&mut report);
+        report.relay_switches = self.wear.map(|wear| wear.switches(name));
+        report
+    }
+
+    pub fn monthly_summary(&self, name: &str, device: &TpLinkDevice, year: i32, month: u32) -> EnergyReport {
+        let start = NaiveDate::from_ymd_opt(year, month, 1).unwrap_or_default();
+        let samples = self.history.between(name, local_midnight(start),
+                                           local_midnight(first_of_next_month(year, month)));
+        let mut report = EnergyReport::from_samples(name, Period::Month { year, month }, samples);
+
+        report.energy_kwh = device.daystat(year, month).ok()
+            .map(|days| days.iter().filter_map(|d| d.energy_kwh()).sum());
+        self.estimate(&mut report, samples);
+        self.price(&mut report);
+        report.relay_switches = self.wear.map(|wear| wear.switches(name));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use chrono::{Local, NaiveDate, TimeZone, Utc};
+    use crate::TpLinkDevice;
+    use crate::carbon::StaticIntensity;
+    use crate::history::History;
+    use crate::protocol::encrypt_payload;
+    use crate::reading::PowerReading;
+    use crate::tariff::Tariff;
+    use crate::types::PlugError;
+    use crate::wear::RelayWear;
+    use super::Reporter;
+
+    fn meter() -> TpLinkDevice {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"emeter":{"get_daystat":{"day_list":[
+                {"year":2024,"month":6,"day":2,"energy_wh":1200},
+                {"year":2024,"month":6,"day":3,"energy_wh":800}],"err_code":0}}}"#.to_vec()))
+        };
+        TpLinkDevice::with_transport("meter", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_daily_summary() {
+        let mut history = History::new();
+        let at = |h, m| Local.with_ymd_and_hms(2024, 6, 3, h, m, 0).unwrap().with_timezone(&Utc);
+        for (t, power_w) in [(at(10, 0), 100.0), (at(10, 5), 300.0), (at(10, 10), 0.5), (at(11, 0), 200.0)] {
+            history.record("kettle", t, PowerReading { power_w, ..PowerReading::default() });
+        }
+
+        let mut wear = RelayWear::new();
+        wear.record("kettle", at(10, 0));
+        wear.record("kettle", at(10, 10));
+        let reporter = Reporter::new(&history).with_tariff(Tariff::new(0.25, "EUR"))
+            .with_carbon(Arc::new(StaticIntensity(250.0)))
+            .with_wear(&wear);
+        let report = reporter.daily_summary("kettle", &meter(), NaiveDate::from_ymd_opt(2024, 6, 3).unwrap());
+
+        assert_eq!(report.energy_kwh, Some(0.8));
+        assert_eq!(report.cost.as_ref().unwrap().amount, 0.2);
+        assert_eq!((report.min_w, report.peak_w), (Some(0.5), Some(300.0)));
+        assert_eq!(report.avg_w, Some(150.125));
+        // Two 5 minute stretches above the threshold, then 50 minutes in standby.
+        assert_eq!(report.hours_on, Some(10.0 / 60.0));
+        assert!(report.to_markdown().contains("| Cost | 0.20 EUR |"));
+        assert!(report.to_markdown().contains("| Emissions | 200 g CO2 |"));
+        assert!(report.to_markdown().contains("| Relay switches | 2 |"));
+        assert!(report.to_json().contains("\"kind\": \"day\""));
+    }
+
+    #[test]
+    fn test_estimated_without_daystat() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Err(PlugError::new("Connection error"))
+        };
+        let device = TpLinkDevice::with_transport("hs100", Arc::new(transport));
+
+        let mut history = History::new();
+        let at = |h, m| Local.with_ymd_and_hms(2024, 6, 3, h, m, 0).unwrap().with_timezone(&Utc);
+        for m in (0..=60).step_by(10) {
+            history.record("lamp", at(20, 0) + chrono::Duration::minutes(m),
+                           PowerReading { power_w: 60.0, ..PowerReading::default() });
+        }
+
+        let report = Reporter::new(&history)
+            .daily_summary("lamp", &device, NaiveDate::from_ymd_opt(2024, 6, 3).unwrap());
+        assert!(report.estimated);
+        assert!((report.energy_kwh.unwrap() - 0.06).abs() < 1e-9);
+        assert!(report.to_markdown().contains("(estimated)"));
+    }
+
+    #[test]
+    fn test_monthly_summary_without_samples() {
+        let history = History::new();
+        let report = Reporter::new(&history).monthly_summary("kettle", &meter(), 2024, 6);
+
+        assert_eq!(report.energy_kwh, Some(2.0));
+        assert_eq!(report.samples, 0);
+        assert_eq!(report.avg_w, None);
+        assert!(report.cost.is_none());
+    }
+}
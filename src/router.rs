@@ -0,0 +1,193 @@
+/*
+ * Which namespace and method carry a logical operation on each device family.
+ * Plugs switch with `system.set_relay_state`, bulbs with the lighting service,
+ * bulbs keep their meter in `smartlife.iot.common.emeter`, and so on.
+ *
+ * `FAMILIES` is searched in order: entries limited to some models come before
+ * the catch-all entry for their kind, and only need the routes that differ.
+ * Supporting a new model that speaks a known dialect is one more entry here.
+ */
+
+use alloc::format;
+use serde_json::{json, Map, Value};
+
+use crate::DeviceType;
+use crate::bulb::LightState;
+use crate::commands::{self, StaticCommand};
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    SetPower,
+    SetBrightness,
+    SetLightState,
+    SetAlias,
+    GetRealtime,
+    GetDaystat,
+    GetMonthstat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub namespace: &'static str,
+    pub method: &'static str,
+    /// What the device calls the operation's argument, if it takes a single one.
+    pub argument: &'static str,
+}
+
+pub struct Family {
+    pub kind: DeviceType,
+    /// Model prefixes the entry is limited to; every model of `kind` if empty.
+    pub models: &'static [&'static str],
+    pub routes: &'static [(Operation, Route)],
+}
+
+const fn route(namespace: &'static str, method: &'static str, argument: &'static str) -> Route {
+    Route {
+        namespace,
+        method,
+        argument,
+    }
+}
+
+const PLUG: &[(Operation, Route)] = &[
+    (Operation::SetPower, route("system", "set_relay_state", "state")),
+    (Operation::SetAlias, route("system", "set_dev_alias", "alias")),
+    (Operation::GetRealtime, route("emeter", "get_realtime", "")),
+    (Operation::GetDaystat, route("emeter", "get_daystat", "")),
+    (Operation::GetMonthstat, route("emeter", "get_monthstat", "")),
+];
+
+const DIMMER: &[(Operation, Route)] = &[
+    (Operation::SetBrightness, route("smartlife.iot.dimmer", "set_brightness", "brightness")),
+];
+
+const BULB: &[(Operation, Route)] = &[
+    (Operation::SetPower, route("smartlife.iot.smartbulb.lightingservice", "transition_light_state", "on_off")),
+    (Operation::SetBrightness, route("smartlife.iot.smartbulb.lightingservice", "transition_light_state", "brightness")),
+    (Operation::SetLightState, route("smartlife.iot.smartbulb.lightingservice", "transition_light_state", "")),
+    (Operation::SetAlias, route("smartlife.iot.common.system", "set_dev_alias", "alias")),
+    (Operation::GetRealtime, route("smartlife.iot.common.emeter", "get_realtime", "")),
+    (Operation::GetDaystat, route("smartlife.iot.common.emeter", "get_daystat", "")),
+    (Operation::GetMonthstat, route("smartlife.iot.common.emeter", "get_monthstat", "")),
+];
+
+const LIGHT_STRIP: &[(Operation, Route)] = &[
+    (Operation::SetPower, route("smartlife.iot.lightStrip", "set_light_state", "on_off")),
+    (Operation::SetBrightness, route("smartlife.iot.lightStrip", "set_light_state", "brightness")),
+    (Operation::SetLightState, route("smartlife.iot.lightStrip", "set_light_state", "")),
+];
+
+pub const FAMILIES: &[Family] = &[
+    Family { kind: DeviceType::Bulb, models: &["KL400", "KL420", "KL430"], routes: LIGHT_STRIP },
+    Family { kind: DeviceType::Bulb, models: &[], routes: BULB },
+    Family { kind: DeviceType::Dimmer, models: &[], routes: DIMMER },
+    Family { kind: DeviceType::Dimmer, models: &[], routes: PLUG },
+    Family { kind: DeviceType::Strip, models: &[], routes: PLUG },
+    Family { kind: DeviceType::Plug, models: &[], routes: PLUG },
+];
+
+/// Devices of unknown kind are routed like plugs.
+pub fn resolve(kind: DeviceType, model: Option<&str>, operation: Operation) -> Option<Route> {
+    let kind = if kind == DeviceType::Unknown { DeviceType::Plug } else { kind };
+    FAMILIES.iter()
+        .filter(|f| f.kind == kind)
+        .filter(|f| f.models.is_empty() || model.is_some_and(|m| f.models.iter().any(|p| m.starts_with(p))))
+        .find_map(|f| f.routes.iter().find(|(op, _)| *op == operation).map(|(_, r)| *r))
+}
+
+/// A logical operation together with its arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request<'a> {
+    Power(bool),
+    Brightness(u8),
+    LightState(&'a LightState),
+    Alias(&'a str),
+    Realtime,
+    Daystat { year: i32, month: u32 },
+    Monthstat { year: i32 },
+}
+
+impl Request<'_> {
+    pub fn operation(&self) -> Operation {
+        match self {
+            Request::Power(_) => Operation::SetPower,
+            Request::Brightness(_) => Operation::SetBrightness,
+            Request::LightState(_) => Operation::SetLightState,
+            Request::Alias(_) => Operation::SetAlias,
+            Request::Realtime => Operation::GetRealtime,
+            Request::Daystat { .. } => Operation::GetDaystat,
+            Request::Monthstat { .. } => Operation::GetMonthstat,
+        }
+    }
+
+    fn arguments(&self, route: &Route) -> Value {
+        let single = |value: Value| {
+            let mut arguments = Map::new();
+            arguments.insert(route.argument.into(), value);
+            Value::Object(arguments)
+        };
+        match self {
+            Request::Power(on) => single(json!(if *on { 1 } else { 0 })),
+            Request::Brightness(brightness) => single(json!(brightness)),
+            Request::Alias(alias) => single(json!(alias)),
+            Request::LightState(state) => serde_json::to_value(state).unwrap_or(Value::Null),
+            Request::Realtime => json!({}),
+            Request::Daystat { year, month } => json!({"year": year, "month": month}),
+            Request::Monthstat { year } => json!({"year": year}),
+        }
+    }
+}
+
+/// The command that carries out `request` on a device of this kind and model.
+pub fn command(kind: DeviceType, model: Option<&str>, request: &Request) -> Result<Value, PlugError> {
+    match resolve(kind, model, request.operation()) {
+        Some(route) => Ok(json!({route.namespace: {route.method: request.arguments(&route)}})),
+        None => Err(PlugError::new(
+            format!("{:?} is not supported by {:?} devices", request.operation(), kind).as_str())),
+    }
+}
+
+/// The precomputed frame for `request` on this kind and model, if there is one.
+pub fn static_command(kind: DeviceType, model: Option<&str>, request: &Request) -> Option<StaticCommand> {
+    let route = resolve(kind, model, request.operation())?;
+    match (request, route.namespace, route.method) {
+        (Request::Realtime, "emeter", "get_realtime") => Some(commands::GET_REALTIME),
+        (Request::Power(true), "system", "set_relay_state") => Some(commands::RELAY_ON),
+        (Request::Power(false), "system", "set_relay_state") => Some(commands::RELAY_OFF),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::DeviceType;
+    use crate::{bindings, commands};
+    use super::{command, static_command, Request};
+
+    #[test]
+    fn test_same_commands_as_before_for_plugs() {
+        assert_eq!(command(DeviceType::Unknown, None, &Request::Power(true)).unwrap(), commands::set_relay_state(1));
+        assert_eq!(command(DeviceType::Plug, Some("HS110(EU)"), &Request::Daystat { year: 2024, month: 6 }).unwrap(),
+                   commands::get_daystat(2024, 6));
+        assert_eq!(command(DeviceType::Plug, None, &Request::Monthstat { year: 2024 }).unwrap(), bindings::get_monthstat(2024));
+        assert!(command(DeviceType::Plug, None, &Request::Brightness(50)).is_err());
+        assert_eq!(static_command(DeviceType::Strip, None, &Request::Power(false)), Some(commands::RELAY_OFF));
+        assert_eq!(static_command(DeviceType::Bulb, None, &Request::Realtime), None);
+    }
+
+    #[test]
+    fn test_family_and_model_routes() {
+        assert_eq!(command(DeviceType::Dimmer, Some("HS220(US)"), &Request::Brightness(40)).unwrap(),
+                   json!({"smartlife.iot.dimmer": {"set_brightness": {"brightness": 40}}}));
+        assert_eq!(command(DeviceType::Dimmer, Some("HS220(US)"), &Request::Power(false)).unwrap(),
+                   commands::set_relay_state(0));
+        assert_eq!(command(DeviceType::Bulb, Some("KL430(US)"), &Request::Power(true)).unwrap(),
+                   json!({"smartlife.iot.lightStrip": {"set_light_state": {"on_off": 1}}}));
+        assert_eq!(command(DeviceType::Bulb, Some("KL430(US)"), &Request::Realtime).unwrap(),
+                   json!({"smartlife.iot.common.emeter": {"get_realtime": {}}}));
+        assert_eq!(command(DeviceType::Bulb, Some("KL130(EU)"), &Request::Monthstat { year: 2024 }).unwrap(),
+                   json!({"smartlife.iot.common.emeter": {"get_monthstat": {"year": 2024}}}));
+    }
+}
@@ -0,0 +1,485 @@
+/*
+ * A small rules engine on top of the watcher's events:
+ *
+ *   let mut engine = RuleEngine::new();
+ *   engine.device("heater", heater).device("fan", fan);
+ *   engine.rule(Rule::new("fan follows heater")
+ *       .when(Trigger::RelayChanged { device: "heater".into(), on: Some(true) })
+ *       .then(Action::TurnOn("fan".into())));
+ *   engine.run(watcher.spawn().into());
+ *
+ * Power triggers fire when a reading crosses the threshold, not on every sample
+ * past it.
+ */
+
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+use std::sync::Arc;
+use chrono::{DateTime, Local, NaiveTime, Timelike, Utc};
+
+use crate::TpLinkDevice;
+use crate::bus::EventBus;
+use crate::carbon::CarbonIntensityProvider;
+use crate::cron::CronSchedule;
+use crate::events::Event;
+use crate::strip::ChildPlug;
+use crate::types::PlugError;
+
+pub enum Trigger {
+    Cron(CronSchedule),
+    PowerAbove { device: String, watts: f64 },
+    PowerBelow { device: String, watts: f64 },
+    /// `on: None` fires on any change.
+    RelayChanged { device: String, on: Option<bool> },
+    Online { device: String },
+    Offline { device: String },
+    /// Any alert raised for the device, e.g. by the anomaly detector.
+    Alert { device: String },
+}
+
+impl Trigger {
+    pub fn at(hour: u32, minute: u32) -> Result<Trigger, PlugError> {
+        Ok(Trigger::Cron(CronSchedule::parse(format!("{} {} * * *", minute, hour).as_str())?))
+    }
+}
+
+pub enum Condition {
+    RelayIs { device: String, on: bool },
+    PowerAbove { device: String, watts: f64 },
+    PowerBelow { device: String, watts: f64 },
+    IsOnline { device: String },
+    /// Wraps around midnight when `from` is later than `to`.
+    TimeBetween { from: NaiveTime, to: NaiveTime },
+    /// Grid carbon intensity, from the engine's provider; never holds without one.
+    CarbonBelow { g_per_kwh: f64 },
+    CarbonAbove { g_per_kwh: f64 },
+}
+
+pub struct Context<'a> {
+    pub rule: &'a str,
+    /// The event that triggered the rule, `None` for time triggers.
+    pub event: Option<&'a Event>,
+}
+
+pub enum Action {
+    TurnOn(String),
+    TurnOff(String),
+    Callback(Box<dyn Fn(&Context) + Send>),
+    /// Posts `{"event": "rule_fired", "rule": ..., "trigger": <event payload or null>}`.
+    #[cfg(feature = "webhook")]
+    Webhook(crate::webhook::Webhook),
+}
+
+pub struct Rule {
+    name: String,
+    triggers: Vec<Trigger>,
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+impl Rule {
+    pub fn new(name: &str) -> Rule {
+        Rule {
+            name: String::from(name),
+            triggers: Vec::new(),
+            conditions: Vec::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    /// Adds a trigger; the rule fires when any of its triggers does.
+    pub fn when(mut self, trigger: Trigger) -> Rule {
+        self.triggers.push(trigger);
+        self
+    }
+
+    /// Adds a condition; all of them must hold for the actions to run.
+    pub fn only_if(mut self, condition: Condition) -> Rule {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn then(mut self, action: Action) -> Rule {
+        self.actions.push(action);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DeviceState {
+    online: Option<bool>,
+    relay_on: Option<bool>,
+    power_w: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub rule: String,
+    pub result: Result<(), PlugError>,
+}
+
+pub struct RuleEngine {
+    devices: HashMap<String, TpLinkDevice>,
+    outlets: HashMap<String, ChildPlug>,
+    rules: Vec<Rule>,
+    states: HashMap<String, DeviceState>,
+    last_tick: Option<DateTime<Local>>,
+    bus: Option<EventBus>,
+    carbon: Option<Arc<dyn CarbonIntensityProvider>>,
+}
+
+impl Default for RuleEngine {
+    fn default() -> RuleEngine {
+        RuleEngine::new()
+    }
+}
+
+fn crossed_above(before: Option<f64>, after: f64, watts: f64) -> bool {
+    after > watts && before.is_none_or(|before| before <= watts)
+}
+
+fn crossed_below(before: Option<f64>, after: f64, watts: f64) -> bool {
+    after < watts && before.is_none_or(|before| before >= watts)
+}
+
+impl RuleEngine {
+    pub fn new() -> RuleEngine {
+        RuleEngine {
+            devices: HashMap::new(),
+            outlets: HashMap::new(),
+            rules: Vec::new(),
+            states: HashMap::new(),
+            last_tick: None,
+            bus: None,
+            carbon: None,
+        }
+    }
+
+    /// Registers a device that actions can refer to by `name`.
+    pub fn device(&mut self, name: &str, device: TpLinkDevice) -> &mut RuleEngine {
+        self.devices.insert(String::from(name), device);
+        self
+    }
+
+    /// Registers a strip outlet that actions can refer to by `name`, like a device.
+    pub fn outlet(&mut self, name: &str, outlet: ChildPlug) -> &mut RuleEngine {
+        self.outlets.insert(String::from(name), outlet);
+        self
+    }
+
+    pub fn rule(&mut self, rule: Rule) -> &mut RuleEngine {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Publishes a `CommandFailed` event whenever a device can't be switched.
+    pub fn publish_to(&mut self, bus: &EventBus) -> &mut RuleEngine {
+        self.bus = Some(bus.clone());
+        self
+    }
+
+    /// Where `Condition::CarbonBelow` and `CarbonAbove` get the grid's intensity.
+    pub fn carbon(&mut self, provider: Arc<dyn CarbonIntensityProvider>) -> &mut RuleEngine {
+        self.carbon = Some(provider);
+        self
+    }
+
+    fn intensity(&self, now: &DateTime<Local>) -> Option<f64> {
+        self.carbon.as_ref()?.intensity(now.with_timezone(&Utc)).ok()
+    }
+
+    fn state(&self, device: &str) -> DeviceState {
+        self.states.get(device).cloned().unwrap_or_default()
+    }
+
+    fn triggered_by(trigger: &Trigger, event: &Event, before: &DeviceState) -> bool {
+        match (trigger, event) {
+            (Trigger::PowerAbove { device, watts }, Event::PowerSample { device: d, reading }) =>
+                device == d && crossed_above(before.power_w, reading.power_w, *watts),
+            (Trigger::PowerBelow { device, watts }, Event::PowerSample { device: d, reading }) =>
+                device == d && crossed_below(before.power_w, reading.power_w, *watts),
+            (Trigger::RelayChanged { device, on }, Event::RelayChanged { device: d, on: now_on }) =>
+                device == d && on.is_none_or(|on| on == *now_on),
+            (Trigger::Online { device }, Event::DeviceOnline { device: d }) => device == d,
+            (Trigger::Offline { device }, Event::DeviceOffline { device: d, .. }) => device == d,
+            (Trigger::Alert { device }, Event::AlertRaised { device: d, .. }) => device == d,
+            _ => false,
+        }
+    }
+
+    fn holds(&self, condition: &Condition, now: &DateTime<Local>) -> bool {
+        match condition {
+            Condition::RelayIs { device, on } => self.state(device).relay_on == Some(*on),
+            Condition::PowerAbove { device, watts } =>
+                self.state(device).power_w.is_some_and(|p| p > *watts),
+            Condition::PowerBelow { device, watts } =>
+                self.state(device).power_w.is_some_and(|p| p < *watts),
+            Condition::IsOnline { device } => self.state(device).online == Some(true),
+            Condition::TimeBetween { from, to } => {
+                let t = now.time();
+                if from <= to {
+                    *from <= t && t < *to
+                } else {
+                    *from <= t || t < *to
+                }
+            }
+            Condition::CarbonBelow { g_per_kwh } => self.intensity(now).is_some_and(|g| g < *g_per_kwh),
+            Condition::CarbonAbove { g_per_kwh } => self.intensity(now).is_some_and(|g| g > *g_per_kwh),
+        }
+    }
+
+    fn switch(&self, name: &str, on: bool) -> Result<(), PlugError> {
+        if let Some(outlet) = self.outlets.get(name) {
+            return if on { outlet.on() } else { outlet.off() }.map(|_| ());
+        }
+        let device = match self.devices.get(name) {
+            Some(device) => device,
+            None => return Err(PlugError::new(format!("Unknown device: {}", name).as_str())),
+        };
+
+        if on { device.on() } else { device.off() }.map(|_| ())
+    }
+
+    /// Switches and reports a failure on the bus, if there is one.
+    fn switched(&self, name: &str, on: bool) -> Result<(), PlugError> {
+        let result = self.switch(name, on);
+        if let (Err(e), Some(bus)) = (&result, &self.bus) {
+            bus.publish(Event::command_failed(name, e));
+        }
+        result
+    }
+
+    fn fire(&self, rule: &Rule, event: Option<&Event>, now: &DateTime<Local>) -> Vec<Outcome> {
+        if !rule.conditions.iter().all(|c| self.holds(c, now)) {
+            return Vec::new();
+        }
+
+        let context = Context {
+            rule: &rule.name,
+            event,
+        };
+
+        rule.actions.iter()
+            .map(|action| {
+                let result = match action {
+                    Action::TurnOn(name) => self.switched(name, true),
+                    Action::TurnOff(name) => self.switched(name, false),
+                    Action::Callback(callback) => {
+                        callback(&context);
+                        Ok(())
+                    }
+                    #[cfg(feature = "webhook")]
+                    Action::Webhook(hook) => hook.post(&serde_json::json!({
+                        "event": "rule_fired",
+                        "rule": rule.name,
+                        "trigger": event.map(crate::webhook::payload),
+                    })),
+                };
+                Outcome {
+                    rule: rule.name.clone(),
+                    result,
+                }
+            })
+            .collect()
+    }
+
+    pub fn handle(&mut self, event: &Event) -> Vec<Outcome> {
+        self.handle_at(event, Local::now())
+    }
+
+    pub fn handle_at(&mut self, event: &Event, now: DateTime<Local>) -> Vec<Outcome> {
+        let before = self.state(event.device());
+
+        let mut after = before.clone();
+        match event {
+            Event::PowerSample { reading, .. } => after.power_w = Some(reading.power_w),
+            Event::RelayChanged { on, .. } => after.relay_on = Some(*on),
+            Event::DeviceOnline { .. } => after.online = Some(true),
+            Event::DeviceOffline { .. } => after.online = Some(false),
+            Event::FieldChanged { .. } | Event::AlertRaised { .. } | Event::CommandFailed { .. } => {}
+        }
+        self.states.insert(String::from(event.device()), after);
+
+        let mut outcomes = Vec::new();
+        for rule in &self.rules {
+            if rule.triggers.iter().any(|t| RuleEngine::triggered_by(t, event, &before)) {
+                outcomes.extend(self.fire(rule, Some(event), &now));
+            }
+        }
+        outcomes
+    }
+
+    /// Fires time triggers that matched a minute in (previous tick, `now`].
+    pub fn tick(&mut self, now: DateTime<Local>) -> Vec<Outcome> {
+        let since = match self.last_tick.replace(now) {
+            Some(since) => since,
+            None => return Vec::new(),
+        };
+
+        let mut outcomes = Vec::new();
+        for rule in &self.rules {
+            let due = rule.triggers.iter().any(|t| match t {
+                Trigger::Cron(cron) => cron.next_after(&since).is_some_and(|next| next <= now),
+                _ => false,
+            });
+            if due {
+                outcomes.extend(self.fire(rule, None, &now));
+            }
+        }
+        outcomes
+    }
+
+    /// Handles events until the sender side hangs up, checking time triggers in between.
+    pub fn run(&mut self, events: Receiver<Event>) {
+        self.tick(Local::now());
+        loop {
+            let until_next_second = Duration::from_nanos(
+                1_000_000_000 - Local::now().nanosecond().min(999_999_999) as u64);
+            match events.recv_timeout(until_next_second) {
+                Ok(event) => {
+                    self.handle(&event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.tick(Local::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use chrono::{Local, TimeZone, Utc};
+    use crate::TpLinkDevice;
+    use crate::events::Event;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::reading::PowerReading;
+    use crate::types::PlugError;
+    use super::*;
+
+    fn sample(device: &str, power_w: f64) -> Event {
+        Event::PowerSample {
+            device: String::from(device),
+            reading: PowerReading { power_w, ..PowerReading::default() },
+        }
+    }
+
+    #[test]
+    fn test_power_threshold_fires_on_crossing() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            seen.lock().unwrap().push(String::from_utf8(decrypt_payload(frame)).unwrap());
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()))
+        };
+
+        let mut engine = RuleEngine::new();
+        engine
+            .device("heater", TpLinkDevice::with_transport("heater", Arc::new(transport)))
+            .rule(Rule::new("shed heater")
+                .when(Trigger::PowerAbove { device: String::from("kettle"), watts: 1000.0 })
+                .only_if(Condition::IsOnline { device: String::from("kettle") })
+                .then(Action::TurnOff(String::from("heater"))));
+
+        // Not online yet, so the condition fails.
+        assert!(engine.handle(&sample("kettle", 2000.0)).is_empty());
+
+        engine.handle(&Event::DeviceOnline { device: String::from("kettle") });
+        assert!(engine.handle(&sample("kettle", 2000.0)).is_empty());
+        assert!(engine.handle(&sample("kettle", 10.0)).is_empty());
+
+        let outcomes = engine.handle(&sample("kettle", 2100.0));
+        assert_eq!(outcomes.len(), 1);
+        assert!(outcomes[0].result.is_ok());
+        assert_eq!(*requests.lock().unwrap(), vec![r#"{"system":{"set_relay_state":{"state":0}}}"#]);
+    }
+
+    #[test]
+    fn test_time_trigger_and_callback() {
+        let fired = Arc::new(Mutex::new(0));
+        let counter = fired.clone();
+
+        let mut engine = RuleEngine::new();
+        engine.rule(Rule::new("morning")
+            .when(Trigger::at(7, 30).unwrap())
+            .then(Action::Callback(Box::new(move |ctx| {
+                assert!(ctx.event.is_none());
+                *counter.lock().unwrap() += 1;
+            }))));
+
+        let t = |h, m| Local.with_ymd_and_hms(2024, 6, 3, h, m, 0).unwrap();
+        engine.tick(t(7, 0));
+        engine.tick(t(7, 29));
+        assert_eq!(*fired.lock().unwrap(), 0);
+        engine.tick(t(7, 31));
+        engine.tick(t(8, 0));
+        assert_eq!(*fired.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_carbon_condition() {
+        let fired = Arc::new(Mutex::new(0));
+        let counter = fired.clone();
+        let mut engine = RuleEngine::new();
+        engine.rule(Rule::new("pool pump")
+            .when(Trigger::Online { device: String::from("pump") })
+            .only_if(Condition::CarbonBelow { g_per_kwh: 150.0 })
+            .then(Action::Callback(Box::new(move |_| *counter.lock().unwrap() += 1))));
+
+        let online = Event::DeviceOnline { device: String::from("pump") };
+        engine.handle(&online);
+        let grid = |at: DateTime<Utc>| -> Result<f64, PlugError> { Ok(if at.hour() < 12 { 120.0 } else { 250.0 }) };
+        engine.carbon(Arc::new(grid));
+        engine.handle_at(&online, Utc.with_ymd_and_hms(2024, 6, 3, 15, 0, 0).unwrap().with_timezone(&Local));
+        assert_eq!(*fired.lock().unwrap(), 0);
+        engine.handle_at(&online, Utc.with_ymd_and_hms(2024, 6, 3, 9, 0, 0).unwrap().with_timezone(&Local));
+        assert_eq!(*fired.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_outlet_action() {
+        let strip = crate::strip::Strip::new(crate::strip::tests::hs300());
+        let mut engine = RuleEngine::new();
+        engine.outlet("lamp", strip.child("Lamp").unwrap())
+            .rule(Rule::new("lamp follows tv")
+                .when(Trigger::Online { device: String::from("tv") })
+                .then(Action::TurnOn(String::from("lamp"))));
+
+        let outcomes = engine.handle(&Event::DeviceOnline { device: String::from("tv") });
+        assert!(outcomes[0].result.is_ok());
+    }
+
+    #[test]
+    fn test_failures_published() {
+        let silent = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> { Ok(Vec::new()) };
+        let bus = crate::bus::EventBus::new();
+        let events = bus.subscribe();
+        let mut engine = RuleEngine::new();
+        engine.device("fan", TpLinkDevice::with_transport("fan", Arc::new(silent)))
+            .publish_to(&bus)
+            .rule(Rule::new("fan follows tv")
+                .when(Trigger::Online { device: String::from("tv") })
+                .then(Action::TurnOn(String::from("fan"))));
+
+        assert!(engine.handle(&Event::DeviceOnline { device: String::from("tv") })[0].result.is_err());
+        assert_eq!(events.try_recv().unwrap(), Event::CommandFailed {
+            device: String::from("fan"),
+            command: String::from("system.set_relay_state"),
+            reason: String::from("Empty response"),
+        });
+    }
+
+    #[test]
+    fn test_unknown_device_action() {
+        let mut engine = RuleEngine::new();
+        engine.rule(Rule::new("r")
+            .when(Trigger::Offline { device: String::from("a") })
+            .then(Action::TurnOn(String::from("missing"))));
+
+        let outcomes = engine.handle(&Event::DeviceOffline {
+            device: String::from("a"), reason: String::from("timeout") });
+        assert!(outcomes[0].result.is_err());
+    }
+}
@@ -0,0 +1,552 @@
+/*
+ * Rules kept on the device itself, so they run without a host: the countdown
+ * timer (`count_down`) and the weekly schedule (`schedule`). Both are available
+ * on plugs and on individual strip outlets:
+ *
+ *   plug.add_schedule(&ScheduleRule::at(7, 30, true).on_days([false, true, true, true, true, true, false]))?;
+ *   strip.child("Lamp")?.add_countdown(&CountdownRule::new(1800, false))?;
+ *
+ * Most firmwares keep a single countdown rule and reject a second one, so
+ * `clear_countdowns` first when replacing it. Schedules are accepted whatever
+ * they hold, and rules beyond the device's capacity or fighting each other
+ * are simply not run; `add_schedule_checked` and `set_schedules` refuse those
+ * with a `PlugError::Schedule` before anything is sent.
+ *
+ * While a countdown runs, the device reports the seconds it has left:
+ *
+ *   if let Some((left, target)) = plug.active_countdown()? {
+ *       println!("turns {} in {}:{:02}", target, left.as_secs() / 60, left.as_secs() % 60);
+ *   }
+ */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Formatter};
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::TpLinkDevice;
+use crate::commands;
+use crate::strip::ChildPlug;
+use crate::types::{PlugError, PlugResponse};
+
+fn flag(on: bool) -> i64 {
+    if on { 1 } else { 0 }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct CountdownRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub enable: i64,
+    /// Seconds until the relay is switched.
+    pub delay: i64,
+    /// 1 to switch on, 0 to switch off.
+    pub act: i64,
+    /// Seconds left while the countdown runs. Only the device fills this in.
+    #[serde(skip_serializing)]
+    pub remain: Option<i64>,
+}
+
+/// What a rule switches the relay to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TargetState {
+    On,
+    Off,
+}
+
+impl fmt::Display for TargetState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TargetState::On => "on",
+            TargetState::Off => "off",
+        })
+    }
+}
+
+impl CountdownRule {
+    pub fn new(delay_s: u32, turn_on: bool) -> CountdownRule {
+        CountdownRule {
+            id: None,
+            name: String::from(if turn_on { "turn on" } else { "turn off" }),
+            enable: 1,
+            delay: delay_s as i64,
+            act: flag(turn_on),
+            remain: None,
+        }
+    }
+
+    pub fn target(&self) -> TargetState {
+        if self.act != 0 { TargetState::On } else { TargetState::Off }
+    }
+
+    /// Time left, `None` unless the rule is enabled and still running.
+    pub fn remaining(&self) -> Option<Duration> {
+        match self.remain {
+            Some(remain) if self.enable != 0 && remain > 0 => Some(Duration::from_secs(remain as u64)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ScheduleRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub enable: i64,
+    /// Sunday first; all zeroes with `repeat` 0 and a date set means once.
+    pub wday: Vec<i64>,
+    pub repeat: i64,
+    /// 0 for `smin`, 1 for sunrise, 2 for sunset.
+    pub stime_opt: i64,
+    /// Start in minutes after midnight, device local time.
+    pub smin: i64,
+    /// 1 to switch on, 0 to switch off.
+    pub sact: i64,
+    /// -1 when the rule has no end action.
+    pub etime_opt: i64,
+    pub emin: i64,
+    pub eact: i64,
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub force: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl ScheduleRule {
+    /// Switches at `hour:minute` every day.
+    pub fn at(hour: u32, minute: u32, turn_on: bool) -> ScheduleRule {
+        ScheduleRule {
+            id: None,
+            name: String::from(if turn_on { "turn on" } else { "turn off" }),
+            enable: 1,
+            wday: alloc::vec![1; 7],
+            repeat: 1,
+            stime_opt: 0,
+            smin: (hour * 60 + minute) as i64,
+            sact: flag(turn_on),
+            etime_opt: -1,
+            emin: 0,
+            eact: -1,
+            ..ScheduleRule::default()
+        }
+    }
+
+    /// Restricts the rule to some days of the week, Sunday first.
+    pub fn on_days(mut self, days: [bool; 7]) -> ScheduleRule {
+        self.wday = days.iter().map(|d| flag(*d)).collect();
+        self
+    }
+
+    pub fn named(mut self, name: &str) -> ScheduleRule {
+        self.name = String::from(name);
+        self
+    }
+
+    /// The rule `minutes` later, or earlier for negative ones. Days move along
+    /// when it crosses midnight; sunrise and sunset rules are left as they are.
+    pub fn shifted(&self, minutes: i64) -> ScheduleRule {
+        if self.stime_opt != 0 {
+            return self.clone();
+        }
+        let start = self.smin + minutes;
+        let mut shifted = ScheduleRule { smin: start.rem_euclid(DAY), ..self.clone() };
+        if self.etime_opt == 0 {
+            shifted.emin = (self.emin + minutes).rem_euclid(DAY);
+        }
+        let days = start.div_euclid(DAY);
+        if days != 0 && shifted.wday.len() == 7 {
+            shifted.wday.rotate_right(days.rem_euclid(7) as usize);
+        }
+        shifted
+    }
+}
+
+/// Most firmwares keep this many schedule rules and quietly drop any beyond.
+pub const MAX_SCHEDULE_RULES: usize = 32;
+
+/// Why `check_rules` refused a list of rules. Rules are given by their position
+/// in the list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleProblem {
+    TooMany { count: usize, max: usize },
+    /// Two rules switch opposite ways at the same minute of `day`, Sunday first.
+    Conflict { first: usize, second: usize, day: usize, minute: i64 },
+    /// Two rules with an end action are active at the same time.
+    Overlap { first: usize, second: usize },
+}
+
+impl fmt::Display for ScheduleProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleProblem::TooMany { count, max } =>
+                write!(f, "{} schedule rules, the device keeps at most {}", count, max),
+            ScheduleProblem::Conflict { first, second, day, minute } =>
+                write!(f, "Rules {} and {} switch opposite ways on day {} at {:02}:{:02}",
+                       first, second, day, minute / 60, minute % 60),
+            ScheduleProblem::Overlap { first, second } =>
+                write!(f, "Rules {} and {} are active at the same time", first, second),
+        }
+    }
+}
+
+const DAY: i64 = 24 * 60;
+const WEEK: i64 = 7 * DAY;
+
+/// Minutes into the week at which `rule` starts, for enabled rules at a fixed
+/// time. Sunrise, sunset and one-off rules move around and aren't checked.
+fn starts(rule: &ScheduleRule) -> Vec<i64> {
+    if rule.enable == 0 || rule.stime_opt != 0 || rule.wday.len() != 7 {
+        return Vec::new();
+    }
+    rule.wday.iter().enumerate()
+        .filter(|(_, on)| **on != 0)
+        .map(|(day, _)| day as i64 * DAY + rule.smin)
+        .collect()
+}
+
+/// How long the rule lasts, if it has an end action at a fixed time.
+fn length(rule: &ScheduleRule) -> Option<i64> {
+    (rule.etime_opt == 0).then(|| (rule.emin - rule.smin).rem_euclid(DAY))
+}
+
+/// Minute into the week and action of everything `rule` does.
+fn switches(rule: &ScheduleRule) -> Vec<(i64, i64)> {
+    let mut switches = Vec::new();
+    for start in starts(rule) {
+        switches.push((start, rule.sact));
+        if let Some(length) = length(rule) {
+            switches.push(((start + length) % WEEK, rule.eact));
+        }
+    }
+    switches
+}
+
+fn overlaps(a: (i64, i64), b: (i64, i64)) -> bool {
+    [-WEEK, 0, WEEK].iter().any(|shift| a.0 < b.1 + shift && b.0 + shift < a.1)
+}
+
+/// Checks rules before they are sent: the firmware doesn't complain about too
+/// many or contradicting rules, it just doesn't run some of them.
+pub fn check_rules(rules: &[ScheduleRule]) -> Result<(), ScheduleProblem> {
+    if rules.len() > MAX_SCHEDULE_RULES {
+        return Err(ScheduleProblem::TooMany { count: rules.len(), max: MAX_SCHEDULE_RULES });
+    }
+    for (first, a) in rules.iter().enumerate() {
+        for (second, b) in rules.iter().enumerate().skip(first + 1) {
+            for (at, act) in switches(a) {
+                if switches(b).iter().any(|(other_at, other_act)| *other_at == at && *other_act != act) {
+                    return Err(ScheduleProblem::Conflict {
+                        first, second, day: (at / DAY) as usize, minute: at % DAY,
+                    });
+                }
+            }
+            if let (Some(a_length), Some(b_length)) = (length(a), length(b)) {
+                for a_start in starts(a) {
+                    if starts(b).iter().any(|b_start| overlaps((a_start, a_start + a_length), (*b_start, b_start + b_length))) {
+                        return Err(ScheduleProblem::Overlap { first, second });
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What turning `actual` into `desired` takes: the desired rules missing from
+/// `actual` and the actual ones not desired. Ids are ignored.
+pub fn diff_rules(actual: &[ScheduleRule], desired: &[ScheduleRule]) -> (Vec<ScheduleRule>, Vec<ScheduleRule>) {
+    let without_id = |rule: &ScheduleRule| ScheduleRule { id: None, ..rule.clone() };
+    let mut removed: Vec<ScheduleRule> = actual.to_vec();
+    let mut added = Vec::new();
+    for rule in desired {
+        match removed.iter().position(|other| without_id(other) == without_id(rule)) {
+            Some(idx) => {
+                removed.remove(idx);
+            }
+            None => added.push(rule.clone()),
+        }
+    }
+    (added, removed)
+}
+
+/// `rule` without what `compact_rules` may merge away.
+fn mergeable(rule: &ScheduleRule) -> Option<ScheduleRule> {
+    (rule.repeat != 0 && rule.wday.len() == 7)
+        .then(|| ScheduleRule { id: None, name: String::new(), wday: Vec::new(), ..rule.clone() })
+}
+
+/// Fewer rules doing the same: duplicates are dropped and rules that differ
+/// only in their days become one rule for all of them. The first of each keeps
+/// its id and name.
+pub fn compact_rules(rules: &[ScheduleRule]) -> Vec<ScheduleRule> {
+    let mut compacted: Vec<ScheduleRule> = Vec::new();
+    for rule in rules {
+        let same = |kept: &&mut ScheduleRule| match (mergeable(kept), mergeable(rule)) {
+            (Some(a), Some(b)) => a == b,
+            _ => ScheduleRule { id: None, name: String::new(), ..(*kept).clone() }
+                == ScheduleRule { id: None, name: String::new(), ..rule.clone() },
+        };
+        match compacted.iter_mut().find(same) {
+            Some(kept) => {
+                for (day, on) in kept.wday.iter_mut().zip(&rule.wday) {
+                    *day = flag(*day != 0 || *on != 0);
+                }
+            }
+            None => compacted.push(rule.clone()),
+        }
+    }
+    compacted
+}
+
+/// Switches the relay at random moments within a time window, to make a home
+/// look occupied.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct AntiTheftRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub enable: i64,
+    /// Sunday first, as for `ScheduleRule`.
+    pub wday: Vec<i64>,
+    pub repeat: i64,
+    pub stime_opt: i64,
+    pub smin: i64,
+    pub etime_opt: i64,
+    pub emin: i64,
+    /// Switches per window.
+    pub frequency: i64,
+    /// How long each switch lasts, in minutes.
+    pub duration: i64,
+    pub lastfor: i64,
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub force: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RuleListResponse<T> {
+    pub rule_list: Vec<T>,
+    pub err_code: i64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct AddRuleResponse {
+    pub id: String,
+    pub err_code: i64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CountdownResponse {
+    pub get_rules: Option<RuleListResponse<CountdownRule>>,
+    pub add_rule: Option<AddRuleResponse>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ScheduleResponse {
+    pub get_rules: Option<RuleListResponse<ScheduleRule>>,
+    pub add_rule: Option<AddRuleResponse>,
+}
+
+fn rule_list<T>(list: Option<RuleListResponse<T>>) -> Result<Vec<T>, PlugError> {
+    match list {
+        Some(list) => Ok(list.rule_list),
+        None => Err(PlugError::new("Response has no rule list")),
+    }
+}
+
+fn added(add: Option<AddRuleResponse>) -> Result<String, PlugError> {
+    match add {
+        Some(add) if add.err_code == 0 => Ok(add.id),
+        Some(add) => Err(PlugError::new(alloc::format!("add_rule failed with err_code {}", add.err_code).as_str())),
+        None => Err(PlugError::new("Response has no rule id")),
+    }
+}
+
+macro_rules! rule_methods {
+    ($target:ty) => {
+        impl $target {
+            pub fn countdown_rules(&self) -> Result<Vec<CountdownRule>, PlugError> {
+                rule_list(self.send(commands::get_countdown_rules())?.count_down.and_then(|c| c.get_rules))
+            }
+
+            /// The running countdown, if any: how long until it fires and what it switches to.
+            pub fn active_countdown(&self) -> Result<Option<(Duration, TargetState)>, PlugError> {
+                Ok(self.countdown_rules()?.iter().find_map(|rule| Some((rule.remaining()?, rule.target()))))
+            }
+
+            /// Returns the id the device gave the rule.
+            pub fn add_countdown(&self, rule: &CountdownRule) -> Result<String, PlugError> {
+                added(self.send(commands::add_countdown_rule(rule))?.count_down.and_then(|c| c.add_rule))
+            }
+
+            pub fn delete_countdown(&self, id: &str) -> Result<PlugResponse, PlugError> {
+                self.send(commands::delete_countdown_rule(id))
+            }
+
+            pub fn clear_countdowns(&self) -> Result<PlugResponse, PlugError> {
+                self.send(commands::delete_all_countdown_rules())
+            }
+
+            pub fn schedule_rules(&self) -> Result<Vec<ScheduleRule>, PlugError> {
+                rule_list(self.send(commands::get_schedule_rules())?.schedule.and_then(|s| s.get_rules))
+            }
+
+            /// Returns the id the device gave the rule.
+            pub fn add_schedule(&self, rule: &ScheduleRule) -> Result<String, PlugError> {
+                added(self.send(commands::add_schedule_rule(rule))?.schedule.and_then(|s| s.add_rule))
+            }
+
+            /// Replaces the rule with the same `id`.
+            pub fn edit_schedule(&self, rule: &ScheduleRule) -> Result<PlugResponse, PlugError> {
+                self.send(commands::edit_schedule_rule(rule))
+            }
+
+            pub fn delete_schedule(&self, id: &str) -> Result<PlugResponse, PlugError> {
+                self.send(commands::delete_schedule_rule(id))
+            }
+
+            pub fn clear_schedules(&self) -> Result<PlugResponse, PlugError> {
+                self.send(commands::delete_all_schedule_rules())
+            }
+
+            /// Adds `rule` if it fits with the rules already on the device.
+            pub fn add_schedule_checked(&self, rule: &ScheduleRule) -> Result<String, PlugError> {
+                let mut rules = self.schedule_rules()?;
+                rules.push(rule.clone());
+                check_rules(&rules)?;
+                self.add_schedule(rule)
+            }
+
+            /// Replaces the whole schedule with `rules`, if they pass `check_rules`.
+            /// Returns the new ids.
+            pub fn set_schedules(&self, rules: &[ScheduleRule]) -> Result<Vec<String>, PlugError> {
+                check_rules(rules)?;
+                self.clear_schedules()?;
+                rules.iter().map(|rule| self.add_schedule(&ScheduleRule { id: None, ..rule.clone() })).collect()
+            }
+        }
+    };
+}
+
+rule_methods!(TpLinkDevice);
+rule_methods!(ChildPlug);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::strip::Strip;
+    use crate::strip::tests::hs300;
+    use crate::types::PlugError;
+    use super::{check_rules, compact_rules, CountdownRule, ScheduleProblem, ScheduleRule, TargetState, MAX_SCHEDULE_RULES};
+
+    #[test]
+    fn test_schedule_rule_wire_format() {
+        let rule = ScheduleRule::at(7, 30, true).on_days([false, true, true, true, true, true, false]);
+        let value = serde_json::to_value(&rule).unwrap();
+        assert_eq!(value["smin"], 450);
+        assert_eq!(value["wday"], json!([0, 1, 1, 1, 1, 1, 0]));
+        assert!(value.get("id").is_none());
+
+        let listed: ScheduleRule = serde_json::from_value(
+            json!({"id": "AB12", "name": "night", "enable": 1, "wday": [1, 1, 1, 1, 1, 1, 1], "repeat": 1,
+                   "stime_opt": 2, "smin": 0, "sact": 0, "etime_opt": -1, "emin": 0, "eact": -1})).unwrap();
+        assert_eq!((listed.id.as_deref(), listed.stime_opt), (Some("AB12"), 2));
+    }
+
+    #[test]
+    fn test_countdown_on_child() {
+        let requests = Arc::new(Mutex::new(Vec::<Value>::new()));
+        let seen = requests.clone();
+        let strip = hs300();
+        let transport = move |address: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            seen.lock().unwrap().push(request.clone());
+            if request.get("count_down").is_some() {
+                return Ok(encrypt_payload(br#"{"count_down":{"add_rule":{"id":"C1","err_code":0}}}"#.to_vec()));
+            }
+            strip.transport.request(address, frame)
+        };
+
+        let strip = Strip::new(TpLinkDevice::with_transport("hs300", Arc::new(transport)));
+        let id = strip.child("Lamp").unwrap().add_countdown(&CountdownRule::new(600, false)).unwrap();
+        assert_eq!(id, "C1");
+
+        let last = requests.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(last["context"]["child_ids"], json!(["800601"]));
+        assert_eq!(last["count_down"]["add_rule"]["delay"], 600);
+        assert_eq!(last["count_down"]["add_rule"]["act"], 0);
+    }
+
+    #[test]
+    fn test_active_countdown() {
+        let rules = Arc::new(Mutex::new(json!([])));
+        let listed = rules.clone();
+        let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let response = json!({"count_down": {"get_rules": {"rule_list": *listed.lock().unwrap(), "err_code": 0}}});
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        let plug = TpLinkDevice::with_transport("plug", Arc::new(transport));
+        assert_eq!(plug.active_countdown().unwrap(), None);
+
+        *rules.lock().unwrap() = json!([
+            {"id": "C0", "name": "turn on", "enable": 0, "delay": 60, "act": 1, "remain": 30},
+            {"id": "C1", "name": "turn off", "enable": 1, "delay": 1800, "act": 0, "remain": 754},
+        ]);
+        assert_eq!(plug.active_countdown().unwrap(), Some((Duration::from_secs(754), TargetState::Off)));
+        let listed = plug.countdown_rules().unwrap();
+        assert_eq!((listed[0].target(), listed[0].remaining()), (TargetState::On, None));
+        assert!(serde_json::to_value(&listed[1]).unwrap().get("remain").is_none());
+    }
+
+    #[test]
+    fn test_check_rules() {
+        let weekdays = [false, true, true, true, true, true, false];
+        let on = ScheduleRule::at(7, 0, true).on_days(weekdays);
+        let off_sunday = ScheduleRule::at(7, 0, false).on_days([true, false, false, false, false, false, false]);
+        assert_eq!(check_rules(&[on.clone(), off_sunday]), Ok(()));
+
+        let off_friday = ScheduleRule::at(7, 0, false).on_days([false, false, false, false, false, true, false]);
+        assert_eq!(check_rules(&[on.clone(), off_friday]),
+                   Err(ScheduleProblem::Conflict { first: 0, second: 1, day: 5, minute: 420 }));
+
+        // 22:00 to 02:00 and 01:00 to 03:00 overlap past midnight.
+        let window = |smin: i64, emin: i64| ScheduleRule { smin, etime_opt: 0, emin, eact: 0, ..on.clone() };
+        let (night, early) = (window(22 * 60, 2 * 60), window(60, 3 * 60));
+        assert_eq!(check_rules(&[night, early]), Err(ScheduleProblem::Overlap { first: 0, second: 1 }));
+
+        let many = vec![on; MAX_SCHEDULE_RULES + 1];
+        assert_eq!(check_rules(&many), Err(ScheduleProblem::TooMany { count: 33, max: 32 }));
+    }
+
+    #[test]
+    fn test_compact_rules() {
+        let monday = ScheduleRule::at(7, 0, true).on_days([false, true, false, false, false, false, false]);
+        let tuesday = ScheduleRule { id: Some(String::from("B")), ..monday.clone() }
+            .on_days([false, false, true, false, false, false, false]);
+        let off = ScheduleRule::at(23, 0, false);
+        let compacted = compact_rules(&[monday.clone(), off.clone(), tuesday, off.clone()]);
+        assert_eq!(compacted, [monday.on_days([false, true, true, false, false, false, false]), off]);
+    }
+}
@@ -0,0 +1,330 @@
+/*
+ * Polls devices on their own interval or cron schedule and hands every sample to
+ * the configured sinks. Deadlines are computed from the schedule rather than from
+ * when the previous poll finished, so a slow device never makes the others drift,
+ * and devices that are due together are polled in parallel.
+ */
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Local, Utc};
+
+use crate::TpLinkDevice;
+use crate::cron::CronSchedule;
+use crate::reading::PowerReading;
+use crate::service::ServiceHandle;
+use crate::sink::Sink;
+use crate::timing::{self, Clock};
+use crate::types::PlugError;
+
+const MAX_SLEEP: Duration = Duration::from_millis(250);
+
+/// The shortest interval `Schedule::every` takes; shorter ones are raised to it.
+pub const MIN_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub struct Sample {
+    pub device: String,
+    pub taken_at: DateTime<Utc>,
+    pub reading: Result<PowerReading, PlugError>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Trigger {
+    Interval(Duration),
+    Cron(CronSchedule),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    trigger: Trigger,
+    jitter: Duration,
+}
+
+impl Schedule {
+    /// Polls every `period`, at least `MIN_PERIOD`.
+    pub fn every(period: Duration) -> Schedule {
+        Schedule {
+            trigger: Trigger::Interval(period.max(MIN_PERIOD)),
+            jitter: Duration::ZERO,
+        }
+    }
+
+    pub fn cron(expr: &str) -> Result<Schedule, PlugError> {
+        Ok(Schedule {
+            trigger: Trigger::Cron(CronSchedule::parse(expr)?),
+            jitter: Duration::ZERO,
+        })
+    }
+
+    /// Delays every run by a random amount below `jitter`, without accumulating it.
+    pub fn with_jitter(mut self, jitter: Duration) -> Schedule {
+        self.jitter = jitter;
+        self
+    }
+}
+
+struct Job {
+    name: String,
+    device: TpLinkDevice,
+    schedule: Schedule,
+    nominal: Instant,
+    due: Instant,
+}
+
+fn jitter(rng: &mut u64, max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+
+    // xorshift64; statistical quality is irrelevant here.
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    Duration::from_nanos(*rng % max.as_nanos().max(1) as u64)
+}
+
+impl Job {
+    fn reschedule(&mut self, now: Instant, wall_now: DateTime<Local>, rng: &mut u64) {
+        match &self.schedule.trigger {
+            Trigger::Interval(period) => {
+                self.nominal += *period;
+                // Skip runs missed while the host was suspended or the poll overran.
+                if self.nominal <= now {
+                    let missed = (now - self.nominal).as_nanos() / period.as_nanos() + 1;
+                    self.nominal += *period * missed.min(u32::MAX as u128) as u32;
+                }
+            }
+            Trigger::Cron(cron) => {
+                self.nominal = match cron.next_after(&wall_now) {
+                    Some(next) => now + (next - wall_now).to_std().unwrap_or(Duration::ZERO),
+                    // Never fires again; park it far in the future.
+                    None => now + Duration::from_secs(100 * 365 * 24 * 3600),
+                };
+            }
+        }
+        self.due = self.nominal + jitter(rng, self.schedule.jitter);
+    }
+}
+
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    sinks: Vec<Box<dyn Sink>>,
+    rng: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Scheduler {
+            jobs: Vec::new(),
+            sinks: Vec::new(),
+            rng: seed | 1,
+            clock: timing::system(),
+        }
+    }
+
+    /// Takes the time from `clock`, e.g. a `MockClock` in tests. Set it before adding devices.
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Scheduler {
+        self.clock = clock;
+        self
+    }
+
+    fn wall_now(&self) -> DateTime<Local> {
+        self.clock.utc().with_timezone(&Local)
+    }
+
+    pub fn add(&mut self, name: &str, device: TpLinkDevice, schedule: Schedule) -> &mut Scheduler {
+        let now = self.clock.now();
+        let mut job = Job {
+            name: String::from(name),
+            device,
+            schedule,
+            nominal: now,
+            due: now,
+        };
+
+        if let Trigger::Cron(_) = job.schedule.trigger {
+            job.reschedule(now, self.wall_now(), &mut self.rng);
+        } else {
+            job.due = now + jitter(&mut self.rng, job.schedule.jitter);
+        }
+        self.jobs.push(job);
+        self
+    }
+
+    pub fn sink<S: Sink + 'static>(&mut self, sink: S) -> &mut Scheduler {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    fn run_due(&mut self, now: Instant) {
+        let due: Vec<usize> = (0..self.jobs.len())
+            .filter(|&idx| self.jobs[idx].due <= now)
+            .collect();
+
+        let jobs = &self.jobs;
+        let clock = self.clock.as_ref();
+        let samples: Vec<Sample> = thread::scope(|s| {
+            let handles: Vec<_> = due.iter()
+                .map(|&idx| s.spawn(move || Sample {
+                    device: jobs[idx].name.clone(),
+                    taken_at: clock.utc(),
+                    reading: jobs[idx].device.power_reading(),
+                }))
+                .collect();
+
+            handles.into_iter().filter_map(|h| h.join().ok()).collect()
+        });
+
+        let wall_now = self.wall_now();
+        for idx in due {
+            self.jobs[idx].reschedule(now, wall_now, &mut self.rng);
+        }
+
+        for sample in &samples {
+            for sink in self.sinks.iter_mut() {
+                sink.write(sample);
+            }
+        }
+    }
+
+    /// Polls until `stop` is set. Returns immediately if no device was added.
+    pub fn run_until(&mut self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            let now = self.clock.now();
+            match self.jobs.iter().map(|job| job.due).min() {
+                None => return,
+                Some(due) if due > now => self.clock.sleep((due - now).min(MAX_SLEEP)),
+                Some(_) => self.run_due(now),
+            }
+        }
+    }
+
+    pub fn run(&mut self) {
+        self.run_until(&AtomicBool::new(false))
+    }
+
+    /// Polls as a service until stopped.
+    pub fn service(mut self) -> ServiceHandle {
+        ServiceHandle::new("scheduler", move |context| {
+            context.running();
+            self.run_until(context.stop_flag());
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use chrono::Local;
+    use crate::TpLinkDevice;
+    use crate::protocol::encrypt_payload;
+    use crate::timing::{Clock, MockClock};
+    use crate::types::PlugError;
+    use super::{Job, Sample, Schedule, Scheduler, MIN_PERIOD};
+
+    #[test]
+    fn test_interval_does_not_drift() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            std::thread::sleep(Duration::from_millis(15));
+            Ok(encrypt_payload(br#"{"emeter":{"get_realtime":{"power_mw":1500,"err_code":0}}}"#.to_vec()))
+        };
+        let device = TpLinkDevice::with_transport("test", Arc::new(transport));
+
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sink_times, sink_stop) = (times.clone(), stop.clone());
+
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler
+            .add("test", device, Schedule::every(Duration::from_millis(40)))
+            .sink(move |sample: &Sample| {
+                assert_eq!(sample.reading.as_ref().unwrap().power_w, 1.5);
+                let mut times = sink_times.lock().unwrap();
+                times.push(start.elapsed());
+                if times.len() == 5 {
+                    sink_stop.store(true, Ordering::Relaxed);
+                }
+            });
+        scheduler.run_until(&stop);
+
+        // Five polls starting at t = 0 every 40 ms, each taking 15 ms: had the 15 ms
+        // accumulated, the last one would land at 235 ms instead of 175 ms.
+        let last = times.lock().unwrap()[4];
+        assert!(last >= Duration::from_millis(175) && last < Duration::from_millis(230), "{:?}", last);
+    }
+
+    #[test]
+    fn test_zero_interval_and_missed_runs() {
+        assert_eq!(Schedule::every(Duration::ZERO), Schedule::every(MIN_PERIOD));
+
+        let start = Instant::now();
+        let mut job = Job {
+            name: String::from("test"),
+            device: TpLinkDevice::with_transport("test", Arc::new(|_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+                unreachable!()
+            })),
+            schedule: Schedule::every(Duration::ZERO),
+            nominal: start,
+            due: start,
+        };
+        // A day asleep skips millions of runs without stepping through them.
+        let woke = start + Duration::from_secs(24 * 3600) + Duration::from_millis(5);
+        job.reschedule(woke, Local::now(), &mut 1);
+        assert_eq!(job.nominal, start + Duration::from_secs(24 * 3600) + MIN_PERIOD);
+    }
+
+    #[test]
+    fn test_cron_on_mock_clock() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"emeter":{"get_realtime":{"power_mw":1500,"err_code":0}}}"#.to_vec()))
+        };
+        let clock = Arc::new(MockClock::new());
+        let taken = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sink_taken, sink_stop) = (taken.clone(), stop.clone());
+
+        let mut scheduler = Scheduler::new();
+        scheduler
+            .clock(clock.clone())
+            .add("test", TpLinkDevice::with_transport("test", Arc::new(transport)), Schedule::cron("*/15 * * * *").unwrap())
+            .sink(move |sample: &Sample| {
+                let mut taken = sink_taken.lock().unwrap();
+                taken.push(sample.taken_at);
+                if taken.len() == 3 {
+                    sink_stop.store(true, Ordering::Relaxed);
+                }
+            });
+        // An hour's worth of quarters without waiting for any of them.
+        let started = Instant::now();
+        scheduler.run_until(&stop);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let taken = taken.lock().unwrap();
+        let minutes: Vec<i64> = taken.windows(2).map(|w| (w[1] - w[0]).num_minutes()).collect();
+        assert_eq!(minutes, [15, 15]);
+        assert!(clock.utc() - taken[0] >= chrono::Duration::minutes(30));
+    }
+
+    #[test]
+    fn test_invalid_cron() {
+        assert!(Schedule::cron("every minute").is_err());
+        assert!(Schedule::cron("*/5 * * * *").is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * A common handle for the things that run in the background, so an
+ * application can start them, see how they are doing and shut them all down:
+ *
+ *   let (mut watcher, events) = watcher.service();
+ *   let mut poller = scheduler.service();
+ *   watcher.start()?;
+ *   poller.start()?;
+ *   ...
+ *   for service in [&watcher, &poller] {
+ *       service.stop();
+ *   }
+ *   watcher.join();
+ *   poller.join();
+ *
+ * A service's thread gets a `ServiceContext` to report its health with and to
+ * sleep on; `stop` wakes it within `STOP_LATENCY` and it returns after the
+ * round it's in. Dropping a handle stops the service and waits for it.
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::types::PlugError;
+
+/// The longest a service sleeps before noticing it was asked to stop.
+pub const STOP_LATENCY: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Health {
+    #[default]
+    NotStarted,
+    Starting,
+    Running,
+    /// Running, but the last round failed, e.g. a discovery broadcast.
+    Degraded { error: String },
+    Stopped,
+    /// The thread panicked.
+    Failed { error: String },
+}
+
+/// What a service's thread is given.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceContext {
+    stop: Arc<AtomicBool>,
+    health: Arc<Mutex<Health>>,
+}
+
+impl ServiceContext {
+    pub fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    /// The flag `stop` sets, for loops such as `Scheduler::run_until` that take one.
+    pub fn stop_flag(&self) -> &AtomicBool {
+        &self.stop
+    }
+
+    /// Sleeps until `deadline`, returning early with `false` when asked to stop.
+    pub fn sleep_until(&self, deadline: Instant) -> bool {
+        while !self.stopping() {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep((deadline - now).min(STOP_LATENCY));
+        }
+        false
+    }
+
+    pub fn running(&self) {
+        self.set(Health::Running);
+    }
+
+    pub fn degraded(&self, error: &PlugError) {
+        self.set(Health::Degraded { error: error.to_string() });
+    }
+
+    fn set(&self, health: Health) {
+        if let Ok(mut current) = self.health.lock() {
+            *current = health;
+        }
+    }
+}
+
+type Body = Box<dyn FnOnce(&ServiceContext) + Send>;
+
+pub struct ServiceHandle {
+    name: String,
+    context: ServiceContext,
+    body: Option<Body>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServiceHandle {
+    /// A service that runs `body` in its own thread once started.
+    pub fn new<F>(name: &str, body: F) -> ServiceHandle
+        where F: FnOnce(&ServiceContext) + Send + 'static {
+        ServiceHandle {
+            name: String::from(name),
+            context: ServiceContext::default(),
+            body: Some(Box::new(body)),
+            thread: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Fails if the service was already started.
+    pub fn start(&mut self) -> Result<(), PlugError> {
+        let body = self.body.take()
+            .ok_or_else(|| PlugError::new(format!("{} was already started", self.name).as_str()))?;
+        self.context.set(Health::Starting);
+        let context = self.context.clone();
+        let thread = thread::Builder::new()
+            .name(self.name.clone())
+            .spawn(move || {
+                body(&context);
+                context.set(Health::Stopped);
+            })?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// Asks the service to stop, without waiting for it to.
+    pub fn stop(&self) {
+        self.context.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for the service's thread to end and returns how it ended.
+    pub fn join(&mut self) -> Health {
+        if let Some(thread) = self.thread.take() {
+            if let Err(panic) = thread.join() {
+                let error = panic.downcast_ref::<&str>().map(|s| String::from(*s))
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("panicked"));
+                self.context.set(Health::Failed { error });
+            }
+        }
+        self.health()
+    }
+
+    pub fn health(&self) -> Health {
+        self.context.health.lock().map(|health| health.clone()).unwrap_or(Health::Failed {
+            error: String::from("health lock poisoned"),
+        })
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+}
+
+impl Drop for ServiceHandle {
+    fn drop(&mut self) {
+        self.stop();
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::types::PlugError;
+    use super::{Health, ServiceHandle};
+
+    #[test]
+    fn test_lifecycle() {
+        let mut service = ServiceHandle::new("ticker", |context| {
+            context.degraded(&PlugError::new("first round failed"));
+            while context.sleep_until(Instant::now() + Duration::from_millis(20)) {
+                context.running();
+            }
+        });
+        assert_eq!(service.health(), Health::NotStarted);
+        service.start().unwrap();
+        assert!(service.start().is_err());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(service.health(), Health::Running);
+        assert!(service.is_running());
+
+        let stopping = Instant::now();
+        service.stop();
+        assert_eq!(service.join(), Health::Stopped);
+        assert!(stopping.elapsed() < Duration::from_millis(500));
+        assert!(!service.is_running());
+    }
+
+    #[test]
+    fn test_panic_is_reported() {
+        let mut service = ServiceHandle::new("broken", |_| panic!("no devices"));
+        service.start().unwrap();
+        assert_eq!(service.join(), Health::Failed { error: String::from("no devices") });
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * Keeps a group of plugs under a power limit, for circuits or inverters that
+ * can't take everything at once:
+ *
+ *   let mut shedder = LoadShedder::new(3000.0);
+ *   shedder.measure("oven")
+ *       .device("heater", heater)      // shed last
+ *       .device("dryer", dryer);       // shed first
+ *   shedder.run(watcher.spawn().into());
+ *
+ * When the total of the latest readings goes over the limit, switchable devices
+ * are turned off from the end of the list until the rest fits. They are turned
+ * back on one at a time, highest priority first, once the total plus what the
+ * device drew before being shed stays below `restore_below`. Nothing is switched
+ * twice within `cooldown`, so readings can catch up with the last change.
+ */
+
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::bus::EventBus;
+use crate::events::Event;
+use crate::types::PlugError;
+
+struct Member {
+    name: String,
+    device: Option<TpLinkDevice>,
+    power_w: Option<f64>,
+    /// What the device drew when it was shed.
+    shed_w: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub device: String,
+    /// `false` when the device was shed, `true` when it was restored.
+    pub on: bool,
+    pub result: Result<(), PlugError>,
+}
+
+pub struct LoadShedder {
+    limit_w: f64,
+    restore_below_w: f64,
+    cooldown: Duration,
+    members: Vec<Member>,
+    last_change: Option<Instant>,
+    bus: Option<EventBus>,
+}
+
+impl LoadShedder {
+    pub fn new(limit_w: f64) -> LoadShedder {
+        LoadShedder {
+            limit_w,
+            restore_below_w: limit_w * 0.9,
+            cooldown: Duration::from_secs(30),
+            members: Vec::new(),
+            last_change: None,
+            bus: None,
+        }
+    }
+
+    /// Headroom kept when restoring, so a device doesn't go straight back over the limit.
+    pub fn restore_below(&mut self, watts: f64) -> &mut LoadShedder {
+        self.restore_below_w = watts.min(self.limit_w);
+        self
+    }
+
+    pub fn cooldown(&mut self, cooldown: Duration) -> &mut LoadShedder {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Publishes a `CommandFailed` event whenever a device can't be switched.
+    pub fn publish_to(&mut self, bus: &EventBus) -> &mut LoadShedder {
+        self.bus = Some(bus.clone());
+        self
+    }
+
+    /// Adds a device that may be shed. Devices added later are shed first.
+    pub fn device(&mut self, name: &str, device: TpLinkDevice) -> &mut LoadShedder {
+        self.add(name, Some(device))
+    }
+
+    /// Adds a device that counts towards the total but is never switched.
+    pub fn measure(&mut self, name: &str) -> &mut LoadShedder {
+        self.add(name, None)
+    }
+
+    fn add(&mut self, name: &str, device: Option<TpLinkDevice>) -> &mut LoadShedder {
+        self.members.push(Member {
+            name: String::from(name),
+            device,
+            power_w: None,
+            shed_w: None,
+        });
+        self
+    }
+
+    /// Sum of the latest readings; devices that are offline or not yet read count as zero.
+    pub fn total_w(&self) -> f64 {
+        self.members.iter().filter_map(|m| m.power_w).sum()
+    }
+
+    pub fn is_shed(&self, name: &str) -> bool {
+        self.members.iter().any(|m| m.name == name && m.shed_w.is_some())
+    }
+
+    fn switch(member: &Member, on: bool) -> Outcome {
+        let result = match &member.device {
+            Some(device) => if on { device.on() } else { device.off() }.map(|_| ()),
+            None => Err(PlugError::new(format!("{} can't be switched", member.name).as_str())),
+        };
+        Outcome {
+            device: member.name.clone(),
+            on,
+            result,
+        }
+    }
+
+    pub fn handle(&mut self, event: &Event) -> Vec<Outcome> {
+        self.handle_at(event, Instant::now())
+    }
+
+    pub fn handle_at(&mut self, event: &Event, now: Instant) -> Vec<Outcome> {
+        let member = match self.members.iter_mut().find(|m| m.name == event.device()) {
+            Some(member) => member,
+            None => return Vec::new(),
+        };
+        match event {
+            Event::PowerSample { reading, .. } => member.power_w = Some(reading.power_w),
+            Event::DeviceOffline { .. } => member.power_w = None,
+            // Switched back on by hand: leave it to the next overload to shed it again.
+            Event::RelayChanged { on: true, .. } => member.shed_w = None,
+            _ => {}
+        }
+
+        if self.last_change.is_some_and(|t| now.duration_since(t) < self.cooldown) {
+            return Vec::new();
+        }
+
+        let outcomes = if self.total_w() > self.limit_w { self.shed() } else { self.restore() };
+        if !outcomes.is_empty() {
+            self.last_change = Some(now);
+        }
+        if let Some(bus) = &self.bus {
+            for outcome in &outcomes {
+                if let Err(e) = &outcome.result {
+                    bus.publish(Event::command_failed(&outcome.device, e));
+                }
+            }
+        }
+        outcomes
+    }
+
+    fn shed(&mut self) -> Vec<Outcome> {
+        let mut total = self.total_w();
+        let mut outcomes = Vec::new();
+        for member in self.members.iter_mut().rev() {
+            if total <= self.limit_w {
+                break;
+            }
+            let power_w = match member.power_w {
+                Some(p) if member.device.is_some() && member.shed_w.is_none() && p > 0.0 => p,
+                _ => continue,
+            };
+
+            let outcome = LoadShedder::switch(member, false);
+            if outcome.result.is_ok() {
+                member.shed_w = Some(power_w);
+                member.power_w = Some(0.0);
+                total -= power_w;
+            }
+            outcomes.push(outcome);
+        }
+        outcomes
+    }
+
+    fn restore(&mut self) -> Vec<Outcome> {
+        let total = self.total_w();
+        let restore_below_w = self.restore_below_w;
+        let member = self.members.iter_mut()
+            .find(|m| m.shed_w.is_some_and(|shed_w| total + shed_w < restore_below_w));
+
+        match member {
+            Some(member) => {
+                let outcome = LoadShedder::switch(member, true);
+                if outcome.result.is_ok() {
+                    member.power_w = member.shed_w.take();
+                }
+                vec![outcome]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Handles events until the sender side hangs up.
+    pub fn run(&mut self, events: Receiver<Event>) {
+        for event in events {
+            self.handle(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use crate::TpLinkDevice;
+    use crate::events::Event;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::reading::PowerReading;
+    use crate::types::PlugError;
+    use super::LoadShedder;
+
+    fn plug(name: &'static str, log: Arc<Mutex<Vec<String>>>) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let state = &request["system"]["set_relay_state"]["state"];
+            log.lock().unwrap().push(format!("{} {}", name, state));
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()))
+        };
+        TpLinkDevice::with_transport(name, Arc::new(transport))
+    }
+
+    fn sample(device: &str, power_w: f64) -> Event {
+        Event::PowerSample {
+            device: String::from(device),
+            reading: PowerReading { power_w, ..PowerReading::default() },
+        }
+    }
+
+    #[test]
+    fn test_sheds_lowest_priority_and_restores() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut shedder = LoadShedder::new(3000.0);
+        shedder.cooldown(Duration::from_secs(10))
+            .measure("oven")
+            .device("heater", plug("heater", log.clone()))
+            .device("dryer", plug("dryer", log.clone()));
+
+        let t0 = Instant::now();
+        shedder.handle_at(&sample("heater", 1500.0), t0);
+        shedder.handle_at(&sample("dryer", 1000.0), t0);
+        // The oven pushes the group over the limit; dropping the dryer is enough.
+        let outcomes = shedder.handle_at(&sample("oven", 1200.0), t0);
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!((outcomes[0].device.as_str(), outcomes[0].on), ("dryer", false));
+        assert!(shedder.is_shed("dryer"));
+
+        // Room again, but still cooling down.
+        assert!(shedder.handle_at(&sample("oven", 0.0), t0 + Duration::from_secs(5)).is_empty());
+        let outcomes = shedder.handle_at(&sample("oven", 0.0), t0 + Duration::from_secs(11));
+        assert_eq!((outcomes[0].device.as_str(), outcomes[0].on), ("dryer", true));
+        assert_eq!(*log.lock().unwrap(), ["dryer 0", "dryer 1"]);
+    }
+
+    #[test]
+    fn test_sheds_several_when_far_over() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut shedder = LoadShedder::new(1000.0);
+        shedder.device("a", plug("a", log.clone()))
+            .device("b", plug("b", log.clone()))
+            .device("c", plug("c", log.clone()));
+
+        let now = Instant::now();
+        shedder.handle_at(&sample("c", 300.0), now);
+        shedder.handle_at(&sample("b", 600.0), now);
+        let outcomes = shedder.handle_at(&sample("a", 600.0), now);
+        assert_eq!(outcomes.iter().map(|o| o.device.as_str()).collect::<Vec<_>>(), ["c", "b"]);
+        assert_eq!(shedder.total_w(), 600.0);
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::scheduler::Sample;
+
+/// Receives every sample taken by the `Scheduler`. Closures taking `&Sample` are sinks too.
+pub trait Sink: Send {
+    fn write(&mut self, sample: &Sample);
+}
+
+impl<F> Sink for F
+where
+    F: FnMut(&Sample) + Send
+{
+    fn write(&mut self, sample: &Sample) {
+        self(sample)
+    }
+}
+
+/// Lets a sink be shared, e.g. to read back from a store the scheduler writes to.
+impl<S: Sink> Sink for Arc<Mutex<S>> {
+    fn write(&mut self, sample: &Sample) {
+        if let Ok(mut sink) = self.lock() {
+            sink.write(sample);
+        }
+    }
+}
+
+/// Writes one human readable line per sample.
+pub struct LogSink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> LogSink<W> {
+    pub fn new(out: W) -> LogSink<W> {
+        LogSink {
+            out
+        }
+    }
+}
+
+impl LogSink<io::Stderr> {
+    pub fn stderr() -> LogSink<io::Stderr> {
+        LogSink::new(io::stderr())
+    }
+}
+
+impl<W: Write + Send> Sink for LogSink<W> {
+    fn write(&mut self, sample: &Sample) {
+        let _ = match &sample.reading {
+            Ok(r) => writeln!(self.out, "{} {}: V = {} V, I = {} A, P = {} W, S = {} VA, E = {} kWh",
+                              sample.taken_at.to_rfc3339(), sample.device,
+                              r.voltage_v, r.current_a, r.power_w, r.apparent_power_va(), r.total_kwh),
+            Err(e) => writeln!(self.out, "{} {}: {}", sample.taken_at.to_rfc3339(), sample.device, e),
+        };
+    }
+}
+
+/// Writes successful samples in InfluxDB line protocol, e.g. for `influx write` or Telegraf.
+pub struct InfluxSink<W: Write + Send> {
+    out: W,
+    measurement: String,
+}
+
+fn escape_tag(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+impl<W: Write + Send> InfluxSink<W> {
+    pub fn new(out: W) -> InfluxSink<W> {
+        InfluxSink::with_measurement(out, "power")
+    }
+
+    pub fn with_measurement(out: W, measurement: &str) -> InfluxSink<W> {
+        InfluxSink {
+            out,
+            measurement: String::from(measurement),
+        }
+    }
+
+    pub fn line(measurement: &str, sample: &Sample) -> Option<String> {
+        let r = sample.reading.as_ref().ok()?;
+        let power_factor = match r.power_factor() {
+            Some(pf) => format!(",power_factor={}", pf),
+            None => String::new(),
+        };
+        Some(format!("{},device={} voltage={},current={},power={},apparent_power={}{},total={} {}",
+                     escape_tag(measurement), escape_tag(&sample.device),
+                     r.voltage_v, r.current_a, r.power_w, r.apparent_power_va(), power_factor, r.total_kwh,
+                     sample.taken_at.timestamp_nanos_opt()?))
+    }
+}
+
+impl<W: Write + Send> Sink for InfluxSink<W> {
+    fn write(&mut self, sample: &Sample) {
+        if let Some(line) = InfluxSink::<W>::line(&self.measurement, sample) {
+            let _ = writeln!(self.out, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::reading::PowerReading;
+    use crate::scheduler::Sample;
+    use super::InfluxSink;
+
+    #[test]
+    fn test_influx_line() {
+        let sample = Sample {
+            device: String::from("living room"),
+            taken_at: Utc.timestamp_opt(1700000000, 0).unwrap(),
+            reading: Ok(PowerReading { voltage_v: 230.0, current_a: 0.25, power_w: 46.0, total_kwh: 3.0, ..PowerReading::default() }),
+        };
+
+        assert_eq!(InfluxSink::<Vec<u8>>::line("power", &sample).unwrap(),
+                   "power,device=living\\ room voltage=230,current=0.25,power=46,apparent_power=57.5,power_factor=0.8,\
+                    total=3 1700000000000000000");
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * What a device looked like at one moment, and what changed between two such
+ * moments:
+ *
+ *   let before = plug.snapshot()?;
+ *   ...
+ *   for change in before.diff(&plug.snapshot()?) {
+ *       println!("{}", change);
+ *   }
+ *
+ * The watcher keeps the last snapshot of every device in its state and turns
+ * differences into `Event::FieldChanged`. Signal strength wanders by a few dB
+ * from one poll to the next, so smaller changes than `RSSI_NOISE_DB` aren't
+ * reported.
+ */
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Formatter};
+#[cfg(feature = "time")]
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::TpLinkDevice;
+use crate::types::{PlugError, SystemGetSysInfoResponse};
+
+/// RSSI changes smaller than this, in dB, are taken as noise.
+pub const RSSI_NOISE_DB: i64 = 6;
+
+/// Built with `new`, or `From<&SystemGetSysInfoResponse>` without a time,
+/// since `taken_at` only exists with the `time` feature.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct DeviceSnapshot {
+    #[cfg(feature = "time")]
+    pub taken_at: DateTime<Utc>,
+    pub alias: String,
+    /// The relay, or a bulb's light; off for strips.
+    pub relay_on: bool,
+    pub sw_ver: String,
+    /// Wi-Fi signal strength in dBm.
+    pub rssi: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum FieldChange {
+    Relay { was: bool, now: bool },
+    Alias { was: String, now: String },
+    Firmware { was: String, now: String },
+    Rssi { was: i64, now: i64 },
+    Location { was: (f64, f64), now: (f64, f64) },
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let relay = |on: &bool| if *on { "on" } else { "off" };
+        match self {
+            FieldChange::Relay { was, now } => write!(f, "relay: {} -> {}", relay(was), relay(now)),
+            FieldChange::Alias { was, now } => write!(f, "alias: {:?} -> {:?}", was, now),
+            FieldChange::Firmware { was, now } => write!(f, "firmware: {} -> {}", was, now),
+            FieldChange::Rssi { was, now } => write!(f, "rssi: {} -> {} dBm ({:+})", was, now, now - was),
+            FieldChange::Location { was, now } =>
+                write!(f, "location: {:.4},{:.4} -> {:.4},{:.4}", was.0, was.1, now.0, now.1),
+        }
+    }
+}
+
+/// An unstamped snapshot, see `new`.
+impl From<&SystemGetSysInfoResponse> for DeviceSnapshot {
+    fn from(sysinfo: &SystemGetSysInfoResponse) -> DeviceSnapshot {
+        DeviceSnapshot {
+            alias: sysinfo.alias.clone(),
+            relay_on: sysinfo.is_on() == Some(true),
+            sw_ver: sysinfo.sw_ver.clone(),
+            rssi: sysinfo.rssi,
+            latitude: sysinfo.latitude,
+            longitude: sysinfo.longitude,
+            #[cfg(feature = "time")]
+            taken_at: DateTime::default(),
+        }
+    }
+}
+
+impl DeviceSnapshot {
+    #[cfg(feature = "time")]
+    pub fn new(sysinfo: &SystemGetSysInfoResponse, taken_at: DateTime<Utc>) -> DeviceSnapshot {
+        DeviceSnapshot {
+            taken_at,
+            ..DeviceSnapshot::from(sysinfo)
+        }
+    }
+
+    /// What changed from this snapshot to the newer `other`.
+    pub fn diff(&self, other: &DeviceSnapshot) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        if self.relay_on != other.relay_on {
+            changes.push(FieldChange::Relay { was: self.relay_on, now: other.relay_on });
+        }
+        if self.alias != other.alias {
+            changes.push(FieldChange::Alias { was: self.alias.clone(), now: other.alias.clone() });
+        }
+        if self.sw_ver != other.sw_ver {
+            changes.push(FieldChange::Firmware { was: self.sw_ver.clone(), now: other.sw_ver.clone() });
+        }
+        if (self.rssi - other.rssi).abs() >= RSSI_NOISE_DB {
+            changes.push(FieldChange::Rssi { was: self.rssi, now: other.rssi });
+        }
+        let (was, now) = ((self.latitude, self.longitude), (other.latitude, other.longitude));
+        if was != now {
+            changes.push(FieldChange::Location { was, now });
+        }
+        changes
+    }
+}
+
+impl TpLinkDevice {
+    #[cfg(feature = "time")]
+    pub fn snapshot(&self) -> Result<DeviceSnapshot, PlugError> {
+        Ok(DeviceSnapshot::new(&self.sysinfo()?, crate::now()))
+    }
+
+    #[cfg(not(feature = "time"))]
+    pub fn snapshot(&self) -> Result<DeviceSnapshot, PlugError> {
+        Ok(DeviceSnapshot::from(&self.sysinfo()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::SystemGetSysInfoResponse;
+    use super::{DeviceSnapshot, FieldChange};
+
+    #[test]
+    fn test_diff() {
+        let sysinfo = SystemGetSysInfoResponse {
+            alias: String::from("Plug"), sw_ver: String::from("1.5.4"), rssi: -60, ..Default::default()
+        };
+        let before = DeviceSnapshot::from(&sysinfo);
+        assert!(before.diff(&DeviceSnapshot { rssi: -64, ..before.clone() }).is_empty());
+
+        let after = DeviceSnapshot {
+            alias: String::from("Heater"), relay_on: true, rssi: -72, ..before.clone()
+        };
+        let changes = before.diff(&after);
+        assert_eq!(changes, [
+            FieldChange::Relay { was: false, now: true },
+            FieldChange::Alias { was: String::from("Plug"), now: String::from("Heater") },
+            FieldChange::Rssi { was: -60, now: -72 },
+        ]);
+        assert_eq!(changes[2].to_string(), "rssi: -60 -> -72 dBm (-12)");
+    }
+}
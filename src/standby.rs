@@ -0,0 +1,190 @@
+/*
+ * Estimates standby ("vampire") draw from the samples kept in a `History`.
+ *
+ * Standby is taken to be the lowest non-zero power a device settles at: samples
+ * within `STANDBY_BAND` of that minimum, in stretches of at least `min_stretch`,
+ * count as standby. The share of observed time spent there is extrapolated to a
+ * year, which is what putting the device on a schedule could save at most:
+ *
+ *   let estimator = StandbyEstimator::new(&history).with_tariff(Tariff::new(0.32, "EUR"));
+ *   for report in estimator.estimate_all() { ... }
+ */
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::history::History;
+use crate::reading::PowerReading;
+use crate::reports::{Cost, MAX_SAMPLE_GAP};
+use crate::tariff::Tariff;
+
+/// Readings at or below this are treated as switched off rather than standby.
+pub const OFF_THRESHOLD_W: f64 = 0.1;
+/// How far above the minimum a reading may be and still count as standby, in W.
+pub const STANDBY_BAND: f64 = 1.0;
+
+const HOURS_PER_YEAR: f64 = 365.25 * 24.0;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StandbyReport {
+    pub device: String,
+    /// Average power while in standby, `None` if the device never settled at one.
+    pub standby_w: Option<f64>,
+    pub standby_hours: f64,
+    pub observed_hours: f64,
+    pub annual_kwh: f64,
+    pub annual_cost: Option<Cost>,
+}
+
+fn hours(d: Duration) -> f64 {
+    d.num_milliseconds() as f64 / 3_600_000.0
+}
+
+pub struct StandbyEstimator<'a> {
+    history: &'a History,
+    tariff: Option<Tariff>,
+    min_stretch: Duration,
+}
+
+impl<'a> StandbyEstimator<'a> {
+    pub fn new(history: &'a History) -> StandbyEstimator<'a> {
+        StandbyEstimator {
+            history,
+            tariff: None,
+            min_stretch: Duration::hours(1),
+        }
+    }
+
+    pub fn with_tariff(mut self, tariff: Tariff) -> StandbyEstimator<'a> {
+        self.tariff = Some(tariff);
+        self
+    }
+
+    /// Shortest run of low readings that counts as standby rather than a pause.
+    pub fn with_min_stretch(mut self, min_stretch: Duration) -> StandbyEstimator<'a> {
+        self.min_stretch = min_stretch;
+        self
+    }
+
+    fn stretches(&self, samples: &[(DateTime<Utc>, PowerReading)], ceiling_w: f64) -> (f64, f64) {
+        let in_band = |r: &PowerReading| r.power_w > OFF_THRESHOLD_W && r.power_w <= ceiling_w;
+        let (mut standby_hours, mut standby_wh) = (0.0, 0.0);
+
+        let mut start = 0;
+        while start < samples.len() {
+            if !in_band(&samples[start].1) {
+                start += 1;
+                continue;
+            }
+            let mut end = start;
+            while end + 1 < samples.len() && in_band(&samples[end + 1].1)
+                && samples[end + 1].0 - samples[end].0 <= MAX_SAMPLE_GAP {
+                end += 1;
+            }
+
+            let length = samples[end].0 - samples[start].0;
+            if length >= self.min_stretch {
+                standby_hours += hours(length);
+                standby_wh += samples[start..=end].windows(2)
+                    .map(|w| (w[0].1.power_w + w[1].1.power_w) / 2.0 * hours(w[1].0 - w[0].0))
+                    .sum::<f64>();
+            }
+            start = end + 1;
+        }
+        (standby_hours, standby_wh)
+    }
+
+    pub fn estimate(&self, name: &str) -> StandbyReport {
+        let samples = self.history.samples(name);
+        let observed_hours = samples.windows(2)
+            .map(|w| w[1].0 - w[0].0)
+            .filter(|gap| *gap <= MAX_SAMPLE_GAP)
+            .map(hours)
+            .sum();
+
+        let minimum = samples.iter()
+            .map(|(_, r)| r.power_w)
+            .filter(|p| *p > OFF_THRESHOLD_W)
+            .reduce(f64::min);
+        let (standby_hours, standby_wh) = match minimum {
+            Some(minimum) => self.stretches(samples, minimum + STANDBY_BAND),
+            None => (0.0, 0.0),
+        };
+
+        let standby_w = if standby_hours > 0.0 { Some(standby_wh / standby_hours) } else { None };
+        let annual_kwh = match standby_w {
+            Some(w) if observed_hours > 0.0 => w * standby_hours / observed_hours * HOURS_PER_YEAR / 1000.0,
+            _ => 0.0,
+        };
+
+        StandbyReport {
+            device: String::from(name),
+            standby_w,
+            standby_hours,
+            observed_hours,
+            annual_kwh,
+            annual_cost: self.tariff.as_ref().map(|tariff| Cost {
+                amount: tariff.cost(annual_kwh),
+                currency: tariff.currency.clone(),
+            }),
+        }
+    }
+
+    /// Reports for every device in the history, biggest standby consumers first.
+    pub fn estimate_all(&self) -> Vec<StandbyReport> {
+        let mut reports: Vec<StandbyReport> = self.history.devices().map(|d| self.estimate(d)).collect();
+        reports.sort_by(|a, b| b.annual_kwh.total_cmp(&a.annual_kwh));
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::history::History;
+    use crate::reading::PowerReading;
+    use crate::tariff::Tariff;
+    use super::StandbyEstimator;
+
+    fn record(history: &mut History, device: &str, minutes: std::ops::Range<i64>, power_w: f64) {
+        let start = Utc.with_ymd_and_hms(2024, 6, 3, 0, 0, 0).unwrap();
+        for m in minutes.step_by(5) {
+            history.record(device, start + Duration::minutes(m), PowerReading { power_w, ..PowerReading::default() });
+        }
+    }
+
+    #[test]
+    fn test_tv_standby() {
+        let mut history = History::new();
+        // 18 hours in standby, a short pause and 6 hours watching.
+        record(&mut history, "tv", 0..18 * 60, 8.0);
+        record(&mut history, "tv", 18 * 60..18 * 60 + 20, 0.0);
+        record(&mut history, "tv", 18 * 60 + 20..24 * 60 + 5, 95.0);
+        record(&mut history, "lamp", 0..24 * 60, 0.0);
+
+        let estimator = StandbyEstimator::new(&history).with_tariff(Tariff::new(0.30, "EUR"));
+        let reports = estimator.estimate_all();
+        assert_eq!(reports[0].device, "tv");
+
+        let tv = &reports[0];
+        assert!((tv.standby_w.unwrap() - 8.0).abs() < 1e-9);
+        assert!((tv.standby_hours - (18.0 - 5.0 / 60.0)).abs() < 1e-9);
+        let expected_kwh = 8.0 * tv.standby_hours / 24.0 * 365.25 * 24.0 / 1000.0;
+        assert!((tv.annual_kwh - expected_kwh).abs() < 1e-9);
+        assert!((tv.annual_cost.as_ref().unwrap().amount - expected_kwh * 0.30).abs() < 1e-9);
+
+        assert_eq!(reports[1].standby_w, None);
+        assert_eq!(reports[1].annual_kwh, 0.0);
+    }
+
+    #[test]
+    fn test_short_dips_are_not_standby() {
+        let mut history = History::new();
+        record(&mut history, "fridge", 0..30, 3.0);
+        record(&mut history, "fridge", 30..120, 80.0);
+
+        let report = StandbyEstimator::new(&history).estimate("fridge");
+        assert_eq!(report.standby_w, None);
+        assert!(report.observed_hours > 1.9);
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * What was last seen of each device, kept on disk so that a restarted process
+ * can show "last seen 2h ago, was on at 3 W" before devices answer again:
+ *
+ *   let mut watcher = Watcher::new(Duration::from_secs(30));
+ *   watcher.add("heater", heater).persist_to("state.json")?;
+ *   if let Some(known) = watcher.last_known("heater") { ... }
+ *
+ * The file is JSON, one entry per device name, and is replaced as a whole
+ * after every poll.
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::reading::PowerReading;
+use crate::snapshot::DeviceSnapshot;
+use crate::types::PlugError;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LastKnown {
+    /// When the device last answered.
+    pub last_seen: Option<DateTime<Utc>>,
+    pub online: Option<bool>,
+    pub relay_on: Option<bool>,
+    pub reading: Option<PowerReading>,
+    /// Wi-Fi signal strength in dBm.
+    pub rssi: Option<i64>,
+    pub snapshot: Option<DeviceSnapshot>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StateStore {
+    devices: BTreeMap<String, LastKnown>,
+}
+
+impl StateStore {
+    pub fn new() -> StateStore {
+        StateStore::default()
+    }
+
+    /// An empty store if the file doesn't exist yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<StateStore, PlugError> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(StateStore::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes to a temporary file first, so a crash can't leave half a file behind.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PlugError> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, device: &str) -> Option<&LastKnown> {
+        self.devices.get(device)
+    }
+
+    pub fn entry(&mut self, device: &str) -> &mut LastKnown {
+        self.devices.entry(String::from(device)).or_default()
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&str, &LastKnown)> {
+        self.devices.iter().map(|(name, known)| (name.as_str(), known))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::reading::PowerReading;
+    use super::StateStore;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("hs110-state-{}.json", std::process::id()));
+        assert_eq!(StateStore::load(&path).unwrap(), StateStore::new());
+
+        let mut store = StateStore::new();
+        let known = store.entry("heater");
+        known.last_seen = Some(Utc.with_ymd_and_hms(2024, 6, 3, 18, 30, 0).unwrap());
+        known.relay_on = Some(true);
+        known.reading = Some(PowerReading { power_w: 3.0, ..Default::default() });
+        store.save(&path).unwrap();
+
+        let loaded = StateStore::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, store);
+        assert_eq!(loaded.get("heater").unwrap().reading.unwrap().power_w, 3.0);
+    }
+}
@@ -0,0 +1,152 @@
+/*
+ * Windowed statistics over a stream of readings, for dashboards that want
+ * smoothed values:
+ *
+ *   let readings = iter::from_fn(|| device.power_reading().ok());
+ *   for stats in readings.stats(RollingStats::new(60)) {
+ *       println!("{:.1} W (avg {:.1} W)", stats.power.ema, stats.power.mean);
+ *   }
+ *
+ * Min, max and mean are over the last `window` readings; the exponential moving
+ * average spans `ema_span` readings and defaults to the window size.
+ */
+
+use alloc::collections::VecDeque;
+
+use crate::reading::PowerReading;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub ema: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub power: Summary,
+    pub voltage: Summary,
+    /// Readings in the window, less than the window size at the start of a stream.
+    pub samples: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Series {
+    values: VecDeque<f64>,
+    ema: Option<f64>,
+}
+
+impl Series {
+    fn push(&mut self, value: f64, window: usize, alpha: f64) -> Summary {
+        if self.values.len() >= window {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+
+        let ema = match self.ema {
+            Some(ema) => ema + alpha * (value - ema),
+            None => value,
+        };
+        self.ema = Some(ema);
+
+        Summary {
+            min: self.values.iter().copied().fold(f64::INFINITY, f64::min),
+            max: self.values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            mean: self.values.iter().sum::<f64>() / self.values.len() as f64,
+            ema,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RollingStats {
+    window: usize,
+    alpha: f64,
+    power: Series,
+    voltage: Series,
+}
+
+impl RollingStats {
+    pub fn new(window: usize) -> RollingStats {
+        let window = window.max(1);
+        RollingStats {
+            window,
+            alpha: 2.0 / (window as f64 + 1.0),
+            power: Series { values: VecDeque::with_capacity(window), ema: None },
+            voltage: Series { values: VecDeque::with_capacity(window), ema: None },
+        }
+    }
+
+    pub fn with_ema_span(mut self, span: usize) -> RollingStats {
+        self.alpha = 2.0 / (span.max(1) as f64 + 1.0);
+        self
+    }
+
+    pub fn push(&mut self, reading: &PowerReading) -> Stats {
+        Stats {
+            power: self.power.push(reading.power_w, self.window, self.alpha),
+            voltage: self.voltage.push(reading.voltage_v, self.window, self.alpha),
+            samples: self.power.values.len(),
+        }
+    }
+}
+
+pub struct StatsIter<I> {
+    readings: I,
+    stats: RollingStats,
+}
+
+impl<I: Iterator<Item = PowerReading>> Iterator for StatsIter<I> {
+    type Item = Stats;
+
+    fn next(&mut self) -> Option<Stats> {
+        let reading = self.readings.next()?;
+        Some(self.stats.push(&reading))
+    }
+}
+
+pub trait StatsExt: Iterator<Item = PowerReading> + Sized {
+    /// Yields the statistics after each reading.
+    fn stats(self, stats: RollingStats) -> StatsIter<Self> {
+        StatsIter {
+            readings: self,
+            stats,
+        }
+    }
+}
+
+impl<I: Iterator<Item = PowerReading>> StatsExt for I {}
+
+#[cfg(test)]
+mod tests {
+    use crate::reading::PowerReading;
+    use super::{RollingStats, StatsExt};
+
+    #[test]
+    fn test_window() {
+        let readings = [100.0, 200.0, 300.0, 0.0].map(|power_w| PowerReading {
+            voltage_v: 230.0,
+            power_w,
+            ..PowerReading::default()
+        });
+        let stats: Vec<_> = readings.into_iter().stats(RollingStats::new(3).with_ema_span(1)).collect();
+
+        assert_eq!(stats[1].power.mean, 150.0);
+        assert_eq!(stats[1].samples, 2);
+        let last = stats[3];
+        assert_eq!((last.power.min, last.power.max, last.power.mean), (0.0, 300.0, 500.0 / 3.0));
+        // A span of one doesn't smooth at all.
+        assert_eq!(last.power.ema, 0.0);
+        assert_eq!(last.voltage.max, 230.0);
+        assert_eq!(last.samples, 3);
+    }
+
+    #[test]
+    fn test_ema() {
+        let mut stats = RollingStats::new(3);
+        stats.push(&PowerReading::default());
+        let power = stats.push(&PowerReading { power_w: 100.0, ..PowerReading::default() }).power;
+        assert_eq!(power.ema, 50.0);
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Sample persistence that can also be read back. A `Store` is a `Sink`, so the
+ * scheduler writes to it like to any other, and reports and the standby
+ * estimator read what they need from it as a `History`:
+ *
+ *   let store = Arc::new(Mutex::new(RingStore::new(1440)));
+ *   scheduler.sink(store.clone());
+ *   let history = store.history(day_start, now)?;
+ *   let reporter = Reporter::new(&history);
+ *
+ * `RingStore` keeps the last few samples per device in memory and `History`
+ * keeps them all; share either as `Arc<Mutex<_>>`. `CsvStore` appends to a
+ * file and clones of it read the same file.
+ */
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+
+use crate::history::History;
+use crate::reading::PowerReading;
+use crate::scheduler::Sample;
+use crate::sink::Sink;
+use crate::types::PlugError;
+
+pub trait Store: Sink {
+    /// Samples of `device` taken in `[from, to)`, oldest first.
+    fn samples(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>)
+        -> Result<Vec<PowerReading>, PlugError>;
+
+    fn devices(&self) -> Result<Vec<String>, PlugError>;
+
+    /// Everything taken in `[from, to)`.
+    fn history(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<History, PlugError> {
+        let mut history = History::new();
+        for device in self.devices()? {
+            for reading in self.samples(&device, from, to)? {
+                history.record(&device, reading.taken_at, reading);
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// Readings stamped with when the sample was taken.
+fn stamped(sample: &Sample) -> Option<PowerReading> {
+    let reading = sample.reading.as_ref().ok()?;
+    Some(PowerReading { taken_at: sample.taken_at, ..*reading })
+}
+
+impl Store for History {
+    fn samples(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>)
+        -> Result<Vec<PowerReading>, PlugError> {
+        Ok(self.between(device, from, to).iter().map(|(t, r)| PowerReading { taken_at: *t, ..*r }).collect())
+    }
+
+    fn devices(&self) -> Result<Vec<String>, PlugError> {
+        Ok(History::devices(self).map(String::from).collect())
+    }
+}
+
+impl<S: Store> Store for Arc<Mutex<S>> {
+    fn samples(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>)
+        -> Result<Vec<PowerReading>, PlugError> {
+        self.lock().map_err(|_| PlugError::new("Store lock poisoned"))?.samples(device, from, to)
+    }
+
+    fn devices(&self) -> Result<Vec<String>, PlugError> {
+        self.lock().map_err(|_| PlugError::new("Store lock poisoned"))?.devices()
+    }
+}
+
+/// The last `capacity` samples of each device.
+#[derive(Clone, Debug)]
+pub struct RingStore {
+    capacity: usize,
+    samples: BTreeMap<String, VecDeque<PowerReading>>,
+}
+
+impl RingStore {
+    pub fn new(capacity: usize) -> RingStore {
+        RingStore {
+            capacity: capacity.max(1),
+            samples: BTreeMap::new(),
+        }
+    }
+}
+
+impl Sink for RingStore {
+    fn write(&mut self, sample: &Sample) {
+        let Some(reading) = stamped(sample) else { return };
+        let samples = self.samples.entry(sample.device.clone()).or_default();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        // Samples normally arrive in order; keep it that way when they don't.
+        let idx = samples.partition_point(|r| r.taken_at <= reading.taken_at);
+        samples.insert(idx, reading);
+    }
+}
+
+impl Store for RingStore {
+    fn samples(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>)
+        -> Result<Vec<PowerReading>, PlugError> {
+        Ok(self.samples.get(device).into_iter().flatten()
+            .filter(|r| r.taken_at >= from && r.taken_at < to)
+            .copied()
+            .collect())
+    }
+
+    fn devices(&self) -> Result<Vec<String>, PlugError> {
+        Ok(self.samples.keys().cloned().collect())
+    }
+}
+
+/// Appends one line per successful sample to a CSV file:
+///
+///   device,taken_at,voltage_v,current_a,power_w,total_kwh
+///
+/// Reading scans the whole file, which is fine for months of samples from a
+/// handful of devices.
+#[derive(Clone, Debug)]
+pub struct CsvStore {
+    path: PathBuf,
+}
+
+const CSV_HEADER: &str = "device,taken_at,voltage_v,current_a,power_w,total_kwh";
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+/// The fields of one line, undoing `quote`.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+fn parse_line(line: &str) -> Option<(String, PowerReading)> {
+    let fields = fields(line);
+    let [device, taken_at, voltage_v, current_a, power_w, total_kwh] = fields.as_slice() else { return None };
+    let reading = PowerReading {
+        voltage_v: voltage_v.parse().ok()?,
+        current_a: current_a.parse().ok()?,
+        power_w: power_w.parse().ok()?,
+        total_kwh: total_kwh.parse().ok()?,
+        taken_at: DateTime::parse_from_rfc3339(taken_at).ok()?.with_timezone(&Utc),
+    };
+    Some((device.clone(), reading))
+}
+
+impl CsvStore {
+    /// The file is created on the first write.
+    pub fn open(path: impl AsRef<Path>) -> CsvStore {
+        CsvStore {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn append(&self, device: &str, reading: &PowerReading) -> Result<(), PlugError> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        writeln!(file, "{},{},{},{},{},{}", quote(device), reading.taken_at.to_rfc3339(),
+                 reading.voltage_v, reading.current_a, reading.power_w, reading.total_kwh)?;
+        Ok(())
+    }
+
+    /// Every well-formed line; a missing file holds no samples.
+    fn read(&self) -> Result<Vec<(String, PowerReading)>, PlugError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(text.lines().skip(1).filter_map(parse_line).collect())
+    }
+}
+
+impl Sink for CsvStore {
+    fn write(&mut self, sample: &Sample) {
+        if let Some(reading) = stamped(sample) {
+            // A sink has nowhere to report to; a lost line only leaves a gap.
+            let _ = self.append(&sample.device, &reading);
+        }
+    }
+}
+
+impl Store for CsvStore {
+    fn samples(&self, device: &str, from: DateTime<Utc>, to: DateTime<Utc>)
+        -> Result<Vec<PowerReading>, PlugError> {
+        let mut samples: Vec<PowerReading> = self.read()?.into_iter()
+            .filter(|(d, r)| d == device && r.taken_at >= from && r.taken_at < to)
+            .map(|(_, r)| r)
+            .collect();
+        samples.sort_by_key(|r| r.taken_at);
+        Ok(samples)
+    }
+
+    fn devices(&self) -> Result<Vec<String>, PlugError> {
+        let mut devices: Vec<String> = self.read()?.into_iter().map(|(d, _)| d).collect();
+        devices.sort();
+        devices.dedup();
+        Ok(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use crate::reading::PowerReading;
+    use crate::scheduler::Sample;
+    use crate::sink::Sink;
+    use crate::types::PlugError;
+    use super::{CsvStore, RingStore, Store};
+
+    fn sample(device: &str, at: DateTime<Utc>, power_w: f64) -> Sample {
+        Sample { device: String::from(device), taken_at: at, reading: Ok(PowerReading { power_w, ..PowerReading::default() }) }
+    }
+
+    #[test]
+    fn test_ring_store() {
+        let start = Utc.timestamp_opt(1700000000, 0).unwrap();
+        let mut store = RingStore::new(2);
+        for minute in 0..3 {
+            store.write(&sample("kitchen", start + Duration::minutes(minute), minute as f64));
+        }
+        store.write(&Sample { reading: Err(PlugError::new("Timed out")), ..sample("kitchen", start, 0.0) });
+
+        let kept = store.samples("kitchen", start, start + Duration::hours(1)).unwrap();
+        assert_eq!(kept.iter().map(|r| r.power_w).collect::<Vec<_>>(), [1.0, 2.0]);
+        assert_eq!(store.history(start, start + Duration::minutes(2)).unwrap().samples("kitchen").len(), 1);
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let path = std::env::temp_dir().join(format!("hs110-store-{}.csv", std::process::id()));
+        let start = Utc.timestamp_opt(1700000000, 0).unwrap();
+        let mut store = CsvStore::open(&path);
+        assert!(store.devices().unwrap().is_empty());
+        store.write(&sample("living room, left", start + Duration::minutes(1), 46.5));
+        store.write(&sample("\"den\"", start, 3.0));
+
+        assert_eq!(store.devices().unwrap(), ["\"den\"", "living room, left"]);
+        let samples = store.samples("living room, left", start, start + Duration::hours(1)).unwrap();
+        assert_eq!((samples[0].power_w, samples[0].taken_at), (46.5, start + Duration::minutes(1)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
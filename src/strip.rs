@@ -0,0 +1,229 @@
+/*
+ * Power strips such as the HS300 answer for all their outlets at one address.
+ * Each outlet is metered separately: commands reach an outlet by naming it in
+ * a `context`, which `ChildPlug` adds for you.
+ *
+ *   let strip = Strip::new(TpLinkDevice::with_transport("10.0.0.5:9999", transport));
+ *   for child in strip.children()? {
+ *       println!("{}: {} W", child.alias(), child.power_reading()?.power_w);
+ *   }
+ *   println!("total: {} W", strip.total_power()?);
+ */
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use serde_json::Value;
+
+use crate::{stamped, TpLinkDevice};
+use crate::commands;
+use crate::reading::PowerReading;
+use crate::types::{EmeterGetDaystatItem, PlugError, PlugResponse, SysInfoChild};
+
+#[derive(Clone)]
+pub struct Strip {
+    device: TpLinkDevice,
+}
+
+#[derive(Clone)]
+pub struct ChildPlug {
+    device: TpLinkDevice,
+    info: SysInfoChild,
+}
+
+impl Strip {
+    pub fn new(device: TpLinkDevice) -> Strip {
+        Strip {
+            device,
+        }
+    }
+
+    pub fn device(&self) -> &TpLinkDevice {
+        &self.device
+    }
+
+    pub fn children(&self) -> Result<Vec<ChildPlug>, PlugError> {
+        let sysinfo = self.device.sysinfo()?;
+        if sysinfo.children.is_empty() {
+            return Err(PlugError::new("Device has no outlets"));
+        }
+        Ok(sysinfo.children.into_iter()
+            .map(|info| ChildPlug { device: self.device.clone(), info })
+            .collect())
+    }
+
+    /// Looks an outlet up by id or alias.
+    pub fn child(&self, id_or_alias: &str) -> Result<ChildPlug, PlugError> {
+        match self.children()?.into_iter().find(|c| c.info.id == id_or_alias || c.info.alias == id_or_alias) {
+            Some(child) => Ok(child),
+            None => Err(PlugError::new(alloc::format!("No outlet named {}", id_or_alias).as_str())),
+        }
+    }
+
+    /// Sum of the outlets' current power draw in W.
+    pub fn total_power(&self) -> Result<f64, PlugError> {
+        let mut total = 0.0;
+        for child in self.children()? {
+            total += child.power_reading()?.power_w;
+        }
+        Ok(total)
+    }
+
+    /// Energy per day summed over the outlets, in the shape of a single plug's daystat.
+    pub fn total_daystat(&self, year: i32, month: u32) -> Result<Vec<EmeterGetDaystatItem>, PlugError> {
+        let mut days: BTreeMap<i64, f64> = BTreeMap::new();
+        for child in self.children()? {
+            for day in child.daystat(year, month)? {
+                *days.entry(day.day).or_default() += day.energy_kwh().unwrap_or(0.0);
+            }
+        }
+        Ok(days.into_iter()
+            .map(|(day, kwh)| EmeterGetDaystatItem {
+                year: year as i64,
+                month: month as i64,
+                day,
+                energy: Some(kwh),
+                energy_wh: None,
+            })
+            .collect())
+    }
+}
+
+impl ChildPlug {
+    pub fn id(&self) -> &str {
+        &self.info.id
+    }
+
+    /// The outlet as listed when it was looked up; see `refresh` for its current state.
+    pub fn info(&self) -> &SysInfoChild {
+        &self.info
+    }
+
+    pub fn alias(&self) -> &str {
+        &self.info.alias
+    }
+
+    pub(crate) fn send(&self, cmd: Value) -> Result<PlugResponse, PlugError> {
+        self.device.send(commands::for_child(cmd, &self.info.id))
+    }
+
+    /// Reads the outlet's current entry from the strip's sysinfo.
+    pub fn refresh(&self) -> Result<SysInfoChild, PlugError> {
+        match self.device.sysinfo()?.children.into_iter().find(|c| c.id == self.info.id) {
+            Some(info) => Ok(info),
+            None => Err(PlugError::new("Outlet is no longer listed")),
+        }
+    }
+
+    pub fn set_alias(&self, alias: &str) -> Result<PlugResponse, PlugError> {
+        self.send(commands::set_device_alias(alias))
+    }
+
+    pub fn is_on(&self) -> Result<bool, PlugError> {
+        Ok(self.refresh()?.state != 0)
+    }
+
+    /// Seconds the outlet has been switched on, 0 while it is off.
+    pub fn on_time(&self) -> Result<i64, PlugError> {
+        Ok(self.refresh()?.on_time)
+    }
+
+    pub fn on(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::set_relay_state(1))
+    }
+
+    pub fn off(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::set_relay_state(0))
+    }
+
+    /// As `TpLinkDevice::ensure_on`, for this outlet.
+    pub fn ensure_on(&self) -> Result<bool, PlugError> {
+        self.ensure(true)
+    }
+
+    pub fn ensure_off(&self) -> Result<bool, PlugError> {
+        self.ensure(false)
+    }
+
+    fn ensure(&self, on: bool) -> Result<bool, PlugError> {
+        if self.is_on()? == on {
+            return Ok(false);
+        }
+        self.send(commands::set_relay_state(if on { 1 } else { 0 }))?;
+        Ok(true)
+    }
+
+    pub fn get_realtime(&self) -> Result<PlugResponse, PlugError> {
+        self.send(commands::get_realtime())
+    }
+
+    pub fn power_reading(&self) -> Result<PowerReading, PlugError> {
+        match self.get_realtime()?.emeter.and_then(|e| e.get_realtime) {
+            Some(realtime) => Ok(stamped(&realtime)),
+            None => Err(PlugError::new("Response has no emeter reading")),
+        }
+    }
+
+    pub fn get_daystat(&self, year: i32, month: u32) -> Result<PlugResponse, PlugError> {
+        self.send(commands::get_daystat(year, month))
+    }
+
+    pub fn daystat(&self, year: i32, month: u32) -> Result<Vec<EmeterGetDaystatItem>, PlugError> {
+        match self.get_daystat(year, month)?.emeter.and_then(|e| e.get_daystat) {
+            Some(daystat) => Ok(daystat.day_list),
+            None => Err(PlugError::new("Response has no daystat")),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::types::PlugError;
+    use super::Strip;
+
+    /// An HS300 with two metered outlets, answering `child_ids` contexts.
+    pub(crate) fn hs300() -> TpLinkDevice {
+        let transport = |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let child = request["context"]["child_ids"][0].as_str().unwrap_or("");
+            let (power_mw, energy_wh) = match child {
+                "800600" => (12000, 100),
+                "800601" => (30500, 250),
+                _ => (0, 0),
+            };
+            let response = if request["system"].get("get_sysinfo").is_some() {
+                json!({"system": {"get_sysinfo": {
+                    "sw_ver": "1.0.6", "hw_ver": "1.0", "mic_type": "IOT.SMARTPLUGSWITCH",
+                    "model": "HS300(US)", "deviceId": "8006", "alias": "Desk", "feature": "TIM:ENE",
+                    "led_off": 0, "child_num": 2, "err_code": 0, "children": [
+                        {"id": "800600", "state": 1, "alias": "Monitor", "on_time": 120, "next_action": {"type": -1}},
+                        {"id": "800601", "state": 0, "alias": "Lamp", "on_time": 0, "next_action": {"type": -1}}
+                    ]
+                }}})
+            } else if request["emeter"].get("get_daystat").is_some() {
+                json!({"emeter": {"get_daystat": {"day_list": [
+                    {"year": 2024, "month": 6, "day": 3, "energy_wh": energy_wh}], "err_code": 0}}})
+            } else {
+                json!({"emeter": {"get_realtime": {"power_mw": power_mw, "err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("hs300", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_children_metered_separately() {
+        let strip = Strip::new(hs300());
+        let children = strip.children().unwrap();
+        assert_eq!(children.iter().map(|c| c.alias()).collect::<Vec<_>>(), ["Monitor", "Lamp"]);
+        assert_eq!(strip.child("Lamp").unwrap().power_reading().unwrap().power_w, 30.5);
+        assert_eq!(strip.total_power().unwrap(), 42.5);
+
+        let daystat = strip.total_daystat(2024, 6).unwrap();
+        assert_eq!(daystat.len(), 1);
+        assert_eq!(daystat[0].energy_kwh(), Some(0.35));
+    }
+}
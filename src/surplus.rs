@@ -0,0 +1,256 @@
+/*
+ * Runs plugs on spare solar power. Something that knows the inverter or the
+ * grid meter sends the surplus, the watts currently being exported:
+ *
+ *   let mut solar = SurplusController::new();
+ *   solar.hysteresis(150.0)
+ *       .device("boiler", boiler, 2000.0)     // started first
+ *       .device("pump", pump, 600.0)
+ *       .min_run("pump", Duration::from_secs(3600));
+ *   solar.run(surplus);                       // a Receiver<f64>
+ *
+ * or calls `solar.handle(watts)` from its own callback.
+ *
+ * The surplus is taken to already include whatever the controlled plugs draw.
+ * An idle device is started, highest priority first, once the surplus covers
+ * its load plus `hysteresis`. When the house imports more than `hysteresis`,
+ * the running device added last is stopped, unless it hasn't run for its
+ * minimum time yet. One plug is switched per update and nothing again within
+ * `cooldown`, so the signal can catch up with the last change. Devices are
+ * assumed off until the controller starts them.
+ */
+
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::bus::EventBus;
+use crate::events::Event;
+use crate::shedding::Outcome;
+use crate::timing::{self, Clock};
+
+struct Member {
+    name: String,
+    device: TpLinkDevice,
+    load_w: f64,
+    min_run: Duration,
+    on_since: Option<Instant>,
+}
+
+pub struct SurplusController {
+    hysteresis_w: f64,
+    cooldown: Duration,
+    members: Vec<Member>,
+    last_change: Option<Instant>,
+    bus: Option<EventBus>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for SurplusController {
+    fn default() -> SurplusController {
+        SurplusController::new()
+    }
+}
+
+impl SurplusController {
+    pub fn new() -> SurplusController {
+        SurplusController {
+            hysteresis_w: 100.0,
+            cooldown: Duration::from_secs(60),
+            members: Vec::new(),
+            last_change: None,
+            bus: None,
+            clock: timing::system(),
+        }
+    }
+
+    /// Margin kept on both sides, so a device doesn't flap around zero export.
+    pub fn hysteresis(&mut self, watts: f64) -> &mut SurplusController {
+        self.hysteresis_w = watts.max(0.0);
+        self
+    }
+
+    pub fn cooldown(&mut self, cooldown: Duration) -> &mut SurplusController {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Publishes a `CommandFailed` for `system.set_relay_state`, under the name
+    /// the device was added with, when starting or stopping it fails.
+    pub fn publish_to(&mut self, bus: &EventBus) -> &mut SurplusController {
+        self.bus = Some(bus.clone());
+        self
+    }
+
+    /// Times cooldowns and minimum runs by `clock`.
+    pub fn with_clock(&mut self, clock: Arc<dyn Clock>) -> &mut SurplusController {
+        self.clock = clock;
+        self
+    }
+
+    /// Adds a device drawing about `load_w` when on. Devices added earlier are started first.
+    pub fn device(&mut self, name: &str, device: TpLinkDevice, load_w: f64) -> &mut SurplusController {
+        self.members.push(Member {
+            name: String::from(name),
+            device,
+            load_w,
+            min_run: Duration::ZERO,
+            on_since: None,
+        });
+        self
+    }
+
+    /// How long `name` stays on once started, whatever the surplus does.
+    pub fn min_run(&mut self, name: &str, min_run: Duration) -> &mut SurplusController {
+        if let Some(member) = self.members.iter_mut().find(|m| m.name == name) {
+            member.min_run = min_run;
+        }
+        self
+    }
+
+    pub fn is_running(&self, name: &str) -> bool {
+        self.members.iter().any(|m| m.name == name && m.on_since.is_some())
+    }
+
+    fn switch(member: &mut Member, on: bool, now: Instant) -> Outcome {
+        let result = if on { member.device.on() } else { member.device.off() }.map(|_| ());
+        if result.is_ok() {
+            member.on_since = if on { Some(now) } else { None };
+        }
+        Outcome {
+            device: member.name.clone(),
+            on,
+            result,
+        }
+    }
+
+    pub fn handle(&mut self, surplus_w: f64) -> Vec<Outcome> {
+        let now = self.clock.now();
+        if self.last_change.is_some_and(|t| now.duration_since(t) < self.cooldown) {
+            return Vec::new();
+        }
+
+        let hysteresis_w = self.hysteresis_w;
+        let member = if surplus_w < -hysteresis_w {
+            // Waits for the last one started rather than stopping one that ranks above it.
+            self.members.iter_mut().rev()
+                .find(|m| m.on_since.is_some())
+                .filter(|m| m.on_since.is_some_and(|t| now.duration_since(t) >= m.min_run))
+                .map(|m| (m, false))
+        } else {
+            self.members.iter_mut()
+                .find(|m| m.on_since.is_none() && surplus_w >= m.load_w + hysteresis_w)
+                .map(|m| (m, true))
+        };
+        let outcome = match member {
+            Some((member, on)) => SurplusController::switch(member, on, now),
+            None => return Vec::new(),
+        };
+
+        self.last_change = Some(now);
+        if let (Some(bus), Err(e)) = (&self.bus, &outcome.result) {
+            bus.publish(Event::command_failed(&outcome.device, e));
+        }
+        vec![outcome]
+    }
+
+    /// Handles surplus figures until the sender side hangs up.
+    pub fn run(&mut self, surplus: Receiver<f64>) {
+        for surplus_w in surplus {
+            self.handle(surplus_w);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::timing::MockClock;
+    use crate::types::PlugError;
+    use super::SurplusController;
+
+    fn plug(name: &'static str, log: Arc<Mutex<Vec<String>>>) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let state = &request["system"]["set_relay_state"]["state"];
+            log.lock().unwrap().push(format!("{} {}", name, state));
+            Ok(encrypt_payload(br#"{"system":{"set_relay_state":{"err_code":0}}}"#.to_vec()))
+        };
+        TpLinkDevice::with_transport(name, Arc::new(transport))
+    }
+
+    #[test]
+    fn test_starts_by_priority_and_stops_after_min_run() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(MockClock::new());
+        let mut solar = SurplusController::new();
+        solar.with_clock(clock.clone())
+            .cooldown(Duration::from_secs(10))
+            .device("boiler", plug("boiler", log.clone()), 2000.0)
+            .device("pump", plug("pump", log.clone()), 500.0)
+            .min_run("pump", Duration::from_secs(600));
+
+        // Not enough for the boiler, but the pump fits.
+        let outcomes = solar.handle(700.0);
+        assert_eq!((outcomes[0].device.as_str(), outcomes[0].on), ("pump", true));
+        // Within the hysteresis band: nothing changes.
+        clock.advance(Duration::from_secs(20));
+        assert!(solar.handle(-50.0).is_empty());
+        // Importing, but the pump hasn't had its ten minutes.
+        clock.advance(Duration::from_secs(10));
+        assert!(solar.handle(-400.0).is_empty());
+        clock.advance(Duration::from_secs(570));
+        let outcomes = solar.handle(-400.0);
+        assert_eq!((outcomes[0].device.as_str(), outcomes[0].on), ("pump", false));
+        assert!(!solar.is_running("pump"));
+        assert_eq!(*log.lock().unwrap(), ["pump 1", "pump 0"]);
+    }
+
+    #[test]
+    fn test_one_change_per_cooldown() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(MockClock::new());
+        let mut solar = SurplusController::new();
+        solar.with_clock(clock.clone())
+            .cooldown(Duration::from_secs(60))
+            .device("boiler", plug("boiler", log.clone()), 2000.0)
+            .device("pump", plug("pump", log.clone()), 500.0);
+
+        assert_eq!(solar.handle(3000.0)[0].device, "boiler");
+        clock.advance(Duration::from_secs(30));
+        assert!(solar.handle(1000.0).is_empty());
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(solar.handle(1000.0)[0].device, "pump");
+        // The pump was added last, so it's the first to go.
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(solar.handle(-800.0)[0].device, "pump");
+        assert!(solar.is_running("boiler"));
+    }
+
+    #[test]
+    fn test_waits_for_the_min_run_of_the_last_started() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(MockClock::new());
+        let mut solar = SurplusController::new();
+        solar.with_clock(clock.clone())
+            .cooldown(Duration::from_secs(10))
+            .device("boiler", plug("boiler", log.clone()), 2000.0)
+            .device("pump", plug("pump", log.clone()), 500.0)
+            .min_run("pump", Duration::from_secs(600));
+
+        solar.handle(3000.0);
+        clock.advance(Duration::from_secs(20));
+        solar.handle(1000.0);
+        // The boiler could go, but it ranks above the pump.
+        clock.advance(Duration::from_secs(20));
+        assert!(solar.handle(-800.0).is_empty());
+        assert!(solar.is_running("boiler"));
+        clock.advance(Duration::from_secs(580));
+        assert_eq!(solar.handle(-800.0)[0].device, "pump");
+        assert_eq!(*log.lock().unwrap(), ["boiler 1", "pump 1", "pump 0"]);
+    }
+}
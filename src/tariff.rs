@@ -0,0 +1,149 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{Datelike, Days, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// A flat energy price, e.g. 0.32 EUR per kWh.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tariff {
+    pub price_per_kwh: f64,
+    pub currency: String,
+}
+
+impl Tariff {
+    pub fn new(price_per_kwh: f64, currency: &str) -> Tariff {
+        Tariff {
+            price_per_kwh,
+            currency: String::from(currency),
+        }
+    }
+
+    pub fn cost(&self, energy_kwh: f64) -> f64 {
+        energy_kwh * self.price_per_kwh
+    }
+}
+
+/// Part of the day with its own price, in local time. A window that ends
+/// before it starts runs past midnight, and belongs to the day it started on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    pub name: String,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    #[serde(default)]
+    pub weekdays_only: bool,
+    pub price_per_kwh: f64,
+}
+
+impl Window {
+    pub fn new(name: &str, start: NaiveTime, end: NaiveTime, price_per_kwh: f64) -> Window {
+        Window {
+            name: String::from(name),
+            start,
+            end,
+            weekdays_only: false,
+            price_per_kwh,
+        }
+    }
+
+    pub fn weekdays_only(mut self) -> Window {
+        self.weekdays_only = true;
+        self
+    }
+
+    fn applies_on(&self, date: NaiveDate) -> bool {
+        !self.weekdays_only || !matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+    }
+
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let (date, time) = (at.date(), at.time());
+        if self.start <= self.end {
+            (self.start..self.end).contains(&time) && self.applies_on(date)
+        } else if time >= self.start {
+            self.applies_on(date)
+        } else {
+            time < self.end && date.pred_opt().is_some_and(|d| self.applies_on(d))
+        }
+    }
+}
+
+/// A time-of-use tariff, e.g. peak 17:00-21:00 on weekdays and off-peak the
+/// rest of the time. The first window containing a time prices it; windows
+/// may share a name, such as a morning and an evening peak.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeOfUse {
+    pub windows: Vec<Window>,
+    /// The name and price of times no window covers.
+    pub otherwise: String,
+    pub otherwise_price_per_kwh: f64,
+    pub currency: String,
+}
+
+impl TimeOfUse {
+    pub fn new(otherwise: &str, price_per_kwh: f64, currency: &str) -> TimeOfUse {
+        TimeOfUse {
+            windows: Vec::new(),
+            otherwise: String::from(otherwise),
+            otherwise_price_per_kwh: price_per_kwh,
+            currency: String::from(currency),
+        }
+    }
+
+    pub fn window(mut self, window: Window) -> TimeOfUse {
+        self.windows.push(window);
+        self
+    }
+
+    /// The name of the window `at` falls in, and its price.
+    pub fn bucket(&self, at: NaiveDateTime) -> (&str, f64) {
+        match self.windows.iter().find(|w| w.contains(at)) {
+            Some(window) => (&window.name, window.price_per_kwh),
+            None => (&self.otherwise, self.otherwise_price_per_kwh),
+        }
+    }
+
+    /// The next time after `at` where the bucket may change.
+    pub fn next_change(&self, at: NaiveDateTime) -> NaiveDateTime {
+        let today = at.date();
+        let tomorrow = today.checked_add_days(Days::new(1)).unwrap_or(NaiveDate::MAX);
+        let midnight = tomorrow.and_time(NaiveTime::MIN);
+        self.windows.iter()
+            .flat_map(|w| [w.start, w.end])
+            .flat_map(|time| [today.and_time(time), tomorrow.and_time(time)])
+            .filter(|t| *t > at)
+            .fold(midnight, |next, t| next.min(t))
+    }
+
+    /// What energy split by bucket name costs.
+    pub fn cost(&self, buckets: &BTreeMap<String, f64>) -> f64 {
+        buckets.iter().map(|(name, kwh)| {
+            let price = self.windows.iter().find(|w| w.name == *name).map(|w| w.price_per_kwh);
+            kwh * price.unwrap_or(self.otherwise_price_per_kwh)
+        }).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+    use super::{TimeOfUse, Window};
+
+    #[test]
+    fn test_time_of_use_buckets() {
+        let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        // 2024-06-07 is a Friday.
+        let at = |d, h, m| -> NaiveDateTime { NaiveDate::from_ymd_opt(2024, 6, d).unwrap().and_time(hm(h, m)) };
+        let tariff = TimeOfUse::new("day", 0.30, "EUR")
+            .window(Window::new("peak", hm(17, 0), hm(21, 0), 0.45).weekdays_only())
+            .window(Window::new("night", hm(23, 0), hm(7, 0), 0.18));
+
+        assert_eq!(tariff.bucket(at(7, 18, 0)), ("peak", 0.45));
+        assert_eq!(tariff.bucket(at(8, 18, 0)).0, "day");
+        assert_eq!(tariff.bucket(at(8, 3, 0)).0, "night");
+        assert_eq!(tariff.bucket(at(7, 21, 0)).0, "day");
+        assert_eq!(tariff.next_change(at(7, 21, 0)), at(7, 23, 0));
+        assert_eq!(tariff.next_change(at(7, 23, 30)), at(8, 0, 0));
+        assert_eq!(tariff.next_change(at(8, 0, 0)), at(8, 7, 0));
+    }
+}
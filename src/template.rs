@@ -0,0 +1,201 @@
+/*
+ * One schedule for many devices, each optionally some minutes later than the
+ * one before so that heaters or pumps don't all start at once:
+ *
+ *   let template = ScheduleTemplate::new(vec![ScheduleRule::at(6, 0, true).on_days(WEEKDAYS)])
+ *       .device("hall", hall)
+ *       .device("bath", bath)
+ *       .stagger(2);
+ *   for change in template.preview() {
+ *       println!("{}:\n{}", change.device, change.result?);
+ *   }
+ *   template.apply();
+ *
+ * `preview` reads every schedule and says what `apply` would add and remove,
+ * rule by rule. `apply` replaces the schedules that differ and leaves the
+ * others alone. Devices are handled in parallel, like a scheduler round.
+ */
+
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use std::thread;
+
+use crate::TpLinkDevice;
+use crate::schedule::{diff_rules, ScheduleRule};
+use crate::types::PlugError;
+
+/// What one device's schedule needs to become the template's.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleDiff {
+    pub added: Vec<ScheduleRule>,
+    pub removed: Vec<ScheduleRule>,
+}
+
+impl RuleDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+fn describe(rule: &ScheduleRule) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let time = match rule.stime_opt {
+        1 => String::from("sunrise"),
+        2 => String::from("sunset"),
+        _ => format!("{:02}:{:02}", rule.smin / 60, rule.smin % 60),
+    };
+    let days: Vec<&str> = rule.wday.iter().zip(DAYS).filter(|(on, _)| **on != 0).map(|(_, d)| d).collect();
+    format!("{} {} {} ({})", time, if rule.sact == 1 { "on" } else { "off" }, days.join(","), rule.name)
+}
+
+impl fmt::Display for RuleDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "unchanged");
+        }
+        for rule in &self.removed {
+            writeln!(f, "- {}", describe(rule))?;
+        }
+        for rule in &self.added {
+            writeln!(f, "+ {}", describe(rule))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Change {
+    pub device: String,
+    pub result: Result<RuleDiff, PlugError>,
+}
+
+pub struct ScheduleTemplate {
+    rules: Vec<ScheduleRule>,
+    devices: Vec<(String, TpLinkDevice)>,
+    stagger: i64,
+    offsets: HashMap<String, i64>,
+}
+
+impl ScheduleTemplate {
+    pub fn new(rules: Vec<ScheduleRule>) -> ScheduleTemplate {
+        ScheduleTemplate {
+            rules,
+            devices: Vec::new(),
+            stagger: 0,
+            offsets: HashMap::new(),
+        }
+    }
+
+    pub fn device(mut self, name: &str, device: TpLinkDevice) -> ScheduleTemplate {
+        self.devices.push((String::from(name), device));
+        self
+    }
+
+    /// Each device runs the rules `minutes` after the one added before it.
+    pub fn stagger(mut self, minutes: i64) -> ScheduleTemplate {
+        self.stagger = minutes;
+        self
+    }
+
+    /// Runs the rules `minutes` late on `name` instead of its staggered offset.
+    pub fn offset(mut self, name: &str, minutes: i64) -> ScheduleTemplate {
+        self.offsets.insert(String::from(name), minutes);
+        self
+    }
+
+    /// The rules as the device at `position` gets them.
+    fn rules_for(&self, position: usize, name: &str) -> Vec<ScheduleRule> {
+        let offset = self.offsets.get(name).copied().unwrap_or(self.stagger * position as i64);
+        self.rules.iter().map(|rule| rule.shifted(offset)).collect()
+    }
+
+    fn each<F>(&self, f: F) -> Vec<Change>
+        where F: Fn(&TpLinkDevice, &[ScheduleRule]) -> Result<RuleDiff, PlugError> + Sync {
+        thread::scope(|s| {
+            let handles: Vec<_> = self.devices.iter().enumerate()
+                .map(|(position, (name, device))| {
+                    let f = &f;
+                    s.spawn(move || f(device, &self.rules_for(position, name)))
+                })
+                .collect();
+            self.devices.iter().zip(handles)
+                .map(|((name, _), handle)| Change {
+                    device: name.clone(),
+                    result: handle.join().unwrap_or_else(|_| Err(PlugError::new("Worker panicked"))),
+                })
+                .collect()
+        })
+    }
+
+    /// What `apply` would change, without changing anything.
+    pub fn preview(&self) -> Vec<Change> {
+        self.each(|device, rules| {
+            let (added, removed) = diff_rules(&device.schedule_rules()?, rules);
+            Ok(RuleDiff { added, removed })
+        })
+    }
+
+    /// Puts the template on every device whose schedule differs and returns what changed.
+    pub fn apply(&self) -> Vec<Change> {
+        self.each(|device, rules| {
+            let (added, removed) = diff_rules(&device.schedule_rules()?, rules);
+            let diff = RuleDiff { added, removed };
+            if !diff.is_empty() {
+                device.set_schedules(rules)?;
+            }
+            Ok(diff)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::schedule::ScheduleRule;
+    use crate::types::PlugError;
+    use super::ScheduleTemplate;
+
+    fn plug(rules: Arc<Mutex<Vec<Value>>>) -> TpLinkDevice {
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let mut rules = rules.lock().unwrap();
+            let schedule = &request["schedule"];
+            let response = if schedule.get("delete_all_rules").is_some() {
+                rules.clear();
+                json!({"schedule": {"delete_all_rules": {"err_code": 0}}})
+            } else if let Some(rule) = schedule.get("add_rule") {
+                rules.push(rule.clone());
+                json!({"schedule": {"add_rule": {"id": "R1", "err_code": 0}}})
+            } else {
+                json!({"schedule": {"get_rules": {"rule_list": *rules, "err_code": 0}}})
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+        TpLinkDevice::with_transport("plug", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_staggered_preview_and_apply() {
+        let hall = Arc::new(Mutex::new(Vec::new()));
+        let bath = Arc::new(Mutex::new(vec![serde_json::to_value(ScheduleRule::at(6, 5, true)).unwrap()]));
+        let template = ScheduleTemplate::new(vec![ScheduleRule::at(6, 0, true)])
+            .device("hall", plug(hall.clone()))
+            .device("bath", plug(bath.clone()))
+            .device("attic", plug(Arc::new(Mutex::new(Vec::new()))))
+            .stagger(5)
+            .offset("attic", 30);
+
+        let preview = template.preview();
+        assert_eq!(preview[0].result.as_ref().unwrap().added[0].smin, 6 * 60);
+        assert!(preview[1].result.as_ref().unwrap().is_empty());
+        assert_eq!(preview[2].result.as_ref().unwrap().added[0].smin, 6 * 60 + 30);
+        assert!(hall.lock().unwrap().is_empty());
+
+        template.apply();
+        assert_eq!(hall.lock().unwrap()[0]["smin"], 6 * 60);
+        assert_eq!(bath.lock().unwrap().len(), 1);
+    }
+}
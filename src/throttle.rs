@@ -0,0 +1,199 @@
+/*
+ * Protects relays from automation that switches them too often, such as a
+ * rule fighting another one:
+ *
+ *   let (device, throttle) = device.throttled(Duration::from_secs(5));
+ *   engine.device("heater", device);
+ *   ...
+ *   for suppressed in throttle.suppressed() { println!("{:?}", suppressed); }
+ *   println!("{} suppressed in all", throttle.suppressed_count());
+ *
+ * Within `min_interval` of the last switch, asking for the state the relay was
+ * just switched to is answered without reaching the device, and asking for
+ * the other state fails. Strip outlets are tracked one by one. Everything
+ * other than `set_relay_state` passes through. Only the most recent
+ * suppressed commands are kept, and all of them are counted.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+use crate::TpLinkDevice;
+use crate::dryrun::acknowledge;
+use crate::protocol::{decrypt_payload, encrypt_payload};
+use crate::timing::{self, Clock};
+use crate::transport::Transport;
+use crate::types::PlugError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suppression {
+    /// The relay was already switched to this state.
+    Coalesced,
+    /// The relay was switched the other way too recently.
+    TooSoon,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Suppressed {
+    pub address: String,
+    pub command: Value,
+    pub why: Suppression,
+}
+
+/// Suppressed commands kept at most; older ones are dropped first.
+pub const MAX_SUPPRESSED: usize = 256;
+
+/// Address and outlets, relay state, when it was switched.
+pub(crate) type Switches = HashMap<(String, String), (bool, Instant)>;
+
+pub struct RelayThrottle {
+    inner: Arc<dyn Transport>,
+    min_interval: Duration,
+    clock: Arc<dyn Clock>,
+    switches: Mutex<Switches>,
+    suppressed: Mutex<(VecDeque<Suppressed>, u64)>,
+}
+
+/// The state asked for, if `cmd` is a `set_relay_state`.
+pub(crate) fn requested_state(cmd: &Value) -> Option<bool> {
+    cmd.get("system")?.get("set_relay_state")?.get("state")?.as_i64().map(|s| s != 0)
+}
+
+pub(crate) fn outlets(cmd: &Value) -> String {
+    cmd.get("context").and_then(|c| c.get("child_ids")).map(Value::to_string).unwrap_or_default()
+}
+
+impl RelayThrottle {
+    pub fn new(inner: Arc<dyn Transport>, min_interval: Duration) -> RelayThrottle {
+        RelayThrottle {
+            inner,
+            min_interval,
+            clock: timing::system(),
+            switches: Mutex::new(HashMap::new()),
+            suppressed: Mutex::new((VecDeque::new(), 0)),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> RelayThrottle {
+        self.clock = clock;
+        self
+    }
+
+    /// The last `MAX_SUPPRESSED` commands that didn't reach the device, oldest first.
+    pub fn suppressed(&self) -> Vec<Suppressed> {
+        self.suppressed.lock().map(|s| s.0.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Commands that didn't reach the device, in all.
+    pub fn suppressed_count(&self) -> u64 {
+        self.suppressed.lock().map(|s| s.1).unwrap_or(0)
+    }
+
+    fn suppress(&self, address: &str, cmd: Value, why: Suppression) {
+        if let Ok(mut suppressed) = self.suppressed.lock() {
+            let (recent, count) = &mut *suppressed;
+            if recent.len() >= MAX_SUPPRESSED {
+                recent.pop_front();
+            }
+            recent.push_back(Suppressed { address: String::from(address), command: cmd, why });
+            *count += 1;
+        }
+    }
+}
+
+impl Transport for RelayThrottle {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        let cmd: Value = serde_json::from_slice(&decrypt_payload(frame))?;
+        let Some(on) = requested_state(&cmd) else { return self.inner.request(address, frame) };
+
+        let key = (String::from(address), outlets(&cmd));
+        let last = self.switches.lock().ok().and_then(|s| s.get(&key).copied());
+        let since = |at: Instant| self.clock.now().saturating_duration_since(at);
+        match last {
+            Some((was_on, at)) if since(at) < self.min_interval && was_on == on => {
+                let response = acknowledge(&cmd);
+                self.suppress(address, cmd, Suppression::Coalesced);
+                return Ok(encrypt_payload(response.to_string().into_bytes()));
+            }
+            Some((_, at)) if since(at) < self.min_interval => {
+                self.suppress(address, cmd, Suppression::TooSoon);
+                return Err(PlugError::new(format!("Relay switched {:.1}s ago, less than the minimum of {:.1}s",
+                                                  since(at).as_secs_f64(), self.min_interval.as_secs_f64()).as_str()));
+            }
+            _ => {}
+        }
+
+        let response = self.inner.request(address, frame)?;
+        if let Ok(mut switches) = self.switches.lock() {
+            switches.insert(key, (on, self.clock.now()));
+        }
+        Ok(response)
+    }
+
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        self.inner.probe(address, timeout)
+    }
+}
+
+impl TpLinkDevice {
+    /// A copy of this device whose relay is switched at most once per `min_interval`.
+    pub fn throttled(&self, min_interval: Duration) -> (TpLinkDevice, Arc<RelayThrottle>) {
+        self.throttled_with_clock(min_interval, timing::system())
+    }
+
+    /// Like `throttled`, with time since the last switch told by `clock`.
+    pub fn throttled_with_clock(&self, min_interval: Duration, clock: Arc<dyn Clock>)
+        -> (TpLinkDevice, Arc<RelayThrottle>) {
+        let throttle = Arc::new(RelayThrottle::new(self.transport.clone(), min_interval).with_clock(clock));
+        (self.with_inner(throttle.clone()), throttle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::timing::MockClock;
+    use crate::types::PlugError;
+    use super::{Suppression, MAX_SUPPRESSED};
+
+    #[test]
+    fn test_rapid_toggles() {
+        let sent = Arc::new(Mutex::new(0));
+        let count = sent.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            if request["system"].get("set_relay_state").is_some() {
+                *count.lock().unwrap() += 1;
+            }
+            Ok(encrypt_payload(json!({"system": {"set_relay_state": {"err_code": 0}}}).to_string().into_bytes()))
+        };
+        let clock = Arc::new(MockClock::new());
+        let (device, throttle) = TpLinkDevice::with_transport("plug", Arc::new(transport))
+            .throttled_with_clock(Duration::from_millis(100), clock.clone());
+
+        device.on().unwrap();
+        device.on().unwrap();
+        assert!(device.off().is_err());
+        assert_eq!(*sent.lock().unwrap(), 1);
+        let why: Vec<Suppression> = throttle.suppressed().iter().map(|s| s.why).collect();
+        assert_eq!(why, [Suppression::Coalesced, Suppression::TooSoon]);
+        assert_eq!(throttle.suppressed_count(), 2);
+
+        clock.advance(Duration::from_millis(120));
+        device.off().unwrap();
+        assert_eq!(*sent.lock().unwrap(), 2);
+
+        for _ in 0..MAX_SUPPRESSED {
+            device.off().unwrap();
+        }
+        assert_eq!(throttle.suppressed().len(), MAX_SUPPRESSED);
+        assert_eq!(throttle.suppressed()[0].why, Suppression::Coalesced);
+        assert_eq!(throttle.suppressed_count(), MAX_SUPPRESSED as u64 + 2);
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * Where the scheduler, the sysinfo cache and the relay throttle get the time
+ * from, so tests can move it along instead of sleeping:
+ *
+ *   let clock = Arc::new(MockClock::new());
+ *   let (device, cache) = plug.cached_with_clock(Duration::from_secs(5), clock.clone());
+ *   device.sysinfo()?;
+ *   clock.advance(Duration::from_secs(6));
+ *   device.sysinfo()?;   // asks the device again
+ *
+ * `SystemClock` is the real one and what everything uses unless told
+ * otherwise. `MockClock` only moves when advanced or slept on, and sleeping on
+ * it returns at once, so a loop that sleeps between rounds runs them back to
+ * back in the test's thread.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+
+pub trait Clock: Send + Sync {
+    /// For measuring intervals, like `Instant::now`.
+    fn now(&self) -> Instant;
+
+    /// For timestamps and calendars, like `Utc::now`.
+    fn utc(&self) -> DateTime<Utc>;
+
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// The clock everything uses unless given another.
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[derive(Debug)]
+pub struct MockClock {
+    started: Instant,
+    started_utc: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    /// Starts at 2024-01-01 00:00 UTC.
+    pub fn new() -> MockClock {
+        MockClock::at(DateTime::from_timestamp(1_704_067_200, 0).unwrap_or_default())
+    }
+
+    pub fn at(utc: DateTime<Utc>) -> MockClock {
+        MockClock {
+            started: Instant::now(),
+            started_utc: utc,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        if let Ok(mut elapsed) = self.elapsed.lock() {
+            *elapsed += by;
+        }
+    }
+
+    /// How far the clock has moved since it was made.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.lock().map(|e| *e).unwrap_or_default()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.started + self.elapsed()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        self.started_utc + chrono::Duration::from_std(self.elapsed()).unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Clock, MockClock};
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let (then, then_utc) = (clock.now(), clock.utc());
+        clock.sleep(Duration::from_secs(90));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.now() - then, Duration::from_millis(90_500));
+        assert_eq!((clock.utc() - then_utc).num_milliseconds(), 90_500);
+        assert_eq!(then_utc.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    }
+}
@@ -0,0 +1,229 @@
+#[cfg(feature = "net")]
+use std::io::{ErrorKind, Read, Write};
+#[cfg(feature = "net")]
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(feature = "net")]
+use std::time::Instant;
+use core::time::Duration;
+
+use alloc::string::ToString;
+#[cfg(feature = "net")]
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::commands;
+use crate::protocol::encrypt_payload;
+use crate::types::PlugError;
+
+/// Moves protocol frames between the host and a device.
+///
+/// `frame` is an encrypted, length-prefixed request and the returned bytes must be the
+/// device's response frame in the same format, prefix included. Implement this to drive
+/// devices over anything other than a blocking `std::net` socket.
+pub trait Transport: Send + Sync {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError>;
+
+    /// Checks that the device answers, as cheaply as the transport can. By default
+    /// this sends a sysinfo request and doesn't look at the reply beyond its arrival.
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        let _ = timeout;
+        self.request(address, &encrypt_payload(commands::get_meter_info().to_string().into_bytes())).map(|_| ())
+    }
+}
+
+impl<F> Transport for F
+where
+    F: Fn(&str, &[u8]) -> Result<Vec<u8>, PlugError> + Send + Sync
+{
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        self(address, frame)
+    }
+}
+
+/// The largest response `TcpTransport` accepts by default. The biggest real
+/// replies, a power strip's sysinfo or a month of daily stats, are a few KiB.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Fills `buf`, waiting at most `idle` for each read and until `deadline` for all of them.
+#[cfg(feature = "net")]
+fn read_exact(stream: &mut TcpStream, buf: &mut [u8], idle: Duration, deadline: Instant) -> Result<(), PlugError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(PlugError::new("Response took longer than the read deadline"));
+        }
+        stream.set_read_timeout(Some(left.min(idle)))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(PlugError::ConnectionClosed { context: Default::default() }),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) && left < idle => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the peer had closed the connection, as opposed to being slow.
+#[cfg(feature = "net")]
+fn closed(error: &std::io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                           | ErrorKind::NotConnected | ErrorKind::UnexpectedEof)
+}
+
+/// A failed `TcpTransport::exchange`.
+#[cfg(feature = "net")]
+pub(crate) struct ExchangeError {
+    pub(crate) error: Box<PlugError>,
+    /// The connection turned out to be closed before any of the reply came:
+    /// writing the request failed, or reading found the end of the stream or a
+    /// reset. A timeout is never stale, since the device may be acting on it.
+    pub(crate) stale: bool,
+}
+
+#[cfg(feature = "net")]
+impl From<PlugError> for ExchangeError {
+    fn from(error: PlugError) -> ExchangeError {
+        ExchangeError { error: Box::new(error), stale: false }
+    }
+}
+
+#[cfg(feature = "net")]
+impl From<ExchangeError> for PlugError {
+    fn from(failed: ExchangeError) -> PlugError {
+        *failed.error
+    }
+}
+
+/// Talks to devices over a new TCP connection per request.
+///
+/// A reply can't take more than `deadline` as a whole, however slowly it
+/// trickles in, and one whose length prefix is over `max_frame_size` is
+/// refused before anything is allocated for it.
+#[cfg(feature = "net")]
+pub struct TcpTransport {
+    /// How long to wait for each read.
+    timeout: Duration,
+    deadline: Duration,
+    max_frame_size: usize,
+}
+
+#[cfg(feature = "net")]
+impl TcpTransport {
+    pub fn new(timeout: Duration) -> TcpTransport {
+        TcpTransport {
+            timeout,
+            deadline: timeout * 2,
+            max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+
+    /// How long a whole reply may take to arrive, twice the timeout unless set.
+    pub fn deadline(mut self, deadline: Duration) -> TcpTransport {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn max_frame_size(mut self, bytes: usize) -> TcpTransport {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Sends `frame` over an open connection and reads the reply, within this transport's limits.
+    pub(crate) fn exchange(&self, stream: &mut TcpStream, frame: &[u8]) -> Result<Vec<u8>, ExchangeError> {
+        let stale = |error: std::io::Error| ExchangeError { stale: closed(&error), error: Box::new(error.into()) };
+        stream.set_write_timeout(Some(self.timeout)).map_err(stale)?;
+        stream.write_all(frame).map_err(stale)?;
+        let deadline = Instant::now() + self.deadline;
+
+        let mut response = vec![0u8; 4];
+        read_exact(stream, &mut response[..1], self.timeout, deadline).map_err(|error| ExchangeError {
+            stale: match &error {
+                PlugError::ConnectionClosed { .. } => true,
+                PlugError::Io { source, .. } => closed(source),
+                _ => false,
+            },
+            error: Box::new(error),
+        })?;
+        read_exact(stream, &mut response[1..], self.timeout, deadline)?;
+
+        let size = crate::protocol::size_from_bytes(&response);
+        if size > self.max_frame_size {
+            return Err(PlugError::new(alloc::format!(
+                "Response of {} bytes is over the limit of {}", size, self.max_frame_size).as_str()).into());
+        }
+        response.resize(4 + size, 0);
+        read_exact(stream, &mut response[4..], self.timeout, deadline)?;
+
+        Ok(response)
+    }
+}
+
+#[cfg(feature = "net")]
+impl Default for TcpTransport {
+    fn default() -> TcpTransport {
+        TcpTransport::new(Duration::from_millis(5000))
+    }
+}
+
+#[cfg(feature = "net")]
+impl Transport for TcpTransport {
+    fn request(&self, address: &str, frame: &[u8]) -> Result<Vec<u8>, PlugError> {
+        Ok(self.exchange(&mut TcpStream::connect(address)?, frame)?)
+    }
+
+    /// Only opens a connection.
+    fn probe(&self, address: &str, timeout: Duration) -> Result<(), PlugError> {
+        let mut last = None;
+        for addr in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(_) => return Ok(()),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.map_or_else(|| PlugError::new("Address resolved to nothing"), PlugError::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "net")]
+    fn test_limits() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+        use std::time::{Duration, Instant};
+        use super::{TcpTransport, Transport};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut request = [0u8; 64];
+            // A length prefix of 4 GiB.
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut request);
+            stream.write_all(&[0xff, 0xff, 0xff, 0xff]).unwrap();
+            // A reply sent a byte at a time, slower than the deadline allows.
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut request);
+            for byte in [0, 0, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8] {
+                if stream.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let transport = TcpTransport::new(Duration::from_secs(1)).deadline(Duration::from_millis(200));
+        let error = transport.request(&address, &[0, 0, 0, 0]).unwrap_err();
+        assert!(error.to_string().contains("over the limit"));
+
+        let started = Instant::now();
+        let error = transport.request(&address, &[0, 0, 0, 0]).unwrap_err();
+        assert!(error.to_string().contains("deadline"));
+        assert!(started.elapsed() < Duration::from_millis(500));
+        server.join().unwrap();
+    }
+}
@@ -1,17 +1,37 @@
-use std::error::Error;
-use std::fmt;
-use std::fmt::Formatter;
+use alloc::format;
+use alloc::string::{FromUtf8Error, String, ToString};
+use alloc::vec::Vec;
+use core::error::Error;
+use core::fmt;
+use core::fmt::Formatter;
 use serde::{Deserialize, Serialize};
 
 
+/// An outlet of a power strip such as the HS300, as listed in its sysinfo.
 #[derive(Clone, Default, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct SysInfoChild {
+    pub id: String,
+    pub state: i64,
+    pub alias: String,
+    pub on_time: i64,
+}
+
+/// Fields a model doesn't report are left at their defaults; strips, for instance,
+/// have no `relay_state` of their own but list their outlets in `children`.
+#[derive(Clone, Default, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+#[non_exhaustive]
 pub struct SystemGetSysInfoResponse {
+    #[serde(rename = "err_code")]
     pub errcode: i64,
     pub sw_ver: String,
     pub hw_ver: String,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", alias = "mic_type")]
     pub hw_type: String,
     pub model: String,
+    #[serde(alias = "mic_mac")]
     pub mac: String,
     #[serde(rename = "deviceId")]
     pub device_id: String,
@@ -33,14 +53,47 @@ pub struct SystemGetSysInfoResponse {
     pub led_off: i64,
     pub latitude: f64,
     pub longitude: f64,
+    /// Some firmware reports the location in 1/10000 degree instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude_i: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude_i: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SysInfoChild>,
+    /// Dimmers only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<i64>,
+    /// Bulbs only.
+    pub is_dimmable: i64,
+    pub is_color: i64,
+    pub is_variable_color_temp: i64,
+    /// Bulbs and light strips, which report this instead of `relay_state`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light_state: Option<crate::bulb::LightState>,
+}
+
+impl SystemGetSysInfoResponse {
+    /// Whether a plug's relay or a bulb's light is on. `None` for a strip,
+    /// which has no state of its own, only that of each of its `children`.
+    pub fn is_on(&self) -> Option<bool> {
+        match &self.light_state {
+            Some(light) => Some(light.on_off != 0),
+            None if !self.children.is_empty() => None,
+            None => Some(self.relay_state != 0),
+        }
+    }
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct SystemResponse {
-    pub get_sysinfo: SystemGetSysInfoResponse
+    pub get_sysinfo: Option<SystemGetSysInfoResponse>
 }
 
+/// Either set of units may be missing, or both present; some firmwares add
+/// fields of their own, which are kept in `extra`.
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct EmeterGetRealtimeResponse {
     pub current: Option<f64>,
     pub current_ma: Option<f64>,
@@ -50,28 +103,56 @@ pub struct EmeterGetRealtimeResponse {
     pub power_mw: Option<f64>,
     pub total: Option<f64>,
     pub total_wh: Option<f64>,
+    /// The outlet a strip's reading is for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_id: Option<i64>,
+    #[serde(default)]
     pub err_code: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub err_msg: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl EmeterGetRealtimeResponse {
+    pub fn voltage_v(&self) -> Option<f64> {
+        match self.voltage_mv {
+            Some(voltage_mv) => Some(voltage_mv / 1000.0),
+            None => self.voltage,
+        }
+    }
+
+    pub fn current_a(&self) -> Option<f64> {
+        match self.current_ma {
+            Some(current_ma) => Some(current_ma / 1000.0),
+            None => self.current,
+        }
+    }
+
+    pub fn power_w(&self) -> Option<f64> {
+        match self.power_mw {
+            Some(power_mw) => Some(power_mw / 1000.0),
+            None => self.power,
+        }
+    }
+
+    pub fn total_kwh(&self) -> Option<f64> {
+        match self.total_wh {
+            Some(total_wh) => Some(total_wh / 1000.0),
+            None => self.total,
+        }
+    }
 }
 
 impl fmt::Display for EmeterGetRealtimeResponse {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        if self.voltage_mv.is_none() {
-            write!(f, "V = {} V, I = {} A, P = {} W",
-                   self.voltage.unwrap() / 1000.0,
-                   self.current.unwrap() / 1000.0,
-                   self.power.unwrap() / 1000.0
-            )
-        } else {
-            write!(f, "V = {} V, I = {} A, P = {} W",
-                   self.voltage_mv.unwrap() / 1000.0,
-                   self.current_ma.unwrap() / 1000.0,
-                   self.power_mw.unwrap() / 1000.0
-            )
-        }
+        let reading = crate::reading::PowerReading::from(self);
+        write!(f, "V = {} V, I = {} A, P = {} W", reading.voltage_v, reading.current_a, reading.power_w)
     }
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct EmeterGetVGainIGainResponse {
     pub vgain: i64,
     pub igain: i64,
@@ -79,53 +160,277 @@ pub struct EmeterGetVGainIGainResponse {
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct EmeterGetDaystatItem {
     pub year: i64,
     pub month: i64,
     pub day: i64,
-    pub energy: f64,
+    pub energy: Option<f64>,
+    pub energy_wh: Option<f64>,
+}
+
+impl EmeterGetDaystatItem {
+    pub fn energy_kwh(&self) -> Option<f64> {
+        match self.energy_wh {
+            Some(energy_wh) => Some(energy_wh / 1000.0),
+            None => self.energy,
+        }
+    }
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct EmeterGetDaystatResponse {
     pub day_list: Vec<EmeterGetDaystatItem>,
     pub err_code: i64,
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EmeterGetMonthstatItem {
+    pub year: i64,
+    pub month: i64,
+    pub energy: Option<f64>,
+    pub energy_wh: Option<f64>,
+}
+
+impl EmeterGetMonthstatItem {
+    pub fn energy_kwh(&self) -> Option<f64> {
+        match self.energy_wh {
+            Some(energy_wh) => Some(energy_wh / 1000.0),
+            None => self.energy,
+        }
+    }
+}
+
+/// A refused year, e.g. one the device no longer keeps, has no `month_list`.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EmeterGetMonthstatResponse {
+    #[serde(default)]
+    pub month_list: Vec<EmeterGetMonthstatItem>,
+    pub err_code: i64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct EmeterResponse {
     pub get_realtime: Option<EmeterGetRealtimeResponse>,
     pub get_vgain_igain: Option<EmeterGetVGainIGainResponse>,
-    pub get_daystat: Option<EmeterGetDaystatResponse>
+    pub get_daystat: Option<EmeterGetDaystatResponse>,
+    pub get_monthstat: Option<EmeterGetMonthstatResponse>,
+}
+
+/// An access point seen by the plug. `channel`, `rssi` and `bssid` only come with
+/// firmwares that do a deep scan.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct NetifGetScaninfoItem {
+    pub ssid: String,
+    pub key_type: i64,
+    pub channel: Option<u32>,
+    pub rssi: Option<i64>,
+    pub bssid: Option<String>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct NetifGetScaninfoResponse {
+    pub ap_list: Vec<NetifGetScaninfoItem>,
+    pub err_code: i64,
+}
+
+/// The network the plug is configured to join.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct NetifGetStainfoResponse {
+    pub ssid: String,
+    pub key_type: i64,
+    pub rssi: Option<i64>,
+    pub err_code: i64,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct NetifResponse {
+    pub get_scaninfo: Option<NetifGetScaninfoResponse>,
+    pub get_stainfo: Option<NetifGetStainfoResponse>,
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct PlugResponse {
     pub system: Option<SystemResponse>,
-    pub emeter: Option<EmeterResponse>
+    /// Bulbs answer in `smartlife.iot.common.emeter` instead.
+    #[serde(alias = "smartlife.iot.common.emeter")]
+    pub emeter: Option<EmeterResponse>,
+    pub netif: Option<NetifResponse>,
+    #[serde(rename = "smartlife.iot.smartbulb.lightingservice", alias = "smartlife.iot.lightStrip")]
+    pub lighting: Option<crate::bulb::LightingResponse>,
+    pub count_down: Option<crate::schedule::CountdownResponse>,
+    pub schedule: Option<crate::schedule::ScheduleResponse>,
+}
+
+/// Which device and command an error came from. Transports and parsers leave it
+/// empty; the send path fills it in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The device's address.
+    pub device: String,
+    /// Known once the device has been detected.
+    pub alias: Option<String>,
+    /// "namespace.method", e.g. "system.get_sysinfo".
+    pub command: String,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.device)?;
+        if let Some(alias) = &self.alias {
+            write!(f, " ({})", alias)?;
+        }
+        if !self.command.is_empty() {
+            if !self.device.is_empty() {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", self.command)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
-pub struct PlugError {
-    details: String
+pub enum PlugError {
+    /// The device answered with nothing, which some firmware does for commands
+    /// it doesn't know.
+    EmptyResponse { context: ErrorContext },
+    /// The device closed the connection before a complete response arrived.
+    ConnectionClosed { context: ErrorContext },
+    /// Connecting, writing or reading failed.
+    #[cfg(feature = "std")]
+    Io { source: std::io::Error, context: ErrorContext },
+    /// The response wasn't the JSON expected.
+    Json { source: serde_json::Error, context: ErrorContext },
+    /// The decrypted response wasn't UTF-8.
+    Utf8 { source: FromUtf8Error, context: ErrorContext },
+    /// Schedule rules refused before sending; see `schedule::check_rules`.
+    Schedule { problem: crate::schedule::ScheduleProblem, context: ErrorContext },
+    Other { message: String, context: ErrorContext },
 }
 
 impl PlugError {
     pub fn new(msg: &str) -> PlugError {
-        PlugError {
-            details: msg.to_string()
+        PlugError::Other {
+            message: msg.to_string(),
+            context: ErrorContext::default(),
+        }
+    }
+
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            PlugError::EmptyResponse { context }
+            | PlugError::ConnectionClosed { context }
+            | PlugError::Json { context, .. }
+            | PlugError::Utf8 { context, .. }
+            | PlugError::Schedule { context, .. }
+            | PlugError::Other { context, .. } => context,
+            #[cfg(feature = "std")]
+            PlugError::Io { context, .. } => context,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        match self {
+            PlugError::EmptyResponse { context }
+            | PlugError::ConnectionClosed { context }
+            | PlugError::Json { context, .. }
+            | PlugError::Utf8 { context, .. }
+            | PlugError::Schedule { context, .. }
+            | PlugError::Other { context, .. } => context,
+            #[cfg(feature = "std")]
+            PlugError::Io { context, .. } => context,
+        }
+    }
+
+    /// Fills in the parts of the context that are still unknown.
+    pub fn in_context(mut self, device: &str, alias: Option<&str>, command: &str) -> PlugError {
+        let context = self.context_mut();
+        if context.device.is_empty() {
+            context.device = device.to_string();
+        }
+        if context.alias.is_none() {
+            context.alias = alias.map(|a| a.to_string());
+        }
+        if context.command.is_empty() {
+            context.command = command.to_string();
+        }
+        self
+    }
+
+    pub fn command(&self) -> Option<&str> {
+        let command = self.context().command.as_str();
+        if command.is_empty() { None } else { Some(command) }
+    }
+
+    /// The error without its context.
+    pub fn message(&self) -> String {
+        match self {
+            PlugError::EmptyResponse { .. } => "Empty response".to_string(),
+            PlugError::ConnectionClosed { .. } => "Connection closed without a response".to_string(),
+            #[cfg(feature = "std")]
+            PlugError::Io { source, .. } => format!("I/O error: {}", source),
+            PlugError::Json { source, .. } => format!("Deserialization failed. Reason: {}", source),
+            PlugError::Utf8 { source, .. } => format!("Decoding failed: {}", source),
+            PlugError::Schedule { problem, .. } => problem.to_string(),
+            PlugError::Other { message, .. } => message.clone(),
         }
     }
 }
 
 impl fmt::Display for PlugError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.details)
+        let context = self.context();
+        if *context == ErrorContext::default() {
+            write!(f, "{}", self.message())
+        } else {
+            write!(f, "{}: {}", context, self.message())
+        }
     }
 }
 
 impl Error for PlugError {
-    fn description(&self) -> &str {
-        &self.details
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            PlugError::Io { source, .. } => Some(source),
+            PlugError::Json { source, .. } => Some(source),
+            PlugError::Utf8 { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for PlugError {
+    fn from(source: std::io::Error) -> PlugError {
+        PlugError::Io { source, context: ErrorContext::default() }
+    }
+}
+
+impl From<serde_json::Error> for PlugError {
+    fn from(source: serde_json::Error) -> PlugError {
+        PlugError::Json { source, context: ErrorContext::default() }
+    }
+}
+
+impl From<crate::schedule::ScheduleProblem> for PlugError {
+    fn from(problem: crate::schedule::ScheduleProblem) -> PlugError {
+        PlugError::Schedule { problem, context: ErrorContext::default() }
+    }
+}
+
+impl From<FromUtf8Error> for PlugError {
+    fn from(source: FromUtf8Error) -> PlugError {
+        PlugError::Utf8 { source, context: ErrorContext::default() }
     }
 }
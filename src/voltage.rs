@@ -0,0 +1,199 @@
+/*
+ * Uses the plugs' meters as simple power-quality sensors. Readings below the
+ * band are sags, above it swells; each excursion raises an alert when it
+ * starts and another when voltage is back, and is kept with its duration and
+ * the furthest the voltage went:
+ *
+ *   let events = VoltageMonitor::default().attach(watcher.spawn().into());
+ *
+ * The default band is 230 V ± 10 %, as in EN 50160. Readings of 0 V come from
+ * plugs without a meter and are ignored.
+ */
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use chrono::{DateTime, Utc};
+
+use crate::events::{Alert, Event, ExcursionKind};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Excursion {
+    pub device: String,
+    pub kind: ExcursionKind,
+    pub started: DateTime<Utc>,
+    /// `None` while it is still going on.
+    pub ended: Option<DateTime<Utc>>,
+    /// Lowest voltage seen in a sag, highest in a swell.
+    pub extreme_v: f64,
+}
+
+impl Excursion {
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        self.ended.map(|ended| ended - self.started)
+    }
+}
+
+pub struct VoltageMonitor {
+    low_v: f64,
+    high_v: f64,
+    active: HashMap<String, Excursion>,
+    excursions: Vec<Excursion>,
+}
+
+impl Default for VoltageMonitor {
+    fn default() -> VoltageMonitor {
+        VoltageMonitor::new(207.0, 253.0)
+    }
+}
+
+impl VoltageMonitor {
+    pub fn new(low_v: f64, high_v: f64) -> VoltageMonitor {
+        VoltageMonitor {
+            low_v,
+            high_v,
+            active: HashMap::new(),
+            excursions: Vec::new(),
+        }
+    }
+
+    /// Finished excursions, oldest first.
+    pub fn excursions(&self) -> &[Excursion] {
+        &self.excursions
+    }
+
+    pub fn active(&self, device: &str) -> Option<&Excursion> {
+        self.active.get(device)
+    }
+
+    fn classify(&self, voltage_v: f64) -> Option<ExcursionKind> {
+        if voltage_v < self.low_v {
+            Some(ExcursionKind::Sag)
+        } else if voltage_v > self.high_v {
+            Some(ExcursionKind::Swell)
+        } else {
+            None
+        }
+    }
+
+    fn finish(&mut self, device: &str, at: DateTime<Utc>) -> Option<Alert> {
+        let mut excursion = self.active.remove(device)?;
+        excursion.ended = Some(at);
+        let alert = Alert::VoltageRestored {
+            kind: excursion.kind,
+            extreme_v: excursion.extreme_v,
+            duration: (at - excursion.started).to_std().unwrap_or_default(),
+        };
+        self.excursions.push(excursion);
+        Some(alert)
+    }
+
+    /// Returns the alerts raised by this reading, at most a restore followed by a new excursion.
+    pub fn observe(&mut self, device: &str, at: DateTime<Utc>, voltage_v: f64) -> Vec<Alert> {
+        if voltage_v <= 0.0 {
+            return Vec::new();
+        }
+
+        let kind = self.classify(voltage_v);
+        let mut alerts = Vec::new();
+        if let Some(active) = self.active.get_mut(device) {
+            if Some(active.kind) == kind {
+                active.extreme_v = match active.kind {
+                    ExcursionKind::Sag => active.extreme_v.min(voltage_v),
+                    ExcursionKind::Swell => active.extreme_v.max(voltage_v),
+                };
+                return alerts;
+            }
+            alerts.extend(self.finish(device, at));
+        }
+
+        if let Some(kind) = kind {
+            self.active.insert(String::from(device), Excursion {
+                device: String::from(device),
+                kind,
+                started: at,
+                ended: None,
+                extreme_v: voltage_v,
+            });
+            alerts.push(Alert::VoltageExcursion { kind, voltage_v });
+        }
+        alerts
+    }
+
+    pub fn handle(&mut self, event: &Event) -> Vec<Event> {
+        self.handle_at(event, Utc::now())
+    }
+
+    pub fn handle_at(&mut self, event: &Event, now: DateTime<Utc>) -> Vec<Event> {
+        match event {
+            Event::PowerSample { device, reading } =>
+                self.observe(device, now, reading.voltage_v).into_iter()
+                    .map(|alert| Event::AlertRaised { device: device.clone(), alert })
+                    .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Passes `events` through with alerts added. The monitor stays reachable through
+    /// the returned handle, e.g. to read `excursions()`.
+    pub fn attach(self, events: Receiver<Event>) -> (Receiver<Event>, Arc<Mutex<VoltageMonitor>>) {
+        let monitor = Arc::new(Mutex::new(self));
+        let shared = monitor.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for event in events {
+                let alerts = match shared.lock() {
+                    Ok(mut monitor) => monitor.handle(&event),
+                    Err(_) => Vec::new(),
+                };
+                for event in std::iter::once(event).chain(alerts) {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (rx, monitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::{TimeZone, Utc};
+    use crate::events::{Alert, ExcursionKind};
+    use super::VoltageMonitor;
+
+    #[test]
+    fn test_sag() {
+        let mut monitor = VoltageMonitor::default();
+        let at = |s| Utc.with_ymd_and_hms(2024, 6, 3, 12, 0, s).unwrap();
+
+        assert!(monitor.observe("fridge", at(0), 231.0).is_empty());
+        assert_eq!(monitor.observe("fridge", at(10), 205.0),
+                   [Alert::VoltageExcursion { kind: ExcursionKind::Sag, voltage_v: 205.0 }]);
+        assert!(monitor.observe("fridge", at(20), 198.5).is_empty());
+        assert!(monitor.observe("fridge", at(25), 0.0).is_empty());
+        assert_eq!(monitor.observe("fridge", at(40), 229.0), [Alert::VoltageRestored {
+            kind: ExcursionKind::Sag,
+            extreme_v: 198.5,
+            duration: Duration::from_secs(30),
+        }]);
+
+        let excursion = &monitor.excursions()[0];
+        assert_eq!(excursion.duration(), Some(chrono::Duration::seconds(30)));
+        assert!(monitor.active("fridge").is_none());
+    }
+
+    #[test]
+    fn test_sag_straight_to_swell() {
+        let mut monitor = VoltageMonitor::new(220.0, 240.0);
+        let now = Utc::now();
+        monitor.observe("tv", now, 210.0);
+        let alerts = monitor.observe("tv", now, 245.0);
+        assert!(matches!(alerts[..], [Alert::VoltageRestored { kind: ExcursionKind::Sag, .. },
+                                      Alert::VoltageExcursion { kind: ExcursionKind::Swell, .. }]));
+        assert_eq!(monitor.active("tv").unwrap().extreme_v, 245.0);
+    }
+}
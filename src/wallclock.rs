@@ -0,0 +1,152 @@
+/*
+ * Schedule rules in the host's wall-clock time, for devices in another zone.
+ * Plugs keep the local time of their own timezone, DST included (see
+ * `clock`), so rules are read in the device's zone. Where the host is
+ * elsewhere, or its zone changes to DST on other dates, as Europe and North
+ * America do weeks apart, a rule written as 07:00 on the host needs shifting
+ * by the difference between the two offsets. `ScheduleKeeper` converts rules
+ * written in the host's local time to the device's zone and pushes them again
+ * whenever that difference changes:
+ *
+ *   let rules = vec![ScheduleRule::at(7, 0, true).on_days(WEEKDAYS)];
+ *   let keeper = ScheduleKeeper::new("heater", heater, Timezone::EuropeBerlin, rules);
+ *   scheduler.add("heater", heater, Schedule::every(Duration::from_secs(60))).sink(keeper);
+ *
+ * For a device in the host's zone the difference is always zero and the rules
+ * are pushed as written, once. As a sink, the keeper checks the offsets
+ * whenever its device is sampled, so the scheduler's poll interval bounds how
+ * late after a transition the rules are corrected. A zone whose DST `Varies`
+ * can't be kept; `check` fails for it.
+ */
+
+use chrono::{DateTime, Local, Offset, Utc};
+
+use crate::TpLinkDevice;
+use crate::clock::Timezone;
+use crate::scheduler::Sample;
+use crate::schedule::ScheduleRule;
+use crate::sink::Sink;
+use crate::types::PlugError;
+
+/// `rule`, written in wall-clock minutes at `wall_offset` minutes east of UTC,
+/// as a device whose local time is `device_offset` minutes east of UTC needs it.
+pub fn to_device_clock(rule: &ScheduleRule, wall_offset: i32, device_offset: i32) -> ScheduleRule {
+    rule.shifted(device_offset as i64 - wall_offset as i64)
+}
+
+/// The host's offset from UTC, in minutes, at `at`.
+pub fn local_offset(at: DateTime<Utc>) -> i32 {
+    at.with_timezone(&Local).offset().fix().local_minus_utc() / 60
+}
+
+pub struct ScheduleKeeper {
+    name: String,
+    device: TpLinkDevice,
+    zone: Timezone,
+    rules: Vec<ScheduleRule>,
+    /// The host's and the device's offsets the rules were last converted at.
+    pushed_offsets: Option<(i32, i32)>,
+}
+
+impl ScheduleKeeper {
+    /// `name` is the device's name in the scheduler; `rules` are in wall-clock time.
+    pub fn new(name: &str, device: TpLinkDevice, zone: Timezone, rules: Vec<ScheduleRule>) -> ScheduleKeeper {
+        ScheduleKeeper {
+            name: String::from(name),
+            device,
+            zone,
+            rules,
+            pushed_offsets: None,
+        }
+    }
+
+    /// The host's and the device's offsets at `now`.
+    fn offsets(&self, now: DateTime<Utc>) -> Result<(i32, i32), PlugError> {
+        match self.zone.utc_offset_at(now) {
+            Some(device) => Ok((local_offset(now), device)),
+            None => Err(PlugError::new(format!("DST rules for {} aren't known", self.zone.name()).as_str())),
+        }
+    }
+
+    /// Replaces the device's rules with `rules` converted at the offsets in force at `now`.
+    pub fn push(&mut self, now: DateTime<Utc>) -> Result<(), PlugError> {
+        let (host, device) = self.offsets(now)?;
+        let converted: Vec<ScheduleRule> = self.rules.iter().map(|rule| to_device_clock(rule, host, device)).collect();
+        self.device.set_schedules(&converted)?;
+        self.pushed_offsets = Some((host, device));
+        Ok(())
+    }
+
+    /// Pushes when nothing was pushed yet or the difference between the offsets
+    /// changed. Returns whether it did.
+    pub fn check(&mut self, now: DateTime<Utc>) -> Result<bool, PlugError> {
+        let (host, device) = self.offsets(now)?;
+        if self.pushed_offsets.is_some_and(|(h, d)| d - h == device - host) {
+            return Ok(false);
+        }
+        self.push(now).map(|_| true)
+    }
+}
+
+impl Sink for ScheduleKeeper {
+    fn write(&mut self, sample: &Sample) {
+        // An unreachable device gets its rules on the next sample it answers.
+        if sample.device == self.name && sample.reading.is_ok() {
+            let _ = self.check(sample.taken_at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use chrono::Utc;
+    use serde_json::{json, Value};
+    use crate::TpLinkDevice;
+    use crate::clock::Timezone;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::schedule::ScheduleRule;
+    use crate::types::PlugError;
+    use super::{local_offset, to_device_clock, ScheduleKeeper};
+
+    #[test]
+    fn test_to_device_clock() {
+        let weekdays = [false, true, true, true, true, true, false];
+        let rule = ScheduleRule::at(7, 0, true).on_days(weekdays);
+        // Same zone, nothing to do; 07:00 in New York (-4h in summer) is 13:00 in Berlin (+2h).
+        assert_eq!(to_device_clock(&rule, 120, 120).smin, 7 * 60);
+        assert_eq!(to_device_clock(&rule, -240, 120).smin, 13 * 60);
+
+        // 00:30 in Berlin is 23:30 the evening before on a plug in London.
+        let early = to_device_clock(&ScheduleRule::at(0, 30, false).on_days(weekdays), 120, 60);
+        assert_eq!(early.smin, 23 * 60 + 30);
+        assert_eq!(early.wday, [1, 1, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_keeper_pushes_once_per_offset() {
+        let added = Arc::new(Mutex::new(Vec::new()));
+        let seen = added.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            if let Some(rule) = request["schedule"].get("add_rule") {
+                seen.lock().unwrap().push(rule["smin"].as_i64().unwrap());
+            }
+            Ok(encrypt_payload(json!({"schedule": {"add_rule": {"id": "R1", "err_code": 0},
+                "delete_all_rules": {"err_code": 0}}}).to_string().into_bytes()))
+        };
+        let device = TpLinkDevice::with_transport("plug", Arc::new(transport));
+        let mut keeper = ScheduleKeeper::new("heater", device, Timezone::EtcUTC, vec![ScheduleRule::at(7, 0, true)]);
+
+        let now = Utc::now();
+        assert!(keeper.check(now).unwrap());
+        assert!(!keeper.check(now).unwrap());
+        assert_eq!(*added.lock().unwrap(), [(7 * 60 - local_offset(now) as i64).rem_euclid(24 * 60)]);
+
+        let device = TpLinkDevice::with_transport("plug", Arc::new(|_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            unreachable!()
+        }));
+        let mut keeper = ScheduleKeeper::new("heater", device, Timezone::AfricaCairo, vec![ScheduleRule::at(7, 0, true)]);
+        assert!(keeper.check(now).unwrap_err().to_string().contains("Africa/Cairo"));
+    }
+}
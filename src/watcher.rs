@@ -0,0 +1,363 @@
+/*
+ * Polls devices and turns what changed between polls into `Event`s: relay
+ * changes, devices going online or offline, other sysinfo changes such as a
+ * new alias or firmware (see `snapshot`), and (for devices with an energy
+ * meter) a power sample on every poll. With `persist_to`, what was last seen
+ * of each device survives restarts (see `state`).
+ *
+ * `spawn` polls in the background until the `Events` it returns are dropped.
+ * Passed on as a plain `Receiver`, as the rules engine and the monitors take
+ * them, the watcher can only tell they are gone when it next has an event.
+ */
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::TpLinkDevice;
+use crate::events::Event;
+use crate::service::{ServiceContext, ServiceHandle};
+use crate::snapshot::{DeviceSnapshot, FieldChange};
+use crate::state::{LastKnown, StateStore};
+use crate::types::PlugError;
+
+struct Watched {
+    name: String,
+    device: TpLinkDevice,
+    online: Option<bool>,
+    relay_on: Option<bool>,
+}
+
+pub struct Watcher {
+    devices: Vec<Watched>,
+    interval: Duration,
+    state: StateStore,
+    state_file: Option<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new(interval: Duration) -> Watcher {
+        Watcher {
+            devices: Vec::new(),
+            interval,
+            state: StateStore::new(),
+            state_file: None,
+        }
+    }
+
+    /// Loads what was last seen from `path`, if it exists, and saves there after every poll in `run`.
+    pub fn persist_to(&mut self, path: impl AsRef<Path>) -> Result<&mut Watcher, PlugError> {
+        self.state = StateStore::load(&path)?;
+        self.state_file = Some(path.as_ref().to_path_buf());
+        Ok(self)
+    }
+
+    pub fn last_known(&self, name: &str) -> Option<&LastKnown> {
+        self.state.get(name)
+    }
+
+    pub fn state(&self) -> &StateStore {
+        &self.state
+    }
+
+    /// Writes the state to the `persist_to` file, if there is one.
+    pub fn save(&self) -> Result<(), PlugError> {
+        match &self.state_file {
+            Some(path) => self.state.save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, device: TpLinkDevice) -> &mut Watcher {
+        self.devices.push(Watched {
+            name: String::from(name),
+            device,
+            online: None,
+            relay_on: None,
+        });
+        self
+    }
+
+    /// Stops watching `name`; what was last seen of it stays in the state.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.devices.len();
+        self.devices.retain(|w| w.name != name);
+        self.devices.len() != before
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.devices.iter().map(|w| w.name.as_str())
+    }
+
+    /// Polls every device once and returns the resulting events.
+    pub fn poll(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for watched in self.devices.iter_mut() {
+            let name = watched.name.clone();
+            let known = self.state.entry(&name);
+            // A relay that changed while we weren't running is still a change.
+            if watched.relay_on.is_none() {
+                watched.relay_on = known.relay_on;
+            }
+            let sysinfo = match watched.device.sysinfo() {
+                Ok(sysinfo) => sysinfo,
+                Err(e) => {
+                    known.online = Some(false);
+                    if watched.online != Some(false) {
+                        watched.online = Some(false);
+                        events.push(Event::DeviceOffline { device: name, reason: e.to_string() });
+                    }
+                    continue;
+                }
+            };
+            known.online = Some(true);
+            known.last_seen = Some(chrono::Utc::now());
+            known.rssi = Some(sysinfo.rssi);
+
+            let snapshot = DeviceSnapshot::new(&sysinfo, chrono::Utc::now());
+            if let Some(previous) = known.snapshot.replace(snapshot.clone()) {
+                events.extend(previous.diff(&snapshot).into_iter()
+                    .filter(|change| !matches!(change, FieldChange::Relay { .. }))
+                    .map(|change| Event::FieldChanged { device: name.clone(), change }));
+            }
+
+            if watched.online != Some(true) {
+                watched.online = Some(true);
+                events.push(Event::DeviceOnline { device: name.clone() });
+            }
+
+            let on = sysinfo.relay_state == 1;
+            if watched.relay_on.is_some_and(|was_on| was_on != on) {
+                events.push(Event::RelayChanged { device: name.clone(), on });
+            }
+            watched.relay_on = Some(on);
+            known.relay_on = Some(on);
+
+            if sysinfo.feature.contains("ENE") {
+                if let Ok(reading) = watched.device.power_reading() {
+                    known.reading = Some(reading);
+                    events.push(Event::PowerSample { device: name, reading });
+                }
+            }
+        }
+
+        events
+    }
+
+    /// Polls in a background thread until the returned events are dropped.
+    pub fn spawn(self) -> Events {
+        let (tx, rx) = mpsc::channel();
+        let context = ServiceContext::default();
+        let watching = context.clone();
+        thread::spawn(move || self.run_in(tx, &watching));
+        Events {
+            receiver: Some(rx),
+            context,
+        }
+    }
+
+    /// Polls as a service, sending events until stopped or until the receiver
+    /// is dropped. Failing to save the state makes it degraded.
+    pub fn service(self) -> (ServiceHandle, Receiver<Event>) {
+        let (tx, rx) = mpsc::channel();
+        (ServiceHandle::new("watcher", move |context| self.run_in(tx, context)), rx)
+    }
+
+    pub fn run(self, tx: Sender<Event>) {
+        self.run_in(tx, &ServiceContext::default())
+    }
+
+    fn run_in(mut self, tx: Sender<Event>, context: &ServiceContext) {
+        let mut deadline = Instant::now();
+        loop {
+            for event in self.poll() {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+            // Losing one save only costs freshness after a restart.
+            match self.save() {
+                Ok(()) => context.running(),
+                Err(e) => context.degraded(&e),
+            }
+
+            deadline += self.interval;
+            let now = Instant::now();
+            if deadline < now {
+                deadline = now;
+            }
+            if !context.sleep_until(deadline) {
+                return;
+            }
+        }
+    }
+}
+
+/// What a spawned watcher sends. Dropping it stops the watcher within
+/// `STOP_LATENCY`, even while nothing changes.
+pub struct Events {
+    /// Only taken by `into`.
+    receiver: Option<Receiver<Event>>,
+    context: ServiceContext,
+}
+
+impl Deref for Events {
+    type Target = Receiver<Event>;
+
+    fn deref(&self) -> &Receiver<Event> {
+        self.receiver.as_ref().expect("events already passed on")
+    }
+}
+
+impl Iterator for Events {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv().ok()
+    }
+}
+
+impl From<Events> for Receiver<Event> {
+    /// The watcher keeps running until sending to the receiver fails.
+    fn from(mut events: Events) -> Receiver<Event> {
+        events.receiver.take().expect("events already passed on")
+    }
+}
+
+impl Drop for Events {
+    fn drop(&mut self) {
+        if self.receiver.is_some() {
+            self.context.stop_flag().store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::json;
+    use crate::{testing, TpLinkDevice};
+    use crate::events::Event;
+    use crate::protocol::{decrypt_payload, encrypt_payload};
+    use crate::service::{Health, STOP_LATENCY};
+    use crate::snapshot::FieldChange;
+    use crate::types::PlugError;
+    use super::Watcher;
+
+    fn sysinfo(relay_state: u8) -> serde_json::Value {
+        json!({"system": {"get_sysinfo": {
+            "err_code": 0, "sw_ver": "1.0.8", "hw_ver": "1.0", "type": "IOT.SMARTPLUGSWITCH",
+            "model": "HS110(EU)", "mac": "50:C7:BF:00:00:01", "deviceId": "D1", "hwId": "H1",
+            "fwId": "F1", "oemId": "O1", "alias": "Heater", "dev_name": "Wi-Fi Smart Plug",
+            "icon_hash": "", "relay_state": relay_state, "on_time": 0, "active_mode": "none",
+            "feature": "TIM:ENE", "updating": 0, "rssi": -60, "led_off": 0,
+            "latitude": 0.0, "longitude": 0.0
+        }}})
+    }
+
+    #[test]
+    fn test_events_from_polls() {
+        // None means unreachable.
+        let relay: Arc<Mutex<Option<u8>>> = Arc::new(Mutex::new(Some(0)));
+        let state = relay.clone();
+        let transport = move |_: &str, frame: &[u8]| -> Result<Vec<u8>, PlugError> {
+            let request: serde_json::Value = serde_json::from_slice(&decrypt_payload(frame)).unwrap();
+            let relay_state = state.lock().unwrap().ok_or(PlugError::new("Connection error"))?;
+            let response = if request.get("emeter").is_some() {
+                json!({"emeter": {"get_realtime": {"power_mw": 2000, "err_code": 0}}})
+            } else {
+                sysinfo(relay_state)
+            };
+            Ok(encrypt_payload(response.to_string().into_bytes()))
+        };
+
+        let mut watcher = Watcher::new(Duration::from_secs(1));
+        watcher.add("heater", TpLinkDevice::with_transport("test", Arc::new(transport)));
+
+        let events = watcher.poll();
+        assert_eq!(events[0], Event::DeviceOnline { device: String::from("heater") });
+        assert!(matches!(&events[1], Event::PowerSample { reading, .. } if reading.power_w == 2.0));
+
+        *relay.lock().unwrap() = Some(1);
+        assert_eq!(watcher.poll()[0], Event::RelayChanged { device: String::from("heater"), on: true });
+
+        *relay.lock().unwrap() = None;
+        assert!(matches!(&watcher.poll()[..], [Event::DeviceOffline { .. }]));
+        assert!(watcher.poll().is_empty());
+
+        assert!(watcher.remove("heater") && !watcher.remove("heater"));
+        assert_eq!(watcher.names().count(), 0);
+    }
+
+    #[test]
+    fn test_state_survives_restart() {
+        let path = std::env::temp_dir().join(format!("hs110-watcher-{}.json", std::process::id()));
+        let plug = |relay_state: u8, sw_ver: &'static str| {
+            let transport = move |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+                let mut response = sysinfo(relay_state);
+                response["system"]["get_sysinfo"]["sw_ver"] = json!(sw_ver);
+                Ok(encrypt_payload(response.to_string().into_bytes()))
+            };
+            TpLinkDevice::with_transport("test", Arc::new(transport))
+        };
+
+        let mut watcher = Watcher::new(Duration::from_secs(1));
+        watcher.add("heater", plug(0, "1.0.8")).persist_to(&path).unwrap();
+        watcher.poll();
+        watcher.save().unwrap();
+
+        let mut restarted = Watcher::new(Duration::from_secs(1));
+        restarted.add("heater", plug(1, "1.0.10")).persist_to(&path).unwrap();
+        assert_eq!(restarted.last_known("heater").unwrap().relay_on, Some(false));
+        let events = restarted.poll();
+        assert!(events.contains(&Event::RelayChanged { device: String::from("heater"), on: true }));
+        assert!(events.contains(&Event::FieldChanged {
+            device: String::from("heater"),
+            change: FieldChange::Firmware { was: String::from("1.0.8"), now: String::from("1.0.10") },
+        }));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_service_stops_between_polls() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(sysinfo(1).to_string().into_bytes()))
+        };
+        let mut watcher = Watcher::new(Duration::from_secs(60));
+        watcher.add("heater", TpLinkDevice::with_transport("test", Arc::new(transport)));
+
+        let (mut service, events) = watcher.service();
+        service.start().unwrap();
+        let first = events.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(first, Event::DeviceOnline { device: String::from("heater") });
+
+        let stopping = std::time::Instant::now();
+        service.stop();
+        assert_eq!(service.join(), Health::Stopped);
+        assert!(stopping.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_spawned_watcher_stops_when_dropped_while_idle() {
+        let polls = Arc::new(Mutex::new(0));
+        let count = polls.clone();
+        let mut watcher = Watcher::new(Duration::from_millis(20));
+        watcher.add("heater", testing::answering("test", move |_| {
+            *count.lock().unwrap() += 1;
+            sysinfo(1)
+        }));
+
+        let events = watcher.spawn();
+        assert_eq!(events.recv_timeout(Duration::from_secs(2)).unwrap(), Event::DeviceOnline { device: String::from("heater") });
+        drop(events);
+        std::thread::sleep(STOP_LATENCY * 2);
+        let stopped_at = *polls.lock().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(*polls.lock().unwrap(), stopped_at);
+    }
+}
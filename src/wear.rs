@@ -0,0 +1,221 @@
+/*
+ * Counts relay switches per device. Mechanical relays are good for some tens
+ * of thousands of cycles, which heavy automation can use up in a few years:
+ *
+ *   let mut wear = RelayWear::new();
+ *   wear.soft_limit(50_000).persist_to("wear.json")?;
+ *   let (events, wear) = wear.attach(watcher.spawn().into());
+ *   engine.run(events);
+ *
+ * Every `RelayChanged` event is one switch, so presses on the button count as
+ * well as commands. Once a device reaches the soft limit an `AlertRaised`
+ * event is passed on after the switch that got it there, once per device,
+ * even if the limit is set below a count loaded from file. With `persist_to`
+ * the counts are saved after every switch, and a save that fails is passed on
+ * as a `CommandFailed` event.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::events::{Alert, Event};
+use crate::metrics::label;
+use crate::types::PlugError;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SwitchCount {
+    pub switches: u64,
+    /// When counting started for the device.
+    pub since: Option<DateTime<Utc>>,
+    pub last_switch: Option<DateTime<Utc>>,
+    /// Whether the soft limit has been reported for the device.
+    pub alerted: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RelayWear {
+    counts: BTreeMap<String, SwitchCount>,
+    soft_limit: Option<u64>,
+    path: Option<PathBuf>,
+}
+
+impl RelayWear {
+    pub fn new() -> RelayWear {
+        RelayWear::default()
+    }
+
+    /// Switches after which a device is reported as worn.
+    pub fn soft_limit(&mut self, switches: u64) -> &mut RelayWear {
+        self.soft_limit = Some(switches);
+        self
+    }
+
+    /// Loads the counts from `path`, if it exists, and saves there after every switch.
+    pub fn persist_to(&mut self, path: impl AsRef<Path>) -> Result<&mut RelayWear, PlugError> {
+        let path = path.as_ref();
+        self.counts = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        self.path = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Writes to a temporary file first, so a crash can't leave half a file behind.
+    pub fn save(&self) -> Result<(), PlugError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(&self.counts)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, device: &str) -> Option<&SwitchCount> {
+        self.counts.get(device)
+    }
+
+    pub fn switches(&self, device: &str) -> u64 {
+        self.get(device).map_or(0, |count| count.switches)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&str, &SwitchCount)> {
+        self.counts.iter().map(|(name, count)| (name.as_str(), count))
+    }
+
+    /// Counts a switch, returning an alert the first time the device is at or past the soft limit.
+    /// Doesn't save.
+    pub fn record(&mut self, device: &str, at: DateTime<Utc>) -> Option<Alert> {
+        let count = self.counts.entry(String::from(device)).or_default();
+        count.since.get_or_insert(at);
+        count.last_switch = Some(at);
+        count.switches += 1;
+        let limit = self.soft_limit.filter(|limit| !count.alerted && count.switches >= *limit)?;
+        count.alerted = true;
+        Some(Alert::RelayWear { switches: count.switches, limit })
+    }
+
+    pub fn handle(&mut self, event: &Event) -> Vec<Event> {
+        self.handle_at(event, Utc::now())
+    }
+
+    /// Records a switch and saves, returning the alert it raised and the save's failure, if any.
+    pub fn handle_at(&mut self, event: &Event, now: DateTime<Utc>) -> Vec<Event> {
+        let Event::RelayChanged { device, .. } = event else { return Vec::new() };
+        let alert = self.record(device, now).map(|alert| Event::AlertRaised { device: device.clone(), alert });
+        let failed = self.save().err().map(|e| Event::CommandFailed {
+            device: device.clone(),
+            command: String::from("save relay switch counts"),
+            reason: e.to_string(),
+        });
+        alert.into_iter().chain(failed).collect()
+    }
+
+    /// Passes `events` through, adding what each switch raised right after it. The counts stay
+    /// reachable through the returned handle, e.g. for `prometheus()`.
+    pub fn attach(self, events: Receiver<Event>) -> (Receiver<Event>, Arc<Mutex<RelayWear>>) {
+        let wear = Arc::new(Mutex::new(self));
+        let shared = wear.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for event in events {
+                let raised = match shared.lock() {
+                    Ok(mut wear) => wear.handle(&event),
+                    Err(_) => Vec::new(),
+                };
+                for event in std::iter::once(event).chain(raised) {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (rx, wear)
+    }
+
+    /// The counts in the Prometheus text exposition format, to serve next to `Metrics::prometheus`.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP hs1x0_relay_switches_total Relay switches counted per device.\n");
+        out.push_str("# TYPE hs1x0_relay_switches_total counter\n");
+        for (device, count) in &self.counts {
+            let _ = writeln!(out, "hs1x0_relay_switches_total{{device=\"{}\"}} {}", label(device), count.switches);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use chrono::{TimeZone, Utc};
+    use crate::events::{Alert, Event};
+    use super::RelayWear;
+
+    #[test]
+    fn test_counts_and_warns_once() {
+        let path = std::env::temp_dir().join(format!("hs110-wear-{}.json", std::process::id()));
+        let now = Utc.with_ymd_and_hms(2024, 6, 3, 18, 30, 0).unwrap();
+        let switched = |on| Event::RelayChanged { device: String::from("pump"), on };
+
+        let mut wear = RelayWear::new();
+        wear.soft_limit(3).persist_to(&path).unwrap();
+        assert_eq!(wear.handle_at(&switched(true), now), []);
+        assert_eq!(wear.handle_at(&Event::DeviceOnline { device: String::from("pump") }, now), []);
+        assert_eq!(wear.handle_at(&switched(false), now), []);
+        assert!(matches!(wear.handle_at(&switched(true), now)[..],
+                         [Event::AlertRaised { alert: Alert::RelayWear { switches: 3, limit: 3 }, .. }]));
+        assert_eq!(wear.handle_at(&switched(false), now), []);
+
+        let mut restarted = RelayWear::new();
+        restarted.persist_to(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restarted.switches("pump"), 4);
+        assert_eq!(restarted.get("pump").unwrap().since, Some(now));
+        assert!(restarted.prometheus().contains("hs1x0_relay_switches_total{device=\"pump\"} 4\n"));
+        // Already reported before the restart.
+        restarted.soft_limit(3);
+        assert!(restarted.record("pump", now).is_none());
+    }
+
+    #[test]
+    fn test_limit_below_the_loaded_count() {
+        let path = std::env::temp_dir().join(format!("hs110-wear-below-{}.json", std::process::id()));
+        let now = Utc.with_ymd_and_hms(2024, 6, 3, 18, 30, 0).unwrap();
+        let mut wear = RelayWear::new();
+        wear.persist_to(&path).unwrap();
+        for _ in 0..5 {
+            assert_eq!(wear.record("pump", now), None);
+        }
+        wear.save().unwrap();
+
+        let mut restarted = RelayWear::new();
+        restarted.soft_limit(3).persist_to(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restarted.record("pump", now), Some(Alert::RelayWear { switches: 6, limit: 3 }));
+        assert_eq!(restarted.record("pump", now), None);
+    }
+
+    #[test]
+    fn test_attach_shares_the_counts_and_reports_failed_saves() {
+        let (tx, events) = mpsc::channel();
+        let mut wear = RelayWear::new();
+        // A directory can't be written over, so every save fails.
+        wear.path = Some(std::env::temp_dir());
+        let (events, wear) = wear.attach(events);
+
+        tx.send(Event::RelayChanged { device: String::from("pump"), on: true }).unwrap();
+        drop(tx);
+        let received: Vec<Event> = events.iter().collect();
+        assert!(matches!(received[..], [Event::RelayChanged { .. }, Event::CommandFailed { .. }]));
+        assert_eq!(wear.lock().unwrap().switches("pump"), 1);
+    }
+}
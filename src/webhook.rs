@@ -0,0 +1,298 @@
+/*
+ * Webhook notifications, enabled with the `webhook` feature.
+ *
+ * Each event is POSTed as a JSON object such as
+ *
+ *   {"event": "relay_changed", "device": "heater", "on": true, "timestamp": "2024-06-03T07:30:00Z"}
+ *
+ * When a secret is configured the body is signed with HMAC-SHA256 and the hex
+ * digest is sent as `X-Hs110-Signature: sha256=<digest>`, the same scheme GitHub
+ * uses, so most receivers can verify it with stock code.
+ */
+
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::events::{Alert, Event, ExcursionKind};
+use crate::types::PlugError;
+
+pub const SIGNATURE_HEADER: &str = "X-Hs110-Signature";
+
+pub fn payload(event: &Event) -> Value {
+    let mut payload = match event {
+        Event::PowerSample { device, reading } => json!({
+            "event": "power_sample",
+            "device": device,
+            "voltage_v": reading.voltage_v,
+            "current_a": reading.current_a,
+            "power_w": reading.power_w,
+            "apparent_power_va": reading.apparent_power_va(),
+            "power_factor": reading.power_factor(),
+            "total_kwh": reading.total_kwh,
+        }),
+        Event::RelayChanged { device, on } => json!({
+            "event": "relay_changed",
+            "device": device,
+            "on": on,
+        }),
+        Event::FieldChanged { device, change } => {
+            let mut payload = json!(change);
+            payload["event"] = json!("field_changed");
+            payload["device"] = json!(device);
+            payload
+        }
+        Event::DeviceOnline { device } => json!({
+            "event": "device_online",
+            "device": device,
+        }),
+        Event::DeviceOffline { device, reason } => json!({
+            "event": "device_offline",
+            "device": device,
+            "reason": reason,
+        }),
+        Event::CommandFailed { device, command, reason } => json!({
+            "event": "command_failed",
+            "device": device,
+            "command": command,
+            "reason": reason,
+        }),
+        Event::AlertRaised { device, alert } => {
+            let mut payload = alert_payload(alert);
+            payload["event"] = json!("alert");
+            payload["device"] = json!(device);
+            payload
+        }
+    };
+
+    payload["timestamp"] = json!(Utc::now().to_rfc3339());
+    payload
+}
+
+fn excursion(kind: &ExcursionKind) -> &'static str {
+    match kind {
+        ExcursionKind::Sag => "sag",
+        ExcursionKind::Swell => "swell",
+    }
+}
+
+fn alert_payload(alert: &Alert) -> Value {
+    match alert {
+        Alert::PowerAnomaly { power_w, baseline_w, deviation } => json!({
+            "alert": "power_anomaly",
+            "power_w": power_w,
+            "baseline_w": baseline_w,
+            "deviation": deviation,
+        }),
+        Alert::VoltageExcursion { kind, voltage_v } => json!({
+            "alert": "voltage_excursion",
+            "kind": excursion(kind),
+            "voltage_v": voltage_v,
+        }),
+        Alert::VoltageRestored { kind, extreme_v, duration } => json!({
+            "alert": "voltage_restored",
+            "kind": excursion(kind),
+            "extreme_v": extreme_v,
+            "duration_s": duration.as_secs_f64(),
+        }),
+        Alert::RelayWear { switches, limit } => json!({
+            "alert": "relay_wear",
+            "switches": switches,
+            "limit": limit,
+        }),
+    }
+}
+
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Clone)]
+pub struct Webhook {
+    url: String,
+    secret: Option<Vec<u8>>,
+    retries: u32,
+    backoff: Duration,
+    agent: ureq::Agent,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Webhook {
+        Webhook {
+            url: String::from(url),
+            secret: None,
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(10)))
+                .build()
+                .into(),
+        }
+    }
+
+    pub fn secret(mut self, secret: &[u8]) -> Webhook {
+        self.secret = Some(secret.to_vec());
+        self
+    }
+
+    /// Retries failed deliveries `retries` times, doubling `backoff` after each attempt.
+    /// Client errors other than 429 are not retried.
+    pub fn retries(mut self, retries: u32, backoff: Duration) -> Webhook {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn post(&self, payload: &Value) -> Result<(), PlugError> {
+        let body = payload.to_string();
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+
+        loop {
+            let mut request = self.agent.post(self.url.as_str())
+                .header("Content-Type", "application/json");
+            if let Some(secret) = &self.secret {
+                request = request.header(SIGNATURE_HEADER,
+                                         format!("sha256={}", sign(secret, body.as_bytes())));
+            }
+
+            let error = match request.send(body.as_str()) {
+                Ok(_) => return Ok(()),
+                Err(ureq::Error::StatusCode(code)) if (400..500).contains(&code) && code != 429 =>
+                    return Err(PlugError::new(
+                        format!("Webhook {} rejected the request with status {}", self.url, code).as_str())),
+                Err(e) => e,
+            };
+
+            if attempt >= self.retries {
+                return Err(PlugError::new(
+                    format!("Webhook {} failed after {} attempts: {}", self.url, attempt + 1, error).as_str()));
+            }
+            attempt += 1;
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    pub fn notify(&self, event: &Event) -> Result<(), PlugError> {
+        self.post(&payload(event))
+    }
+}
+
+/// Forwards events to a set of webhooks. Power samples are skipped unless asked for, since
+/// they arrive on every poll.
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    include_samples: bool,
+}
+
+impl Default for Webhooks {
+    fn default() -> Webhooks {
+        Webhooks::new()
+    }
+}
+
+impl Webhooks {
+    pub fn new() -> Webhooks {
+        Webhooks {
+            hooks: Vec::new(),
+            include_samples: false,
+        }
+    }
+
+    pub fn add(&mut self, hook: Webhook) -> &mut Webhooks {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn include_samples(&mut self, include: bool) -> &mut Webhooks {
+        self.include_samples = include;
+        self
+    }
+
+    /// Delivers `event` to every webhook, returning the failures.
+    pub fn notify(&self, event: &Event) -> Vec<PlugError> {
+        if !self.include_samples && matches!(event, Event::PowerSample { .. }) {
+            return Vec::new();
+        }
+
+        let payload = payload(event);
+        self.hooks.iter()
+            .filter_map(|hook| hook.post(&payload).err())
+            .collect()
+    }
+
+    /// Delivers events until the sender side hangs up.
+    pub fn forward(&self, events: Receiver<Event>) {
+        for event in events {
+            self.notify(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use crate::events::Event;
+    use super::{sign, Webhook, SIGNATURE_HEADER};
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231, test case 2.
+        assert_eq!(sign(b"Jefe", b"what do ya want for nothing?"),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn test_retries_until_delivered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut deliveries = Vec::new();
+            for (attempt, stream) in listener.incoming().take(2).enumerate() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut headers = Vec::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    headers.push(line);
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                deliveries.push((headers, String::from_utf8(body).unwrap()));
+
+                let status = if attempt == 0 { "503 Service Unavailable" } else { "204 No Content" };
+                write!(reader.get_mut(), "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                       status).unwrap();
+            }
+            deliveries
+        });
+
+        let hook = Webhook::new(&url).secret(b"s3cret").retries(2, Duration::from_millis(10));
+        hook.notify(&Event::RelayChanged { device: String::from("heater"), on: true }).unwrap();
+
+        let deliveries = server.join().unwrap();
+        assert_eq!(deliveries.len(), 2);
+        let (headers, body) = &deliveries[1];
+        assert!(body.contains(r#""event":"relay_changed""#));
+        let expected = format!("{}: sha256={}", SIGNATURE_HEADER, sign(b"s3cret", body.as_bytes()));
+        assert!(headers.iter().any(|h| h.trim().eq_ignore_ascii_case(&expected)));
+    }
+}
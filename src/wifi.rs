@@ -0,0 +1,98 @@
+/*
+ * Helpers for moving plugs between access points. HS1x0 radios are 2.4 GHz
+ * only, so APs reported on 5 GHz channels are never recommended.
+ */
+
+use crate::types::{NetifGetScaninfoItem, NetifGetStainfoResponse};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Security {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Unknown(i64),
+}
+
+impl From<i64> for Security {
+    fn from(key_type: i64) -> Security {
+        match key_type {
+            0 => Security::Open,
+            1 => Security::Wep,
+            2 => Security::Wpa,
+            3 => Security::Wpa2,
+            other => Security::Unknown(other),
+        }
+    }
+}
+
+impl NetifGetScaninfoItem {
+    pub fn security(&self) -> Security {
+        Security::from(self.key_type)
+    }
+
+    /// Whether the plug can join it. Without a channel the AP is given the benefit of the doubt.
+    pub fn is_compatible(&self) -> bool {
+        !matches!(self.security(), Security::Unknown(_)) && self.channel.is_none_or(|c| (1..=14).contains(&c))
+    }
+}
+
+impl NetifGetStainfoResponse {
+    pub fn security(&self) -> Security {
+        Security::from(self.key_type)
+    }
+}
+
+/// The compatible AP with the strongest signal, optionally only among those named `ssid`.
+/// APs that didn't report a signal strength are only picked if none did.
+pub fn recommend<'a>(aps: &'a [NetifGetScaninfoItem], ssid: Option<&str>) -> Option<&'a NetifGetScaninfoItem> {
+    let candidates = aps.iter()
+        .filter(|ap| ap.is_compatible() && ssid.is_none_or(|ssid| ap.ssid == ssid));
+    let first = candidates.clone().next();
+    candidates.filter(|ap| ap.rssi.is_some()).max_by_key(|ap| ap.rssi).or(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::TpLinkDevice;
+    use crate::protocol::encrypt_payload;
+    use crate::types::{NetifGetScaninfoResponse, PlugError};
+    use super::{recommend, Security};
+
+    #[test]
+    fn test_recommend() {
+        let scan: NetifGetScaninfoResponse = serde_json::from_str(r#"{"ap_list":[
+            {"ssid":"home","key_type":3,"channel":6,"rssi":-78},
+            {"ssid":"home","key_type":3,"channel":36,"rssi":-40},
+            {"ssid":"home","key_type":3,"channel":11,"rssi":-55},
+            {"ssid":"guest","key_type":0,"channel":1,"rssi":-50}],"err_code":0}"#).unwrap();
+
+        let best = recommend(&scan.ap_list, Some("home")).unwrap();
+        assert_eq!((best.channel, best.security()), (Some(11), Security::Wpa2));
+        assert_eq!(recommend(&scan.ap_list, None).unwrap().ssid, "guest");
+        assert!(recommend(&scan.ap_list, Some("office")).is_none());
+    }
+
+    #[test]
+    fn test_shallow_scan() {
+        let scan: NetifGetScaninfoResponse = serde_json::from_str(
+            r#"{"ap_list":[{"ssid":"home","key_type":3},{"ssid":"old","key_type":9}],"err_code":0}"#).unwrap();
+        assert_eq!(recommend(&scan.ap_list, None).unwrap().ssid, "home");
+    }
+
+    #[test]
+    fn test_current_wifi() {
+        let transport = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(
+                br#"{"netif":{"get_stainfo":{"ssid":"home","key_type":3,"rssi":-61,"err_code":0}}}"#.to_vec()))
+        };
+        let wifi = TpLinkDevice::with_transport("plug", Arc::new(transport)).current_wifi().unwrap();
+        assert_eq!((wifi.ssid.as_str(), wifi.security(), wifi.rssi), ("home", Security::Wpa2, Some(-61)));
+
+        let unsupported = |_: &str, _: &[u8]| -> Result<Vec<u8>, PlugError> {
+            Ok(encrypt_payload(br#"{"netif":{"err_code":-2,"err_msg":"member not support"}}"#.to_vec()))
+        };
+        assert!(TpLinkDevice::with_transport("plug", Arc::new(unsupported)).current_wifi().is_err());
+    }
+}